@@ -1,17 +1,26 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use crate::cuckoo_filter::*;
+use crate::full_index::*;
 use crate::hash::*;
 use crate::hash_index::*;
 use crate::iovec::*;
+use crate::parent::*;
 use crate::paths;
+use crate::similarity::*;
 use crate::slab::*;
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::num::NonZeroUsize;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 pub const SLAB_SIZE_TARGET: usize = 4 * 1024 * 1024;
 
+// New full index entries are written out as a run once this many have
+// accumulated (or when the pack completes).
+const FULL_INDEX_RUN_ENTRIES: usize = 1 << 20;
+
 pub struct Data {
     seen: CuckooFilter,
     hashes: lru::LruCache<u32, ByHash>,
@@ -23,10 +32,36 @@ pub struct Data {
     current_entries: usize,
     current_index: IndexBuilder,
 
+    // The number of slabs when opened.  The filter can only be rebuilt
+    // from the hashes file until something's been added, or once all
+    // the new slabs have been written.
+    initial_slabs: u32,
+
+    // Entries left out of the filter because it was full, and had
+    // MAX_LEVELS levels, part way through a pack.
+    filter_dropped: usize,
+
     data_buf: Vec<u8>,
     hashes_buf: Vec<u8>,
 
     slabs: lru::LruCache<u32, ByIndex>,
+
+    // Only present if the archive's index mode is 'disk'.
+    full_index: Option<FullIndex>,
+    full_index_pending: Vec<IndexEntry>,
+    full_index_end: u32,
+    prefetched: BTreeMap<Hash256, Option<(u32, u32)>>,
+
+    // Only present if delta compression is enabled.
+    similarity: Option<SimilarityIndex>,
+
+    // Read only archives consulted for data this one doesn't hold.
+    // Archive id n refers to parents[n - 1].
+    parents: Vec<ParentArchive>,
+
+    // Opened by a reader; nothing may be added, and nothing is written
+    // back when dropped.
+    read_only: bool,
 }
 
 fn complete_slab_(slab: &mut SlabFile, buf: &mut Vec<u8>) -> Result<()> {
@@ -35,6 +70,32 @@ fn complete_slab_(slab: &mut SlabFile, buf: &mut Vec<u8>) -> Result<()> {
     Ok(())
 }
 
+pub fn calculate_offsets(
+    offset: u32,
+    nr_entries: u32,
+    info: &ByIndex,
+    partial: Option<(u32, u32)>,
+) -> (usize, usize) {
+    let (data_begin, data_end) = if nr_entries == 1 {
+        let (data_begin, data_end, _expected_hash) = info.get(offset as usize).unwrap();
+        (*data_begin as usize, *data_end as usize)
+    } else {
+        let (data_begin, _data_end, _expected_hash) = info.get(offset as usize).unwrap();
+        let (_data_begin, data_end, _expected_hash) = info
+            .get((offset as usize) + (nr_entries as usize) - 1)
+            .unwrap();
+        (*data_begin as usize, *data_end as usize)
+    };
+
+    if let Some((begin, end)) = partial {
+        let data_end = data_begin + end as usize;
+        let data_begin = data_begin + begin as usize;
+        (data_begin, data_end)
+    } else {
+        (data_begin, data_end)
+    }
+}
+
 pub fn complete_slab(slab: &mut SlabFile, buf: &mut Vec<u8>, threshold: usize) -> Result<bool> {
     if buf.len() > threshold {
         complete_slab_(slab, buf)?;
@@ -67,42 +128,223 @@ impl Data {
             data_file,
             hashes_file,
             current_slab: nr_slabs,
+            initial_slabs: nr_slabs,
+            filter_dropped: 0,
             current_index: IndexBuilder::with_capacity(1024), // FIXME: estimate
             current_entries: 0,
             data_buf: Vec::new(),
             hashes_buf: Vec::new(),
             slabs,
+            full_index: None,
+            full_index_pending: Vec::new(),
+            full_index_end: 0,
+            prefetched: BTreeMap::new(),
+            similarity: None,
+            parents: Vec::new(),
+            read_only: false,
         })
     }
 
-    fn get_info(&mut self, slab: u32) -> Result<&ByIndex> {
-        self.slabs.try_get_or_insert(slab, || {
-            let mut hf = self.hashes_file.lock().unwrap();
-            let hashes = hf.read(slab)?;
-            ByIndex::new(hashes)
-        })
+    /// Opens the archive for reading only.  The slab files must have been
+    /// opened without writers, and nothing is written back when the Data
+    /// is dropped, so readers may share an archive with a pack, or use
+    /// one they can't write to.
+    pub fn open_read_only(
+        data_file: SlabFile,
+        hashes_file: Arc<Mutex<SlabFile>>,
+        slab_capacity: usize,
+    ) -> Result<Self> {
+        let mut data = Self::new(data_file, hashes_file, slab_capacity)?;
+        data.read_only = true;
+        Ok(data)
     }
 
-    pub fn ensure_extra_capacity(&mut self, blocks: usize) -> Result<()> {
-        if self.seen.capacity() < self.seen.len() + blocks {
-            self.rebuild_index(self.seen.len() + blocks)?;
-            eprintln!("resized index to {}", self.seen.capacity());
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!("archive was opened read only"));
+        }
+        Ok(())
+    }
+
+    /// Use the on disk full hash index for lookups, and keep it up to
+    /// date.  Any slabs not yet covered by the index are added first.
+    pub fn enable_full_index(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let mut index = FullIndex::open(paths::full_index_path())?;
+
+        let covered = index.covered_until();
+        let mut begin = covered;
+        let mut entries = Vec::new();
+        for s in covered..self.current_slab {
+            let info = {
+                let mut hashes_file = self.hashes_file.lock().unwrap();
+                ByIndex::new(hashes_file.read(s)?)?
+            };
+
+            for i in 0..info.len() {
+                let (_, _, hash) = info.get(i).unwrap();
+                entries.push(IndexEntry {
+                    hash: *hash,
+                    slab: s,
+                    offset: i as u32,
+                });
+            }
+
+            if entries.len() >= FULL_INDEX_RUN_ENTRIES {
+                index.add_run(std::mem::take(&mut entries), begin, s + 1)?;
+                begin = s + 1;
+            }
+        }
+
+        if begin < self.current_slab {
+            index.add_run(entries, begin, self.current_slab)?;
         }
 
+        self.full_index_end = self.current_slab;
+        self.full_index = Some(index);
         Ok(())
     }
 
-    fn get_hash_index(&mut self, slab: u32) -> Result<&ByHash> {
-        // the current slab is not inserted into the self.hashes
-        assert!(slab != self.current_slab);
+    // Writes the entries for all completed slabs to the full index.
+    fn flush_full_index(&mut self, force: bool) -> Result<()> {
+        if let Some(index) = &mut self.full_index {
+            if self.full_index_end < self.current_slab
+                && (force || self.full_index_pending.len() >= FULL_INDEX_RUN_ENTRIES)
+            {
+                let entries = std::mem::take(&mut self.full_index_pending);
+                index.add_run(entries, self.full_index_end, self.current_slab)?;
+                self.full_index_end = self.current_slab;
+            }
+        }
 
-        self.hashes.try_get_or_insert(slab, || {
-            let mut hashes_file = self.hashes_file.lock().unwrap();
-            let buf = hashes_file.read(slab)?;
-            ByHash::new(buf)
+        Ok(())
+    }
+
+    // Is this slab covered by the full index?
+    fn in_full_index(&self, slab: u32) -> bool {
+        self.full_index.is_some() && slab < self.full_index_end
+    }
+
+    /// Looks up, in a single pass over the full index, any of these
+    /// hashes that the cuckoo filter can't rule out.  The results are
+    /// used by subsequent calls to is_known().  Does nothing if the full
+    /// index isn't enabled.
+    pub fn prefetch(&mut self, hashes: &[Hash256]) -> Result<()> {
+        if self.full_index.is_none() {
+            return Ok(());
+        }
+
+        self.prefetched.clear();
+        let mut wanted = Vec::new();
+        for h in hashes {
+            if let InsertResult::PossiblyPresent(s) = self.seen.test(hash_le_u64(h))? {
+                if self.in_full_index(s) {
+                    wanted.push(*h);
+                }
+            }
+        }
+
+        let results = self.full_index.as_mut().unwrap().lookup_batch(&wanted)?;
+        for (h, r) in wanted.into_iter().zip(results) {
+            self.prefetched.insert(h, r);
+        }
+
+        Ok(())
+    }
+
+    fn full_index_lookup(&mut self, h: &Hash256) -> Result<Option<(u32, u32)>> {
+        if let Some(r) = self.prefetched.get(h) {
+            return Ok(*r);
+        }
+
+        self.full_index.as_mut().unwrap().lookup(h)
+    }
+
+    /// Load the similarity index, so near-duplicate chunks can be found.
+    pub fn enable_similarity(&mut self) -> Result<()> {
+        let path = paths::similarity_index_path();
+        let index = if path.exists() {
+            SimilarityIndex::read(path)?
+        } else {
+            SimilarityIndex::default()
+        };
+        self.similarity = Some(index);
+        Ok(())
+    }
+
+    /// Finds a stored chunk that resembles the sketch, returning its
+    /// slab and offset.
+    pub fn similar_chunk(&self, sketch: &Sketch) -> Option<(u32, u32)> {
+        self.similarity.as_ref().and_then(|s| s.lookup(sketch))
+    }
+
+    /// The contents of a chunk returned by similar_chunk().  Slabs that
+    /// have been completed, but not yet written, can't be read so None is
+    /// returned.
+    pub fn similar_data(&mut self, loc: (u32, u32)) -> Result<Option<Vec<u8>>> {
+        if loc.0 == self.current_slab {
+            if let Some((begin, len)) = self.current_index.get(loc.1) {
+                return Ok(Some(self.data_buf[begin..begin + len].to_vec()));
+            }
+        } else if self.slab_readable(loc.0) {
+            let (data, begin, end) = self.data_get(0, loc.0, loc.1, 1, None)?;
+            return Ok(Some(data[begin..end].to_vec()));
+        }
+
+        Ok(None)
+    }
+
+    fn slab_readable(&self, slab: u32) -> bool {
+        let hashes_file = self.hashes_file.lock().unwrap();
+        (slab as usize) < self.data_file.get_nr_slabs()
+            && (slab as usize) < hashes_file.get_nr_slabs()
+    }
+
+    /// Records a newly stored chunk as a candidate base for deltas.
+    pub fn add_similar(&mut self, sketch: &Sketch, loc: (u32, u32)) {
+        if let Some(index) = &mut self.similarity {
+            index.insert(sketch, loc);
+        }
+    }
+
+    /// Open the archive's parents, in the order given in its config.
+    pub fn open_parents(&mut self, parents: &[String], cache_nr_entries: usize) -> Result<()> {
+        for p in parents {
+            self.parents
+                .push(ParentArchive::open(Path::new(p), cache_nr_entries)?);
+        }
+        Ok(())
+    }
+
+    /// Looks for the hash in the parent archives, returning the archive
+    /// id, slab and offset of the first that holds it.
+    pub fn parent_lookup(&mut self, h: &Hash256) -> Result<Option<(u8, u32, u32)>> {
+        for (i, p) in self.parents.iter_mut().enumerate() {
+            if let Some((slab, offset)) = p.is_known(h)? {
+                return Ok(Some((i as u8 + 1, slab, offset)));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn filter(&self) -> &CuckooFilter {
+        &self.seen
+    }
+
+    pub fn full_index(&self) -> Option<&FullIndex> {
+        self.full_index.as_ref()
+    }
+
+    fn get_info(&mut self, slab: u32) -> Result<&ByIndex> {
+        self.slabs.try_get_or_insert(slab, || {
+            let mut hf = self.hashes_file.lock().unwrap();
+            let hashes = hf.read(slab)?;
+            ByIndex::new(hashes)
         })
     }
 
+    // Rebuilds the filter as a single level by re-reading every hash in
+    // the archive.
     fn rebuild_index(&mut self, mut new_capacity: usize) -> Result<()> {
         loop {
             let mut seen = CuckooFilter::with_capacity(new_capacity);
@@ -136,6 +378,39 @@ impl Data {
         }
     }
 
+    /// Reserves room in the filter for the entries a pack is expected
+    /// to add.  Should be called before anything is added.
+    pub fn ensure_extra_capacity(&mut self, blocks: usize) -> Result<()> {
+        self.check_writable()?;
+        let needed = self.seen.len() + blocks;
+        if self.seen.capacity() < needed {
+            let untouched = self.current_entries == 0 && self.current_slab == self.initial_slabs;
+            if untouched && self.seen.nr_levels() + 1 >= MAX_LEVELS {
+                // Another level would leave none spare in case the
+                // estimate is short, so merge them all into one while
+                // every hash is on disk, with room to double before it
+                // next needs to grow.
+                self.rebuild_index(needed * 2)?;
+            } else if self.seen.can_grow() {
+                self.seen.grow(needed - self.seen.capacity());
+            }
+            eprintln!("resized index to {}", self.seen.capacity());
+        }
+
+        Ok(())
+    }
+
+    fn get_hash_index(&mut self, slab: u32) -> Result<&ByHash> {
+        // the current slab is not inserted into the self.hashes
+        assert!(slab != self.current_slab);
+
+        self.hashes.try_get_or_insert(slab, || {
+            let mut hashes_file = self.hashes_file.lock().unwrap();
+            let buf = hashes_file.read(slab)?;
+            ByHash::new(buf)
+        })
+    }
+
     fn complete_data_slab(&mut self) -> Result<()> {
         if complete_slab(&mut self.data_file, &mut self.data_buf, 0)? {
             let mut builder = IndexBuilder::with_capacity(1024); // FIXME: estimate properly
@@ -147,8 +422,11 @@ impl Data {
 
             let mut hashes_file = self.hashes_file.lock().unwrap();
             complete_slab_(&mut hashes_file, &mut self.hashes_buf)?;
+            drop(hashes_file);
             self.current_slab += 1;
             self.current_entries = 0;
+
+            self.flush_full_index(false)?;
         }
         Ok(())
     }
@@ -157,29 +435,50 @@ impl Data {
     pub fn data_add(&mut self, h: Hash256, iov: &IoVec, len: u64) -> Result<((u32, u32), u64)> {
         // There is an inherent race condition between checking if we have it and adding it,
         // check before we add when this functionality ends up on a server side.
+        self.check_writable()?;
         if let Some(location) = self.is_known(&h)? {
             return Ok((location, 0));
         }
 
-        // Add entry to cuckoo filter, not checking return value as we could get indication that
-        // it's "PossiblyPresent" when our logical expectation is "Inserted".
-        let ts_result = self.seen.test_and_set(hash_le_u64(&h), self.current_slab);
-        if ts_result.is_err() {
-            // Exceeded capacity, rebuild with more capacity.
-            let s = self.seen.capacity() * 2;
-            self.rebuild_index(s)?;
-        }
-
+        // Complete the slab first, so the filter records the slab the entry
+        // actually ends up in.
         if self.data_buf.len() as u64 + len > SLAB_SIZE_TARGET as u64 {
             self.complete_data_slab()?;
         }
 
+        // Add entry to cuckoo filter, not checking return value as we could get indication that
+        // it's "PossiblyPresent" when our logical expectation is "Inserted".
+        let mini_hash = hash_le_u64(&h);
+        if self
+            .seen
+            .test_and_set(mini_hash, self.current_slab)
+            .is_err()
+        {
+            if self.seen.can_grow() {
+                // Exceeded capacity, add another (minimum sized) level to the filter and retry.
+                self.seen.grow(0);
+                self.seen.test_and_set(mini_hash, self.current_slab)?;
+            } else {
+                // The hashes can't be re-read until this pack's slabs are
+                // written, so leave the entry out for now; this only costs
+                // dedup.  The filter is rebuilt when the archive is closed.
+                self.filter_dropped += 1;
+            }
+        }
+
         let r = (self.current_slab, self.current_entries as u32);
         for v in iov {
             self.data_buf.extend_from_slice(v);
         }
         self.current_entries += 1;
         self.current_index.insert(h, len as usize);
+        if self.full_index.is_some() {
+            self.full_index_pending.push(IndexEntry {
+                hash: h,
+                slab: r.0,
+                offset: r.1,
+            });
+        }
         Ok((r, len))
     }
 
@@ -196,6 +495,8 @@ impl Data {
                     } else {
                         None
                     }
+                } else if self.in_full_index(s) {
+                    self.full_index_lookup(h)?
                 } else {
                     let hi = self.get_hash_index(s)?;
                     hi.lookup(h).map(|offset| (s, offset as u32))
@@ -216,41 +517,59 @@ impl Data {
         (self.data_file.get_file_size(), hashes_written)
     }
 
-    fn calculate_offsets(
+    fn parent(&mut self, archive: u8) -> Result<&mut ParentArchive> {
+        self.parents
+            .get_mut(archive as usize - 1)
+            .ok_or_else(|| anyhow!("stream refers to unknown archive {}", archive))
+    }
+
+    /// The length of a run of data entries.  Only the hashes slab is
+    /// read, so this is much cheaper than data_get.
+    pub fn data_len(
+        &mut self,
+        archive: u8,
+        slab: u32,
         offset: u32,
         nr_entries: u32,
-        info: &ByIndex,
-        partial: Option<(u32, u32)>,
-    ) -> (usize, usize) {
-        let (data_begin, data_end) = if nr_entries == 1 {
-            let (data_begin, data_end, _expected_hash) = info.get(offset as usize).unwrap();
-            (*data_begin as usize, *data_end as usize)
-        } else {
-            let (data_begin, _data_end, _expected_hash) = info.get(offset as usize).unwrap();
-            let (_data_begin, data_end, _expected_hash) = info
-                .get((offset as usize) + (nr_entries as usize) - 1)
-                .unwrap();
-            (*data_begin as usize, *data_end as usize)
-        };
+    ) -> Result<u64> {
+        if archive > 0 {
+            return self.parent(archive)?.data_len(slab, offset, nr_entries);
+        }
 
-        if let Some((begin, end)) = partial {
-            let data_end = data_begin + end as usize;
-            let data_begin = data_begin + begin as usize;
-            (data_begin, data_end)
-        } else {
-            (data_begin, data_end)
+        let info = self.get_info(slab)?;
+        let (data_begin, data_end) = calculate_offsets(offset, nr_entries, info, None);
+        Ok((data_end - data_begin) as u64)
+    }
+
+    /// The hash of a single stored chunk.
+    pub fn data_hash(&mut self, archive: u8, slab: u32, offset: u32) -> Result<Hash256> {
+        if archive > 0 {
+            return self.parent(archive)?.data_hash(slab, offset);
         }
+
+        let info = self.get_info(slab)?;
+        let (_, _, h) = info
+            .get(offset as usize)
+            .ok_or_else(|| anyhow!("no entry {} in data slab {}", offset, slab))?;
+        Ok(*h)
     }
 
     pub fn data_get(
         &mut self,
+        archive: u8,
         slab: u32,
         offset: u32,
         nr_entries: u32,
         partial: Option<(u32, u32)>,
     ) -> Result<(Arc<Vec<u8>>, usize, usize)> {
+        if archive > 0 {
+            return self
+                .parent(archive)?
+                .data_get(slab, offset, nr_entries, partial);
+        }
+
         let info = self.get_info(slab)?;
-        let (data_begin, data_end) = Self::calculate_offsets(offset, nr_entries, info, partial);
+        let (data_begin, data_end) = calculate_offsets(offset, nr_entries, info, partial);
         let data = self.data_file.read(slab)?;
 
         Ok((data, data_begin, data_end))
@@ -260,27 +579,46 @@ impl Data {
     // we received the newly created stream file for a pack operation.  The reason this is done is
     // until you complete a slab, you cannot locate it in the data_get path for unpack operation.
     pub fn flush(&mut self) -> Result<()> {
+        self.check_writable()?;
         self.complete_data_slab()
     }
 
     fn sync_and_close(&mut self) {
         self.complete_data_slab()
             .expect("Data.drop: complete_data_slab error!");
-        let mut hashes_file = self.hashes_file.lock().unwrap();
-        hashes_file
+        self.flush_full_index(true)
+            .expect("Data.drop: flush_full_index error!");
+        self.hashes_file
+            .lock()
+            .unwrap()
             .close()
             .expect("Data.drop: hashes_file.close() error!");
         self.data_file
             .close()
             .expect("Data.drop: data_file.close() error!");
+        if self.filter_dropped > 0 {
+            // Every hash is on disk now, so the entries that didn't fit
+            // can be recovered.
+            let needed = self.seen.len() + self.filter_dropped;
+            self.rebuild_index(needed * 2)
+                .expect("Data.drop: rebuild_index error!");
+        }
         self.seen
             .write(paths::index_path())
             .expect("Data.drop: seen.write() error!");
+        if let Some(index) = &self.similarity {
+            index
+                .write(paths::similarity_index_path())
+                .expect("Data.drop: similarity.write() error!");
+        }
     }
 }
 
 impl Drop for Data {
     fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
         self.sync_and_close();
     }
 }
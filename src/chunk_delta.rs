@@ -0,0 +1,253 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+//-----------------------------------------
+
+// Encodes a chunk as a sequence of copies from a similar, base, chunk
+// and literal inserts.
+//
+// delta := <target len varint> <op>*
+// op := <0u8> <len varint> <bytes> |      insert
+//       <1u8> <offset varint> <len varint> copy from base
+
+const OP_INSERT: u8 = 0;
+const OP_COPY: u8 = 1;
+
+// Matches shorter than this cost more to encode than they save.
+const MIN_MATCH: usize = 16;
+
+// Only every STRIDE'th position of the base is indexed.
+const STRIDE: usize = 4;
+
+// The splitter never produces chunks larger than 8 times the block
+// size, which is at most 1M.
+const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+fn write_varint(w: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        w.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    w.push(v as u8);
+}
+
+fn read_varint(input: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut v = 0u64;
+    let mut shift = 0;
+    loop {
+        let b = *input
+            .get(*pos)
+            .ok_or_else(|| anyhow!("truncated chunk delta"))?;
+        *pos += 1;
+        if shift > 63 {
+            return Err(anyhow!("bad varint in chunk delta"));
+        }
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+        shift += 7;
+    }
+}
+
+fn key(data: &[u8]) -> u64 {
+    let mut k = [0u8; 8];
+    k.copy_from_slice(&data[0..8]);
+    let lo = u64::from_le_bytes(k);
+    k.copy_from_slice(&data[8..16]);
+    let hi = u64::from_le_bytes(k);
+    (lo ^ hi.rotate_left(29)).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+}
+
+fn emit_insert(w: &mut Vec<u8>, data: &[u8]) {
+    if !data.is_empty() {
+        w.push(OP_INSERT);
+        write_varint(w, data.len() as u64);
+        w.extend_from_slice(data);
+    }
+}
+
+fn emit_copy(w: &mut Vec<u8>, offset: usize, len: usize) {
+    w.push(OP_COPY);
+    write_varint(w, offset as u64);
+    write_varint(w, len as u64);
+}
+
+/// Encodes target as a delta against base.
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut w = Vec::new();
+    write_varint(&mut w, target.len() as u64);
+
+    if base.len() < MIN_MATCH || target.len() < MIN_MATCH {
+        emit_insert(&mut w, target);
+        return w;
+    }
+
+    let mut index: HashMap<u64, usize> = HashMap::with_capacity(base.len() / STRIDE);
+    let mut p = 0;
+    while p + MIN_MATCH <= base.len() {
+        index.entry(key(&base[p..])).or_insert(p);
+        p += STRIDE;
+    }
+
+    let mut literal_begin = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= target.len() {
+        let candidate = index
+            .get(&key(&target[i..]))
+            .filter(|p| base[**p..**p + MIN_MATCH] == target[i..i + MIN_MATCH]);
+
+        if let Some(p) = candidate {
+            // extend backwards into the pending literal, and forwards
+            let mut b = *p;
+            let mut t = i;
+            while t > literal_begin && b > 0 && base[b - 1] == target[t - 1] {
+                b -= 1;
+                t -= 1;
+            }
+
+            let mut len = (i - t) + MIN_MATCH;
+            while b + len < base.len() && t + len < target.len() && base[b + len] == target[t + len]
+            {
+                len += 1;
+            }
+
+            emit_insert(&mut w, &target[literal_begin..t]);
+            emit_copy(&mut w, b, len);
+            i = t + len;
+            literal_begin = i;
+        } else {
+            i += 1;
+        }
+    }
+    emit_insert(&mut w, &target[literal_begin..]);
+
+    w
+}
+
+/// Rebuilds the target from the base and a delta.
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    let target_len = read_varint(delta, &mut pos)? as usize;
+    let mut r = Vec::with_capacity(std::cmp::min(target_len, MAX_CHUNK_SIZE));
+
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        match op {
+            OP_INSERT => {
+                let len = read_varint(delta, &mut pos)? as usize;
+                let data = pos
+                    .checked_add(len)
+                    .and_then(|end| delta.get(pos..end))
+                    .ok_or_else(|| anyhow!("truncated chunk delta"))?;
+                r.extend_from_slice(data);
+                pos += len;
+            }
+            OP_COPY => {
+                let offset = read_varint(delta, &mut pos)? as usize;
+                let len = read_varint(delta, &mut pos)? as usize;
+                let data = offset
+                    .checked_add(len)
+                    .and_then(|end| base.get(offset..end))
+                    .ok_or_else(|| anyhow!("chunk delta copies beyond end of base"))?;
+                r.extend_from_slice(data);
+            }
+            _ => return Err(anyhow!("bad chunk delta op {}", op)),
+        }
+    }
+
+    if r.len() != target_len {
+        return Err(anyhow!(
+            "chunk delta produced {} bytes, expected {}",
+            r.len(),
+            target_len
+        ));
+    }
+
+    Ok(r)
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod chunk_delta_tests {
+    use super::*;
+    use rand::prelude::*;
+    use rand_chacha::ChaCha20Rng;
+
+    fn rand_buffer(rng: &mut ChaCha20Rng, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        rng.fill_bytes(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let base = rand_buffer(&mut rng, 8192);
+
+        // A few bytes stamped over a copy of the base.
+        let mut target = base.clone();
+        for _ in 0..4 {
+            let offset = rng.gen_range(0..target.len() - 8);
+            target[offset..offset + 8].copy_from_slice(&rand_buffer(&mut rng, 8));
+        }
+
+        let delta = encode(&base, &target);
+        assert!(delta.len() < target.len() / 10);
+        assert_eq!(apply(&base, &delta)?, target);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insertions() -> Result<()> {
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let base = rand_buffer(&mut rng, 4096);
+
+        let mut target = base[..1000].to_vec();
+        target.extend_from_slice(&rand_buffer(&mut rng, 37));
+        target.extend_from_slice(&base[1000..]);
+
+        let delta = encode(&base, &target);
+        assert!(delta.len() < 128);
+        assert_eq!(apply(&base, &delta)?, target);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unrelated() -> Result<()> {
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let base = rand_buffer(&mut rng, 4096);
+        for len in [0, 1, 15, 16, 4096] {
+            let target = rand_buffer(&mut rng, len);
+            let delta = encode(&base, &target);
+            assert_eq!(apply(&base, &delta)?, target);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let base = vec![0u8; 64];
+        let mut delta = Vec::new();
+        write_varint(&mut delta, 10);
+        emit_copy(&mut delta, 60, 10);
+        assert!(apply(&base, &delta).is_err());
+
+        // Lengths that overflow, and a huge target length, are errors
+        // rather than panics or aborts.
+        let mut delta = Vec::new();
+        write_varint(&mut delta, u64::MAX);
+        emit_copy(&mut delta, 1, usize::MAX);
+        assert!(apply(&base, &delta).is_err());
+
+        let mut delta = Vec::new();
+        write_varint(&mut delta, 10);
+        delta.push(OP_INSERT);
+        write_varint(&mut delta, u64::MAX);
+        assert!(apply(&base, &delta).is_err());
+    }
+}
+
+//-----------------------------------------
@@ -6,13 +6,14 @@ use std::io;
 use std::io::Take;
 use std::ops::Range;
 use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 
 use crate::run_iter::*;
 
 //-----------------------------------------
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Chunk {
     Mapped(Vec<u8>),
     Unmapped(u64),
@@ -78,6 +79,175 @@ impl Iterator for ThickChunker {
 
 //-----------------------------------------
 
+// Holes shorter than this are read as data, to avoid fragmenting the
+// stream with lots of tiny unmapped runs.
+const MIN_HOLE: u64 = 1024 * 1024;
+
+/// Chunks a regular file, skipping over its holes rather than reading
+/// them.  The holes are found with SEEK_DATA/SEEK_HOLE, and come out as
+/// unmapped chunks.
+pub struct SparseChunker {
+    input: File,
+    input_size: u64,
+    block_size: u64,
+
+    pos: u64,
+    current_run: Option<(bool, u64)>,
+}
+
+impl SparseChunker {
+    pub fn new(input_path: &Path, block_size: u64) -> Result<Self> {
+        let input_size = thinp::file_utils::file_size(input_path)?;
+        let input = OpenOptions::new()
+            .read(true)
+            .write(false)
+            .open(input_path)
+            .context("couldn't open input file")?;
+
+        Ok(Self {
+            input,
+            input_size,
+            block_size,
+            pos: 0,
+            current_run: None,
+        })
+    }
+
+    // Returns the offset of the next data or hole at, or after, pos.
+    // Filesystems that don't know about holes report the whole file
+    // as data.
+    fn seek(&self, pos: u64, whence: libc::c_int) -> Result<u64> {
+        let r = unsafe { libc::lseek(self.input.as_raw_fd(), pos as libc::off_t, whence) };
+        if r >= 0 {
+            return Ok(std::cmp::min(r as u64, self.input_size));
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // No more data after pos.
+            Some(libc::ENXIO) => Ok(self.input_size),
+            Some(libc::EINVAL) if whence == libc::SEEK_DATA => Ok(pos),
+            Some(libc::EINVAL) => Ok(self.input_size),
+            _ => Err(err).context("couldn't seek in input file"),
+        }
+    }
+
+    // Returns whether the run starting at pos is data, and where it ends.
+    fn next_run(&self, pos: u64) -> Result<(bool, u64)> {
+        let data = self.seek(pos, libc::SEEK_DATA)?;
+        if data - pos >= MIN_HOLE {
+            return Ok((false, data));
+        }
+
+        // Extend the data run over any short holes.
+        let mut end = data;
+        loop {
+            if end >= self.input_size {
+                return Ok((true, self.input_size));
+            }
+            let hole = self.seek(end, libc::SEEK_HOLE)?;
+            if hole >= self.input_size {
+                return Ok((true, self.input_size));
+            }
+            let next_data = self.seek(hole, libc::SEEK_DATA)?;
+            if next_data - hole >= MIN_HOLE {
+                return Ok((true, hole));
+            }
+            end = next_data;
+        }
+    }
+
+    /// Walks the holes to work out how much of the file is data,
+    /// without reading any of it.
+    pub fn mapped_size(&self) -> Result<u64> {
+        let mut pos = 0;
+        let mut total = 0;
+        while pos < self.input_size {
+            let (mapped, end) = self.next_run(pos)?;
+            if mapped {
+                total += end - pos;
+            }
+            pos = end;
+        }
+        Ok(total)
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        if self.pos >= self.input_size {
+            return Ok(None);
+        }
+
+        let (mapped, end) = match self.current_run {
+            Some(run) => run,
+            None => self.next_run(self.pos)?,
+        };
+
+        if !mapped {
+            let len = end - self.pos;
+            self.pos = end;
+            self.current_run = None;
+            return Ok(Some(Chunk::Unmapped(len)));
+        }
+
+        let len = std::cmp::min(end - self.pos, self.block_size);
+        let mut buf = vec![0; len as usize];
+        self.input.read_exact_at(&mut buf, self.pos)?;
+        self.pos += len;
+        self.current_run = if self.pos < end {
+            Some((true, end))
+        } else {
+            None
+        };
+        Ok(Some(Chunk::Mapped(buf)))
+    }
+}
+
+impl Iterator for SparseChunker {
+    type Item = Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+//-----------------------------------------
+
+/// Chunks a pipe, or anything else whose size isn't known until EOF.
+pub struct StreamingChunker<R: Read> {
+    input: R,
+    block_size: u64,
+}
+
+impl<R: Read> StreamingChunker<R> {
+    pub fn new(input: R, block_size: u64) -> Self {
+        Self { input, block_size }
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        // Pipes return short reads, so keep going until the block is full.
+        let mut buffer = Vec::with_capacity(self.block_size as usize);
+        (&mut self.input)
+            .take(self.block_size)
+            .read_to_end(&mut buffer)?;
+
+        if buffer.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(Chunk::Mapped(buffer)))
+        }
+    }
+}
+
+impl<R: Read> Iterator for StreamingChunker<R> {
+    type Item = Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+//-----------------------------------------
+
 pub struct ThinChunker {
     input: File,
     run_iter: RunIter,
@@ -145,6 +315,193 @@ impl Iterator for ThinChunker {
 
 //-----------------------------------------
 
+/// A region of a thin device in a pool, in data blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolRegion {
+    Unmapped(u64),
+    Mapped { data_begin: u64, len: u64 },
+
+    // Same as the stream this one is a delta of.
+    Unchanged(u64),
+}
+
+/// Chunks a thin device by reading its provisioned blocks straight from
+/// the pool's data device, for when the pool isn't active, or its
+/// metadata is held in a snapshot.
+pub struct PoolDataChunker {
+    input: File,
+    regions: std::vec::IntoIter<PoolRegion>,
+    data_block_size: u64,
+    size: u64,
+
+    max_read_size: u64,
+    pos: u64,
+    current: Option<PoolRegion>,
+}
+
+impl PoolDataChunker {
+    /// size is the size of the thin device in bytes; regions past it
+    /// are ignored, and any gap at the end is unmapped.
+    pub fn new(input: File, regions: Vec<PoolRegion>, data_block_size: u64, size: u64) -> Self {
+        Self {
+            input,
+            regions: regions.into_iter(),
+            data_block_size,
+            size,
+            max_read_size: 16 * 1024 * 1024,
+            pos: 0,
+            current: None,
+        }
+    }
+
+    // Clamps a region, converted to bytes, to the end of the device.
+    fn next_region(&mut self) -> Option<PoolRegion> {
+        use PoolRegion::*;
+
+        let remaining = self.size - self.pos;
+        if remaining == 0 {
+            return None;
+        }
+
+        let bs = self.data_block_size;
+        match self.regions.next() {
+            None => Some(Unmapped(remaining)),
+            Some(Unmapped(len)) => Some(Unmapped(std::cmp::min(len * bs, remaining))),
+            Some(Unchanged(len)) => Some(Unchanged(std::cmp::min(len * bs, remaining))),
+            Some(Mapped { data_begin, len }) => Some(Mapped {
+                data_begin: data_begin * bs,
+                len: std::cmp::min(len * bs, remaining),
+            }),
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        use PoolRegion::*;
+
+        let region = match self.current.take().or_else(|| self.next_region()) {
+            None => return Ok(None),
+            Some(r) => r,
+        };
+
+        match region {
+            Unmapped(len) => {
+                self.pos += len;
+                Ok(Some(Chunk::Unmapped(len)))
+            }
+            Unchanged(len) => {
+                self.pos += len;
+                Ok(Some(Chunk::Ref(len)))
+            }
+            Mapped { data_begin, len } => {
+                let read_len = std::cmp::min(len, self.max_read_size);
+                let mut buf = vec![0; read_len as usize];
+                self.input.read_exact_at(&mut buf, data_begin)?;
+                self.pos += read_len;
+                if read_len < len {
+                    self.current = Some(Mapped {
+                        data_begin: data_begin + read_len,
+                        len: len - read_len,
+                    });
+                }
+                Ok(Some(Chunk::Mapped(buf)))
+            }
+        }
+    }
+}
+
+impl Iterator for PoolDataChunker {
+    type Item = Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+//-----------------------------------------
+
+/// Chunks a thick device tracked by dm-era, only reading the runs of
+/// blocks written since the stream it's a delta of.  Everything else
+/// refers back to that stream.
+pub struct EraChunker {
+    input: File,
+    changed: std::vec::IntoIter<Range<u64>>,
+    block_size: u64,
+    size: u64,
+
+    max_read_size: u64,
+    pos: u64,
+    current: Option<Range<u64>>,
+}
+
+impl EraChunker {
+    /// changed holds runs of blocks, in order.  size is the size of the
+    /// device in bytes.
+    pub fn new(input: File, changed: Vec<Range<u64>>, block_size: u64, size: u64) -> Self {
+        Self {
+            input,
+            changed: changed.into_iter(),
+            block_size,
+            size,
+            max_read_size: 16 * 1024 * 1024,
+            pos: 0,
+            current: None,
+        }
+    }
+
+    // The next changed run, in bytes, clamped to the end of the device.
+    fn next_run(&mut self) -> Option<Range<u64>> {
+        for run in self.changed.by_ref() {
+            let start = std::cmp::min(run.start * self.block_size, self.size);
+            let end = std::cmp::min(run.end * self.block_size, self.size);
+            if start < end {
+                return Some(start..end);
+            }
+        }
+        None
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        if self.pos >= self.size {
+            return Ok(None);
+        }
+
+        let run = match self.current.take().or_else(|| self.next_run()) {
+            None => {
+                let len = self.size - self.pos;
+                self.pos = self.size;
+                return Ok(Some(Chunk::Ref(len)));
+            }
+            Some(run) => run,
+        };
+
+        if self.pos < run.start {
+            let len = run.start - self.pos;
+            self.pos = run.start;
+            self.current = Some(run);
+            return Ok(Some(Chunk::Ref(len)));
+        }
+
+        let read_len = std::cmp::min(run.end - self.pos, self.max_read_size);
+        let mut buf = vec![0; read_len as usize];
+        self.input.read_exact_at(&mut buf, self.pos)?;
+        self.pos += read_len;
+        if self.pos < run.end {
+            self.current = Some(run);
+        }
+        Ok(Some(Chunk::Mapped(buf)))
+    }
+}
+
+impl Iterator for EraChunker {
+    type Item = Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+//-----------------------------------------
+
 pub struct DeltaChunker {
     input: File,
     deltas: DualIter,
@@ -166,7 +523,8 @@ impl DeltaChunker {
         }
     }
 
-    // FIXME: removals are being ignored
+    // Removals (Right) are emitted as unmapped, so the delta builder
+    // replaces whatever the old stream had there.
     fn next_run_bytes(&mut self) -> Option<(DualType, Range<u64>)> {
         self.deltas.next().map(|(t, Range { start, end })| {
             (
@@ -221,3 +579,86 @@ impl Iterator for DeltaChunker {
 }
 
 //-----------------------------------------
+
+#[cfg(test)]
+mod delta_chunker_tests {
+    use super::*;
+    use roaring::RoaringBitmap;
+
+    fn chunks(added: &[u32], removed: &[u32], nr_blocks: u32) -> Vec<Chunk> {
+        let data: Vec<u8> = (0..nr_blocks as u64 * 16).map(|i| i as u8).collect();
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&data).unwrap();
+
+        let left: RoaringBitmap = added.iter().cloned().collect();
+        let right: RoaringBitmap = removed.iter().cloned().collect();
+        let chunker = DeltaChunker::new(file, DualIter::new(left, right, nr_blocks), 16);
+        chunker.map(|c| c.unwrap()).collect()
+    }
+
+    fn mapped(begin: u64, end: u64) -> Chunk {
+        Chunk::Mapped((begin..end).map(|i| i as u8).collect())
+    }
+
+    #[test]
+    fn removed_blocks_are_unmapped() {
+        assert_eq!(
+            chunks(&[1, 2], &[4, 5, 6], 10),
+            vec![
+                Chunk::Ref(16),
+                mapped(16, 48),
+                Chunk::Ref(16),
+                Chunk::Unmapped(48),
+                Chunk::Ref(48),
+            ]
+        );
+
+        assert_eq!(chunks(&[], &[0, 1], 2), vec![Chunk::Unmapped(32)]);
+    }
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod era_chunker_tests {
+    use super::*;
+
+    fn chunks(changed: Vec<Range<u64>>, size: u64) -> Vec<Chunk> {
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&data).unwrap();
+
+        let mut chunker = EraChunker::new(file, changed, 16, size);
+        chunker.max_read_size = 32;
+        chunker.map(|c| c.unwrap()).collect()
+    }
+
+    fn mapped(begin: u64, end: u64) -> Chunk {
+        Chunk::Mapped((begin..end).map(|i| i as u8).collect())
+    }
+
+    #[test]
+    fn only_written_blocks_are_read() {
+        assert_eq!(
+            chunks(vec![1..2, 3..7], 150),
+            vec![
+                Chunk::Ref(16),
+                mapped(16, 32),
+                Chunk::Ref(16),
+                mapped(48, 80),
+                mapped(80, 112),
+                Chunk::Ref(38),
+            ]
+        );
+
+        // Runs are clamped to the end of the device.
+        assert_eq!(
+            chunks(vec![0..1, 9..20, 30..40], 150),
+            vec![mapped(0, 16), Chunk::Ref(128), mapped(144, 150)]
+        );
+
+        assert_eq!(chunks(vec![], 100), vec![Chunk::Ref(100)]);
+    }
+}
+
+//-----------------------------------------
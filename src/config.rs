@@ -10,12 +10,47 @@ use crate::paths::*;
 
 //-----------------------------------------
 
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexMode {
+    /// Hashes are found via the cuckoo filter and cached hash slabs.
+    #[default]
+    Memory,
+
+    /// A persistent full hash index is also maintained, see full_index.rs.
+    Disk,
+}
+
+impl std::str::FromStr for IndexMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "memory" => Ok(IndexMode::Memory),
+            "disk" => Ok(IndexMode::Disk),
+            _ => Err(anyhow!("unknown index mode '{}'", s)),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Config {
     pub block_size: usize,
     pub splitter_alg: String,
     pub hash_cache_size_meg: usize,
     pub data_cache_size_meg: usize,
+    #[serde(default)]
+    pub index_mode: IndexMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_budget_meg: Option<usize>,
+    #[serde(default)]
+    pub enforce_memory_budget: bool,
+    #[serde(default)]
+    pub delta_compression: bool,
+
+    // Absolute paths of read only parent archives, see parent.rs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parents: Vec<String>,
 }
 
 fn numeric_override<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>> {
@@ -29,13 +64,18 @@ fn numeric_override<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> R
     }
 }
 
-pub fn read_config<P: AsRef<Path>>(root: P, overrides: &ArgMatches) -> Result<Config> {
+/// Reads the config as stored, for commands that take no overrides.
+pub fn read_config_file<P: AsRef<Path>>(root: P) -> Result<Config> {
     let mut p = PathBuf::new();
     p.push(root);
     p.push("dm-archive.yaml");
     let input = fs::read_to_string(p).context("couldn't read config file")?;
-    let mut config: Config =
-        serde_yaml_ng::from_str(&input).context("couldn't parse config file")?;
+    let config: Config = serde_yaml_ng::from_str(&input).context("couldn't parse config file")?;
+    Ok(config)
+}
+
+pub fn read_config<P: AsRef<Path>>(root: P, overrides: &ArgMatches) -> Result<Config> {
+    let mut config = read_config_file(root)?;
 
     if let Some(data_cache_meg) = numeric_override::<usize>(overrides, "DATA_CACHE_SIZE_MEG")? {
         config.data_cache_size_meg = data_cache_meg;
@@ -43,6 +83,19 @@ pub fn read_config<P: AsRef<Path>>(root: P, overrides: &ArgMatches) -> Result<Co
     Ok(config)
 }
 
+/// The pack commands can also override the memory budget.
+pub fn read_pack_config<P: AsRef<Path>>(root: P, overrides: &ArgMatches) -> Result<Config> {
+    let mut config = read_config(root, overrides)?;
+
+    if let Some(budget_meg) = numeric_override::<usize>(overrides, "MEMORY_BUDGET_MEG")? {
+        config.memory_budget_meg = Some(budget_meg);
+    }
+    if overrides.get_flag("ENFORCE_MEMORY_BUDGET") {
+        config.enforce_memory_budget = true;
+    }
+    Ok(config)
+}
+
 //-----------------------------------------
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -54,6 +107,28 @@ pub struct StreamConfig {
     pub mapped_size: u64,
     pub packed_size: u64,
     pub thin_id: Option<u32>,
+
+    // Identifies the thin device packed, so pack --auto-delta can find
+    // it again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_uuid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dm_uuid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creation_time: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshotted_time: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction_id: Option<u64>,
+    // The logical volume packed with pack --lv.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vg_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lv_name: Option<String>,
+    // The dm-era era current when a thick device was packed, so a later
+    // pack can be a delta of this stream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub era: Option<u32>,
 }
 
 pub fn read_stream_config(stream_id: &str) -> Result<StreamConfig> {
@@ -78,6 +153,60 @@ pub fn write_stream_config(stream_id: &str, cfg: &StreamConfig) -> Result<()> {
     Ok(())
 }
 
+//-----------------------------------------
+
+/// A thin device within a pool set, see pool.rs.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct PoolSetThin {
+    pub thin_id: u32,
+    pub stream_id: String,
+    pub size: u64,
+    pub mapped_blocks: u64,
+    pub transaction_id: u64,
+    pub creation_time: u32,
+    pub snapshotted_time: u32,
+
+    // The thin this one was packed as a delta of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct PoolSetConfig {
+    pub name: String,
+    pub source_path: String,
+    pub pack_time: String,
+    pub data_block_size: u32,
+    pub thins: Vec<PoolSetThin>,
+}
+
+pub fn read_pool_set_config(pool_set_id: &str) -> Result<PoolSetConfig> {
+    let p = pool_set_config(pool_set_id);
+    let input = fs::read_to_string(&p)
+        .with_context(|| format!("couldn't read pool set config '{:?}", &p))?;
+    let config: PoolSetConfig =
+        serde_yaml_ng::from_str(&input).context("couldn't parse pool set config file")?;
+    Ok(config)
+}
+
+pub fn write_pool_set_config(pool_set_id: &str, cfg: &PoolSetConfig) -> Result<()> {
+    let p = pool_set_config(pool_set_id);
+    if let Some(dir) = p.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut output = fs::OpenOptions::new()
+        .read(false)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(p)?;
+    let yaml = serde_yaml_ng::to_string(cfg).unwrap();
+    output.write_all(yaml.as_bytes())?;
+    Ok(())
+}
+
+//-----------------------------------------
+
 pub fn now() -> String {
     let dt = Utc::now();
     dt.to_rfc3339()
@@ -94,6 +223,20 @@ mod config_tests {
 
     use super::*;
 
+    #[test]
+    fn test_overrides() {
+        let matches = clap::Command::new("test")
+            .arg(clap::Arg::new("DATA_CACHE_SIZE_MEG").long("data-cache-size-meg"))
+            .get_matches_from(["test", "--data-cache-size-meg", "64"]);
+
+        let n = numeric_override::<usize>(&matches, "DATA_CACHE_SIZE_MEG").unwrap();
+        assert_eq!(n, Some(64));
+
+        // An override the command doesn't define is a bug, not a default.
+        assert!(numeric_override::<usize>(&matches, "MEMORY_BUDGET_MEG").is_err());
+        assert!(numeric_override::<usize>(&matches, "DATA_CACHE_SIZE").is_err());
+    }
+
     #[test]
     fn test_simple() {
         let config = StreamConfig {
@@ -104,10 +247,50 @@ mod config_tests {
             mapped_size: u64::MAX,
             packed_size: u64::MAX,
             thin_id: None,
+            pool_uuid: None,
+            dm_uuid: None,
+            creation_time: None,
+            snapshotted_time: None,
+            transaction_id: None,
+            vg_name: None,
+            lv_name: None,
+            era: None,
+        };
+
+        let ser = serde_yaml_ng::to_string(&config).unwrap();
+        let des_config: StreamConfig = serde_yaml_ng::from_str(&ser).unwrap();
+        assert!(config == des_config);
+    }
+
+    #[test]
+    fn test_thin_source() {
+        let config = StreamConfig {
+            name: Some(String::from("snap")),
+            source_path: String::from("/dev/mapper/vg-snap"),
+            pack_time: String::from("2023-11-14T22:06:02.101221624+00:00"),
+            size: 1 << 30,
+            mapped_size: 1 << 20,
+            packed_size: 1 << 19,
+            thin_id: Some(7),
+            pool_uuid: Some(String::from("LVM-abc-tpool")),
+            dm_uuid: Some(String::from("LVM-abc")),
+            creation_time: Some(3),
+            snapshotted_time: Some(4),
+            transaction_id: Some(12),
+            vg_name: Some(String::from("vg")),
+            lv_name: Some(String::from("data")),
+            era: None,
         };
 
         let ser = serde_yaml_ng::to_string(&config).unwrap();
         let des_config: StreamConfig = serde_yaml_ng::from_str(&ser).unwrap();
         assert!(config == des_config);
+
+        // Older configs don't have the source fields.
+        let old = "name: snap\nsource_path: /dev/x\npack_time: '2023-11-14T22:06:02+00:00'\n\
+                   size: 1\nmapped_size: 1\npacked_size: 1\nthin_id: 7\n";
+        let des_config: StreamConfig = serde_yaml_ng::from_str(old).unwrap();
+        assert_eq!(des_config.thin_id, Some(7));
+        assert_eq!(des_config.pool_uuid, None);
     }
 }
@@ -53,39 +53,43 @@ impl ContentSensitiveSplitter {
         self.consume_c.block -= first_used;
     }
 
-    fn consume(&mut self, len: usize) -> IoVec {
+    fn consume(&mut self, lens: &[usize]) -> Vec<IoVec<'_>> {
         let c = &mut self.consume_c;
         let blocks = &self.blocks;
 
-        assert!(len != 0);
-
-        let mut remaining = len;
-        let mut r = IoVec::new();
-        while remaining > 0 {
-            let b = &blocks[c.block];
-            let blen = b.len() - c.offset;
-
-            if blen == 0 {
-                c.offset = 0;
-                c.block += 1;
-            } else if blen > remaining {
-                r.push(&b[c.offset..(c.offset + remaining)]);
-                c.offset += remaining;
-                remaining = 0;
-            } else {
-                r.push(&b[c.offset..]);
-                remaining -= blen;
-                c.offset = 0;
-                c.block += 1;
+        let mut iovs = Vec::with_capacity(lens.len());
+        for len in lens {
+            assert!(*len != 0);
+
+            let mut remaining = *len;
+            let mut r = IoVec::new();
+            while remaining > 0 {
+                let b = &blocks[c.block];
+                let blen = b.len() - c.offset;
+
+                if blen == 0 {
+                    c.offset = 0;
+                    c.block += 1;
+                } else if blen > remaining {
+                    r.push(&b[c.offset..(c.offset + remaining)]);
+                    c.offset += remaining;
+                    remaining = 0;
+                } else {
+                    r.push(&b[c.offset..]);
+                    remaining -= blen;
+                    c.offset = 0;
+                    c.block += 1;
+                }
             }
+            self.unconsumed_len -= *len as u64;
+            iovs.push(r);
         }
-        self.unconsumed_len -= len as u64;
 
         if self.unconsumed_len == 0 {
             assert!(c.block == self.blocks.len());
         }
 
-        r
+        iovs
     }
 
     fn consume_all(&mut self) -> IoVec {
@@ -158,8 +162,8 @@ impl Splitter for ContentSensitiveSplitter {
         self.blocks.push_back(buffer);
         self.unconsumed_len += len as u64;
 
-        for consume_len in consumes {
-            handler.handle_data(&self.consume(consume_len))?;
+        if !consumes.is_empty() {
+            handler.handle_batch(&self.consume(&consumes))?;
         }
 
         self.drop_old_blocks();
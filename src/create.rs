@@ -9,6 +9,7 @@ use thinp::report::*;
 
 use crate::config::*;
 use crate::cuckoo_filter::*;
+use crate::parent::resolve_parents;
 use crate::paths;
 use crate::paths::*;
 use crate::slab::builder::*;
@@ -23,12 +24,7 @@ fn create_sub_dir(root: &Path, sub: &str) -> Result<()> {
     Ok(())
 }
 
-fn write_config(
-    root: &Path,
-    block_size: usize,
-    hash_cache_size_meg: usize,
-    data_cache_size_meg: usize,
-) -> Result<()> {
+fn write_config(root: &Path, config: &Config) -> Result<()> {
     let mut p = PathBuf::new();
     p.push(root);
     p.push("dm-archive.yaml");
@@ -40,14 +36,7 @@ fn write_config(
         .truncate(true)
         .open(p)?;
 
-    let config = Config {
-        block_size,
-        splitter_alg: "RollingHashV0".to_string(),
-        hash_cache_size_meg,
-        data_cache_size_meg,
-    };
-
-    write!(output, "{}", &serde_yaml_ng::to_string(&config).unwrap())?;
+    write!(output, "{}", &serde_yaml_ng::to_string(config).unwrap())?;
     Ok(())
 }
 
@@ -98,12 +87,43 @@ pub fn run(matches: &ArgMatches, report: Arc<Report>) -> Result<()> {
     }
     let hash_cache_size_meg = numeric_option::<usize>(matches, "HASH_CACHE_SIZE_MEG", 1024)?;
     let data_cache_size_meg = numeric_option::<usize>(matches, "DATA_CACHE_SIZE_MEG", 1024)?;
+    let index_mode = matches
+        .get_one::<String>("INDEX_MODE")
+        .unwrap()
+        .parse::<IndexMode>()?;
+    let memory_budget_meg = matches
+        .get_one::<String>("MEMORY_BUDGET_MEG")
+        .map(|s| s.parse::<usize>())
+        .transpose()
+        .map_err(|_| anyhow!("could not parse MEMORY_BUDGET_MEG argument"))?;
+    let enforce_memory_budget = matches.get_flag("ENFORCE_MEMORY_BUDGET");
+    let delta_compression = matches.get_one::<String>("DELTA_COMPRESSION").unwrap() == "y";
+    let parents: Vec<PathBuf> = matches
+        .get_many::<String>("PARENT")
+        .map(|ps| ps.map(PathBuf::from).collect())
+        .unwrap_or_default();
+    let parents = resolve_parents(&parents, block_size)?;
+
+    let config = Config {
+        block_size,
+        splitter_alg: "RollingHashV0".to_string(),
+        hash_cache_size_meg,
+        data_cache_size_meg,
+        index_mode,
+        memory_budget_meg,
+        enforce_memory_budget,
+        delta_compression,
+        parents,
+    };
 
     fs::create_dir(dir)?;
-    write_config(dir, block_size, hash_cache_size_meg, data_cache_size_meg)?;
+    write_config(dir, &config)?;
     create_sub_dir(dir, "data")?;
     create_sub_dir(dir, "streams")?;
     create_sub_dir(dir, "indexes")?;
+    if index_mode == IndexMode::Disk {
+        create_sub_dir(dir, "indexes/full")?;
+    }
 
     std::env::set_current_dir(dir)?;
 
@@ -120,6 +140,12 @@ pub fn run(matches: &ArgMatches, report: Arc<Report>) -> Result<()> {
         .build()?;
     hashes_file.close()?;
 
+    let mut stream_slabs = SlabFileBuilder::create(stream_slabs_path())
+        .queue_depth(1)
+        .compressed(true)
+        .build()?;
+    stream_slabs.close()?;
+
     // Write empty index
     let index = CuckooFilter::with_capacity(1 << 10);
     index.write(paths::index_path())?;
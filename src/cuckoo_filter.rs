@@ -15,6 +15,10 @@ use crate::utils::is_pow2;
 const ENTRIES_PER_BUCKET: usize = 4;
 const MAX_KICKS: usize = 500;
 
+/// Once a filter has this many levels it should be rebuilt as a single
+/// level, rather than grown.
+pub const MAX_LEVELS: usize = 4;
+
 #[derive(Clone)]
 struct Bucket {
     entries: [u16; ENTRIES_PER_BUCKET],
@@ -36,15 +40,30 @@ pub enum InsertResult {
     Inserted,
 }
 
-pub struct CuckooFilter {
-    rng: ChaCha20Rng,
+// The filter is made up of one or more levels.  Each level is a
+// conventional cuckoo filter with a power of 2 number of buckets.  When
+// the filter runs out of space a new level is appended, sized to the
+// shortfall, rather than rehashing everything into a table twice the
+// size (which would require us to re-read every hash in the archive).
+// Lookups have to check every level, so the false positive rate, and
+// the cache misses per lookup, grow linearly with the number of levels.
+// To keep the number of levels down, each new level is at least a
+// quarter of the existing capacity, and there are never more than
+// MAX_LEVELS of them.  The archive rebuilds the filter from its hashes,
+// as a single level, before a pack would need the last one.
+struct Level {
     len: usize,
-    scatter: Vec<usize>,
     bucket_counts: Vec<u8>,
     buckets: Vec<Bucket>,
     mask: usize,
 }
 
+pub struct CuckooFilter {
+    rng: ChaCha20Rng,
+    scatter: Vec<usize>,
+    levels: Vec<Level>,
+}
+
 fn parse_bucket(input: &[u8], nr: usize) -> IResult<&[u8], Bucket> {
     use nom::multi::*;
     use nom::number::complete::*;
@@ -94,44 +113,23 @@ pub fn calculate_signature(values: &[usize]) -> u64 {
     u64::from_le_bytes(hasher.finalize().into())
 }
 
-impl CuckooFilter {
-    fn make_scatter(rng: &mut ChaCha20Rng) -> Vec<usize> {
-        let scatter: Vec<usize> = repeat_with(|| rng.gen())
-            .take(u16::MAX as usize + 1)
-            .collect();
-
-        // Ensure that the scatter is identical every time it's constructed
-        // We cannot use the DefaultHasher as it's documented to not be consistent across
-        // versions/time
-        assert!(4224213928824907068 == calculate_signature(scatter.as_slice()));
-
-        scatter
-    }
+fn nr_buckets_for(n: usize) -> usize {
+    let n = ((n * 5) / 4) / ENTRIES_PER_BUCKET;
+    cmp::max(n, 4096).next_power_of_two()
+}
 
-    pub fn with_capacity(mut n: usize) -> Self {
-        n = (n * 5) / 4;
-        n /= ENTRIES_PER_BUCKET;
-        let mut rng = ChaCha20Rng::seed_from_u64(1);
-        let nr_buckets = cmp::max(n, 4096).next_power_of_two();
-        let scatter = Self::make_scatter(&mut rng);
+impl Level {
+    fn with_buckets(nr_buckets: usize) -> Self {
         Self {
-            rng,
             len: 0,
-            scatter,
             bucket_counts: vec![0; nr_buckets],
             buckets: vec![Bucket::default(); nr_buckets],
             mask: nr_buckets - 1,
         }
     }
 
-    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
-        // all the data goes in a single slab
-        let mut file = SlabFileBuilder::open(path).build()?;
-        let input = file.read(0)?;
-
-        let mut rng = ChaCha20Rng::seed_from_u64(1);
-
-        let (input, nr_buckets) = parse_nr(&input[..]).map_err(|_| anyhow!("couldn't parse nr"))?;
+    fn parse(input: &[u8]) -> Result<Self> {
+        let (input, nr_buckets) = parse_nr(input).map_err(|_| anyhow!("couldn't parse nr"))?;
         let nr_buckets = nr_buckets as usize;
         let (input, bucket_counts) =
             parse_counts(input, nr_buckets).map_err(|_| anyhow!("couldn't parse counts"))?;
@@ -143,7 +141,6 @@ impl CuckooFilter {
             return Err(anyhow!("extra bytes at end of index file"));
         }
 
-        let scatter = Self::make_scatter(&mut rng);
         if !is_pow2(nr_buckets) {
             return Err(anyhow!("nr_buckets({nr_buckets}) is not a power of 2"));
         }
@@ -151,16 +148,14 @@ impl CuckooFilter {
         let len = bucket_counts.iter().map(|n| *n as usize).sum();
 
         Ok(Self {
-            rng,
             len,
-            scatter,
             bucket_counts,
             buckets,
             mask,
         })
     }
 
-    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+    fn pack(&self) -> Result<Vec<u8>> {
         let mut out: Vec<u8> = Vec::new();
 
         out.write_u32::<LittleEndian>(self.bucket_counts.len() as u32)?;
@@ -178,20 +173,24 @@ impl CuckooFilter {
             }
         }
 
-        let mut file = SlabFileBuilder::create(path)
-            .queue_depth(1)
-            .compressed(false)
-            .build()?;
-        file.write_slab(&out)?;
-        file.close()?;
+        Ok(out)
+    }
 
-        Ok(())
+    fn nr_buckets(&self) -> usize {
+        self.buckets.len()
     }
 
-    pub fn capacity(&self) -> usize {
+    fn capacity(&self) -> usize {
         (self.buckets.len() * ENTRIES_PER_BUCKET * 4) / 5
     }
 
+    fn indexes(&self, scatter: &[usize], h: u64) -> (u16, usize, usize) {
+        let fingerprint: u16 = (h & 0xffff) as u16;
+        let index1: usize = ((h >> 16) as usize) & self.mask;
+        let index2: usize = (index1 ^ scatter[fingerprint as usize]) & self.mask;
+        (fingerprint, index1, index2)
+    }
+
     fn present(&self, fp: u16, index: usize) -> Option<u32> {
         for entry in 0..self.bucket_counts[index] as usize {
             if self.buckets[index].entries[entry] == fp {
@@ -202,6 +201,12 @@ impl CuckooFilter {
         None
     }
 
+    fn test(&self, scatter: &[usize], h: u64) -> Option<u32> {
+        let (fingerprint, index1, index2) = self.indexes(scatter, h);
+        self.present(fingerprint, index1)
+            .or_else(|| self.present(fingerprint, index2))
+    }
+
     fn insert(&mut self, fp: u16, slab: u32, index: usize) -> bool {
         let entry = self.bucket_counts[index] as usize;
         if entry >= ENTRIES_PER_BUCKET {
@@ -214,87 +219,226 @@ impl CuckooFilter {
         }
     }
 
-    // h must be randomly distributed across u64. Does not overwrite
-    // slab if there's already an entry.
-    fn test_and_set_(&mut self, h: u64, mut slab: u32) -> Result<InsertResult> {
-        use InsertResult::*;
+    // Caller must have checked the entry isn't already present.  If
+    // no space can be found any entries that were kicked are put back
+    // where they came from, so a failed insert leaves the level
+    // unchanged.
+    fn try_insert(
+        &mut self,
+        rng: &mut ChaCha20Rng,
+        scatter: &[usize],
+        h: u64,
+        mut slab: u32,
+    ) -> bool {
+        let (mut fingerprint, index1, index2) = self.indexes(scatter, h);
+
+        if self.insert(fingerprint, slab, index1) || self.insert(fingerprint, slab, index2) {
+            self.len += 1;
+            return true;
+        }
 
-        let mut fingerprint: u16 = (h & 0xffff) as u16;
-        let index1: usize = ((h >> 16) as usize) & self.mask;
+        let mut i = if rng.gen() { index1 } else { index2 };
+        let mut kicks = Vec::with_capacity(MAX_KICKS);
+
+        for _ in 0..MAX_KICKS {
+            // randomly select entry from bucket i
+            let entry = rng.gen_range(0..self.bucket_counts[i]) as usize;
+            kicks.push((i, entry));
+
+            // swap with fp
+            std::mem::swap(&mut fingerprint, &mut self.buckets[i].entries[entry]);
+            std::mem::swap(&mut slab, &mut self.buckets[i].slabs[entry]);
+
+            // i = i ^ hash(new fp)
+            i = (i ^ scatter[fingerprint as usize]) & self.mask;
+
+            if self.insert(fingerprint, slab, i) {
+                self.len += 1;
+                return true;
+            }
+        }
 
-        if let Some(s) = self.present(fingerprint, index1) {
-            return Ok(PossiblyPresent(s));
+        // Undo the kicks in reverse order.
+        while let Some((i, entry)) = kicks.pop() {
+            std::mem::swap(&mut fingerprint, &mut self.buckets[i].entries[entry]);
+            std::mem::swap(&mut slab, &mut self.buckets[i].slabs[entry]);
+        }
+
+        false
+    }
+}
+
+impl CuckooFilter {
+    fn make_scatter(rng: &mut ChaCha20Rng) -> Vec<usize> {
+        let scatter: Vec<usize> = repeat_with(|| rng.gen())
+            .take(u16::MAX as usize + 1)
+            .collect();
+
+        // Ensure that the scatter is identical every time it's constructed
+        // We cannot use the DefaultHasher as it's documented to not be consistent across
+        // versions/time
+        assert!(4224213928824907068 == calculate_signature(scatter.as_slice()));
+
+        scatter
+    }
+
+    fn with_levels(levels: Vec<Level>) -> Self {
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let scatter = Self::make_scatter(&mut rng);
+        Self {
+            rng,
+            scatter,
+            levels,
         }
+    }
+
+    pub fn with_capacity(n: usize) -> Self {
+        Self::with_levels(vec![Level::with_buckets(nr_buckets_for(n))])
+    }
 
-        let index2: usize = (index1 ^ self.scatter[fingerprint as usize]) & self.mask;
-        if let Some(s) = self.present(fingerprint, index2) {
-            return Ok(PossiblyPresent(s));
+    // Each level is stored in its own slab.  Older archives only ever
+    // have a single level.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = SlabFileBuilder::open(path).build()?;
+        let nr_levels = file.get_nr_slabs();
+        if nr_levels == 0 {
+            return Err(anyhow!("index file is empty"));
         }
 
-        if self.insert(fingerprint, slab, index1) {
-            return Ok(Inserted);
+        let mut levels = Vec::with_capacity(nr_levels);
+        for s in 0..nr_levels {
+            let input = file.read(s as u32)?;
+            levels.push(Level::parse(&input[..])?);
         }
 
-        if self.insert(fingerprint, slab, index2) {
-            return Ok(Inserted);
+        Ok(Self::with_levels(levels))
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut file = SlabFileBuilder::create(path)
+            .queue_depth(1)
+            .compressed(false)
+            .build()?;
+        for level in &self.levels {
+            file.write_slab(&level.pack()?)?;
         }
+        file.close()?;
 
-        let mut i = if self.rng.gen() { index1 } else { index2 };
+        Ok(())
+    }
 
-        for _ in 0..MAX_KICKS {
-            // randomly select entry from bucket i
-            let entry = self.rng.gen_range(0..self.bucket_counts[i]);
+    fn grow_buckets(&self, extra: usize) -> usize {
+        nr_buckets_for(cmp::max(extra, self.capacity() / 4))
+    }
 
-            // swap with fp
-            std::mem::swap(
-                &mut fingerprint,
-                &mut self.buckets[i].entries[entry as usize],
-            );
-            std::mem::swap(&mut slab, &mut self.buckets[i].slabs[entry as usize]);
+    /// Appends a new level with room for at least `extra` more entries.
+    /// Existing entries are left where they are, so this is cheap.
+    pub fn grow(&mut self, extra: usize) {
+        let nr_buckets = self.grow_buckets(extra);
+        self.levels.push(Level::with_buckets(nr_buckets));
+    }
 
-            // i = i ^ hash(new fp)
-            i = (i ^ self.scatter[fingerprint as usize]) & self.mask;
+    /// The capacity of the level that grow(extra) would add.
+    pub fn grow_capacity(&self, extra: usize) -> usize {
+        (self.grow_buckets(extra) * ENTRIES_PER_BUCKET * 4) / 5
+    }
 
-            if self.bucket_counts[i] < (ENTRIES_PER_BUCKET as u8) {
-                self.insert(fingerprint, slab, i);
-                return Ok(Inserted);
-            }
+    /// Memory used by the buckets and their counts.
+    pub fn mem_size(&self) -> usize {
+        self.nr_buckets() * (std::mem::size_of::<Bucket>() + 1)
+    }
+
+    /// Memory that will be used once there's room for `extra` more
+    /// entries.
+    pub fn projected_mem_size(&self, extra: usize) -> usize {
+        let free = self.capacity() - cmp::min(self.len(), self.capacity());
+        if extra <= free {
+            self.mem_size()
+        } else {
+            self.mem_size() + self.grow_buckets(extra - free) * (std::mem::size_of::<Bucket>() + 1)
         }
+    }
 
-        Err(anyhow!("cuckoo table full"))
+    /// Fraction of the entry slots that are in use.  New entries are
+    /// refused once a level reaches 80%.
+    pub fn load_factor(&self) -> f64 {
+        self.len() as f64 / (self.nr_buckets() * ENTRIES_PER_BUCKET) as f64
+    }
+
+    /// Estimated chance that a lookup of a hash that isn't present
+    /// reports it as possibly present.  Each level compares the 16 bit
+    /// fingerprint against every entry in two buckets.
+    pub fn false_positive_rate(&self) -> f64 {
+        let miss_one = 1.0 - 1.0 / (1u64 << 16) as f64;
+        let mut miss_all = 1.0;
+        for level in &self.levels {
+            let entries_checked = 2.0 * level.len as f64 / level.nr_buckets() as f64;
+            miss_all *= miss_one.powf(entries_checked);
+        }
+        1.0 - miss_all
+    }
+
+    /// False once the filter has MAX_LEVELS levels, at which point it
+    /// should be rebuilt instead.
+    pub fn can_grow(&self) -> bool {
+        self.levels.len() < MAX_LEVELS
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.levels.iter().map(|l| l.capacity()).sum()
+    }
+
+    pub fn nr_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn nr_buckets(&self) -> usize {
+        self.levels.iter().map(|l| l.nr_buckets()).sum()
     }
 
     pub fn test(&mut self, h: u64) -> Result<InsertResult> {
         use InsertResult::*;
 
-        let fingerprint: u16 = (h & 0xffff) as u16;
-        let index1: usize = ((h >> 16) as usize) & self.mask;
-
-        if let Some(s) = self.present(fingerprint, index1) {
-            return Ok(PossiblyPresent(s));
+        // Newer levels hold more recently added slabs, which are more
+        // likely to be in the hash cache.
+        for level in self.levels.iter().rev() {
+            if let Some(s) = level.test(&self.scatter, h) {
+                return Ok(PossiblyPresent(s));
+            }
         }
 
-        let index2: usize = (index1 ^ self.scatter[fingerprint as usize]) & self.mask;
-        if let Some(s) = self.present(fingerprint, index2) {
-            return Ok(PossiblyPresent(s));
-        }
         Ok(Inserted)
     }
 
+    // h must be randomly distributed across u64. Does not overwrite
+    // slab if there's already an entry.  Returns an error if every level
+    // is full, in which case the caller should grow() the filter and
+    // retry.
     pub fn test_and_set(&mut self, h: u64, slab: u32) -> Result<InsertResult> {
-        let r = self.test_and_set_(h, slab)?;
-        if r == InsertResult::Inserted {
-            self.len += 1;
+        use InsertResult::*;
+
+        let r = self.test(h)?;
+        if r != Inserted {
+            return Ok(r);
+        }
+
+        for level in self.levels.iter_mut() {
+            if level.len < level.capacity()
+                && level.try_insert(&mut self.rng, &self.scatter, h, slab)
+            {
+                return Ok(Inserted);
+            }
         }
-        Ok(r)
+
+        Err(anyhow!("cuckoo table full"))
     }
 
     pub fn len(&self) -> usize {
-        self.len
+        self.levels.iter().map(|l| l.len).sum()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.len == 0
+        self.len() == 0
     }
 }
 
@@ -355,4 +499,103 @@ mod cuckoo_tests {
         eprintln!("false positives: {}", false_positives);
         assert!(false_positives < 0.001);
     }
+
+    fn fill(cf: &mut CuckooFilter, values: &[u64]) {
+        for v in values {
+            match cf.test_and_set(*v, *v as u32) {
+                Ok(_) => {}
+                Err(_) => {
+                    cf.grow(0);
+                    cf.test_and_set(*v, *v as u32)
+                        .expect("insert after grow failed");
+                }
+            }
+        }
+    }
+
+    fn count_hits(cf: &mut CuckooFilter, values: &[u64]) -> usize {
+        let mut hits = 0;
+        for v in values {
+            if let InsertResult::PossiblyPresent(slab) = cf.test(*v).expect("test failed") {
+                if slab == *v as u32 {
+                    hits += 1;
+                }
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut cf = CuckooFilter::with_capacity(1_000);
+        let initial_capacity = cf.capacity();
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let values: Vec<u64> = repeat_with(|| rng.gen()).take(50_000).collect();
+
+        fill(&mut cf, &values);
+        assert!(cf.nr_levels() > 1);
+        assert!(cf.capacity() > initial_capacity);
+        assert!(cf.capacity() >= cf.len());
+
+        // Nothing should have been lost when levels were added.
+        let hits = count_hits(&mut cf, &values);
+        assert!(hits as f64 / values.len() as f64 > 0.999);
+    }
+
+    #[test]
+    fn test_grow_tracks_len() {
+        let mut cf = CuckooFilter::with_capacity(1 << 20);
+        let capacity = cf.capacity();
+
+        // A small increment should not double the size of the filter.
+        cf.grow(1000);
+        assert_eq!(cf.nr_levels(), 2);
+        assert!(cf.capacity() < capacity * 2);
+
+        while cf.can_grow() {
+            cf.grow(0);
+        }
+        assert_eq!(cf.nr_levels(), MAX_LEVELS);
+    }
+
+    #[test]
+    fn test_projections() {
+        let mut cf = CuckooFilter::with_capacity(1 << 16);
+        assert_eq!(cf.false_positive_rate(), 0.0);
+        assert_eq!(cf.projected_mem_size(cf.capacity()), cf.mem_size());
+
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let values: Vec<u64> = repeat_with(|| rng.gen()).take(10_000).collect();
+        fill(&mut cf, &values);
+        let fp = cf.false_positive_rate();
+        assert!(fp > 0.0 && fp < 1.0 / 10_000.0);
+
+        // The projection should match what growing actually does.
+        let extra = cf.capacity();
+        let projected = cf.projected_mem_size(extra);
+        assert!(projected > cf.mem_size());
+        let free = cf.capacity() - cf.len();
+        assert!(cf.grow_capacity(extra - free) >= extra - free);
+        cf.grow(extra - free);
+        assert_eq!(cf.mem_size(), projected);
+    }
+
+    #[test]
+    fn test_write_read() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let path = td.path().join("seen");
+
+        let mut cf = CuckooFilter::with_capacity(1_000);
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let values: Vec<u64> = repeat_with(|| rng.gen()).take(30_000).collect();
+        fill(&mut cf, &values);
+        cf.write(&path)?;
+
+        let mut cf2 = CuckooFilter::read(&path)?;
+        assert_eq!(cf.nr_levels(), cf2.nr_levels());
+        assert_eq!(cf.len(), cf2.len());
+        assert_eq!(cf.capacity(), cf2.capacity());
+        assert_eq!(count_hits(&mut cf, &values), count_hits(&mut cf2, &values));
+        Ok(())
+    }
 }
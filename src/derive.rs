@@ -0,0 +1,457 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+use serde_json::json;
+use serde_json::to_string_pretty;
+use size_display::Size;
+use std::env;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::archive::{Data, SLAB_SIZE_TARGET};
+use crate::config;
+use crate::output::Output;
+use crate::pack::new_stream_path;
+use crate::partition;
+use crate::stream::*;
+use crate::stream_builders::*;
+use crate::stream_reader::{open_archive, SeekIndex, StreamReader};
+use crate::stream_slabs::StreamSlabsWriter;
+
+//-----------------------------------------
+
+// Each part of the new stream is one of these, in order.
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Range {
+        stream: String,
+        offset: u64,
+        len: Option<u64>,
+    },
+    Partition {
+        stream: String,
+        n: u32,
+    },
+    Fill {
+        byte: u8,
+        len: u64,
+    },
+    Unmapped {
+        len: u64,
+    },
+}
+
+fn parse_u64(s: &str) -> Result<u64> {
+    s.parse::<u64>()
+        .with_context(|| format!("'{}' is not a number", s))
+}
+
+// Accepts 'OFFSET+LEN' or just 'OFFSET'.
+fn parse_range(s: &str) -> Result<(u64, Option<u64>)> {
+    match s.split_once('+') {
+        Some((offset, len)) => Ok((parse_u64(offset)?, Some(parse_u64(len)?))),
+        None => Ok((parse_u64(s)?, None)),
+    }
+}
+
+// STREAM, STREAM:OFFSET[+LEN], STREAM:pN, zero:LEN or unmapped:LEN
+fn parse_segment(s: &str) -> Result<Segment> {
+    let (stream, rest) = match s.split_once(':') {
+        Some((stream, rest)) => (stream, Some(rest)),
+        None => (s, None),
+    };
+
+    let seg = match (stream, rest) {
+        ("zero", Some(len)) => Segment::Fill {
+            byte: 0,
+            len: parse_u64(len)?,
+        },
+        ("unmapped", Some(len)) => Segment::Unmapped {
+            len: parse_u64(len)?,
+        },
+        ("zero" | "unmapped", None) => return Err(anyhow!("'{}' needs a length", s)),
+        (stream, None) => Segment::Range {
+            stream: stream.to_string(),
+            offset: 0,
+            len: None,
+        },
+        (stream, Some(rest)) => match rest.strip_prefix('p') {
+            Some(n) => Segment::Partition {
+                stream: stream.to_string(),
+                n: n.parse::<u32>()
+                    .with_context(|| format!("bad partition in '{}'", s))?,
+            },
+            None => {
+                let (offset, len) =
+                    parse_range(rest).with_context(|| format!("bad range in '{}'", s))?;
+                Segment::Range {
+                    stream: stream.to_string(),
+                    offset,
+                    len,
+                }
+            }
+        },
+    };
+
+    Ok(seg)
+}
+
+// Cuts the parts of an entry, starting at byte pos of the new stream,
+// that fall in the sorted zero ranges and replaces them with fills.
+fn apply_zeroes(e: &MapEntry, len: u64, pos: u64, zeroes: &[(u64, u64)]) -> Vec<(MapEntry, u64)> {
+    let mut r = Vec::new();
+    let mut e = *e;
+    let (mut pos, mut len) = (pos, len);
+
+    for (z_begin, z_end) in zeroes {
+        if *z_end <= pos {
+            continue;
+        }
+        if *z_begin >= pos + len {
+            break;
+        }
+
+        if *z_begin > pos {
+            let (before, after) = split_entry(&e, len, z_begin - pos);
+            r.push((before, z_begin - pos));
+            e = after;
+            len -= z_begin - pos;
+            pos = *z_begin;
+        }
+
+        let n = std::cmp::min(z_end - pos, len);
+        r.push((MapEntry::Fill { byte: 0, len: n }, n));
+        if n == len {
+            return r;
+        }
+        e = split_entry(&e, len, n).1;
+        len -= n;
+        pos += n;
+    }
+
+    r.push((e, len));
+    r
+}
+
+//-----------------------------------------
+
+// Writes the new stream.  Only stream slabs are written, the entries
+// refer to data already in the archive.
+struct Deriver {
+    stream_file: StreamSlabsWriter,
+    stream_buf: Vec<u8>,
+    builder: MappingBuilder,
+    zeroes: Vec<(u64, u64)>,
+
+    size: u64,
+    mapped_size: u64,
+}
+
+impl Deriver {
+    fn add(&mut self, e: &MapEntry, len: u64) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        for (e, len) in apply_zeroes(e, len, self.size, &self.zeroes) {
+            if !matches!(e, MapEntry::Unmapped { .. }) {
+                self.mapped_size += len;
+            }
+            self.builder.next(&e, len, &mut self.stream_buf)?;
+            self.size += len;
+            let cuts = self.builder.take_cuts();
+            self.stream_file
+                .complete_slabs(&mut self.stream_buf, &cuts, SLAB_SIZE_TARGET)?;
+        }
+        Ok(())
+    }
+
+    // Copies len bytes of a stream, starting at the reader's position.
+    fn copy(&mut self, reader: &mut StreamReader, len: u64) -> Result<()> {
+        let mut remaining = len;
+        while remaining > 0 {
+            let (e, n) = reader
+                .next_entry(remaining)?
+                .ok_or_else(|| anyhow!("range extends beyond the end of the stream"))?;
+            self.add(&e, n)?;
+            remaining -= n;
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        self.builder.complete(&mut self.stream_buf)?;
+        let cuts = self.builder.take_cuts();
+        self.stream_file
+            .complete_slabs(&mut self.stream_buf, &cuts, 0)?;
+        self.stream_file.close()?;
+        Ok(())
+    }
+}
+
+//-----------------------------------------
+
+fn parse_zeroes(matches: &ArgMatches) -> Result<Vec<(u64, u64)>> {
+    let mut zeroes = Vec::new();
+    if let Some(ranges) = matches.get_many::<String>("ZERO") {
+        for z in ranges {
+            match parse_range(z)? {
+                (offset, Some(len)) if len > 0 => zeroes.push((offset, offset + len)),
+                _ => return Err(anyhow!("--zero takes OFFSET+LEN, not '{}'", z)),
+            }
+        }
+    }
+
+    // Overlapping ranges are merged.
+    zeroes.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(zeroes.len());
+    for (b, e) in zeroes {
+        match merged.last_mut() {
+            Some((_, last_end)) if b <= *last_end => *last_end = std::cmp::max(*last_end, e),
+            _ => merged.push((b, e)),
+        }
+    }
+    Ok(merged)
+}
+
+// The number of bytes a segment adds to the new stream.
+fn segment_len(seg: &Segment, archive: &Arc<Mutex<Data>>) -> Result<u64> {
+    match seg {
+        Segment::Range {
+            stream,
+            offset,
+            len,
+        } => {
+            let reader = StreamReader::with_archive(stream, archive.clone())?;
+            if *offset > reader.size() {
+                return Err(anyhow!("offset {} is beyond the end of {}", offset, stream));
+            }
+            Ok(len.unwrap_or(reader.size() - *offset))
+        }
+        Segment::Partition { stream, n } => {
+            let mut reader = StreamReader::with_archive(stream, archive.clone())?;
+            let (_, len) = partition::find_partition(&mut reader, *n)?;
+            Ok(len)
+        }
+        Segment::Fill { len, .. } | Segment::Unmapped { len } => Ok(*len),
+    }
+}
+
+fn derive(
+    mut deriver: Deriver,
+    segments: &[Segment],
+    archive: &Arc<Mutex<Data>>,
+) -> Result<Deriver> {
+    // The stream slabs are shared, so check the zero ranges before any
+    // are written.
+    if let Some((_, end)) = deriver.zeroes.last() {
+        let mut size = 0;
+        for seg in segments {
+            size += segment_len(seg, archive)?;
+        }
+        if *end > size {
+            return Err(anyhow!("--zero range extends beyond the new stream"));
+        }
+    }
+
+    for seg in segments {
+        match seg {
+            Segment::Range {
+                stream,
+                offset,
+                len,
+            } => {
+                let mut reader = StreamReader::with_archive(stream, archive.clone())?;
+                if *offset > reader.size() {
+                    return Err(anyhow!("offset {} is beyond the end of {}", offset, stream));
+                }
+                let len = len.unwrap_or(reader.size() - *offset);
+                reader.seek(SeekFrom::Start(*offset))?;
+                deriver.copy(&mut reader, len)?;
+            }
+            Segment::Partition { stream, n } => {
+                let mut reader = StreamReader::with_archive(stream, archive.clone())?;
+                let (offset, len) = partition::find_partition(&mut reader, *n)?;
+                reader.seek(SeekFrom::Start(offset))?;
+                deriver.copy(&mut reader, len)?;
+            }
+            Segment::Fill { byte, len } => deriver.add(
+                &MapEntry::Fill {
+                    byte: *byte,
+                    len: *len,
+                },
+                *len,
+            )?,
+            Segment::Unmapped { len } => deriver.add(&MapEntry::Unmapped { len: *len }, *len)?,
+        }
+    }
+    deriver.complete()?;
+    Ok(deriver)
+}
+
+pub fn run(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
+    let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap()).canonicalize()?;
+    let specs: Vec<&String> = matches.get_many::<String>("SEGMENT").unwrap().collect();
+    let segments = specs
+        .iter()
+        .map(|s| parse_segment(s))
+        .collect::<Result<Vec<Segment>>>()?;
+    let zeroes = parse_zeroes(matches)?;
+
+    env::set_current_dir(archive_dir)?;
+    let config = config::read_config(".", matches)?;
+    let archive = Arc::new(Mutex::new(open_archive(&config)?));
+
+    let (stream_id, stream_dir) = new_stream_path()?;
+    std::fs::create_dir(&stream_dir)?;
+    let stream_file = StreamSlabsWriter::new(&stream_id)?;
+
+    let deriver = Deriver {
+        stream_file,
+        stream_buf: Vec::new(),
+        builder: MappingBuilder::default(),
+        zeroes,
+        size: 0,
+        mapped_size: 0,
+    };
+
+    output
+        .report
+        .set_title(&format!("Deriving stream {} ...", stream_id));
+    let deriver = match derive(deriver, &segments, &archive) {
+        Ok(deriver) => deriver,
+        Err(e) => {
+            // Nothing refers to the partial stream.
+            let _ = std::fs::remove_dir_all(&stream_dir);
+            return Err(e);
+        }
+    };
+
+    let stream_written = deriver.stream_file.get_file_size();
+    let cfg = config::StreamConfig {
+        name: matches.get_one::<String>("NAME").cloned(),
+        source_path: format!(
+            "derive {}",
+            specs
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+        pack_time: config::now(),
+        size: deriver.size,
+        mapped_size: deriver.mapped_size,
+        packed_size: stream_written,
+        thin_id: None,
+        pool_uuid: None,
+        dm_uuid: None,
+        creation_time: None,
+        snapshotted_time: None,
+        transaction_id: None,
+        vg_name: None,
+        lv_name: None,
+        era: None,
+    };
+    config::write_stream_config(&stream_id, &cfg)?;
+    SeekIndex::create(&stream_id, &mut archive.lock().unwrap())?;
+
+    if output.json {
+        let result = json!({ "stream_id": stream_id, "size": deriver.size });
+        println!("{}", to_string_pretty(&result).unwrap());
+    } else {
+        output
+            .report
+            .info(&format!("stream id        : {}", stream_id));
+        output
+            .report
+            .info(&format!("size             : {:.2}", Size(deriver.size)));
+        output
+            .report
+            .info(&format!("stream written   : {:.2}", Size(stream_written)));
+    }
+
+    Ok(())
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod derive_tests {
+    use super::*;
+
+    fn data(slab: u32) -> MapEntry {
+        MapEntry::Data {
+            archive: 0,
+            slab,
+            offset: 0,
+            nr_entries: 1,
+        }
+    }
+
+    #[test]
+    fn segments() -> Result<()> {
+        let range = |offset, len| Segment::Range {
+            stream: "0123456789abcdef".to_string(),
+            offset,
+            len,
+        };
+        assert_eq!(parse_segment("0123456789abcdef")?, range(0, None));
+        assert_eq!(parse_segment("0123456789abcdef:4096")?, range(4096, None));
+        assert_eq!(
+            parse_segment("0123456789abcdef:4096+512")?,
+            range(4096, Some(512))
+        );
+        assert_eq!(
+            parse_segment("0123456789abcdef:p2")?,
+            Segment::Partition {
+                stream: "0123456789abcdef".to_string(),
+                n: 2
+            }
+        );
+        assert_eq!(
+            parse_segment("zero:100")?,
+            Segment::Fill { byte: 0, len: 100 }
+        );
+        assert_eq!(
+            parse_segment("unmapped:100")?,
+            Segment::Unmapped { len: 100 }
+        );
+        assert!(parse_segment("zero").is_err());
+        assert!(parse_segment("0123456789abcdef:x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn zeroes_split_entries() {
+        let zeroes = [(100, 200), (300, 350)];
+
+        // Entirely outside
+        assert_eq!(
+            apply_zeroes(&data(1), 100, 0, &zeroes),
+            vec![(data(1), 100)]
+        );
+
+        // Straddling both ranges
+        let r = apply_zeroes(&data(1), 400, 0, &zeroes);
+        let lens: Vec<u64> = r.iter().map(|(_, len)| *len).collect();
+        assert_eq!(lens, vec![100, 100, 100, 50, 50]);
+        assert_eq!(r[1].0, MapEntry::Fill { byte: 0, len: 100 });
+        assert_eq!(r[3].0, MapEntry::Fill { byte: 0, len: 50 });
+        assert!(matches!(
+            r[4].0,
+            MapEntry::Partial {
+                begin: 350,
+                end: 400,
+                ..
+            }
+        ));
+
+        // Entirely within
+        assert_eq!(
+            apply_zeroes(&data(1), 20, 310, &zeroes),
+            vec![(MapEntry::Fill { byte: 0, len: 20 }, 20)]
+        );
+    }
+}
+
+//-----------------------------------------
@@ -0,0 +1,428 @@
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use serde_json::json;
+use serde_json::to_string_pretty;
+use size_display::Size;
+use std::collections::VecDeque;
+use std::env;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::archive::Data;
+use crate::config;
+use crate::output::Output;
+use crate::stream::*;
+use crate::stream_reader::{open_archive, StreamReader};
+use crate::stream_slabs::StreamSlabs;
+
+//-----------------------------------------
+
+// Part of a stream, never bigger than a single chunk unless it's a fill.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Piece {
+    // Unmapped regions read as zeroes, so they're fills too.
+    Fill(u8),
+    Chunk {
+        archive: u8,
+        slab: u32,
+        offset: u32,
+
+        // Deltas are identified by where the delta is stored.  Their
+        // hash is that of the delta, so can't be compared.
+        delta: bool,
+
+        // Where the piece starts within the chunk, and whether it
+        // covers all of it.
+        begin: u64,
+        whole: bool,
+    },
+}
+
+// Compares len bytes, starting a_off and b_off bytes into the pieces,
+// using only the stream entries.  None means the data has to be looked
+// at.
+fn compare_refs(a: &Piece, a_off: u64, b: &Piece, b_off: u64) -> Option<bool> {
+    use Piece::*;
+
+    match (a, b) {
+        (Fill(x), Fill(y)) => Some(x == y),
+        (
+            Chunk {
+                archive: a_archive,
+                slab: a_slab,
+                offset: a_offset,
+                delta: a_delta,
+                begin: a_begin,
+                ..
+            },
+            Chunk {
+                archive: b_archive,
+                slab: b_slab,
+                offset: b_offset,
+                delta: b_delta,
+                begin: b_begin,
+                ..
+            },
+        ) if (a_archive, a_slab, a_offset, a_delta) == (b_archive, b_slab, b_offset, b_delta)
+            && a_begin + a_off == b_begin + b_off =>
+        {
+            Some(true)
+        }
+        _ => None,
+    }
+}
+
+//-----------------------------------------
+
+// One of the streams being compared.  Entries are broken into pieces as
+// they're needed, so only the current entry is ever held.
+struct Side {
+    id: String,
+    size: u64,
+    entries: StreamIter,
+    pieces: VecDeque<(Piece, u64)>,
+
+    // How much of the front piece has already been compared.
+    consumed: u64,
+
+    // Only opened if the data has to be read.
+    reader: Option<StreamReader>,
+}
+
+impl Side {
+    // Assumes current directory is the root of the archive.
+    fn new(id: &str) -> Result<Self> {
+        let file = StreamSlabs::open(id)?;
+        Ok(Self {
+            id: id.to_string(),
+            size: config::read_stream_config(id)?.size,
+            entries: StreamIter::new(file)?,
+            pieces: VecDeque::new(),
+            consumed: 0,
+            reader: None,
+        })
+    }
+
+    fn push_run(
+        &mut self,
+        archive: &mut Data,
+        (data_archive, slab, offset, nr_entries): (u8, u32, u32, u32),
+        range: Option<(u32, u32)>,
+    ) -> Result<()> {
+        let (begin, end) = range
+            .map(|(b, e)| (b as u64, e as u64))
+            .unwrap_or((0, u64::MAX));
+
+        let mut chunk_start = 0;
+        for i in 0..nr_entries {
+            let chunk_len = archive.data_len(data_archive, slab, offset + i, 1)?;
+            let chunk_end = chunk_start + chunk_len;
+            let (b, e) = (begin.max(chunk_start), end.min(chunk_end));
+            if b < e {
+                let piece = Piece::Chunk {
+                    archive: data_archive,
+                    slab,
+                    offset: offset + i,
+                    delta: false,
+                    begin: b - chunk_start,
+                    whole: e - b == chunk_len,
+                };
+                self.pieces.push_back((piece, e - b));
+            }
+            chunk_start = chunk_end;
+        }
+        Ok(())
+    }
+
+    fn push_entry(&mut self, archive: &mut Data, e: &MapEntry) -> Result<()> {
+        use MapEntry::*;
+
+        match e {
+            Fill { byte, len } => self.pieces.push_back((Piece::Fill(*byte), *len)),
+            Unmapped { len } => self.pieces.push_back((Piece::Fill(0), *len)),
+            Data {
+                archive: a,
+                slab,
+                offset,
+                nr_entries,
+            } => self.push_run(archive, (*a, *slab, *offset, *nr_entries), None)?,
+            Partial {
+                begin,
+                end,
+                archive: a,
+                slab,
+                offset,
+                nr_entries,
+            } => self.push_run(
+                archive,
+                (*a, *slab, *offset, *nr_entries),
+                Some((*begin, *end)),
+            )?,
+            Delta {
+                slab,
+                offset,
+                len,
+                partial,
+                ..
+            } => {
+                let (begin, end) = partial.unwrap_or((0, *len));
+                let piece = Piece::Chunk {
+                    archive: 0,
+                    slab: *slab,
+                    offset: *offset,
+                    delta: true,
+                    begin: begin as u64,
+                    whole: end - begin == *len,
+                };
+                self.pieces.push_back((piece, (end - begin) as u64));
+            }
+            Ref { .. } => return Err(anyhow!("unexpected MapEntry::Ref in stream")),
+        }
+        Ok(())
+    }
+
+    // Returns the current piece, how far into it we are, and how many
+    // bytes of it remain.  None at the end of the stream.
+    fn front(&mut self, archive: &mut Data) -> Result<Option<(Piece, u64, u64)>> {
+        while self.pieces.is_empty() {
+            match self.entries.next() {
+                Some(e) => self.push_entry(archive, &e?)?,
+                None => return Ok(None),
+            }
+        }
+
+        let (piece, len) = self.pieces.front().unwrap();
+        Ok(Some((*piece, self.consumed, len - self.consumed)))
+    }
+
+    fn advance(&mut self, len: u64) {
+        self.consumed += len;
+        if self.consumed == self.pieces.front().unwrap().1 {
+            self.pieces.pop_front();
+            self.consumed = 0;
+        }
+    }
+
+    fn read(&mut self, archive: &Arc<Mutex<Data>>, pos: u64, buf: &mut [u8]) -> Result<()> {
+        if self.reader.is_none() {
+            self.reader = Some(StreamReader::with_archive(&self.id, archive.clone())?);
+        }
+
+        let reader = self.reader.as_mut().unwrap();
+        reader.seek(SeekFrom::Start(pos))?;
+        reader.read_exact(buf)?;
+        Ok(())
+    }
+}
+
+//-----------------------------------------
+
+struct Differ {
+    archive: Arc<Mutex<Data>>,
+    a: Side,
+    b: Side,
+
+    // Changed byte ranges, adjacent ones are merged.
+    changed: Vec<(u64, u64)>,
+}
+
+impl Differ {
+    fn mark_changed(&mut self, begin: u64, end: u64) {
+        match self.changed.last_mut() {
+            Some((_, last_end)) if *last_end == begin => *last_end = end,
+            _ => self.changed.push((begin, end)),
+        }
+    }
+
+    // Looks at the data of len bytes at pos.  Whole chunks have their
+    // hashes compared rather than being read.
+    fn same_data(
+        &mut self,
+        pos: u64,
+        len: u64,
+        a: &Piece,
+        b: &Piece,
+        in_full: bool,
+    ) -> Result<bool> {
+        use Piece::*;
+
+        if let (
+            Chunk {
+                archive: a_archive,
+                slab: a_slab,
+                offset: a_offset,
+                delta: false,
+                whole: true,
+                ..
+            },
+            Chunk {
+                archive: b_archive,
+                slab: b_slab,
+                offset: b_offset,
+                delta: false,
+                whole: true,
+                ..
+            },
+        ) = (a, b)
+        {
+            if in_full {
+                let mut archive = self.archive.lock().unwrap();
+                let a_hash = archive.data_hash(*a_archive, *a_slab, *a_offset)?;
+                let b_hash = archive.data_hash(*b_archive, *b_slab, *b_offset)?;
+                return Ok(a_hash == b_hash);
+            }
+        }
+
+        let mut a_buf = vec![0; len as usize];
+        let mut b_buf = vec![0; len as usize];
+        self.a.read(&self.archive, pos, &mut a_buf)?;
+        self.b.read(&self.archive, pos, &mut b_buf)?;
+        Ok(a_buf == b_buf)
+    }
+
+    fn diff(&mut self, output: &Output) -> Result<()> {
+        let common = std::cmp::min(self.a.size, self.b.size);
+        let mut pos = 0;
+
+        output.report.progress(0);
+        while pos < common {
+            let (a, b) = {
+                let mut archive = self.archive.lock().unwrap();
+                (self.a.front(&mut archive)?, self.b.front(&mut archive)?)
+            };
+            let ((a, a_off, a_len), (b, b_off, b_len)) = match (a, b) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return Err(anyhow!("stream is shorter than its recorded size")),
+            };
+
+            let len = std::cmp::min(std::cmp::min(a_len, b_len), common - pos);
+            let same = match compare_refs(&a, a_off, &b, b_off) {
+                Some(same) => same,
+                None => {
+                    // Hashes are only useful if both chunks are compared in full.
+                    let in_full = a_off == 0 && b_off == 0 && len == a_len && len == b_len;
+                    self.same_data(pos, len, &a, &b, in_full)?
+                }
+            };
+            if !same {
+                self.mark_changed(pos, pos + len);
+            }
+
+            self.a.advance(len);
+            self.b.advance(len);
+            pos += len;
+            output.report.progress(((pos * 100) / common) as u8);
+        }
+
+        // Anything beyond the end of the shorter stream has changed.
+        let end = std::cmp::max(self.a.size, self.b.size);
+        if end > common {
+            self.mark_changed(common, end);
+        }
+        output.report.progress(100);
+
+        Ok(())
+    }
+}
+
+//-----------------------------------------
+
+pub fn run(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
+    let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap()).canonicalize()?;
+    let streams: Vec<&String> = matches.get_many::<String>("STREAM").unwrap().collect();
+    if streams.len() != 2 {
+        return Err(anyhow!("diff needs exactly two streams"));
+    }
+
+    env::set_current_dir(archive_dir)?;
+    let config = config::read_config(".", matches)?;
+
+    let mut differ = Differ {
+        archive: Arc::new(Mutex::new(open_archive(&config)?)),
+        a: Side::new(streams[0])?,
+        b: Side::new(streams[1])?,
+        changed: Vec::new(),
+    };
+
+    output
+        .report
+        .set_title(&format!("Comparing {} and {} ...", streams[0], streams[1]));
+    differ.diff(&output)?;
+
+    let total: u64 = differ.changed.iter().map(|(b, e)| e - b).sum();
+    if output.json {
+        let ranges: Vec<_> = differ
+            .changed
+            .iter()
+            .map(|(b, e)| json!({"offset": b, "len": e - b}))
+            .collect();
+        let result = json!({
+            "streams": [streams[0], streams[1]],
+            "sizes": [differ.a.size, differ.b.size],
+            "changed": ranges,
+            "nr_ranges": differ.changed.len(),
+            "changed_bytes": total,
+        });
+        println!("{}", to_string_pretty(&result).unwrap());
+    } else {
+        let width = format!("{}", std::cmp::max(differ.a.size, differ.b.size)).len();
+        for (b, e) in &differ.changed {
+            output
+                .report
+                .to_stdout(&format!("{:width$} {:width$}", b, e - b));
+        }
+        output
+            .report
+            .to_stdout(&format!("changed ranges   : {}", differ.changed.len()));
+        output
+            .report
+            .to_stdout(&format!("changed bytes    : {:.2}", Size(total)));
+    }
+
+    Ok(())
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod diff_tests {
+    use super::*;
+
+    fn chunk(slab: u32, offset: u32, begin: u64) -> Piece {
+        Piece::Chunk {
+            archive: 0,
+            slab,
+            offset,
+            delta: false,
+            begin,
+            whole: false,
+        }
+    }
+
+    #[test]
+    fn fills_compare_by_byte() {
+        use Piece::*;
+        assert_eq!(compare_refs(&Fill(0), 0, &Fill(0), 100), Some(true));
+        assert_eq!(compare_refs(&Fill(0), 0, &Fill(1), 0), Some(false));
+        assert_eq!(compare_refs(&Fill(0), 0, &chunk(1, 2, 0), 0), None);
+    }
+
+    #[test]
+    fn chunks_compare_by_location() {
+        let a = chunk(3, 7, 0);
+        assert_eq!(compare_refs(&a, 10, &a, 10), Some(true));
+        assert_eq!(compare_refs(&a, 100, &chunk(3, 7, 90), 10), Some(true));
+
+        // Different locations, or parts, need the data looking at.
+        assert_eq!(compare_refs(&a, 0, &a, 10), None);
+        assert_eq!(compare_refs(&a, 0, &chunk(3, 8, 0), 0), None);
+        let mut d = a;
+        if let Piece::Chunk { delta, .. } = &mut d {
+            *delta = true;
+        }
+        assert_eq!(compare_refs(&a, 0, &d, 0), None);
+    }
+}
+
+//-----------------------------------------
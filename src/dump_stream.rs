@@ -1,22 +1,297 @@
 use anyhow::Result;
 use clap::ArgMatches;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
 use std::env;
 use std::path::Path;
 use std::sync::Arc;
 
+use crate::config;
 use crate::output::Output;
 use crate::stream::*;
+use crate::stream_reader::StreamReader;
+
+//-----------------------------------------
+
+// Columns after offset, len and kind.
+const CSV_FIELDS: [&str; 10] = [
+    "archive",
+    "slab",
+    "slab_offset",
+    "nr_entries",
+    "begin",
+    "end",
+    "byte",
+    "base_slab",
+    "base_offset",
+    "chunk_len",
+];
+
+// The kind of an entry along with its fields.  Fields that don't
+// apply to the kind are left out.
+fn entry_fields(e: &MapEntry) -> (&'static str, Vec<(&'static str, u64)>) {
+    use MapEntry::*;
+
+    match e {
+        Fill { byte, .. } => ("fill", vec![("byte", *byte as u64)]),
+        Unmapped { .. } => ("unmapped", vec![]),
+        Data {
+            archive,
+            slab,
+            offset,
+            nr_entries,
+        } => (
+            "data",
+            vec![
+                ("archive", *archive as u64),
+                ("slab", *slab as u64),
+                ("slab_offset", *offset as u64),
+                ("nr_entries", *nr_entries as u64),
+            ],
+        ),
+        Partial {
+            begin,
+            end,
+            archive,
+            slab,
+            offset,
+            nr_entries,
+        } => (
+            "partial",
+            vec![
+                ("archive", *archive as u64),
+                ("slab", *slab as u64),
+                ("slab_offset", *offset as u64),
+                ("nr_entries", *nr_entries as u64),
+                ("begin", *begin as u64),
+                ("end", *end as u64),
+            ],
+        ),
+        Delta {
+            base_slab,
+            base_offset,
+            slab,
+            offset,
+            len,
+            partial,
+        } => {
+            let mut fields = vec![
+                ("slab", *slab as u64),
+                ("slab_offset", *offset as u64),
+                ("base_slab", *base_slab as u64),
+                ("base_offset", *base_offset as u64),
+                ("chunk_len", *len as u64),
+            ];
+            if let Some((begin, end)) = partial {
+                fields.push(("begin", *begin as u64));
+                fields.push(("end", *end as u64));
+            }
+            ("delta", fields)
+        }
+        Ref { .. } => ("ref", vec![]),
+    }
+}
+
+// Calls fn for every entry of the stream, with its logical offset and
+// length.
+fn walk<F>(reader: &mut StreamReader, mut f: F) -> Result<()>
+where
+    F: FnMut(u64, u64, &MapEntry) -> Result<()>,
+{
+    let mut offset = 0;
+    while let Some((e, len)) = reader.next_entry(u64::MAX)? {
+        f(offset, len, &e)?;
+        offset += len;
+    }
+    Ok(())
+}
+
+fn dump_json(reader: &mut StreamReader) -> Result<()> {
+    println!("[");
+    let mut first = true;
+    walk(reader, |offset, len, e| {
+        let (kind, fields) = entry_fields(e);
+        let mut m = Map::new();
+        m.insert("offset".to_string(), json!(offset));
+        m.insert("len".to_string(), json!(len));
+        m.insert("kind".to_string(), json!(kind));
+        for (k, v) in fields {
+            m.insert(k.to_string(), json!(v));
+        }
+
+        if !first {
+            println!(",");
+        }
+        first = false;
+        print!("  {}", Value::Object(m));
+        Ok(())
+    })?;
+    println!("\n]");
+    Ok(())
+}
+
+fn dump_csv(reader: &mut StreamReader) -> Result<()> {
+    println!("offset,len,kind,{}", CSV_FIELDS.join(","));
+    walk(reader, |offset, len, e| {
+        let (kind, fields) = entry_fields(e);
+        let values: Vec<String> = CSV_FIELDS
+            .iter()
+            .map(|c| {
+                fields
+                    .iter()
+                    .find(|(k, _)| k == c)
+                    .map(|(_, v)| v.to_string())
+                    .unwrap_or_default()
+            })
+            .collect();
+        println!("{},{},{},{}", offset, len, kind, values.join(","));
+        Ok(())
+    })
+}
+
+//-----------------------------------------
+
+#[derive(Default)]
+struct EntryStats {
+    entries: u64,
+    data_bytes: u64,
+    fill_bytes: u64,
+    unmapped_bytes: u64,
+    delta_entries: u64,
+
+    // Entries referring to each data slab, keyed by (archive, slab).
+    slab_refs: BTreeMap<(u8, u32), u64>,
+
+    // How often consecutive entries refer to different data slabs.
+    slab_switches: u64,
+    last_slab: Option<(u8, u32)>,
+}
+
+impl EntryStats {
+    fn add_slab(&mut self, slab: (u8, u32)) {
+        *self.slab_refs.entry(slab).or_default() += 1;
+        if self.last_slab.is_some_and(|last| last != slab) {
+            self.slab_switches += 1;
+        }
+        self.last_slab = Some(slab);
+    }
+
+    fn add(&mut self, len: u64, e: &MapEntry) {
+        use MapEntry::*;
+
+        self.entries += 1;
+        match e {
+            Fill { .. } => self.fill_bytes += len,
+            Unmapped { .. } => self.unmapped_bytes += len,
+            Data { archive, slab, .. } | Partial { archive, slab, .. } => {
+                self.data_bytes += len;
+                self.add_slab((*archive, *slab));
+            }
+            Delta {
+                base_slab, slab, ..
+            } => {
+                // Rebuilding a delta reads the base too.
+                self.data_bytes += len;
+                self.delta_entries += 1;
+                self.add_slab((0, *base_slab));
+                self.add_slab((0, *slab));
+            }
+            Ref { .. } => {}
+        }
+    }
+
+    fn to_json(&self, size: u64) -> Value {
+        let nr_slabs = self.slab_refs.len() as u64;
+        let nr_refs: u64 = self.slab_refs.values().sum();
+        let max_refs = self.slab_refs.values().max().cloned().unwrap_or(0);
+        let gigs = self.data_bytes as f64 / (1024.0 * 1024.0 * 1024.0);
+
+        json!({
+            "size": size,
+            "entries": self.entries,
+            "data_bytes": self.data_bytes,
+            "fill_bytes": self.fill_bytes,
+            "unmapped_bytes": self.unmapped_bytes,
+            "delta_entries": self.delta_entries,
+            "data_slabs": nr_slabs,
+            "slab_refs": nr_refs,
+            "mean_refs_per_slab": if nr_slabs > 0 { nr_refs as f64 / nr_slabs as f64 } else { 0.0 },
+            "max_refs_per_slab": max_refs,
+            "slab_switches": self.slab_switches,
+            "slabs_per_gb": if gigs > 0.0 { nr_slabs as f64 / gigs } else { 0.0 },
+        })
+    }
+}
+
+fn dump_stats(
+    stream: &str,
+    reader: &mut StreamReader,
+    format: Option<&String>,
+    output: Arc<Output>,
+) -> Result<()> {
+    let mut stats = EntryStats::default();
+    walk(reader, |_offset, len, e| {
+        stats.add(len, e);
+        Ok(())
+    })?;
+    let mut summary = stats.to_json(reader.size());
+
+    let instructions = Dumper::new(stream)?.instruction_stats()?;
+    summary["instructions"] = json!(instructions.iter().cloned().collect::<BTreeMap<_, _>>());
+
+    match format.map(|f| f.as_str()) {
+        Some("csv") => {
+            println!("name,value");
+            for (k, v) in summary.as_object().unwrap() {
+                if k != "instructions" {
+                    println!("{},{}", k, v);
+                }
+            }
+            for (instr, count) in instructions {
+                println!("instructions.{},{}", instr, count);
+            }
+        }
+        Some("json") => println!("{}", serde_json::to_string_pretty(&summary)?),
+        _ if output.json => println!("{}", serde_json::to_string_pretty(&summary)?),
+        _ => {
+            let m = summary.as_object().unwrap();
+            for (k, v) in m {
+                if k != "instructions" {
+                    println!("{:>20} {}", k, v);
+                }
+            }
+            println!("\nInstruction frequencies:\n");
+            for (instr, count) in instructions {
+                println!("    {:>15} {:<10}", instr, count);
+            }
+        }
+    }
+    Ok(())
+}
 
 //-----------------------------------------
 
 pub fn run(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
     let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap()).canonicalize()?;
     let stream = matches.get_one::<String>("STREAM").unwrap();
+    let format = matches.get_one::<String>("FORMAT");
 
     env::set_current_dir(archive_dir)?;
 
-    let mut d = Dumper::new(stream)?;
-    d.dump(output)
+    if !matches.get_flag("STATS") && format.is_none() {
+        let mut d = Dumper::new(stream)?;
+        return d.dump(output);
+    }
+
+    let config = config::read_config(".", matches)?;
+    let mut reader = StreamReader::new(stream, &config)?;
+    if matches.get_flag("STATS") {
+        dump_stats(stream, &mut reader, format, output)
+    } else if format.unwrap() == "csv" {
+        dump_csv(&mut reader)
+    } else {
+        dump_json(&mut reader)
+    }
 }
 
 //-----------------------------------------
@@ -0,0 +1,276 @@
+use anyhow::{anyhow, Result};
+use devicemapper::*;
+use nom::IResult;
+use roaring::bitmap::RoaringBitmap;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thinp::era::superblock::*;
+use thinp::era::writeset::Writeset;
+use thinp::io_engine::*;
+use thinp::pdata::array::unpack_array_block;
+use thinp::pdata::btree_walker::btree_to_map;
+use thinp::pdata::unpack::Unpack;
+
+use crate::thin_metadata::{collect_dm_devs, dm_uuid, find_device, get_table, parse_dev, DevMap};
+
+//---------------------------------
+
+/// The blocks of a dm-era device written since a given era.
+pub struct EraInfo {
+    pub data_block_size: u32,
+    pub nr_blocks: u32,
+
+    // The era current when the metadata was read.  Anything written
+    // after that is recorded against this era or a later one.
+    pub current_era: u32,
+
+    // Runs of blocks written since the era asked for, in order.
+    pub changed: Vec<Range<u64>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct EraDetails {
+    metadata_major: u32,
+    metadata_minor: u32,
+}
+
+fn parse_era_table(input: &str) -> IResult<&str, EraDetails> {
+    use nom::character::complete::*;
+
+    let (input, (metadata_major, metadata_minor)) = parse_dev(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, _origin) = parse_dev(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, _block_size) = u32(input)?;
+
+    Ok((
+        input,
+        EraDetails {
+            metadata_major,
+            metadata_minor,
+        },
+    ))
+}
+
+// The dm name of the device at path, if it's an era target.
+fn era_name<P: AsRef<Path>>(dm: &mut DM, dm_devs: &DevMap, path: P) -> Result<Option<DmNameBuf>> {
+    let rdev = std::fs::metadata(path)?.rdev();
+    let dev = Device::from(rdev);
+    let name = match dm_devs.get(&(dev.major, dev.minor)) {
+        None => return Ok(None),
+        Some(name) => name.clone(),
+    };
+
+    match get_table(dm, &DevId::Name(&name), "era") {
+        Ok(args) if parse_era_table(&args).is_ok() => Ok(Some(name)),
+        _ => Ok(None),
+    }
+}
+
+pub fn is_era_device<P: AsRef<Path>>(path: P) -> Result<bool> {
+    if !std::fs::metadata(&path)?.file_type().is_block_device() {
+        return Ok(false);
+    }
+
+    let mut dm = DM::new()?;
+    let dm_devs = collect_dm_devs(&mut dm)?;
+    Ok(era_name(&mut dm, &dm_devs, path)?.is_some())
+}
+
+//---------------------------------
+
+// An array is a btree of array blocks, keyed by their index.
+fn read_array<V: Unpack + Copy>(
+    engine: &Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+) -> Result<Vec<V>> {
+    let mut path = vec![];
+    let ablocks: BTreeMap<u64, u64> = btree_to_map(&mut path, engine.clone(), false, root)?;
+
+    let mut values = Vec::new();
+    for loc in ablocks.values() {
+        let b = engine.read(*loc)?;
+        let ablock = unpack_array_block::<V>(&[*loc], b.get_data())
+            .map_err(|e| anyhow!("couldn't read era array block {}: {}", loc, e))?;
+        values.extend(ablock.values);
+    }
+    Ok(values)
+}
+
+fn to_runs(blocks: &RoaringBitmap) -> Vec<Range<u64>> {
+    let mut runs: Vec<Range<u64>> = Vec::new();
+    for b in blocks.iter() {
+        let b = b as u64;
+        match runs.last_mut() {
+            Some(run) if run.end == b => run.end += 1,
+            _ => runs.push(b..(b + 1)),
+        }
+    }
+    runs
+}
+
+// Writesets are only folded into the era array lazily, so a block has
+// been written since an era if its array entry is that era or later, or
+// it's set in the writeset of that era or a later one.
+fn written_since(
+    since: u32,
+    nr_blocks: u32,
+    eras: &[u32],
+    writesets: &[Vec<u64>],
+) -> Vec<Range<u64>> {
+    let mut written = RoaringBitmap::new();
+    for (b, era) in eras.iter().take(nr_blocks as usize).enumerate() {
+        if *era >= since {
+            written.insert(b as u32);
+        }
+    }
+
+    for bits in writesets {
+        for (i, word) in bits.iter().enumerate() {
+            let mut w = *word;
+            while w != 0 {
+                let b = i as u64 * 64 + w.trailing_zeros() as u64;
+                if b < nr_blocks as u64 {
+                    written.insert(b as u32);
+                }
+                w &= w - 1;
+            }
+        }
+    }
+
+    to_runs(&written)
+}
+
+fn read_info(metadata: &Path, since: Option<u32>) -> Result<EraInfo> {
+    let engine: Arc<dyn IoEngine + Send + Sync> =
+        Arc::new(SyncIoEngine::new_with(metadata, false, false)?);
+
+    // Everything is read from the metadata snapshot, since the live
+    // metadata is changing under us.
+    let sb = read_superblock(&*engine, SUPERBLOCK_LOCATION)?;
+    if sb.metadata_snap == 0 {
+        return Err(anyhow!("era metadata doesn't have a snapshot"));
+    }
+    let sb = read_superblock(&*engine, sb.metadata_snap)?;
+
+    let changed = match since {
+        None => Vec::new(),
+        Some(since) if since > sb.current_era => {
+            return Err(anyhow!(
+                "era {} is later than the current era {}, has the metadata been recreated?",
+                since,
+                sb.current_era
+            ));
+        }
+        Some(since) => {
+            let eras: Vec<u32> = read_array(&engine, sb.era_array_root)?;
+
+            let mut path = vec![];
+            let archived: BTreeMap<u64, Writeset> =
+                btree_to_map(&mut path, engine.clone(), false, sb.writeset_tree_root)?;
+            let mut writesets = Vec::new();
+            for ws in archived.range(since as u64..).map(|(_, ws)| ws) {
+                writesets.push(read_array::<u64>(&engine, ws.root)?);
+            }
+            writesets.push(read_array::<u64>(&engine, sb.current_writeset.root)?);
+
+            written_since(since, sb.nr_blocks, &eras, &writesets)
+        }
+    };
+
+    Ok(EraInfo {
+        data_block_size: sb.data_block_size,
+        nr_blocks: sb.nr_blocks,
+        current_era: sb.current_era,
+        changed,
+    })
+}
+
+//---------------------------------
+
+/// An active dm-era device.
+pub struct EraDevice {
+    dm: DM,
+    name: DmNameBuf,
+    metadata_path: PathBuf,
+    pub dm_uuid: Option<String>,
+}
+
+impl EraDevice {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut dm = DM::new()?;
+        let dm_devs = collect_dm_devs(&mut dm)?;
+
+        let name = era_name(&mut dm, &dm_devs, path)?
+            .ok_or_else(|| anyhow!("input isn't an era device"))?;
+        let args = get_table(&mut dm, &DevId::Name(&name), "era")?;
+        let (_, details) =
+            parse_era_table(&args).map_err(|_| anyhow!("couldn't parse era table"))?;
+
+        let metadata_path = find_device(details.metadata_major, details.metadata_minor)
+            .ok_or_else(|| anyhow!("Couldn't find era metadata device"))?;
+        let dm_uuid = dm_uuid(&dm, &name);
+
+        Ok(Self {
+            dm,
+            name,
+            metadata_path,
+            dm_uuid,
+        })
+    }
+
+    /// Taking the metadata snapshot also starts a new era, so blocks
+    /// written from now on are caught by a later delta.
+    pub fn read_changes(&self, since: Option<u32>) -> Result<EraInfo> {
+        let id = DevId::Name(&self.name);
+
+        self.dm.target_msg(&id, None, "take_metadata_snap")?;
+        let r = read_info(&self.metadata_path, since);
+        self.dm.target_msg(&id, None, "drop_metadata_snap")?;
+
+        r
+    }
+}
+
+//---------------------------------
+
+#[cfg(test)]
+mod era_tests {
+    use super::*;
+
+    #[test]
+    fn era_table() {
+        let (_, details) = parse_era_table("253:3 253:4 128").unwrap();
+        assert_eq!(
+            details,
+            EraDetails {
+                metadata_major: 253,
+                metadata_minor: 3,
+            }
+        );
+        assert!(parse_era_table("253:3").is_err());
+    }
+
+    #[test]
+    fn written_blocks() {
+        // Blocks 1 and 2 were last written in era 5, 3 in era 2.  The
+        // writesets not yet folded into the array add 7, 8 and 64.
+        let eras = vec![0, 5, 5, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let writesets = vec![vec![1 << 7, 0], vec![1 << 8, 1]];
+
+        assert_eq!(
+            written_since(3, 100, &eras, &writesets),
+            vec![1..3, 7..9, 64..65]
+        );
+        assert_eq!(written_since(1, 100, &eras, &[]), vec![1..4]);
+        assert_eq!(written_since(6, 100, &eras, &[]), vec![]);
+
+        // Bits past the end of the device are ignored.
+        assert_eq!(written_since(6, 10, &eras, &writesets), vec![7..9]);
+    }
+}
+
+//---------------------------------
@@ -0,0 +1,539 @@
+use anyhow::{anyhow, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::hash::*;
+use crate::slab::*;
+
+//------------------------------
+// An optional on disk index that maps every hash in the archive to its
+// (slab, offset).  Used to avoid paging in whole hash slabs when the
+// cuckoo filter reports a possible match.
+//
+// The index is a set of immutable, sorted runs.  A run is written each
+// time a batch of data slabs is completed.  Each run is a slab file
+// where every slab is a page of sorted entries, followed by a final
+// summary slab:
+//
+// page := <entry>*
+// entry := <hash256> <slab u32> <offset u32>
+// summary := <nr entries u64> <slab begin u32> <slab end u32> <first hash of each page>*
+//
+// The summaries are held in memory, so a lookup reads at most one page
+// per run.  Runs are merged, LSM style, to keep the number of runs
+// logarithmic in the number of entries.
+
+const ENTRY_SIZE: usize = 32 + 4 + 4;
+const ENTRIES_PER_PAGE: usize = 100;
+const PAGE_CACHE_SIZE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub hash: Hash256,
+    pub slab: u32,
+    pub offset: u32,
+}
+
+fn entry_at(page: &[u8], i: usize) -> Result<IndexEntry> {
+    let b = i * ENTRY_SIZE;
+    let hash = *Hash256::from_slice(&page[b..b + 32]);
+    let mut c = Cursor::new(&page[b + 32..b + ENTRY_SIZE]);
+    let slab = c.read_u32::<LittleEndian>()?;
+    let offset = c.read_u32::<LittleEndian>()?;
+    Ok(IndexEntry { hash, slab, offset })
+}
+
+fn search_page(page: &[u8], h: &Hash256) -> Result<Option<(u32, u32)>> {
+    use std::cmp::Ordering;
+
+    let mut lo = 0;
+    let mut hi = page.len() / ENTRY_SIZE;
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let b = mid * ENTRY_SIZE;
+        match page[b..b + 32].cmp(h.as_slice()) {
+            Ordering::Less => lo = mid + 1,
+            Ordering::Greater => hi = mid,
+            Ordering::Equal => {
+                let e = entry_at(page, mid)?;
+                return Ok(Some((e.slab, e.offset)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn offsets_path(p: &Path) -> PathBuf {
+    let mut p = p.to_path_buf();
+    p.set_extension("offsets");
+    p
+}
+
+fn run_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("run-{:>08}", seq))
+}
+
+//------------------------------
+
+struct Run {
+    path: PathBuf,
+    file: SlabFile,
+    nr_entries: u64,
+    slab_begin: u32,
+    slab_end: u32,
+    first_hashes: Vec<Hash256>,
+}
+
+impl Run {
+    fn open(path: PathBuf) -> Result<Self> {
+        let mut file = SlabFileBuilder::open(&path)
+            .cache_nr_entries(PAGE_CACHE_SIZE)
+            .build()
+            .with_context(|| format!("couldn't open index run {:?}", path))?;
+        let nr_slabs = file.get_nr_slabs();
+        if nr_slabs == 0 {
+            return Err(anyhow!("index run {:?} has no summary", path));
+        }
+
+        let summary = file.read(nr_slabs as u32 - 1)?;
+        let mut c = Cursor::new(&summary[..]);
+        let nr_entries = c.read_u64::<LittleEndian>()?;
+        let slab_begin = c.read_u32::<LittleEndian>()?;
+        let slab_end = c.read_u32::<LittleEndian>()?;
+
+        let hashes = &summary[16..];
+        if hashes.len() != (nr_slabs - 1) * 32 {
+            return Err(anyhow!("index run {:?} summary is corrupt", path));
+        }
+        let first_hashes = hashes
+            .chunks_exact(32)
+            .map(|h| *Hash256::from_slice(h))
+            .collect();
+
+        Ok(Self {
+            path,
+            file,
+            nr_entries,
+            slab_begin,
+            slab_end,
+            first_hashes,
+        })
+    }
+
+    fn nr_pages(&self) -> usize {
+        self.first_hashes.len()
+    }
+
+    // The only page that could hold h.
+    fn page_for(&self, h: &Hash256) -> Option<usize> {
+        let p = self.first_hashes.partition_point(|f| f <= h);
+        if p == 0 {
+            None
+        } else {
+            Some(p - 1)
+        }
+    }
+
+    fn read_page(&mut self, page: usize) -> Result<Arc<Vec<u8>>> {
+        self.file.read(page as u32)
+    }
+
+    fn remove(mut self) -> Result<()> {
+        self.file.close()?;
+        fs::remove_file(&self.path)?;
+        fs::remove_file(offsets_path(&self.path))?;
+        Ok(())
+    }
+}
+
+//------------------------------
+
+struct RunWriter {
+    path: PathBuf,
+    file: SlabFile,
+    page: Vec<u8>,
+    first_hashes: Vec<Hash256>,
+    nr_entries: u64,
+    last: Option<Hash256>,
+}
+
+impl RunWriter {
+    fn new(path: PathBuf) -> Result<Self> {
+        let file = SlabFileBuilder::create(&path)
+            .queue_depth(16)
+            .compressed(false)
+            .build()
+            .with_context(|| format!("couldn't create index run {:?}", path))?;
+
+        Ok(Self {
+            path,
+            file,
+            page: Vec::with_capacity(ENTRIES_PER_PAGE * ENTRY_SIZE),
+            first_hashes: Vec::new(),
+            nr_entries: 0,
+            last: None,
+        })
+    }
+
+    // Entries must be pushed in hash order.  Duplicates are dropped.
+    fn push(&mut self, e: &IndexEntry) -> Result<()> {
+        if let Some(last) = &self.last {
+            if *last == e.hash {
+                return Ok(());
+            }
+            assert!(*last < e.hash);
+        }
+        self.last = Some(e.hash);
+
+        if self.page.is_empty() {
+            self.first_hashes.push(e.hash);
+        }
+        self.page.extend_from_slice(&e.hash);
+        self.page.write_u32::<LittleEndian>(e.slab)?;
+        self.page.write_u32::<LittleEndian>(e.offset)?;
+        self.nr_entries += 1;
+
+        if self.page.len() == ENTRIES_PER_PAGE * ENTRY_SIZE {
+            self.file.write_slab(&self.page)?;
+            self.page.clear();
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self, slab_begin: u32, slab_end: u32) -> Result<PathBuf> {
+        if !self.page.is_empty() {
+            self.file.write_slab(&self.page)?;
+        }
+
+        let mut summary = Vec::with_capacity(16 + self.first_hashes.len() * 32);
+        summary.write_u64::<LittleEndian>(self.nr_entries)?;
+        summary.write_u32::<LittleEndian>(slab_begin)?;
+        summary.write_u32::<LittleEndian>(slab_end)?;
+        for h in &self.first_hashes {
+            summary.extend_from_slice(h);
+        }
+        self.file.write_slab(&summary)?;
+        self.file.close()?;
+
+        Ok(self.path)
+    }
+}
+
+// Walks the entries of a run in hash order.
+struct RunCursor {
+    run: Run,
+    page_index: usize,
+    page: Option<Arc<Vec<u8>>>,
+    entry: usize,
+}
+
+impl RunCursor {
+    fn new(run: Run) -> Self {
+        Self {
+            run,
+            page_index: 0,
+            page: None,
+            entry: 0,
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<IndexEntry>> {
+        loop {
+            if self.page_index >= self.run.nr_pages() {
+                return Ok(None);
+            }
+
+            if self.page.is_none() {
+                self.page = Some(self.run.read_page(self.page_index)?);
+                self.entry = 0;
+            }
+
+            let page = self.page.as_ref().unwrap();
+            if self.entry < page.len() / ENTRY_SIZE {
+                return Ok(Some(entry_at(page, self.entry)?));
+            }
+
+            self.page = None;
+            self.page_index += 1;
+        }
+    }
+
+    fn advance(&mut self) {
+        self.entry += 1;
+    }
+}
+
+//------------------------------
+
+pub struct FullIndex {
+    dir: PathBuf,
+    next_seq: u64,
+
+    // Oldest (and largest) first.
+    runs: Vec<Run>,
+}
+
+impl FullIndex {
+    /// Opens the index in the given directory, creating the directory if
+    /// it doesn't exist.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        if !dir.exists() {
+            fs::create_dir(&dir)?;
+        }
+
+        let mut seqs = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(seq) = name.strip_prefix("run-") {
+                if let Ok(seq) = seq.parse::<u64>() {
+                    seqs.push(seq);
+                }
+            }
+        }
+        seqs.sort();
+
+        let mut runs = Vec::with_capacity(seqs.len());
+        for seq in &seqs {
+            runs.push(Run::open(run_path(&dir, *seq))?);
+        }
+
+        let next_seq = seqs.last().map(|s| s + 1).unwrap_or(0);
+        Ok(Self {
+            dir,
+            next_seq,
+            runs,
+        })
+    }
+
+    /// All data slabs below this have been indexed.
+    pub fn covered_until(&self) -> u32 {
+        self.runs.iter().map(|r| r.slab_end).max().unwrap_or(0)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.runs.iter().map(|r| r.nr_entries).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn nr_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Memory held for the page summaries, plus the most the page caches
+    /// may grow to.
+    pub fn mem_size(&self) -> usize {
+        self.runs
+            .iter()
+            .map(|r| r.nr_pages() * 32 + PAGE_CACHE_SIZE * ENTRIES_PER_PAGE * ENTRY_SIZE)
+            .sum()
+    }
+
+    fn alloc_path(&mut self) -> PathBuf {
+        let p = run_path(&self.dir, self.next_seq);
+        self.next_seq += 1;
+        p
+    }
+
+    /// Writes a new run covering data slabs [slab_begin, slab_end).
+    pub fn add_run(
+        &mut self,
+        mut entries: Vec<IndexEntry>,
+        slab_begin: u32,
+        slab_end: u32,
+    ) -> Result<()> {
+        entries.sort_by_key(|e| e.hash);
+
+        let mut w = RunWriter::new(self.alloc_path())?;
+        for e in &entries {
+            w.push(e)?;
+        }
+        let path = w.finish(slab_begin, slab_end)?;
+        self.runs.push(Run::open(path)?);
+
+        self.compact()
+    }
+
+    // Merge the newest runs while they're of a similar size.
+    fn compact(&mut self) -> Result<()> {
+        while self.runs.len() >= 2 {
+            let n = self.runs.len();
+            if self.runs[n - 2].nr_entries > 2 * self.runs[n - 1].nr_entries {
+                break;
+            }
+
+            let newer = self.runs.pop().unwrap();
+            let older = self.runs.pop().unwrap();
+            let merged = self.merge(older, newer)?;
+            self.runs.push(merged);
+        }
+
+        Ok(())
+    }
+
+    fn merge(&mut self, older: Run, newer: Run) -> Result<Run> {
+        let slab_begin = std::cmp::min(older.slab_begin, newer.slab_begin);
+        let slab_end = std::cmp::max(older.slab_end, newer.slab_end);
+
+        let mut w = RunWriter::new(self.alloc_path())?;
+        let mut lhs = RunCursor::new(older);
+        let mut rhs = RunCursor::new(newer);
+
+        loop {
+            match (lhs.peek()?, rhs.peek()?) {
+                (None, None) => break,
+                (Some(l), None) => {
+                    w.push(&l)?;
+                    lhs.advance();
+                }
+                (None, Some(r)) => {
+                    w.push(&r)?;
+                    rhs.advance();
+                }
+                (Some(l), Some(r)) => {
+                    if l.hash <= r.hash {
+                        w.push(&l)?;
+                        lhs.advance();
+                    } else {
+                        w.push(&r)?;
+                        rhs.advance();
+                    }
+                }
+            }
+        }
+
+        let path = w.finish(slab_begin, slab_end)?;
+        let merged = Run::open(path)?;
+
+        // The merged run is complete, so it's safe to remove the inputs.
+        lhs.run.remove()?;
+        rhs.run.remove()?;
+
+        Ok(merged)
+    }
+
+    /// Looks up a batch of hashes.  The hashes are sorted internally so
+    /// each page is read at most once per batch.
+    pub fn lookup_batch(&mut self, hashes: &[Hash256]) -> Result<Vec<Option<(u32, u32)>>> {
+        let mut order: Vec<usize> = (0..hashes.len()).collect();
+        order.sort_by(|l, r| hashes[*l].cmp(&hashes[*r]));
+
+        let mut results = vec![None; hashes.len()];
+
+        // Search newest first, it's more likely to hold recently packed data.
+        for run in self.runs.iter_mut().rev() {
+            let mut current: Option<(usize, Arc<Vec<u8>>)> = None;
+
+            for i in &order {
+                if results[*i].is_some() {
+                    continue;
+                }
+
+                let h = &hashes[*i];
+                if let Some(page) = run.page_for(h) {
+                    let data = match &current {
+                        Some((p, data)) if *p == page => data.clone(),
+                        _ => {
+                            let data = run.read_page(page)?;
+                            current = Some((page, data.clone()));
+                            data
+                        }
+                    };
+                    results[*i] = search_page(&data, h)?;
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    pub fn lookup(&mut self, h: &Hash256) -> Result<Option<(u32, u32)>> {
+        Ok(self.lookup_batch(std::slice::from_ref(h))?[0])
+    }
+}
+
+//------------------------------
+
+#[cfg(test)]
+mod full_index_tests {
+    use super::*;
+    use rand::prelude::*;
+    use rand_chacha::ChaCha20Rng;
+
+    fn mk_entries(rng: &mut ChaCha20Rng, slab_begin: u32, slab_end: u32) -> Vec<IndexEntry> {
+        let mut entries = Vec::new();
+        for slab in slab_begin..slab_end {
+            for offset in 0..150 {
+                let mut hash = Hash256::default();
+                rng.fill_bytes(&mut hash);
+                entries.push(IndexEntry { hash, slab, offset });
+            }
+        }
+        entries
+    }
+
+    #[test]
+    fn test_add_lookup() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let mut index = FullIndex::open(td.path().join("full"))?;
+        assert_eq!(index.covered_until(), 0);
+
+        let mut all = Vec::new();
+        for i in 0..5 {
+            let entries = mk_entries(&mut rng, i * 4, (i + 1) * 4);
+            all.extend_from_slice(&entries);
+            index.add_run(entries, i * 4, (i + 1) * 4)?;
+        }
+
+        assert_eq!(index.covered_until(), 20);
+        assert_eq!(index.len(), all.len() as u64);
+        assert!(index.nr_runs() < 5);
+
+        let hashes: Vec<Hash256> = all.iter().map(|e| e.hash).collect();
+        let results = index.lookup_batch(&hashes)?;
+        for (e, r) in all.iter().zip(results) {
+            assert_eq!(r, Some((e.slab, e.offset)));
+        }
+
+        let mut missing = Hash256::default();
+        rng.fill_bytes(&mut missing);
+        assert_eq!(index.lookup(&missing)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reopen() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir = td.path().join("full");
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+
+        let entries = mk_entries(&mut rng, 0, 3);
+        let (nr_runs, len) = {
+            let mut index = FullIndex::open(&dir)?;
+            index.add_run(entries.clone(), 0, 3)?;
+            index.add_run(mk_entries(&mut rng, 3, 4), 3, 4)?;
+            (index.nr_runs(), index.len())
+        };
+
+        let mut index = FullIndex::open(&dir)?;
+        assert_eq!(index.nr_runs(), nr_runs);
+        assert_eq!(index.len(), len);
+        assert_eq!(index.covered_until(), 4);
+        for e in &entries {
+            assert_eq!(index.lookup(&e.hash)?, Some((e.slab, e.offset)));
+        }
+        Ok(())
+    }
+}
+
+//------------------------------
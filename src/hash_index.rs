@@ -75,6 +75,11 @@ impl IndexBuilder {
     pub fn lookup(&self, h: &Hash256) -> Option<u32> {
         self.index.get(h).cloned()
     }
+
+    /// Returns the (begin, len) of an entry within the slab being built.
+    pub fn get(&self, index: u32) -> Option<(usize, usize)> {
+        self.entries.get(index as usize).map(|e| (e.begin, e.len))
+    }
 }
 
 //--------------------------------
@@ -0,0 +1,175 @@
+use anyhow::Result;
+use clap::ArgMatches;
+use serde_json::json;
+use serde_json::to_string_pretty;
+use size_display::Size;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::config;
+use crate::cuckoo_filter::*;
+use crate::full_index::*;
+use crate::output::Output;
+use crate::paths;
+use crate::similarity::SimilarityIndex;
+
+//-----------------------------------------
+
+/// Memory needed by the indexes while packing.
+#[derive(serde::Serialize, Default, Debug)]
+pub struct MemoryEstimate {
+    pub filter: u64,
+    pub hash_cache: u64,
+    pub full_index: u64,
+    pub similarity: u64,
+}
+
+impl MemoryEstimate {
+    /// Estimate the memory needed to pack `extra` more entries.  The hash
+    /// cache is assumed to fill completely.
+    pub fn new(
+        filter: &CuckooFilter,
+        full_index: Option<&FullIndex>,
+        hash_cache_size_meg: usize,
+        delta_compression: bool,
+        extra: usize,
+    ) -> Self {
+        Self {
+            filter: filter.projected_mem_size(extra) as u64,
+            hash_cache: hash_cache_size_meg as u64 * 1024 * 1024,
+            full_index: full_index.map(|i| i.mem_size() as u64).unwrap_or(0),
+            similarity: if delta_compression {
+                SimilarityIndex::mem_size() as u64
+            } else {
+                0
+            },
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.filter + self.hash_cache + self.full_index + self.similarity
+    }
+}
+
+fn file_size(p: &Path) -> u64 {
+    fs::metadata(p).map(|m| m.len()).unwrap_or(0)
+}
+
+pub fn run(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
+    let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap()).canonicalize()?;
+    env::set_current_dir(archive_dir)?;
+    let config = config::read_config_file(".")?;
+
+    let filter = CuckooFilter::read(paths::index_path())?;
+    let mut disk_size = file_size(&paths::index_path());
+    disk_size += file_size(&paths::index_path().with_extension("offsets"));
+
+    let full_index = if config.index_mode == config::IndexMode::Disk {
+        Some(FullIndex::open(paths::full_index_path())?)
+    } else {
+        None
+    };
+
+    let mem = MemoryEstimate::new(
+        &filter,
+        full_index.as_ref(),
+        config.hash_cache_size_meg,
+        config.delta_compression,
+        0,
+    );
+
+    // The next resize happens when a pack needs more room than is free,
+    // and adds at least a quarter of the current capacity.
+    let free = filter.capacity() - std::cmp::min(filter.len(), filter.capacity());
+    let next_level = filter.grow_capacity(0);
+    let next_mem = MemoryEstimate::new(
+        &filter,
+        full_index.as_ref(),
+        config.hash_cache_size_meg,
+        config.delta_compression,
+        free + 1,
+    );
+    let unique_data_until_resize = free as u64 * config.block_size as u64;
+
+    if output.json {
+        let mut result = json!({
+            "filter": {
+                "levels": filter.nr_levels(),
+                "capacity": filter.capacity(),
+                "entries": filter.len(),
+                "load_factor": filter.load_factor(),
+                "false_positive_rate": filter.false_positive_rate(),
+                "disk_size": disk_size,
+                "mem_size": filter.mem_size(),
+            },
+            "memory": mem,
+            "next_resize": {
+                "free_entries": free,
+                "unique_data": unique_data_until_resize,
+                "added_capacity": next_level,
+                "memory": next_mem,
+            },
+            "memory_budget_meg": config.memory_budget_meg,
+        });
+
+        if let Some(index) = &full_index {
+            result["full_index"] = json!({
+                "entries": index.len(),
+                "runs": index.nr_runs(),
+                "mem_size": index.mem_size(),
+            });
+        }
+
+        println!("{}", to_string_pretty(&result).unwrap());
+    } else {
+        let r = &output.report;
+        r.to_stdout(&format!("filter levels    : {}", filter.nr_levels()));
+        r.to_stdout(&format!("filter capacity  : {}", filter.capacity()));
+        r.to_stdout(&format!("filter entries   : {}", filter.len()));
+        r.to_stdout(&format!(
+            "load factor      : {:.2}%",
+            filter.load_factor() * 100.0
+        ));
+        r.to_stdout(&format!(
+            "false positives  : {:.6}%",
+            filter.false_positive_rate() * 100.0
+        ));
+        r.to_stdout(&format!("filter disk size : {:.2}", Size(disk_size)));
+        r.to_stdout(&format!("filter mem size  : {:.2}", Size(mem.filter)));
+        r.to_stdout(&format!("hash cache size  : {:.2}", Size(mem.hash_cache)));
+        if let Some(index) = &full_index {
+            r.to_stdout(&format!(
+                "full index       : {} entries in {} runs",
+                index.len(),
+                index.nr_runs()
+            ));
+            r.to_stdout(&format!("full index mem   : {:.2}", Size(mem.full_index)));
+        }
+        if config.delta_compression {
+            r.to_stdout(&format!("similarity mem   : {:.2}", Size(mem.similarity)));
+        }
+        r.to_stdout(&format!("total mem        : {:.2}", Size(mem.total())));
+        r.to_stdout(&format!(
+            "next resize      : after {} more entries (~{:.2} of unique data)",
+            free,
+            Size(unique_data_until_resize)
+        ));
+        r.to_stdout(&format!(
+            "                   adds {} entries, total mem {:.2}",
+            next_level,
+            Size(next_mem.total())
+        ));
+        if let Some(budget) = config.memory_budget_meg {
+            r.to_stdout(&format!(
+                "memory budget    : {:.2}",
+                Size(budget as u64 * 1024 * 1024)
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+//-----------------------------------------
@@ -6,6 +6,16 @@ pub type IoVec<'a> = Vec<&'a [u8]>;
 
 pub trait IoVecHandler {
     fn handle_data(&mut self, iov: &IoVec) -> Result<()>;
+
+    // Called with all the chunks found in a buffer, gives the handler a
+    // chance to batch work across them.
+    fn handle_batch(&mut self, iovs: &[IoVec]) -> Result<()> {
+        for iov in iovs {
+            self.handle_data(iov)?;
+        }
+        Ok(())
+    }
+
     fn complete(&mut self) -> Result<()>;
 }
 
@@ -1,23 +1,38 @@
 pub mod archive;
+pub mod chunk_delta;
 pub mod chunkers;
 pub mod config;
 pub mod content_sensitive_splitter;
 pub mod create;
 pub mod cuckoo_filter;
+pub mod derive;
+pub mod diff;
 pub mod dump_stream;
+pub mod era_metadata;
+pub mod full_index;
 pub mod hash;
 pub mod hash_index;
+pub mod index_info;
 pub mod iovec;
 pub mod list;
+pub mod lvm;
+pub mod mount;
+pub mod nbd;
 pub mod output;
 pub mod pack;
+pub mod parent;
+pub mod partition;
 pub mod paths;
+pub mod pool;
 pub mod run_iter;
+pub mod similarity;
 pub mod slab;
 pub mod splitter;
 pub mod stack;
 pub mod stream;
 pub mod stream_builders;
+pub mod stream_reader;
+pub mod stream_slabs;
 pub mod thin_metadata;
 pub mod unpack;
 pub mod utils;
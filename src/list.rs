@@ -18,18 +18,22 @@ fn fmt_time(t: &chrono::DateTime<FixedOffset>) -> String {
     t.format("%b %d %y %H:%M").to_string()
 }
 
+/// Returns the ids of all streams in the archive.  Assumes current
+/// directory is the root of the archive.
+pub fn stream_ids() -> Result<Vec<String>> {
+    let paths = fs::read_dir(Path::new("./streams"))?;
+    Ok(paths
+        .filter_map(|entry| entry.ok().and_then(|e| e.file_name().into_string().ok()))
+        .collect())
+}
+
 pub fn run(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
     let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap()).canonicalize()?;
 
     env::set_current_dir(&archive_dir)?;
 
-    let paths = fs::read_dir(Path::new("./streams"))?;
-    let stream_ids = paths
-        .filter_map(|entry| entry.ok().and_then(|e| e.file_name().into_string().ok()))
-        .collect::<Vec<String>>();
-
     let mut streams = Vec::new();
-    for id in stream_ids {
+    for id in stream_ids()? {
         let cfg = config::read_stream_config(&id)?;
         streams.push((id, config::to_date_time(&cfg.pack_time), cfg));
     }
@@ -0,0 +1,320 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::prelude::*;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::Command;
+
+//-----------------------------------------
+
+/// A logical volume, named as vg/lv.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LvName {
+    pub vg: String,
+    pub lv: String,
+}
+
+impl LvName {
+    pub fn parse(name: &str) -> Result<Self> {
+        let name = name.strip_prefix("/dev/").unwrap_or(name);
+        match name.split_once('/') {
+            Some((vg, lv)) if !vg.is_empty() && !lv.is_empty() && !lv.contains('/') => Ok(Self {
+                vg: vg.to_string(),
+                lv: lv.to_string(),
+            }),
+            _ => Err(anyhow!("'{}' isn't of the form vg/lv", name)),
+        }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        ["/dev", &self.vg, &self.lv].iter().collect()
+    }
+}
+
+impl std::fmt::Display for LvName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.vg, self.lv)
+    }
+}
+
+//-----------------------------------------
+
+pub struct LvInfo {
+    pub name: LvName,
+    pub size: u64,
+
+    // Thin volumes get thin snapshots, everything else old style CoW ones.
+    pub thin: bool,
+}
+
+fn lvm(args: &[&str]) -> Result<String> {
+    let out = Command::new("lvm")
+        .args(args)
+        .output()
+        .with_context(|| format!("couldn't run lvm {}", args[0]))?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "lvm {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+// Parses a line of 'lvs --nameprefixes' output, eg,
+//   LVM2_LV_NAME='lv' LVM2_SEGTYPE='thin'
+// LVM doesn't allow spaces or quotes in names, so splitting on
+// whitespace is safe.
+fn parse_fields(line: &str) -> Result<BTreeMap<&str, &str>> {
+    let mut fields = BTreeMap::new();
+    for field in line.split_whitespace() {
+        let (k, v) = field
+            .split_once('=')
+            .ok_or_else(|| anyhow!("couldn't parse lvs output: {}", line))?;
+        let v = v.trim_matches('\'');
+        fields.insert(k.strip_prefix("LVM2_").unwrap_or(k), v);
+    }
+    Ok(fields)
+}
+
+fn parse_lvs(name: &LvName, out: &str) -> Result<LvInfo> {
+    let line = out
+        .lines()
+        .find(|l| !l.trim().is_empty())
+        .ok_or_else(|| anyhow!("couldn't find logical volume {}", name))?;
+    let fields = parse_fields(line)?;
+    let get = |k: &str| {
+        fields
+            .get(k)
+            .ok_or_else(|| anyhow!("lvs didn't report {}", k))
+    };
+
+    let size = get("LV_SIZE")?
+        .parse::<u64>()
+        .context("couldn't parse lv size")?;
+    let segtype = get("SEGTYPE")?;
+    if *segtype == "thin-pool" {
+        return Err(anyhow!("{} is a thin pool, use pack-pool", name));
+    }
+
+    Ok(LvInfo {
+        name: name.clone(),
+        size,
+        thin: *segtype == "thin",
+    })
+}
+
+pub fn lookup(name: &str) -> Result<LvInfo> {
+    let name = LvName::parse(name)?;
+    let out = lvm(&[
+        "lvs",
+        "--noheadings",
+        "--nameprefixes",
+        "--units",
+        "b",
+        "--nosuffix",
+        "-o",
+        "lv_name,segtype,lv_size",
+        &name.to_string(),
+    ])?;
+    parse_lvs(&name, &out)
+}
+
+fn remove(name: &LvName) -> Result<()> {
+    lvm(&["lvremove", "-y", &name.to_string()])?;
+    Ok(())
+}
+
+// Parses 'lvs --nameprefixes -o lv_name' output.
+fn parse_lv_names(out: &str) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for line in out.lines().filter(|l| !l.trim().is_empty()) {
+        let fields = parse_fields(line)?;
+        let name = fields
+            .get("LV_NAME")
+            .ok_or_else(|| anyhow!("lvs didn't report LV_NAME"))?;
+        names.push(name.to_string());
+    }
+    Ok(names)
+}
+
+/// Activates a logical volume, returning its path.
+pub fn activate(name: &LvName) -> Result<PathBuf> {
+    // -K, since thin snapshots are created with activation skip set.
+    lvm(&["lvchange", "-ay", "-K", &name.to_string()])?;
+    Ok(name.path())
+}
+
+//-----------------------------------------
+
+// Snapshots are named after their origin and when they were taken, to
+// the microsecond so two packs in quick succession don't collide.
+fn snapshot_prefix(origin: &LvName) -> String {
+    format!("{}_blk_archive_", origin.lv)
+}
+
+fn is_snapshot_of(origin: &LvName, lv: &str) -> bool {
+    lv.strip_prefix(&snapshot_prefix(origin))
+        .is_some_and(|time| time.len() == 20 && time.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// A read only snapshot of a logical volume, taken so it can be packed
+/// while the origin is in use.  Call finish() once it's been packed;
+/// if it's dropped before then the pack failed, and it's removed.
+pub struct LvSnapshot {
+    pub name: LvName,
+    pub path: PathBuf,
+    origin: LvName,
+    keep: bool,
+
+    // Cleared by finish().
+    remove_on_drop: bool,
+}
+
+impl LvSnapshot {
+    /// cow_size is only used for thick volumes, and is either a size
+    /// (eg, '1G') or an extent percentage (eg, '20%ORIGIN').
+    pub fn create(origin: &LvInfo, cow_size: &str, keep: bool) -> Result<Self> {
+        let name = LvName {
+            vg: origin.name.vg.clone(),
+            lv: format!(
+                "{}{}",
+                snapshot_prefix(&origin.name),
+                Utc::now().format("%Y%m%d%H%M%S%6f")
+            ),
+        };
+        let origin_name = origin.name.to_string();
+
+        let mut args = vec!["lvcreate", "-s", "-pr", "-n", &name.lv];
+        if origin.thin {
+            args.push("-kn");
+        } else if cow_size.contains('%') {
+            args.extend(["-l", cow_size]);
+        } else {
+            args.extend(["-L", cow_size]);
+        }
+        args.push(&origin_name);
+        lvm(&args)?;
+
+        let snap = Self {
+            path: name.path(),
+            name,
+            origin: origin.name.clone(),
+            keep,
+            remove_on_drop: true,
+        };
+        activate(&snap.name)?;
+        Ok(snap)
+    }
+
+    /// Removes the snapshot now it's been packed, unless it's being
+    /// kept.  A kept snapshot is the base of the next delta, so the ones
+    /// kept by earlier packs of the origin are removed instead.
+    pub fn finish(mut self) -> Result<()> {
+        self.remove_on_drop = false;
+        if !self.keep {
+            return remove(&self.name);
+        }
+
+        let out = lvm(&[
+            "lvs",
+            "--noheadings",
+            "--nameprefixes",
+            "-o",
+            "lv_name",
+            &self.origin.vg,
+        ])?;
+        for lv in parse_lv_names(&out)? {
+            if lv != self.name.lv && is_snapshot_of(&self.origin, &lv) {
+                remove(&LvName {
+                    vg: self.origin.vg.clone(),
+                    lv,
+                })
+                .context("couldn't remove a previously kept snapshot")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LvSnapshot {
+    fn drop(&mut self) {
+        if self.remove_on_drop {
+            if let Err(e) = remove(&self.name) {
+                eprintln!("couldn't remove snapshot {}: {}", self.name, e);
+            }
+        }
+    }
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod lvm_tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let name = LvName::parse("vg0/root").unwrap();
+        assert_eq!(name.vg, "vg0");
+        assert_eq!(name.lv, "root");
+        assert_eq!(name.path(), PathBuf::from("/dev/vg0/root"));
+        assert_eq!(LvName::parse("/dev/vg0/root").unwrap(), name);
+
+        assert!(LvName::parse("root").is_err());
+        assert!(LvName::parse("vg0/").is_err());
+        assert!(LvName::parse("/dev/mapper/vg0-root/x").is_err());
+    }
+
+    #[test]
+    fn lvs_output() {
+        let name = LvName::parse("vg0/data").unwrap();
+
+        let info = parse_lvs(
+            &name,
+            "  LVM2_LV_NAME='data' LVM2_SEGTYPE='thin' LVM2_LV_SIZE='10737418240'\n",
+        )
+        .unwrap();
+        assert!(info.thin);
+        assert_eq!(info.size, 10 << 30);
+
+        let info = parse_lvs(
+            &name,
+            "  LVM2_LV_NAME='data' LVM2_SEGTYPE='linear' LVM2_LV_SIZE='4194304'\n",
+        )
+        .unwrap();
+        assert!(!info.thin);
+        assert_eq!(info.size, 4 << 20);
+
+        assert!(parse_lvs(
+            &name,
+            "  LVM2_LV_NAME='data' LVM2_SEGTYPE='thin-pool' LVM2_LV_SIZE='4194304'\n"
+        )
+        .is_err());
+        assert!(parse_lvs(&name, "\n").is_err());
+    }
+
+    #[test]
+    fn kept_snapshots() {
+        let origin = LvName::parse("vg0/data").unwrap();
+        let names = parse_lv_names(
+            "  LVM2_LV_NAME='data'\n  LVM2_LV_NAME='data_blk_archive_20240101120000123456'\n\
+             \n  LVM2_LV_NAME='data_blk_archive_x'\n  LVM2_LV_NAME='data2_blk_archive_20240101120000123456'\n\
+             \n  LVM2_LV_NAME='data_blk_archive_20240102080000654321'\n",
+        )
+        .unwrap();
+        let snaps: Vec<&String> = names
+            .iter()
+            .filter(|lv| is_snapshot_of(&origin, lv))
+            .collect();
+        assert_eq!(
+            snaps,
+            vec![
+                "data_blk_archive_20240101120000123456",
+                "data_blk_archive_20240102080000654321"
+            ]
+        );
+    }
+}
+
+//-----------------------------------------
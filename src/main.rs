@@ -6,10 +6,16 @@ use std::sync::Arc;
 use thinp::report::*;
 
 use blk_archive::create;
+use blk_archive::derive;
+use blk_archive::diff;
 use blk_archive::dump_stream;
+use blk_archive::index_info;
 use blk_archive::list;
+use blk_archive::mount;
+use blk_archive::nbd;
 use blk_archive::output::Output;
 use blk_archive::pack;
+use blk_archive::pool;
 use blk_archive::unpack;
 
 //-----------------------
@@ -57,6 +63,19 @@ fn main_() -> Result<()> {
         .value_name("DATA_CACHE_SIZE_MEG")
         .num_args(1);
 
+    let memory_budget: Arg = Arg::new("MEMORY_BUDGET_MEG")
+        .help("Specify how much memory the indexes may use when packing")
+        .required(false)
+        .long("memory-budget-meg")
+        .value_name("MEMORY_BUDGET_MEG")
+        .num_args(1);
+
+    let enforce_memory_budget: Arg = Arg::new("ENFORCE_MEMORY_BUDGET")
+        .help("Refuse to pack, rather than warn, if the memory budget would be exceeded")
+        .required(false)
+        .long("enforce-memory-budget")
+        .action(ArgAction::SetTrue);
+
     let matches = command!()
         .arg(json)
         .propagate_version(true)
@@ -101,15 +120,43 @@ fn main_() -> Result<()> {
                         .value_parser(["y", "n"]) // Restrict values
                         .default_value("y")
                         .action(ArgAction::Set),
-                ),
+                )
+                .arg(
+                    Arg::new("INDEX_MODE")
+                        .long("index-mode")
+                        .value_name("memory|disk")
+                        .help("Keep only the in-memory hash index, or also maintain a full hash index on disk")
+                        .value_parser(["memory", "disk"])
+                        .default_value("memory")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("DELTA_COMPRESSION")
+                        .long("delta-compression")
+                        .value_name("y|n")
+                        .help("Store chunks that resemble existing data as deltas against them")
+                        .value_parser(["y", "n"])
+                        .default_value("n")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("PARENT")
+                        .help("Specify a read only archive to dedup against; may be given more than once")
+                        .required(false)
+                        .long("parent")
+                        .value_name("ARCHIVE")
+                        .action(ArgAction::Append),
+                )
+                .arg(memory_budget.clone())
+                .arg(enforce_memory_budget.clone()),
         )
         .subcommand(
             Command::new("pack")
                 .about("packs a stream into the archive")
                 .arg(
                     Arg::new("INPUT")
-                        .help("Specify a device or file to archive")
-                        .required(true)
+                        .help("Specify a device or file to archive, or '-' for stdin")
+                        .required_unless_present("LV")
                         .value_name("INPUT")
                         .num_args(1),
                 )
@@ -117,7 +164,8 @@ fn main_() -> Result<()> {
                 .arg(
                     Arg::new("DELTA_STREAM")
                         .help(
-                            "Specify the stream that contains an older version of this thin device",
+                            "Specify the stream that contains an older version of this thin or \
+                             dm-era device",
                         )
                         .required(false)
                         .long("delta-stream")
@@ -134,6 +182,158 @@ fn main_() -> Result<()> {
                         .value_name("DELTA_DEVICE")
                         .num_args(1),
                 )
+                .arg(
+                    Arg::new("AUTO_DELTA")
+                        .help(
+                            "Pack as a delta of the most recent stream of another thin device \
+                             that's still in the same pool, or of the same dm-era device",
+                        )
+                        .long("auto-delta")
+                        .conflicts_with_all(["DELTA_STREAM", "DELTA_DEVICE"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("THIN_METADATA")
+                        .help(
+                            "Read the thin mappings from this metadata device/image, or thin_dump \
+                             xml, rather than an active pool.  INPUT is then the pool's data device",
+                        )
+                        .long("thin-metadata")
+                        .value_name("METADATA")
+                        .requires("THIN_ID")
+                        .conflicts_with_all(["DELTA_DEVICE", "AUTO_DELTA"])
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("THIN_ID")
+                        .help("The id of the thin device to pack from --thin-metadata")
+                        .long("thin-id")
+                        .value_name("ID")
+                        .value_parser(clap::value_parser!(u32))
+                        .requires("THIN_METADATA")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("LV")
+                        .help(
+                            "Pack a snapshot of this logical volume, as a delta of its last \
+                             stream if that snapshot was kept",
+                        )
+                        .long("lv")
+                        .value_name("VG/LV")
+                        .conflicts_with_all([
+                            "INPUT",
+                            "DELTA_STREAM",
+                            "DELTA_DEVICE",
+                            "AUTO_DELTA",
+                            "THIN_METADATA",
+                        ])
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("COW_SIZE")
+                        .help(
+                            "Size of the snapshot of a thick logical volume, eg, '1G' or \
+                             '20%ORIGIN'",
+                        )
+                        .long("cow-size")
+                        .value_name("SIZE")
+                        .default_value("20%ORIGIN")
+                        .requires("LV")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("KEEP_SNAPSHOT")
+                        .help(
+                            "Keep the snapshot of a thin logical volume, so the next pack can be \
+                             a delta of it.  Snapshots kept by earlier packs are removed",
+                        )
+                        .long("keep-snapshot")
+                        .requires("LV")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("THIN_SIZE")
+                        .help(
+                            "Size of the thin device in bytes, since it isn't held in the \
+                             metadata.  Defaults to the end of the last mapping",
+                        )
+                        .long("thin-size")
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(u64))
+                        .requires("THIN_METADATA")
+                        .num_args(1),
+                )
+                .arg(data_cache_size.clone())
+                .arg(memory_budget.clone())
+                .arg(enforce_memory_budget.clone()),
+        )
+        .subcommand(
+            Command::new("pack-pool")
+                .about("packs every thin device in a pool into the archive as a pool set")
+                .arg(
+                    Arg::new("POOL")
+                        .help("Specify an active thin pool, or its data device with --thin-metadata")
+                        .required(true)
+                        .value_name("POOL")
+                        .num_args(1),
+                )
+                .arg(archive_arg.clone())
+                .arg(
+                    Arg::new("THIN_METADATA")
+                        .help(
+                            "Read the pool from this metadata device/image, or thin_dump xml, \
+                             rather than an active pool.  POOL is then the pool's data device",
+                        )
+                        .long("thin-metadata")
+                        .value_name("METADATA")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("THIN_SIZE")
+                        .help(
+                            "Size of a thin device in bytes, given as THIN_ID:BYTES; may be given \
+                             more than once.  The metadata doesn't hold it, so it's needed for \
+                             every thin that isn't active",
+                        )
+                        .long("thin-size")
+                        .value_name("THIN_ID:BYTES")
+                        .action(ArgAction::Append),
+                )
+                .arg(data_cache_size.clone())
+                .arg(memory_budget.clone())
+                .arg(enforce_memory_budget.clone()),
+        )
+        .subcommand(
+            Command::new("restore-pool")
+                .about("recreates the thin devices of a pool set in an active pool")
+                .arg(
+                    Arg::new("POOL")
+                        .help("Specify the active thin pool to create the thin devices in")
+                        .required(true)
+                        .value_name("POOL")
+                        .num_args(1),
+                )
+                .arg(archive_arg.clone())
+                .arg(
+                    Arg::new("POOL_SET")
+                        .help("Specify the pool set to restore")
+                        .required(true)
+                        .long("pool-set")
+                        .short('p')
+                        .value_name("POOL_SET")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("NAME_PREFIX")
+                        .help(
+                            "Prefix for the names of the activated thin devices, which are \
+                             <prefix>-<thin id>.  Defaults to the name of the packed pool",
+                        )
+                        .long("name-prefix")
+                        .value_name("PREFIX")
+                        .num_args(1),
+                )
                 .arg(data_cache_size.clone()),
         )
         .subcommand(
@@ -141,17 +341,50 @@ fn main_() -> Result<()> {
                 .about("unpacks a stream from the archive")
                 .arg(
                     Arg::new("OUTPUT")
-                        .help("Specify a device or file as the destination")
-                        .required(true)
+                        .help("Specify a device or file as the destination, or '-' for stdout")
+                        .required_unless_present("LV")
                         .value_name("OUTPUT")
                         .index(1),
                 )
+                .arg(
+                    Arg::new("LV")
+                        .help("Unpack to this logical volume, activating it if needed")
+                        .long("lv")
+                        .value_name("VG/LV")
+                        .conflicts_with_all(["OUTPUT", "CREATE"])
+                        .num_args(1),
+                )
                 .arg(
                     Arg::new("CREATE")
                         .help("Create a new file rather than unpack to an existing device/file.")
                         .long("create")
                         .action(clap::ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("OFFSET")
+                        .help("Unpack only from this byte offset within the stream")
+                        .long("offset")
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(u64))
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("LENGTH")
+                        .help("Unpack only this many bytes of the stream")
+                        .long("length")
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(u64))
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("PARTITION")
+                        .help("Unpack only this partition, found in the MBR/GPT held in the stream")
+                        .long("partition")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u32))
+                        .conflicts_with_all(["OFFSET", "LENGTH"])
+                        .num_args(1),
+                )
                 .arg(data_cache_size.clone())
                 .arg(archive_arg.clone())
                 .arg(stream_arg.clone()),
@@ -170,17 +403,125 @@ fn main_() -> Result<()> {
                 .arg(archive_arg.clone())
                 .arg(stream_arg.clone()),
         )
+        .subcommand(
+            Command::new("mount")
+                .about("mounts the archive's streams as read only files (FUSE)")
+                .arg(
+                    Arg::new("MOUNT_POINT")
+                        .help("Specify the directory to mount on")
+                        .required(true)
+                        .value_name("MOUNT_POINT")
+                        .index(1),
+                )
+                .arg(data_cache_size.clone())
+                .arg(archive_arg.clone()),
+        )
+        .subcommand(
+            Command::new("nbd-serve")
+                .about("exports a stream as a block device over NBD")
+                .arg(
+                    Arg::new("SOCKET")
+                        .help("Listen on this Unix socket rather than localhost TCP")
+                        .long("socket")
+                        .value_name("PATH")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("PORT")
+                        .help("Specify the localhost TCP port to listen on")
+                        .long("port")
+                        .value_name("PORT")
+                        .value_parser(clap::value_parser!(u16))
+                        .default_value("10809")
+                        .conflicts_with("SOCKET")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("OVERLAY")
+                        .help("Make the export writable, keeping writes in this file outside the archive")
+                        .long("overlay")
+                        .value_name("FILE")
+                        .num_args(1),
+                )
+                .arg(data_cache_size.clone())
+                .arg(archive_arg.clone())
+                .arg(stream_arg.clone()),
+        )
         .subcommand(
             Command::new("dump-stream")
                 .about("dumps stream instructions (development tool)")
+                .arg(
+                    Arg::new("FORMAT")
+                        .help("Dump the decoded entries, with their logical offsets, instead")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .value_parser(["json", "csv"]),
+                )
+                .arg(
+                    Arg::new("STATS")
+                        .help(
+                            "Summarise the stream: instruction frequencies, data slab fan-out, \
+                             fragmentation and fill/unmapped totals",
+                        )
+                        .long("stats")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(data_cache_size.clone())
                 .arg(archive_arg.clone())
                 .arg(stream_arg.clone()),
         )
+        .subcommand(
+            Command::new("derive")
+                .about("builds a new stream from parts of existing ones, without reading any data")
+                .arg(
+                    Arg::new("SEGMENT")
+                        .help(
+                            "The parts of the new stream, in order: STREAM, STREAM:OFFSET[+LEN], \
+                             STREAM:pN (partition N), zero:LEN or unmapped:LEN",
+                        )
+                        .required(true)
+                        .value_name("SEGMENT")
+                        .num_args(1..),
+                )
+                .arg(
+                    Arg::new("ZERO")
+                        .help("Zero a range of the new stream, may be given more than once")
+                        .long("zero")
+                        .value_name("OFFSET+LEN")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("NAME")
+                        .help("Specify a name for the new stream")
+                        .long("name")
+                        .value_name("NAME")
+                        .num_args(1),
+                )
+                .arg(data_cache_size.clone())
+                .arg(archive_arg.clone()),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("lists the byte ranges that differ between two streams")
+                .arg(
+                    stream_arg
+                        .clone()
+                        .help("Specify the two streams to compare, e.g. -s OLD -s NEW")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(data_cache_size.clone())
+                .arg(archive_arg.clone()),
+        )
         .subcommand(
             Command::new("list")
                 .about("lists the streams in the archive")
                 .arg(archive_arg.clone()),
         )
+        .subcommand(
+            Command::new("index-info")
+                .about("reports on the size and state of the archive's indexes")
+                .arg(archive_arg.clone()),
+        )
         .get_matches();
 
     let report = mk_report(&matches);
@@ -196,18 +537,39 @@ fn main_() -> Result<()> {
         Some(("pack", sub_matches)) => {
             pack::run(sub_matches, output)?;
         }
+        Some(("pack-pool", sub_matches)) => {
+            pool::run_pack(sub_matches, output)?;
+        }
+        Some(("restore-pool", sub_matches)) => {
+            pool::run_restore(sub_matches, output)?;
+        }
         Some(("unpack", sub_matches)) => {
             unpack::run_unpack(sub_matches, output)?;
         }
         Some(("verify", sub_matches)) => {
             unpack::run_verify(sub_matches, output)?;
         }
+        Some(("mount", sub_matches)) => {
+            mount::run(sub_matches, output)?;
+        }
+        Some(("nbd-serve", sub_matches)) => {
+            nbd::run(sub_matches, output)?;
+        }
+        Some(("derive", sub_matches)) => {
+            derive::run(sub_matches, output)?;
+        }
+        Some(("diff", sub_matches)) => {
+            diff::run(sub_matches, output)?;
+        }
         Some(("list", sub_matches)) => {
             list::run(sub_matches, output)?;
         }
         Some(("dump-stream", sub_matches)) => {
             dump_stream::run(sub_matches, output)?;
         }
+        Some(("index-info", sub_matches)) => {
+            index_info::run(sub_matches, output)?;
+        }
         _ => unreachable!("Exhausted list of subcommands and subcommand_required prevents 'None'"),
     }
 
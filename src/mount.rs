@@ -0,0 +1,481 @@
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    ReplyLseek, ReplyOpen, Request,
+};
+use std::env;
+use std::ffi::OsStr;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::archive::Data;
+use crate::config;
+use crate::list;
+use crate::output::Output;
+use crate::stream_reader::{open_archive, StreamReader};
+
+//-----------------------------------------
+
+// The streams are listed once, at mount time, and nothing in the mount
+// changes after that.  Streams packed later need a remount to show up.
+const TTL: Duration = Duration::from_secs(60);
+
+const ROOT_INO: u64 = fuser::FUSE_ROOT_ID;
+
+// What a stream file needs from its reader.
+trait StreamContents: Read + Seek {
+    fn find_extent(&mut self, from: u64, mapped: bool) -> Result<Option<u64>>;
+}
+
+impl StreamContents for StreamReader {
+    fn find_extent(&mut self, from: u64, mapped: bool) -> Result<Option<u64>> {
+        StreamReader::find_extent(self, from, mapped)
+    }
+}
+
+type OpenFn = Box<dyn Fn(&str) -> Result<Box<dyn StreamContents>>>;
+
+struct StreamFile {
+    id: String,
+    name: String,
+    size: u64,
+    mapped_size: u64,
+    pack_time: SystemTime,
+
+    // Opened on first use, since building the seek index may mean
+    // reading the whole stream.
+    reader: Option<Box<dyn StreamContents>>,
+}
+
+impl StreamFile {
+    fn reader(&mut self, open: &OpenFn) -> Result<&mut Box<dyn StreamContents>> {
+        if self.reader.is_none() {
+            self.reader = Some(open(&self.id)?);
+        }
+        Ok(self.reader.as_mut().unwrap())
+    }
+
+    fn read(&mut self, open: &OpenFn, offset: u64, len: u32) -> Result<Vec<u8>> {
+        let len = std::cmp::min(len as u64, self.size.saturating_sub(offset));
+        let mut buf = vec![0; len as usize];
+        if len > 0 {
+            let reader = self.reader(open)?;
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(&mut buf)?;
+        }
+        Ok(buf)
+    }
+}
+
+/// Presents each stream as a read only file in a single directory.
+struct StreamFs {
+    open: OpenFn,
+    files: Vec<StreamFile>,
+    uid: u32,
+    gid: u32,
+}
+
+impl StreamFs {
+    // Assumes current directory is the root of the archive.
+    fn new(config: &config::Config) -> Result<Self> {
+        let mut files = Vec::new();
+        for id in list::stream_ids()? {
+            let cfg = config::read_stream_config(&id)?;
+            let name = match &cfg.name {
+                Some(name) => format!("{}-{}", id, name.replace('/', "_")),
+                None => id.clone(),
+            };
+            files.push(StreamFile {
+                id,
+                name,
+                size: cfg.size,
+                mapped_size: cfg.mapped_size,
+                pack_time: config::to_date_time(&cfg.pack_time).into(),
+                reader: None,
+            });
+        }
+
+        let archive: Arc<Mutex<Data>> = Arc::new(Mutex::new(open_archive(config)?));
+        let open: OpenFn = Box::new(move |id| {
+            let reader = StreamReader::with_archive(id, archive.clone())?;
+            Ok(Box::new(reader) as Box<dyn StreamContents>)
+        });
+        Ok(Self::with_files(open, files))
+    }
+
+    fn with_files(open: OpenFn, mut files: Vec<StreamFile>) -> Self {
+        files.sort_by_key(|f| f.pack_time);
+        Self {
+            open,
+            files,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    // Inode 1 is the root, the streams follow.
+    fn file_index(&self, ino: u64) -> Option<usize> {
+        let i = ino.checked_sub(ROOT_INO + 1)? as usize;
+        (i < self.files.len()).then_some(i)
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, perm, size, blocks, time) = if ino == ROOT_INO {
+            (FileType::Directory, 0o555, 0, 0, SystemTime::now())
+        } else {
+            let f = &self.files[self.file_index(ino)?];
+            // Unmapped regions don't count towards the blocks used.
+            let blocks = f.mapped_size.div_ceil(512);
+            (FileType::RegularFile, 0o444, f.size, blocks, f.pack_time)
+        };
+
+        Some(FileAttr {
+            ino,
+            size,
+            blocks,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    // The methods below do the work of the Filesystem calls, returning
+    // an errno on failure.
+
+    fn lookup_name(&self, parent: u64, name: &OsStr) -> Result<FileAttr, i32> {
+        let i = self.files.iter().position(|f| OsStr::new(&f.name) == name);
+        match i {
+            Some(i) if parent == ROOT_INO => Ok(self.attr(ROOT_INO + 1 + i as u64).unwrap()),
+            _ => Err(libc::ENOENT),
+        }
+    }
+
+    fn open_file(&mut self, ino: u64, flags: i32) -> Result<(), i32> {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(libc::EROFS);
+        }
+
+        let i = self.file_index(ino).ok_or(libc::ENOENT)?;
+        match self.files[i].reader(&self.open) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(libc::EIO),
+        }
+    }
+
+    fn read_file(&mut self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>, i32> {
+        let i = self.file_index(ino).ok_or(libc::ENOENT)?;
+        self.files[i]
+            .read(&self.open, offset, size)
+            .map_err(|_| libc::EIO)
+    }
+
+    // Returns (ino, next offset, kind, name) for the entries from 'offset'.
+    fn dir_entries(&self, ino: u64, offset: i64) -> Result<Vec<(u64, i64, FileType, &str)>, i32> {
+        if ino != ROOT_INO {
+            return Err(libc::ENOTDIR);
+        }
+
+        let entries = [
+            (ROOT_INO, FileType::Directory, "."),
+            (ROOT_INO, FileType::Directory, ".."),
+        ]
+        .into_iter()
+        .chain(
+            self.files
+                .iter()
+                .enumerate()
+                .map(|(i, f)| (ROOT_INO + 1 + i as u64, FileType::RegularFile, &f.name[..])),
+        );
+
+        Ok(entries
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(i, (ino, kind, name))| (ino, (i + 1) as i64, kind, name))
+            .collect())
+    }
+
+    // Unmapped regions of a stream show up as holes.
+    fn seek_file(&mut self, ino: u64, offset: u64, whence: i32) -> Result<u64, i32> {
+        let mapped = match whence {
+            libc::SEEK_DATA => true,
+            libc::SEEK_HOLE => false,
+            _ => return Err(libc::EINVAL),
+        };
+
+        let i = self.file_index(ino).ok_or(libc::ENOENT)?;
+        match self.files[i]
+            .reader(&self.open)
+            .and_then(|r| r.find_extent(offset, mapped))
+        {
+            Ok(Some(pos)) => Ok(pos),
+            Ok(None) => Err(libc::ENXIO),
+            Err(_) => Err(libc::EIO),
+        }
+    }
+}
+
+impl Filesystem for StreamFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_name(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.open_file(ino, flags) {
+            Ok(()) => reply.opened(0, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_file(ino, offset as u64, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        match self.dir_entries(ino, offset) {
+            Ok(entries) => {
+                for (ino, next, kind, name) in entries {
+                    if reply.add(ino, next, kind, name) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        match self.seek_file(ino, offset as u64, whence) {
+            Ok(pos) => reply.offset(pos as i64),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+//-----------------------------------------
+
+pub fn run(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
+    let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap()).canonicalize()?;
+    let mount_point = Path::new(matches.get_one::<String>("MOUNT_POINT").unwrap())
+        .canonicalize()
+        .context("Bad mount point")?;
+
+    env::set_current_dir(archive_dir)?;
+    let config = config::read_config(".", matches)?;
+    let fs = StreamFs::new(&config)?;
+
+    output.report.info(&format!(
+        "Serving {} streams at {}, unmount with 'fusermount -u' to exit",
+        fs.files.len(),
+        mount_point.display()
+    ));
+
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("blk-archive".to_string()),
+        MountOption::Subtype("blk-archive".to_string()),
+    ];
+    fuser::mount2(fs, &mount_point, &options).context("couldn't mount archive")?;
+    Ok(())
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod mount_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // The first half is data, the second half a hole.
+    struct MemContents(Cursor<Vec<u8>>);
+
+    impl Read for MemContents {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Seek for MemContents {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl StreamContents for MemContents {
+        fn find_extent(&mut self, from: u64, mapped: bool) -> Result<Option<u64>> {
+            let size = self.0.get_ref().len() as u64;
+            let half = size / 2;
+            Ok(match (mapped, from < half) {
+                _ if from >= size => None,
+                (true, true) | (false, false) => Some(from),
+                (true, false) => None,
+                (false, true) => Some(half),
+            })
+        }
+    }
+
+    fn contents(id: &str) -> Vec<u8> {
+        let seed = id.len() as u64;
+        (0..64 * 1024u64)
+            .map(|i| ((i + seed) % 251) as u8)
+            .collect()
+    }
+
+    fn mk_fs() -> StreamFs {
+        let open: OpenFn = Box::new(|id| {
+            if id == "broken" {
+                return Err(anyhow::anyhow!("couldn't open stream"));
+            }
+            Ok(Box::new(MemContents(Cursor::new(contents(id)))) as Box<dyn StreamContents>)
+        });
+
+        // Listed newest first, to check they're sorted by pack time.
+        let files = ["ccc", "broken", "a"]
+            .iter()
+            .enumerate()
+            .map(|(i, id)| StreamFile {
+                id: id.to_string(),
+                name: format!("{}-name", id),
+                size: 64 * 1024,
+                mapped_size: 32 * 1024,
+                pack_time: SystemTime::UNIX_EPOCH + Duration::from_secs(100 - i as u64),
+                reader: None,
+            })
+            .collect();
+        StreamFs::with_files(open, files)
+    }
+
+    #[test]
+    fn inodes_and_dir() {
+        let fs = mk_fs();
+
+        let names: Vec<(u64, i64, &str)> = fs
+            .dir_entries(ROOT_INO, 0)
+            .unwrap()
+            .into_iter()
+            .map(|(ino, next, _, name)| (ino, next, name))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                (ROOT_INO, 1, "."),
+                (ROOT_INO, 2, ".."),
+                (ROOT_INO + 1, 3, "a-name"),
+                (ROOT_INO + 2, 4, "broken-name"),
+                (ROOT_INO + 3, 5, "ccc-name"),
+            ]
+        );
+
+        // Listing resumes from the offset of the last entry returned.
+        let rest = fs.dir_entries(ROOT_INO, 3).unwrap();
+        assert_eq!(rest.len(), 2);
+        assert_eq!(rest[0].3, "broken-name");
+        assert_eq!(fs.dir_entries(ROOT_INO + 1, 0), Err(libc::ENOTDIR));
+
+        for (ino, _, _, name) in fs.dir_entries(ROOT_INO, 2).unwrap() {
+            let attr = fs.lookup_name(ROOT_INO, OsStr::new(name)).unwrap();
+            assert_eq!(attr.ino, ino);
+            assert_eq!(attr.kind, FileType::RegularFile);
+            assert_eq!(attr.size, 64 * 1024);
+            assert_eq!(attr.blocks, 64);
+        }
+        assert_eq!(
+            fs.lookup_name(ROOT_INO, OsStr::new("missing")).unwrap_err(),
+            libc::ENOENT
+        );
+        assert_eq!(
+            fs.lookup_name(ROOT_INO + 1, OsStr::new("a-name"))
+                .unwrap_err(),
+            libc::ENOENT
+        );
+
+        assert_eq!(fs.attr(ROOT_INO).unwrap().kind, FileType::Directory);
+        assert!(fs.attr(ROOT_INO + 4).is_none());
+        assert!(fs.attr(0).is_none());
+    }
+
+    #[test]
+    fn reads() {
+        let mut fs = mk_fs();
+        let ino = ROOT_INO + 3;
+        let expected = contents("ccc");
+
+        assert_eq!(fs.open_file(ino, libc::O_RDONLY), Ok(()));
+        assert_eq!(fs.open_file(ino, libc::O_RDWR), Err(libc::EROFS));
+        assert_eq!(fs.open_file(ROOT_INO + 2, libc::O_RDONLY), Err(libc::EIO));
+        assert_eq!(
+            fs.open_file(ROOT_INO + 4, libc::O_RDONLY),
+            Err(libc::ENOENT)
+        );
+
+        for (offset, len) in [(0, 4096), (1000, 10), (60 * 1024, 4096)] {
+            let data = fs.read_file(ino, offset, len).unwrap();
+            let end = std::cmp::min(offset as usize + len as usize, expected.len());
+            assert_eq!(data, &expected[offset as usize..end]);
+        }
+
+        // Reads past the end are short.
+        assert!(fs.read_file(ino, 64 * 1024, 4096).unwrap().is_empty());
+        assert_eq!(fs.read_file(ROOT_INO + 2, 0, 10), Err(libc::EIO));
+
+        assert_eq!(fs.seek_file(ino, 100, libc::SEEK_DATA), Ok(100));
+        assert_eq!(fs.seek_file(ino, 100, libc::SEEK_HOLE), Ok(32 * 1024));
+        assert_eq!(
+            fs.seek_file(ino, 40 * 1024, libc::SEEK_DATA),
+            Err(libc::ENXIO)
+        );
+        assert_eq!(fs.seek_file(ino, 0, libc::SEEK_SET), Err(libc::EINVAL));
+    }
+}
+
+//-----------------------------------------
@@ -0,0 +1,998 @@
+use anyhow::{anyhow, Context, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use clap::ArgMatches;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config;
+use crate::output::Output;
+use crate::stream_reader::StreamReader;
+
+//-----------------------------------------
+
+// See https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+// Only the fixed newstyle handshake is supported.
+const NBD_MAGIC: u64 = 0x4e42444d41474943;
+const IHAVEOPT: u64 = 0x49484156454f5054;
+const OPT_REPLY_MAGIC: u64 = 0x0003e889045565a9;
+const REQUEST_MAGIC: u32 = 0x25609513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+const STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+const OPT_STRUCTURED_REPLY: u32 = 8;
+const OPT_LIST_META_CONTEXT: u32 = 9;
+const OPT_SET_META_CONTEXT: u32 = 10;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_META_CONTEXT: u32 = 4;
+const REP_ERR_UNSUP: u32 = (1 << 31) | 1;
+const REP_ERR_INVALID: u32 = (1 << 31) | 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) | 6;
+
+const INFO_EXPORT: u16 = 0;
+
+const TFLAG_HAS_FLAGS: u16 = 1 << 0;
+const TFLAG_READ_ONLY: u16 = 1 << 1;
+const TFLAG_SEND_FLUSH: u16 = 1 << 2;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_BLOCK_STATUS: u16 = 7;
+const CMD_FLAG_REQ_ONE: u16 = 1 << 3;
+
+const REPLY_FLAG_DONE: u16 = 1 << 0;
+const REPLY_TYPE_NONE: u16 = 0;
+const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+const REPLY_TYPE_ERROR: u16 = (1 << 15) | 1;
+
+const NBD_EPERM: u32 = 1;
+const NBD_EIO: u32 = 5;
+const NBD_EINVAL: u32 = 22;
+
+// The only metadata context we offer, reporting unmapped regions.
+const BASE_ALLOCATION: &str = "base:allocation";
+const BASE_ALLOCATION_ID: u32 = 1;
+pub const STATE_HOLE: u32 = 1 << 0;
+pub const STATE_ZERO: u32 = 1 << 1;
+
+const MAX_OPTION_LEN: u32 = 64 * 1024;
+const MAX_REQUEST_LEN: u32 = 32 * 1024 * 1024;
+
+//-----------------------------------------
+
+/// Something that can be served over NBD.
+pub trait Export {
+    fn size(&self) -> u64;
+    fn read_only(&self) -> bool;
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+    fn flush(&mut self) -> Result<()>;
+
+    /// Returns the length and STATE_* flags of the extent starting at
+    /// offset.  The length may run past the end of any request.
+    fn block_status(&mut self, offset: u64) -> Result<(u64, u32)>;
+}
+
+struct Connection<'a, S: Read + Write, E: Export> {
+    sock: S,
+    export: &'a mut E,
+    name: &'a str,
+    structured: bool,
+    meta_context: bool,
+}
+
+fn read_bytes<R: Read>(r: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// A u32 length prefixed string, as used in option data.
+fn parse_string(data: &mut &[u8]) -> Result<String> {
+    let len = data.read_u32::<BigEndian>()? as usize;
+    if len > data.len() {
+        return Err(anyhow!("string runs past end of option"));
+    }
+    let s = String::from_utf8(data[..len].to_vec())?;
+    *data = &data[len..];
+    Ok(s)
+}
+
+impl<'a, S: Read + Write, E: Export> Connection<'a, S, E> {
+    fn name_ok(&self, name: &str) -> bool {
+        // The empty name selects the default export.
+        name.is_empty() || name == self.name
+    }
+
+    fn transmission_flags(&self) -> u16 {
+        if self.export.read_only() {
+            TFLAG_HAS_FLAGS | TFLAG_READ_ONLY
+        } else {
+            TFLAG_HAS_FLAGS | TFLAG_SEND_FLUSH
+        }
+    }
+
+    fn option_reply(&mut self, opt: u32, reply: u32, data: &[u8]) -> Result<()> {
+        let mut w = Vec::with_capacity(20 + data.len());
+        w.write_u64::<BigEndian>(OPT_REPLY_MAGIC)?;
+        w.write_u32::<BigEndian>(opt)?;
+        w.write_u32::<BigEndian>(reply)?;
+        w.write_u32::<BigEndian>(data.len() as u32)?;
+        w.write_all(data)?;
+        self.sock.write_all(&w)?;
+        self.sock.flush()?;
+        Ok(())
+    }
+
+    // Handles INFO and GO, returning true if the client is ready to
+    // move to the transmission phase.
+    fn info(&mut self, opt: u32, mut data: &[u8]) -> Result<bool> {
+        let name = match parse_string(&mut data) {
+            Ok(name) => name,
+            Err(_) => {
+                self.option_reply(opt, REP_ERR_INVALID, &[])?;
+                return Ok(false);
+            }
+        };
+        if !self.name_ok(&name) {
+            self.option_reply(opt, REP_ERR_UNKNOWN, &[])?;
+            return Ok(false);
+        }
+
+        // We only ever send NBD_INFO_EXPORT, whatever was asked for.
+        let mut info = Vec::new();
+        info.write_u16::<BigEndian>(INFO_EXPORT)?;
+        info.write_u64::<BigEndian>(self.export.size())?;
+        info.write_u16::<BigEndian>(self.transmission_flags())?;
+        self.option_reply(opt, REP_INFO, &info)?;
+        self.option_reply(opt, REP_ACK, &[])?;
+        Ok(opt == OPT_GO)
+    }
+
+    fn meta_context(&mut self, opt: u32, mut data: &[u8]) -> Result<()> {
+        let parse = |data: &mut &[u8]| -> Result<(String, Vec<String>)> {
+            let name = parse_string(data)?;
+            let nr_queries = data.read_u32::<BigEndian>()?;
+            let mut queries = Vec::new();
+            for _ in 0..nr_queries {
+                queries.push(parse_string(data)?);
+            }
+            Ok((name, queries))
+        };
+
+        let (name, queries) = match parse(&mut data) {
+            Ok(v) => v,
+            Err(_) => {
+                self.option_reply(opt, REP_ERR_INVALID, &[])?;
+                return Ok(());
+            }
+        };
+        if opt == OPT_SET_META_CONTEXT && !self.structured {
+            self.option_reply(opt, REP_ERR_INVALID, &[])?;
+            return Ok(());
+        }
+        if !self.name_ok(&name) {
+            self.option_reply(opt, REP_ERR_UNKNOWN, &[])?;
+            return Ok(());
+        }
+
+        let matched = if opt == OPT_LIST_META_CONTEXT {
+            queries.is_empty() || queries.iter().any(|q| q == "base:" || q == BASE_ALLOCATION)
+        } else {
+            queries.iter().any(|q| q == BASE_ALLOCATION)
+        };
+
+        if matched {
+            let mut reply = Vec::new();
+            let id = if opt == OPT_SET_META_CONTEXT {
+                BASE_ALLOCATION_ID
+            } else {
+                0
+            };
+            reply.write_u32::<BigEndian>(id)?;
+            reply.write_all(BASE_ALLOCATION.as_bytes())?;
+            self.option_reply(opt, REP_META_CONTEXT, &reply)?;
+        }
+        if opt == OPT_SET_META_CONTEXT {
+            self.meta_context = matched;
+        }
+        self.option_reply(opt, REP_ACK, &[])
+    }
+
+    // Returns false if the client gave up before the transmission phase.
+    fn handshake(&mut self) -> Result<bool> {
+        let mut w = Vec::new();
+        w.write_u64::<BigEndian>(NBD_MAGIC)?;
+        w.write_u64::<BigEndian>(IHAVEOPT)?;
+        w.write_u16::<BigEndian>(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)?;
+        self.sock.write_all(&w)?;
+        self.sock.flush()?;
+
+        let client_flags = self.sock.read_u32::<BigEndian>()?;
+        let no_zeroes = client_flags & FLAG_C_NO_ZEROES != 0;
+
+        loop {
+            if self.sock.read_u64::<BigEndian>()? != IHAVEOPT {
+                return Err(anyhow!("bad option magic"));
+            }
+            let opt = self.sock.read_u32::<BigEndian>()?;
+            let len = self.sock.read_u32::<BigEndian>()?;
+            if len > MAX_OPTION_LEN {
+                return Err(anyhow!("option too long"));
+            }
+            let data = read_bytes(&mut self.sock, len as usize)?;
+
+            match opt {
+                OPT_EXPORT_NAME => {
+                    // There's no way to refuse this nicely, so just hang up.
+                    let name = String::from_utf8(data)?;
+                    if !self.name_ok(&name) {
+                        return Err(anyhow!("unknown export '{}'", name));
+                    }
+
+                    let mut w = Vec::new();
+                    w.write_u64::<BigEndian>(self.export.size())?;
+                    w.write_u16::<BigEndian>(self.transmission_flags())?;
+                    if !no_zeroes {
+                        w.write_all(&[0; 124])?;
+                    }
+                    self.sock.write_all(&w)?;
+                    self.sock.flush()?;
+                    return Ok(true);
+                }
+                OPT_ABORT => {
+                    self.option_reply(opt, REP_ACK, &[])?;
+                    return Ok(false);
+                }
+                OPT_LIST => {
+                    let mut reply = Vec::new();
+                    reply.write_u32::<BigEndian>(self.name.len() as u32)?;
+                    reply.write_all(self.name.as_bytes())?;
+                    self.option_reply(opt, REP_SERVER, &reply)?;
+                    self.option_reply(opt, REP_ACK, &[])?;
+                }
+                OPT_INFO | OPT_GO => {
+                    if self.info(opt, &data)? {
+                        return Ok(true);
+                    }
+                }
+                OPT_STRUCTURED_REPLY => {
+                    if data.is_empty() {
+                        self.structured = true;
+                        self.option_reply(opt, REP_ACK, &[])?;
+                    } else {
+                        self.option_reply(opt, REP_ERR_INVALID, &[])?;
+                    }
+                }
+                OPT_LIST_META_CONTEXT | OPT_SET_META_CONTEXT => {
+                    self.meta_context(opt, &data)?;
+                }
+                _ => {
+                    self.option_reply(opt, REP_ERR_UNSUP, &[])?;
+                }
+            }
+        }
+    }
+
+    //-----------------
+
+    fn chunk(&mut self, handle: u64, flags: u16, ty: u16, payload: &[u8]) -> Result<()> {
+        let mut w = Vec::with_capacity(20 + payload.len());
+        w.write_u32::<BigEndian>(STRUCTURED_REPLY_MAGIC)?;
+        w.write_u16::<BigEndian>(flags)?;
+        w.write_u16::<BigEndian>(ty)?;
+        w.write_u64::<BigEndian>(handle)?;
+        w.write_u32::<BigEndian>(payload.len() as u32)?;
+        w.write_all(payload)?;
+        self.sock.write_all(&w)?;
+        self.sock.flush()?;
+        Ok(())
+    }
+
+    fn simple_reply(&mut self, handle: u64, err: u32, data: &[u8]) -> Result<()> {
+        let mut w = Vec::with_capacity(16 + data.len());
+        w.write_u32::<BigEndian>(SIMPLE_REPLY_MAGIC)?;
+        w.write_u32::<BigEndian>(err)?;
+        w.write_u64::<BigEndian>(handle)?;
+        w.write_all(data)?;
+        self.sock.write_all(&w)?;
+        self.sock.flush()?;
+        Ok(())
+    }
+
+    fn reply_ok(&mut self, handle: u64) -> Result<()> {
+        if self.structured {
+            self.chunk(handle, REPLY_FLAG_DONE, REPLY_TYPE_NONE, &[])
+        } else {
+            self.simple_reply(handle, 0, &[])
+        }
+    }
+
+    fn reply_error(&mut self, handle: u64, err: u32) -> Result<()> {
+        if self.structured {
+            let mut payload = Vec::new();
+            payload.write_u32::<BigEndian>(err)?;
+            payload.write_u16::<BigEndian>(0)?;
+            self.chunk(handle, REPLY_FLAG_DONE, REPLY_TYPE_ERROR, &payload)
+        } else {
+            self.simple_reply(handle, err, &[])
+        }
+    }
+
+    fn read(&mut self, handle: u64, offset: u64, len: u32) -> Result<()> {
+        let mut buf = vec![0; len as usize];
+        if self.export.read(offset, &mut buf).is_err() {
+            return self.reply_error(handle, NBD_EIO);
+        }
+
+        if self.structured {
+            let mut payload = Vec::with_capacity(8 + buf.len());
+            payload.write_u64::<BigEndian>(offset)?;
+            payload.write_all(&buf)?;
+            self.chunk(handle, REPLY_FLAG_DONE, REPLY_TYPE_OFFSET_DATA, &payload)
+        } else {
+            self.simple_reply(handle, 0, &buf)
+        }
+    }
+
+    fn block_status(&mut self, handle: u64, flags: u16, offset: u64, len: u32) -> Result<()> {
+        let end = offset + len as u64;
+        let mut descs: Vec<(u32, u32)> = Vec::new();
+        let mut pos = offset;
+        while pos < end {
+            let (extent_len, state) = match self.export.block_status(pos) {
+                Ok((l, state)) if l > 0 => (l, state),
+                _ => return self.reply_error(handle, NBD_EIO),
+            };
+            let extent_len = std::cmp::min(extent_len, end - pos);
+
+            match descs.last_mut() {
+                Some((l, s)) if *s == state => *l += extent_len as u32,
+                _ => descs.push((extent_len as u32, state)),
+            }
+            pos += extent_len;
+
+            if flags & CMD_FLAG_REQ_ONE != 0 {
+                break;
+            }
+        }
+
+        let mut payload = Vec::with_capacity(4 + descs.len() * 8);
+        payload.write_u32::<BigEndian>(BASE_ALLOCATION_ID)?;
+        for (l, s) in descs {
+            payload.write_u32::<BigEndian>(l)?;
+            payload.write_u32::<BigEndian>(s)?;
+        }
+        self.chunk(handle, REPLY_FLAG_DONE, REPLY_TYPE_BLOCK_STATUS, &payload)
+    }
+
+    fn transmission(&mut self) -> Result<()> {
+        loop {
+            let magic = match self.sock.read_u32::<BigEndian>() {
+                Ok(magic) => magic,
+                // Clients may just drop the connection.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            if magic != REQUEST_MAGIC {
+                return Err(anyhow!("bad request magic"));
+            }
+            let flags = self.sock.read_u16::<BigEndian>()?;
+            let cmd = self.sock.read_u16::<BigEndian>()?;
+            let handle = self.sock.read_u64::<BigEndian>()?;
+            let offset = self.sock.read_u64::<BigEndian>()?;
+            let len = self.sock.read_u32::<BigEndian>()?;
+
+            let in_range = len <= MAX_REQUEST_LEN
+                && offset
+                    .checked_add(len as u64)
+                    .is_some_and(|end| end <= self.export.size());
+
+            match cmd {
+                CMD_READ if in_range => self.read(handle, offset, len)?,
+                CMD_WRITE => {
+                    // The payload has to be consumed whatever happens.
+                    if len > MAX_REQUEST_LEN {
+                        return Err(anyhow!("write request too large"));
+                    }
+                    let data = read_bytes(&mut self.sock, len as usize)?;
+                    if self.export.read_only() {
+                        self.reply_error(handle, NBD_EPERM)?;
+                    } else if !in_range {
+                        self.reply_error(handle, NBD_EINVAL)?;
+                    } else if self.export.write(offset, &data).is_err() {
+                        self.reply_error(handle, NBD_EIO)?;
+                    } else {
+                        self.reply_ok(handle)?;
+                    }
+                }
+                CMD_DISC => {
+                    return self.export.flush();
+                }
+                CMD_FLUSH => {
+                    if self.export.flush().is_err() {
+                        self.reply_error(handle, NBD_EIO)?;
+                    } else {
+                        self.reply_ok(handle)?;
+                    }
+                }
+                CMD_BLOCK_STATUS if in_range && self.meta_context => {
+                    self.block_status(handle, flags, offset, len)?
+                }
+                _ => self.reply_error(handle, NBD_EINVAL)?,
+            }
+        }
+    }
+}
+
+/// Runs a single client connection to completion.
+pub fn serve_connection<S: Read + Write, E: Export>(
+    sock: S,
+    export: &mut E,
+    name: &str,
+) -> Result<()> {
+    let mut conn = Connection {
+        sock,
+        export,
+        name,
+        structured: false,
+        meta_context: false,
+    };
+
+    if conn.handshake()? {
+        conn.transmission()?;
+    }
+    Ok(())
+}
+
+//-----------------------------------------
+
+// Returns the offset of the next data or hole in a sparse file, or None
+// if there's no more data.
+fn seek_sparse(file: &File, offset: u64, whence: i32) -> Result<Option<u64>> {
+    let r = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ENXIO) {
+            return Ok(None);
+        }
+        return Err(e.into());
+    }
+    Ok(Some(r as u64))
+}
+
+/// Exports a stream read only.
+pub struct StreamExport {
+    reader: StreamReader,
+}
+
+impl Export for StreamExport {
+    fn size(&self) -> u64 {
+        self.reader.size()
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn write(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        Err(anyhow!("export is read only"))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn block_status(&mut self, offset: u64) -> Result<(u64, u32)> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        match self.reader.extent()? {
+            Some((true, len)) => Ok((len, 0)),
+            Some((false, len)) => Ok((len, STATE_HOLE | STATE_ZERO)),
+            None => Err(anyhow!("block status beyond end of stream")),
+        }
+    }
+}
+
+/// Makes an export writable.  Writes are kept in a sparse overlay file,
+/// and the blocks allocated in it are the ones that have been written,
+/// so it can be reused across runs without any extra metadata.
+pub struct CowExport<E: Export> {
+    base: E,
+    overlay: File,
+    block_size: u64,
+}
+
+impl<E: Export> CowExport<E> {
+    pub fn new(base: E, path: &Path) -> Result<Self> {
+        let overlay = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("couldn't open overlay {}", path.display()))?;
+
+        let meta = overlay.metadata()?;
+        if meta.len() == 0 {
+            overlay.set_len(base.size())?;
+        } else if meta.len() != base.size() {
+            return Err(anyhow!(
+                "overlay {} is not the same size as the stream",
+                path.display()
+            ));
+        }
+
+        Ok(Self {
+            base,
+            overlay,
+            block_size: meta.blksize(),
+        })
+    }
+
+    // Returns the end of the written extent holding offset, if offset has
+    // been written.
+    fn written(&self, offset: u64) -> Result<Option<u64>> {
+        match seek_sparse(&self.overlay, offset, libc::SEEK_DATA)? {
+            Some(data) if data == offset => seek_sparse(&self.overlay, offset, libc::SEEK_HOLE),
+            _ => Ok(None),
+        }
+    }
+
+    // The start of the next written region after offset, if any.
+    fn next_written(&self, offset: u64) -> Result<Option<u64>> {
+        seek_sparse(&self.overlay, offset, libc::SEEK_DATA)
+    }
+
+    // Copies a whole block into the overlay before it's partially
+    // written, so the rest of it still reads back as the base.
+    fn copy_up(&mut self, block: u64) -> Result<()> {
+        if self.written(block)?.is_some() {
+            return Ok(());
+        }
+
+        let len = std::cmp::min(self.block_size, self.base.size() - block);
+        let mut buf = vec![0; len as usize];
+        self.base.read(block, &mut buf)?;
+        self.overlay.write_all_at(&buf, block)?;
+        Ok(())
+    }
+}
+
+impl<E: Export> Export for CowExport<E> {
+    fn size(&self) -> u64 {
+        self.base.size()
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let end = offset + buf.len() as u64;
+        let mut pos = offset;
+        while pos < end {
+            let b = (pos - offset) as usize;
+            match self.written(pos)? {
+                Some(data_end) => {
+                    let len = (std::cmp::min(data_end, end) - pos) as usize;
+                    self.overlay.read_exact_at(&mut buf[b..b + len], pos)?;
+                    pos += len as u64;
+                }
+                None => {
+                    let next = self.next_written(pos)?.unwrap_or(end);
+                    let len = (std::cmp::min(next, end) - pos) as usize;
+                    self.base.read(pos, &mut buf[b..b + len])?;
+                    pos += len as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let bs = self.block_size;
+        let end = offset + data.len() as u64;
+        let (head, tail) = (offset % bs, end % bs);
+        if head > 0 {
+            self.copy_up(offset - head)?;
+        }
+        if tail > 0 && end < self.size() {
+            self.copy_up(end - tail)?;
+        }
+
+        self.overlay.write_all_at(data, offset)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.overlay.sync_data()?;
+        Ok(())
+    }
+
+    fn block_status(&mut self, offset: u64) -> Result<(u64, u32)> {
+        if let Some(data_end) = self.written(offset)? {
+            return Ok((data_end - offset, 0));
+        }
+
+        let (len, state) = self.base.block_status(offset)?;
+        match self.next_written(offset)? {
+            Some(next) => Ok((std::cmp::min(len, next - offset), state)),
+            None => Ok((len, state)),
+        }
+    }
+}
+
+//-----------------------------------------
+
+// Paths are resolved before we change into the archive dir.
+fn absolute(path: &str) -> Result<PathBuf> {
+    Ok(env::current_dir()?.join(path))
+}
+
+pub fn run(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
+    let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap()).canonicalize()?;
+    let stream = matches.get_one::<String>("STREAM").unwrap();
+    let socket = matches
+        .get_one::<String>("SOCKET")
+        .map(|s| absolute(s))
+        .transpose()?;
+    let overlay = matches
+        .get_one::<String>("OVERLAY")
+        .map(|s| absolute(s))
+        .transpose()?;
+    let port = *matches.get_one::<u16>("PORT").unwrap();
+
+    if let Some(overlay) = &overlay {
+        if overlay.starts_with(&archive_dir) {
+            return Err(anyhow!("the overlay must be outside the archive"));
+        }
+    }
+
+    env::set_current_dir(&archive_dir)?;
+    let config = config::read_config(".", matches)?;
+    let export = StreamExport {
+        reader: StreamReader::new(stream, &config)?,
+    };
+
+    match overlay {
+        Some(path) => {
+            let export = CowExport::new(export, &path)?;
+            listen(export, stream, socket, port, output)
+        }
+        None => listen(export, stream, socket, port, output),
+    }
+}
+
+// Clients are served one at a time.
+fn listen<E: Export>(
+    mut export: E,
+    stream: &str,
+    socket: Option<PathBuf>,
+    port: u16,
+    output: Arc<Output>,
+) -> Result<()> {
+    let mode = if export.read_only() {
+        "read only"
+    } else {
+        "writable"
+    };
+
+    match socket {
+        Some(path) => {
+            let listener = UnixListener::bind(&path)
+                .with_context(|| format!("couldn't bind to {}", path.display()))?;
+            output.report.info(&format!(
+                "Serving stream {} ({}) on {}",
+                stream,
+                mode,
+                path.display()
+            ));
+            for conn in listener.incoming() {
+                if let Err(e) = serve_connection(conn?, &mut export, stream) {
+                    output.report.info(&format!("connection failed: {:?}", e));
+                }
+            }
+        }
+        None => {
+            let listener = TcpListener::bind(("127.0.0.1", port))
+                .with_context(|| format!("couldn't listen on port {}", port))?;
+            output.report.info(&format!(
+                "Serving stream {} ({}) on localhost:{}",
+                stream, mode, port
+            ));
+            for conn in listener.incoming() {
+                let conn = conn?;
+                conn.set_nodelay(true)?;
+                if let Err(e) = serve_connection(conn, &mut export, stream) {
+                    output.report.info(&format!("connection failed: {:?}", e));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod nbd_tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    // The first half is data, the second half a hole.
+    struct MemExport {
+        data: Vec<u8>,
+    }
+
+    impl Export for MemExport {
+        fn size(&self) -> u64 {
+            self.data.len() as u64
+        }
+
+        fn read_only(&self) -> bool {
+            false
+        }
+
+        fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+            let offset = offset as usize;
+            self.data[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn block_status(&mut self, offset: u64) -> Result<(u64, u32)> {
+            let half = self.size() / 2;
+            if offset < half {
+                Ok((half - offset, 0))
+            } else {
+                Ok((self.size() - offset, STATE_HOLE | STATE_ZERO))
+            }
+        }
+    }
+
+    #[test]
+    fn cow_overlay() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let path = td.path().join("overlay");
+        let size = 256 * 1024;
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let mut expected = data.clone();
+
+        let mut cow = CowExport::new(MemExport { data: data.clone() }, &path)?;
+        let bs = cow.block_size as usize;
+
+        // Unaligned writes must leave the rest of their blocks alone.
+        for (offset, len) in [(bs + 100, 10), (3 * bs - 5, 20), (0, bs)] {
+            let buf = vec![0xff; len];
+            cow.write(offset as u64, &buf)?;
+            expected[offset..offset + len].copy_from_slice(&buf);
+        }
+        let mut actual = vec![0; size];
+        cow.read(0, &mut actual)?;
+        assert_eq!(actual, expected);
+        // The base is never touched.
+        assert_eq!(cow.base.data, data);
+
+        // Written blocks are reported as data, even in the base's hole.
+        cow.write(size as u64 - 1, &[1])?;
+        let (len, state) = cow.block_status(size as u64 / 2)?;
+        assert_eq!(state, STATE_HOLE | STATE_ZERO);
+        assert!(len < size as u64 / 2);
+        assert_eq!(cow.block_status(size as u64 - 1)?, (1, 0));
+
+        // The overlay can be reopened.
+        let mut cow = CowExport::new(MemExport { data }, &path)?;
+        expected[size - 1] = 1;
+        cow.read(0, &mut actual)?;
+        assert_eq!(actual, expected);
+        Ok(())
+    }
+
+    fn send_option(sock: &mut UnixStream, opt: u32, data: &[u8]) -> Result<()> {
+        sock.write_u64::<BigEndian>(IHAVEOPT)?;
+        sock.write_u32::<BigEndian>(opt)?;
+        sock.write_u32::<BigEndian>(data.len() as u32)?;
+        sock.write_all(data)?;
+        Ok(())
+    }
+
+    // Returns (reply type, data)
+    fn recv_option_reply(sock: &mut UnixStream, opt: u32) -> Result<(u32, Vec<u8>)> {
+        assert_eq!(sock.read_u64::<BigEndian>()?, OPT_REPLY_MAGIC);
+        assert_eq!(sock.read_u32::<BigEndian>()?, opt);
+        let reply = sock.read_u32::<BigEndian>()?;
+        let len = sock.read_u32::<BigEndian>()?;
+        Ok((reply, read_bytes(sock, len as usize)?))
+    }
+
+    fn send_request(
+        sock: &mut UnixStream,
+        cmd: u16,
+        handle: u64,
+        offset: u64,
+        len: u32,
+    ) -> Result<()> {
+        sock.write_u32::<BigEndian>(REQUEST_MAGIC)?;
+        sock.write_u16::<BigEndian>(0)?;
+        sock.write_u16::<BigEndian>(cmd)?;
+        sock.write_u64::<BigEndian>(handle)?;
+        sock.write_u64::<BigEndian>(offset)?;
+        sock.write_u32::<BigEndian>(len)?;
+        Ok(())
+    }
+
+    // Returns (flags, type, payload)
+    fn recv_chunk(sock: &mut UnixStream, handle: u64) -> Result<(u16, u16, Vec<u8>)> {
+        assert_eq!(sock.read_u32::<BigEndian>()?, STRUCTURED_REPLY_MAGIC);
+        let flags = sock.read_u16::<BigEndian>()?;
+        let ty = sock.read_u16::<BigEndian>()?;
+        assert_eq!(sock.read_u64::<BigEndian>()?, handle);
+        let len = sock.read_u32::<BigEndian>()?;
+        Ok((flags, ty, read_bytes(sock, len as usize)?))
+    }
+
+    fn name_data(name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_u32::<BigEndian>(name.len() as u32).unwrap();
+        data.extend_from_slice(name.as_bytes());
+        data
+    }
+
+    #[test]
+    fn structured_session() -> Result<()> {
+        let size = 64 * 1024;
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let (mut client, server) = UnixStream::pair()?;
+
+        let expected = data.clone();
+        let server = thread::spawn(move || {
+            let mut export = MemExport { data };
+            serve_connection(server, &mut export, "stream").unwrap();
+            export.data
+        });
+
+        assert_eq!(client.read_u64::<BigEndian>()?, NBD_MAGIC);
+        assert_eq!(client.read_u64::<BigEndian>()?, IHAVEOPT);
+        let _flags = client.read_u16::<BigEndian>()?;
+        client.write_u32::<BigEndian>(FLAG_C_NO_ZEROES | 1)?;
+
+        send_option(&mut client, OPT_STRUCTURED_REPLY, &[])?;
+        assert_eq!(
+            recv_option_reply(&mut client, OPT_STRUCTURED_REPLY)?.0,
+            REP_ACK
+        );
+
+        let mut meta = name_data("stream");
+        meta.write_u32::<BigEndian>(1)?;
+        meta.extend(name_data(BASE_ALLOCATION));
+        send_option(&mut client, OPT_SET_META_CONTEXT, &meta)?;
+        let (reply, context) = recv_option_reply(&mut client, OPT_SET_META_CONTEXT)?;
+        assert_eq!(reply, REP_META_CONTEXT);
+        assert_eq!(&context[4..], BASE_ALLOCATION.as_bytes());
+        assert_eq!(
+            recv_option_reply(&mut client, OPT_SET_META_CONTEXT)?.0,
+            REP_ACK
+        );
+
+        // An unknown export is refused, but the session continues.
+        let mut go = name_data("other");
+        go.write_u16::<BigEndian>(0)?;
+        send_option(&mut client, OPT_GO, &go)?;
+        assert_eq!(recv_option_reply(&mut client, OPT_GO)?.0, REP_ERR_UNKNOWN);
+
+        let mut go = name_data("");
+        go.write_u16::<BigEndian>(0)?;
+        send_option(&mut client, OPT_GO, &go)?;
+        let (reply, info) = recv_option_reply(&mut client, OPT_GO)?;
+        assert_eq!(reply, REP_INFO);
+        assert_eq!((&info[2..10]).read_u64::<BigEndian>()?, size as u64);
+        assert_eq!(recv_option_reply(&mut client, OPT_GO)?.0, REP_ACK);
+
+        send_request(&mut client, CMD_READ, 1, 1000, 5000)?;
+        let (flags, ty, payload) = recv_chunk(&mut client, 1)?;
+        assert_eq!((flags, ty), (REPLY_FLAG_DONE, REPLY_TYPE_OFFSET_DATA));
+        assert_eq!((&payload[..8]).read_u64::<BigEndian>()?, 1000);
+        assert_eq!(&payload[8..], &expected[1000..6000]);
+
+        send_request(&mut client, CMD_BLOCK_STATUS, 2, 16 * 1024, 32 * 1024)?;
+        let (_, ty, payload) = recv_chunk(&mut client, 2)?;
+        assert_eq!(ty, REPLY_TYPE_BLOCK_STATUS);
+        let mut p = &payload[..];
+        assert_eq!(p.read_u32::<BigEndian>()?, BASE_ALLOCATION_ID);
+        let descs: Vec<(u32, u32)> = (0..2)
+            .map(|_| {
+                (
+                    p.read_u32::<BigEndian>().unwrap(),
+                    p.read_u32::<BigEndian>().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            descs,
+            [(16 * 1024, 0), (16 * 1024, STATE_HOLE | STATE_ZERO)]
+        );
+
+        // Out of range
+        send_request(&mut client, CMD_READ, 3, size as u64 - 10, 20)?;
+        let (_, ty, _) = recv_chunk(&mut client, 3)?;
+        assert_eq!(ty, REPLY_TYPE_ERROR);
+
+        send_request(&mut client, CMD_WRITE, 4, 0, 4)?;
+        client.write_all(&[9, 9, 9, 9])?;
+        let (_, ty, _) = recv_chunk(&mut client, 4)?;
+        assert_eq!(ty, REPLY_TYPE_NONE);
+
+        send_request(&mut client, CMD_DISC, 5, 0, 0)?;
+        let data = server.join().unwrap();
+        assert_eq!(&data[..4], &[9, 9, 9, 9]);
+        Ok(())
+    }
+
+    #[test]
+    fn export_name_simple_replies() -> Result<()> {
+        let (mut client, server) = UnixStream::pair()?;
+        let server = thread::spawn(move || {
+            let mut export = MemExport {
+                data: vec![7; 4096],
+            };
+            serve_connection(server, &mut export, "stream").unwrap();
+        });
+
+        let _ = read_bytes(&mut client, 18)?;
+        client.write_u32::<BigEndian>(1)?;
+        send_option(&mut client, OPT_EXPORT_NAME, b"stream")?;
+        assert_eq!(client.read_u64::<BigEndian>()?, 4096);
+        let _flags = client.read_u16::<BigEndian>()?;
+        let _zeroes = read_bytes(&mut client, 124)?;
+
+        send_request(&mut client, CMD_READ, 1, 0, 16)?;
+        assert_eq!(client.read_u32::<BigEndian>()?, SIMPLE_REPLY_MAGIC);
+        assert_eq!(client.read_u32::<BigEndian>()?, 0);
+        assert_eq!(client.read_u64::<BigEndian>()?, 1);
+        assert_eq!(read_bytes(&mut client, 16)?, vec![7; 16]);
+
+        drop(client);
+        server.join().unwrap();
+        Ok(())
+    }
+}
+
+//-----------------------------------------
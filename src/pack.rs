@@ -13,20 +13,29 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use crate::archive::*;
+use crate::chunk_delta;
 use crate::chunkers::*;
 use crate::config;
 use crate::content_sensitive_splitter::*;
+use crate::era_metadata::*;
 use crate::hash::*;
+use crate::index_info::MemoryEstimate;
 use crate::iovec::*;
+use crate::list::stream_ids;
+use crate::lvm::*;
 use crate::output::Output;
 use crate::paths::*;
 use crate::run_iter::*;
+use crate::similarity::*;
 use crate::slab::builder::*;
 use crate::slab::*;
 use crate::splitter::*;
 use crate::stream::*;
 use crate::stream_builders::*;
+use crate::stream_reader::{open_archive_with, SeekIndex};
+use crate::stream_slabs::*;
 use crate::thin_metadata::*;
+use thinp::thin::device_detail::DeviceDetail;
 
 //-----------------------------------------
 
@@ -68,25 +77,37 @@ struct DedupStats {
     data_written: u64,
     mapped_size: u64,
     fill_size: u64,
+    delta_size: u64,
+
+    // How much smaller the deltas are than the chunks they stand for.
+    delta_saved: u64,
+    parent_size: u64,
+
+    // Mapped in the base of a delta, but unmapped now.
+    discarded_size: u64,
 }
 
 struct DedupHandler {
     nr_chunks: usize,
 
-    stream_file: SlabFile,
+    stream_file: StreamSlabsWriter,
     stream_buf: Vec<u8>,
 
     mapping_builder: Arc<Mutex<dyn Builder>>,
 
     stats: DedupStats,
     archive: Data,
+
+    // Only present if delta compression is enabled.
+    sketcher: Option<Sketcher>,
 }
 
 impl DedupHandler {
     fn new(
-        stream_file: SlabFile,
+        stream_file: StreamSlabsWriter,
         mapping_builder: Arc<Mutex<dyn Builder>>,
         archive: Data,
+        sketcher: Option<Sketcher>,
     ) -> Result<Self> {
         let stats = DedupStats::default();
 
@@ -97,16 +118,14 @@ impl DedupHandler {
             mapping_builder,
             stats,
             archive,
+            sketcher,
         })
     }
 
     fn maybe_complete_stream(&mut self) -> Result<()> {
-        complete_slab(
-            &mut self.stream_file,
-            &mut self.stream_buf,
-            SLAB_SIZE_TARGET,
-        )?;
-        Ok(())
+        let cuts = self.mapping_builder.lock().unwrap().take_cuts();
+        self.stream_file
+            .complete_slabs(&mut self.stream_buf, &cuts, SLAB_SIZE_TARGET)
     }
 
     fn add_stream_entry(&mut self, e: &MapEntry, len: u64) -> Result<()> {
@@ -135,14 +154,121 @@ impl DedupHandler {
     }
 }
 
-impl IoVecHandler for DedupHandler {
-    fn handle_data(&mut self, iov: &IoVec) -> Result<()> {
+impl DedupHandler {
+    fn handle_hashed_data(&mut self, iov: &IoVec, h: Hash256) -> Result<()> {
         self.nr_chunks += 1;
         let len = iov_len_(iov);
         self.stats.mapped_size += len;
         assert!(len != 0);
 
+        let me = match self.archive.is_known(&h)? {
+            Some((slab, offset)) => MapEntry::Data {
+                archive: 0,
+                slab,
+                offset,
+                nr_entries: 1,
+            },
+            None => match self.archive.parent_lookup(&h)? {
+                Some((archive, slab, offset)) => {
+                    self.stats.parent_size += len;
+                    MapEntry::Data {
+                        archive,
+                        slab,
+                        offset,
+                        nr_entries: 1,
+                    }
+                }
+                None => self.add_new_data(iov, h, len)?,
+            },
+        };
+        self.add_stream_entry(&me, len)?;
+        self.maybe_complete_stream()?;
+
+        Ok(())
+    }
+
+    // Stores a chunk that isn't in the archive, either as a delta against
+    // a similar chunk, or in full.  Only chunks stored in full are used as
+    // bases, so rebuilding a delta never needs more than two reads.
+    fn add_new_data(&mut self, iov: &IoVec, h: Hash256, len: u64) -> Result<MapEntry> {
+        let sketch = match &self.sketcher {
+            Some(sketcher) => sketcher.sketch(iov),
+            None => {
+                let (loc, data_written) = self.archive.data_add(h, iov, len)?;
+                self.stats.data_written += data_written;
+                return Ok(MapEntry::Data {
+                    archive: 0,
+                    slab: loc.0,
+                    offset: loc.1,
+                    nr_entries: 1,
+                });
+            }
+        };
+
+        if let Some(base) = self.archive.similar_chunk(&sketch) {
+            let key = delta_key(&h, base);
+            let delta_entry = |loc: (u32, u32)| MapEntry::Delta {
+                base_slab: base.0,
+                base_offset: base.1,
+                slab: loc.0,
+                offset: loc.1,
+                len: len as u32,
+                partial: None,
+            };
+
+            // This chunk may already be stored as a delta of the same base.
+            if let Some(loc) = self.archive.is_known(&key)? {
+                return Ok(delta_entry(loc));
+            }
+
+            if let Some(base_data) = self.archive.similar_data(base)? {
+                let target = iov.concat();
+                let delta = chunk_delta::encode(&base_data, &target);
+
+                // Not worth the extra read unless it at least halves the size.
+                let delta_len = delta.len() as u64;
+                if delta_len <= len / 2 {
+                    let (loc, data_written) =
+                        self.archive.data_add(key, &vec![&delta[..]], delta_len)?;
+                    self.stats.data_written += data_written;
+                    self.stats.delta_size += len;
+                    self.stats.delta_saved += len - delta_len;
+                    return Ok(delta_entry(loc));
+                }
+            }
+        }
+
+        let (loc, data_written) = self.archive.data_add(h, iov, len)?;
+        self.stats.data_written += data_written;
+        self.archive.add_similar(&sketch, loc);
+        Ok(MapEntry::Data {
+            archive: 0,
+            slab: loc.0,
+            offset: loc.1,
+            nr_entries: 1,
+        })
+    }
+}
+
+// Deltas are keyed by the chunk they rebuild and its base, rather than
+// by their own contents, so a delta can never be mistaken for a chunk.
+fn delta_key(h: &Hash256, base: (u32, u32)) -> Hash256 {
+    hash_256_iov(&vec![
+        b"delta",
+        &h[..],
+        &base.0.to_le_bytes(),
+        &base.1.to_le_bytes(),
+    ])
+}
+
+impl IoVecHandler for DedupHandler {
+    fn handle_data(&mut self, iov: &IoVec) -> Result<()> {
         if let Some(first_byte) = all_same(iov) {
+            self.nr_chunks += 1;
+            let len = iov_len_(iov);
+            self.stats.mapped_size += len;
+            assert!(len != 0);
+
             self.stats.fill_size += len;
             self.add_stream_entry(
                 &MapEntry::Fill {
@@ -152,19 +278,30 @@ impl IoVecHandler for DedupHandler {
                 len,
             )?;
             self.maybe_complete_stream()?;
+            Ok(())
         } else {
-            let h = hash_256_iov(iov);
-            // Note: add_data_entry returns existing entry if present, else returns newly inserted
-            // entry.
-            let (entry_location, data_written) = self.archive.data_add(h, iov, len)?;
-            let me = MapEntry::Data {
-                slab: entry_location.0,
-                offset: entry_location.1,
-                nr_entries: 1,
-            };
-            self.stats.data_written += data_written;
-            self.add_stream_entry(&me, len)?;
-            self.maybe_complete_stream()?;
+            self.handle_hashed_data(iov, hash_256_iov(iov))
+        }
+    }
+
+    fn handle_batch(&mut self, iovs: &[IoVec]) -> Result<()> {
+        // Hash everything up front so lookups in the full index can be batched.
+        let hashes: Vec<Option<Hash256>> = iovs
+            .iter()
+            .map(|iov| match all_same(iov) {
+                Some(_) => None,
+                None => Some(hash_256_iov(iov)),
+            })
+            .collect();
+
+        let wanted: Vec<Hash256> = hashes.iter().flatten().cloned().collect();
+        self.archive.prefetch(&wanted)?;
+
+        for (iov, h) in iovs.iter().zip(hashes) {
+            match h {
+                Some(h) => self.handle_hashed_data(iov, h)?,
+                None => self.handle_data(iov)?,
+            }
         }
 
         Ok(())
@@ -173,9 +310,11 @@ impl IoVecHandler for DedupHandler {
     fn complete(&mut self) -> Result<()> {
         let mut builder = self.mapping_builder.lock().unwrap();
         builder.complete(&mut self.stream_buf)?;
+        let cuts = builder.take_cuts();
         drop(builder);
 
-        complete_slab(&mut self.stream_file, &mut self.stream_buf, 0)?;
+        self.stream_file
+            .complete_slabs(&mut self.stream_buf, &cuts, 0)?;
         self.stream_file.close()?;
 
         Ok(())
@@ -200,7 +339,7 @@ fn new_stream_path_(rng: &mut ChaCha20Rng) -> Result<Option<(String, PathBuf)>>
     }
 }
 
-fn new_stream_path() -> Result<(String, PathBuf)> {
+pub fn new_stream_path() -> Result<(String, PathBuf)> {
     let mut rng = ChaCha20Rng::from_entropy();
     loop {
         if let Some(r) = new_stream_path_(&mut rng)? {
@@ -211,17 +350,60 @@ fn new_stream_path() -> Result<(String, PathBuf)> {
     // Can't get here
 }
 
+// Identifies the thin device a stream is packed from.
+#[derive(Default)]
+struct ThinSource {
+    thin_id: u32,
+    pool_uuid: Option<String>,
+    dm_uuid: Option<String>,
+    details: Option<DeviceDetail>,
+}
+
+impl ThinSource {
+    fn new(
+        thin_id: u32,
+        details: DeviceDetail,
+        pool_uuid: Option<String>,
+        dm_uuid: Option<String>,
+    ) -> Self {
+        Self {
+            thin_id,
+            pool_uuid,
+            dm_uuid,
+            details: Some(details),
+        }
+    }
+}
+
+// Identifies the dm-era device a stream is packed from, and the era it
+// was packed in.
+struct EraSource {
+    era: u32,
+    dm_uuid: Option<String>,
+}
+
 struct Packer {
     output: Arc<Output>,
     input_path: PathBuf,
     stream_name: String,
     it: Box<dyn Iterator<Item = Result<Chunk>>>,
-    input_size: u64,
+
+    // The sizes aren't known up front when packing from a pipe.
+    input_size: Option<u64>,
     mapping_builder: Arc<Mutex<dyn Builder>>,
-    mapped_size: u64,
+    mapped_size: Option<u64>,
     block_size: usize,
-    thin_id: Option<u32>,
+    thin: Option<ThinSource>,
     hash_cache_size_meg: usize,
+    index_mode: config::IndexMode,
+    memory_budget_meg: Option<usize>,
+    enforce_memory_budget: bool,
+    delta_compression: bool,
+    parents: Vec<String>,
+
+    // Set when packing a snapshot of a logical volume.
+    lv: Option<LvName>,
+    era: Option<EraSource>,
 }
 
 impl Packer {
@@ -231,12 +413,17 @@ impl Packer {
         input_path: PathBuf,
         stream_name: String,
         it: Box<dyn Iterator<Item = Result<Chunk>>>,
-        input_size: u64,
+        input_size: Option<u64>,
         mapping_builder: Arc<Mutex<dyn Builder>>,
-        mapped_size: u64,
+        mapped_size: Option<u64>,
         block_size: usize,
-        thin_id: Option<u32>,
+        thin: Option<ThinSource>,
         hash_cache_size_meg: usize,
+        index_mode: config::IndexMode,
+        memory_budget_meg: Option<usize>,
+        enforce_memory_budget: bool,
+        delta_compression: bool,
+        parents: Vec<String>,
     ) -> Self {
         Self {
             output,
@@ -247,41 +434,87 @@ impl Packer {
             mapping_builder,
             mapped_size,
             block_size,
-            thin_id,
+            thin,
             hash_cache_size_meg,
+            index_mode,
+            memory_budget_meg,
+            enforce_memory_budget,
+            delta_compression,
+            parents,
+            lv: None,
+            era: None,
         }
     }
 
-    fn pack(mut self, hashes_file: Arc<Mutex<SlabFile>>) -> Result<()> {
+    // Warns, or fails, if the indexes are projected to need more memory
+    // than the budget allows.
+    fn check_memory_budget(&self, ad: &Data) -> Result<()> {
+        if let Some(budget_meg) = self.memory_budget_meg {
+            let mem = MemoryEstimate::new(
+                ad.filter(),
+                ad.full_index(),
+                self.hash_cache_size_meg,
+                self.delta_compression,
+                self.mapped_size.unwrap_or(0) as usize / self.block_size,
+            );
+
+            let budget = budget_meg as u64 * 1024 * 1024;
+            if mem.total() > budget {
+                let msg = format!(
+                    "projected index memory {:.2} exceeds budget of {:.2}",
+                    Size(mem.total()),
+                    Size(budget)
+                );
+                if self.enforce_memory_budget {
+                    return Err(anyhow!(msg));
+                }
+                self.output.report.warning(&msg);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pack(mut self, hashes_file: Arc<Mutex<SlabFile>>) -> Result<String> {
         let mut splitter = ContentSensitiveSplitter::new(self.block_size as u32);
 
+        // Delta compression reads back base chunks, so cache a few slabs.
         let data_file = SlabFileBuilder::open(data_path())
             .write(true)
             .queue_depth(128)
+            .cache_nr_entries(if self.delta_compression { 16 } else { 1 })
             .build()
             .context("couldn't open data slab file")?;
 
-        let (stream_id, mut stream_path) = new_stream_path()?;
-
-        std::fs::create_dir(stream_path.clone())?;
-        stream_path.push("stream");
-
-        let stream_file = SlabFileBuilder::create(stream_path)
-            .queue_depth(16)
-            .compressed(true)
-            .build()
-            .context("couldn't open stream slab file")?;
-
         let hashes_per_slab = std::cmp::max(SLAB_SIZE_TARGET / self.block_size, 1);
         let slab_capacity = ((self.hash_cache_size_meg * 1024 * 1024)
             / std::mem::size_of::<Hash256>())
             / hashes_per_slab;
 
-        let ad: Data = Data::new(data_file, hashes_file, slab_capacity)?;
+        let mut ad: Data = Data::new(data_file, hashes_file, slab_capacity)?;
+        ad.open_parents(&self.parents, 16)?;
+        if self.index_mode == config::IndexMode::Disk {
+            ad.enable_full_index()?;
+        }
+        let sketcher = if self.delta_compression {
+            ad.enable_similarity()?;
+            Some(Sketcher::default())
+        } else {
+            None
+        };
+        self.check_memory_budget(&ad)?;
+
+        let (stream_id, stream_path) = new_stream_path()?;
+
+        std::fs::create_dir(stream_path)?;
+        let stream_file = StreamSlabsWriter::new(&stream_id)?;
 
-        let mut handler = DedupHandler::new(stream_file, self.mapping_builder.clone(), ad)?;
+        let mut handler =
+            DedupHandler::new(stream_file, self.mapping_builder.clone(), ad, sketcher)?;
 
-        handler.ensure_extra_capacity(self.mapped_size as usize / self.block_size)?;
+        if let Some(mapped_size) = self.mapped_size {
+            handler.ensure_extra_capacity(mapped_size as usize / self.block_size)?;
+        }
 
         self.output.report.progress(0);
         let start_time: DateTime<Utc> = Utc::now();
@@ -293,9 +526,11 @@ impl Packer {
                     let len = buffer.len();
                     splitter.next_data(buffer, &mut handler)?;
                     total_read += len as u64;
-                    self.output
-                        .report
-                        .progress(((100 * total_read) / self.mapped_size) as u8);
+                    if let Some(mapped_size) = self.mapped_size {
+                        self.output
+                            .report
+                            .progress(((100 * total_read) / mapped_size) as u8);
+                    }
                 }
                 Chunk::Unmapped(len) => {
                     assert!(len > 0);
@@ -310,14 +545,18 @@ impl Packer {
         }
 
         splitter.complete(&mut handler)?;
+        handler.stats.discarded_size = self.mapping_builder.lock().unwrap().discarded();
         self.output.report.progress(100);
         handler.archive.flush()?;
         let end_time: DateTime<Utc> = Utc::now();
         let elapsed = end_time - start_time;
         let elapsed = elapsed.num_milliseconds() as f64 / 1000.0;
         let stream_written = handler.stream_file.get_file_size();
-        let ratio =
-            (self.mapped_size as f64) / ((handler.stats.data_written + stream_written) as f64);
+
+        // Everything read from a pipe is mapped.
+        let input_size = self.input_size.unwrap_or(total_read);
+        let mapped_size = self.mapped_size.unwrap_or(total_read);
+        let ratio = (mapped_size as f64) / ((handler.stats.data_written + stream_written) as f64);
 
         if self.output.json {
             // Should all the values simply be added to the json too?  We can always add entries, but
@@ -333,10 +572,10 @@ impl Packer {
                 .info(&format!("stream id        : {}", stream_id));
             self.output
                 .report
-                .info(&format!("file size        : {:.2}", Size(self.input_size)));
+                .info(&format!("file size        : {:.2}", Size(input_size)));
             self.output
                 .report
-                .info(&format!("mapped size      : {:.2}", Size(self.mapped_size)));
+                .info(&format!("mapped size      : {:.2}", Size(mapped_size)));
             self.output
                 .report
                 .info(&format!("total read       : {:.2}", Size(total_read)));
@@ -344,9 +583,36 @@ impl Packer {
                 "fills size       : {:.2}",
                 Size(handler.stats.fill_size)
             ));
+            if self.delta_compression {
+                self.output.report.info(&format!(
+                    "delta data       : {:.2}",
+                    Size(handler.stats.delta_size)
+                ));
+                self.output.report.info(&format!(
+                    "delta saved      : {:.2}",
+                    Size(handler.stats.delta_saved)
+                ));
+            }
+            if !self.parents.is_empty() {
+                self.output.report.info(&format!(
+                    "parent data      : {:.2}",
+                    Size(handler.stats.parent_size)
+                ));
+            }
+            if self.thin.is_some() {
+                self.output.report.info(&format!(
+                    "discarded        : {:.2}",
+                    Size(handler.stats.discarded_size)
+                ));
+            }
             self.output.report.info(&format!(
                 "duplicate data   : {:.2}",
-                Size(total_read - handler.stats.data_written - handler.stats.fill_size)
+                Size(
+                    total_read
+                        - handler.stats.data_written
+                        - handler.stats.fill_size
+                        - handler.stats.delta_saved
+                )
             ));
 
             self.output.report.info(&format!(
@@ -366,18 +632,33 @@ impl Packer {
         }
 
         // write the stream config
+        let thin = self.thin.as_ref();
+        let era = self.era.as_ref();
         let cfg = config::StreamConfig {
             name: Some(self.stream_name.to_string()),
             source_path: self.input_path.display().to_string(),
             pack_time: config::now(),
-            size: self.input_size,
-            mapped_size: self.mapped_size,
+            size: input_size,
+            mapped_size,
             packed_size: handler.stats.data_written + stream_written,
-            thin_id: self.thin_id,
+            thin_id: thin.map(|t| t.thin_id),
+            pool_uuid: thin.and_then(|t| t.pool_uuid.clone()),
+            dm_uuid: thin
+                .and_then(|t| t.dm_uuid.clone())
+                .or_else(|| era.and_then(|e| e.dm_uuid.clone())),
+            creation_time: thin.and_then(|t| t.details).map(|d| d.creation_time),
+            snapshotted_time: thin.and_then(|t| t.details).map(|d| d.snapshotted_time),
+            transaction_id: thin.and_then(|t| t.details).map(|d| d.transaction_id),
+            vg_name: self.lv.as_ref().map(|lv| lv.vg.clone()),
+            lv_name: self.lv.as_ref().map(|lv| lv.lv.clone()),
+            era: era.map(|e| e.era),
         };
         config::write_stream_config(&stream_id, &cfg)?;
 
-        Ok(())
+        drop(handler);
+        SeekIndex::create(&stream_id, &mut open_archive_with(&self.parents, 16)?)?;
+
+        Ok(stream_id)
     }
 }
 
@@ -391,9 +672,17 @@ fn thick_packer(
 ) -> Result<Packer> {
     let input_size = thinp::file_utils::file_size(input_file)?;
 
-    let mapped_size = input_size;
-    let input_iter = Box::new(ThickChunker::new(input_file, 16 * 1024 * 1024)?);
-    let thin_id = None;
+    // Regular files may be sparse, in which case we skip the holes.
+    let (input_iter, mapped_size): (Box<dyn Iterator<Item = Result<Chunk>>>, u64) =
+        if std::fs::metadata(input_file)?.is_file() {
+            let chunker = SparseChunker::new(input_file, 16 * 1024 * 1024)?;
+            let mapped_size = chunker.mapped_size()?;
+            (Box::new(chunker), mapped_size)
+        } else {
+            let chunker = ThickChunker::new(input_file, 16 * 1024 * 1024)?;
+            (Box::new(chunker), input_size)
+        };
+    let thin = None;
     let builder = Arc::new(Mutex::new(MappingBuilder::default()));
 
     Ok(Packer::new(
@@ -401,15 +690,43 @@ fn thick_packer(
         input_file.to_path_buf(),
         input_name,
         input_iter,
-        input_size,
+        Some(input_size),
         builder,
-        mapped_size,
+        Some(mapped_size),
         config.block_size,
-        thin_id,
+        thin,
         config.hash_cache_size_meg,
+        config.index_mode,
+        config.memory_budget_meg,
+        config.enforce_memory_budget,
+        config.delta_compression,
+        config.parents.clone(),
     ))
 }
 
+fn stdin_packer(output: Arc<Output>, input_name: String, config: &config::Config) -> Packer {
+    let input_iter = Box::new(StreamingChunker::new(std::io::stdin(), 16 * 1024 * 1024));
+    let builder = Arc::new(Mutex::new(MappingBuilder::default()));
+
+    Packer::new(
+        output,
+        PathBuf::from("-"),
+        input_name,
+        input_iter,
+        None,
+        builder,
+        None,
+        config.block_size,
+        None,
+        config.hash_cache_size_meg,
+        config.index_mode,
+        config.memory_budget_meg,
+        config.enforce_memory_budget,
+        config.delta_compression,
+        config.parents.clone(),
+    )
+}
+
 fn thin_packer(
     output: Arc<Output>,
     input_file: &Path,
@@ -434,7 +751,12 @@ fn thin_packer(
         run_iter,
         mappings.data_block_size as u64 * 512,
     ));
-    let thin_id = Some(mappings.thin_id);
+    let thin = Some(ThinSource::new(
+        mappings.thin_id,
+        mappings.details,
+        mappings.pool_uuid,
+        mappings.dm_uuid,
+    ));
     let builder = Arc::new(Mutex::new(MappingBuilder::default()));
 
     output
@@ -445,20 +767,126 @@ fn thin_packer(
         input_file.to_path_buf(),
         input_name,
         input_iter,
-        input_size,
+        Some(input_size),
         builder,
-        mapped_size,
+        Some(mapped_size),
         config.block_size,
-        thin_id,
+        thin,
         config.hash_cache_size_meg,
+        config.index_mode,
+        config.memory_budget_meg,
+        config.enforce_memory_budget,
+        config.delta_compression,
+        config.parents.clone(),
+    ))
+}
+
+// Packs a thin device from its pool's data device, using mappings read
+// from offline metadata.  A delta is packed against a stream of another
+// thin in the same metadata.
+#[allow(clippy::too_many_arguments)]
+fn thin_offline_packer(
+    output: Arc<Output>,
+    data_dev: &Path,
+    input_name: String,
+    config: &config::Config,
+    metadata: &Path,
+    thin_id: u32,
+    thin_size: Option<u64>,
+    delta_stream: Option<&str>,
+    hashes_file: Arc<Mutex<SlabFile>>,
+) -> Result<Packer> {
+    let input = OpenOptions::new()
+        .read(true)
+        .write(false)
+        .open(data_dev)
+        .context("couldn't open pool data device")?;
+
+    let mappings = read_offline_thin_mappings(metadata, thin_id)?;
+    let block_size = mappings.data_block_size as u64 * 512;
+    let mapped_end = mappings.nr_mapped_blocks() * block_size;
+    let input_size = thin_size.unwrap_or(mapped_end);
+    if input_size < mapped_end {
+        return Err(anyhow!(
+            "thin device {} has mappings beyond --thin-size",
+            thin_id
+        ));
+    }
+
+    let mapped_size = mappings.nr_provisioned() * block_size;
+    let nr_blocks = input_size.div_ceil(block_size);
+    let (regions, builder): (Vec<PoolRegion>, Arc<Mutex<dyn Builder>>) = match delta_stream {
+        Some(delta_id) => {
+            let old_config = config::read_stream_config(delta_id)?;
+            let old_thin_id = old_config
+                .thin_id
+                .ok_or_else(|| anyhow!("stream {} isn't of a thin device", delta_id))?;
+            if old_config.size < input_size {
+                return Err(anyhow!("--delta-stream is smaller than the thin device"));
+            }
+            let old_mappings = read_offline_thin_mappings(metadata, old_thin_id)?;
+            let regions = delta_regions(
+                &old_mappings.runs,
+                old_config.size / block_size,
+                &mappings.runs,
+                nr_blocks,
+            );
+            (regions, delta_builder(config, delta_id, hashes_file)?)
+        }
+        None => (
+            full_regions(&mappings.runs, nr_blocks),
+            Arc::new(Mutex::new(MappingBuilder::default())),
+        ),
+    };
+    let input_iter = Box::new(PoolDataChunker::new(input, regions, block_size, input_size));
+
+    Ok(Packer::new(
+        output,
+        data_dev.to_path_buf(),
+        input_name,
+        input_iter,
+        Some(input_size),
+        builder,
+        Some(mapped_size),
+        config.block_size,
+        Some(ThinSource {
+            thin_id,
+            ..Default::default()
+        }),
+        config.hash_cache_size_meg,
+        config.index_mode,
+        config.memory_budget_meg,
+        config.enforce_memory_budget,
+        config.delta_compression,
+        config.parents.clone(),
     ))
 }
 
 // FIXME: slow
-fn open_thin_stream(stream_id: &str) -> Result<SlabFile> {
-    SlabFileBuilder::open(stream_path(stream_id))
-        .build()
-        .context("couldn't open old stream file")
+fn open_thin_stream(stream_id: &str) -> Result<StreamSlabs> {
+    StreamSlabs::open(stream_id).context("couldn't open old stream file")
+}
+
+fn delta_builder(
+    config: &config::Config,
+    delta_id: &str,
+    hashes_file: Arc<Mutex<SlabFile>>,
+) -> Result<Arc<Mutex<DeltaBuilder>>> {
+    let old_stream = open_thin_stream(delta_id)?;
+    let old_entries = StreamIter::new(old_stream)?;
+
+    // The old stream may refer to data in any of the parents.
+    let mut hashes_files = vec![hashes_file];
+    for p in &config.parents {
+        let file = SlabFileBuilder::open(Path::new(p).join(hashes_path()))
+            .build()
+            .with_context(|| format!("couldn't open hashes of parent {}", p))?;
+        hashes_files.push(Arc::new(Mutex::new(file)));
+    }
+    Ok(Arc::new(Mutex::new(DeltaBuilder::new(
+        old_entries,
+        hashes_files,
+    ))))
 }
 
 fn thin_delta_packer(
@@ -466,7 +894,7 @@ fn thin_delta_packer(
     input_file: &Path,
     input_name: String,
     config: &config::Config,
-    delta_device: &Path,
+    mappings: DeltaInfo,
     delta_id: &str,
     hashes_file: Arc<Mutex<SlabFile>>,
 ) -> Result<Packer> {
@@ -476,10 +904,17 @@ fn thin_delta_packer(
         .open(input_file)
         .context("couldn't open input file/dev")?;
     let input_size = thinp::file_utils::file_size(input_file)?;
+    let old_size = config::read_stream_config(delta_id)?.size;
+    if old_size < input_size {
+        return Err(anyhow!(
+            "stream {} ({} bytes) is smaller than the input ({} bytes)",
+            delta_id,
+            old_size,
+            input_size
+        ));
+    }
 
-    let mappings = read_thin_delta(delta_device, input_file)?;
-    let old_config = config::read_stream_config(delta_id)?;
-    let mapped_size = old_config.mapped_size;
+    let mapped_size = mappings.details.mapped_blocks * mappings.data_block_size as u64 * 512;
 
     let run_iter = DualIter::new(
         mappings.additions,
@@ -492,11 +927,14 @@ fn thin_delta_packer(
         run_iter,
         mappings.data_block_size as u64 * 512,
     ));
-    let thin_id = Some(mappings.thin_id);
+    let thin = Some(ThinSource::new(
+        mappings.thin_id,
+        mappings.details,
+        mappings.pool_uuid,
+        mappings.dm_uuid,
+    ));
 
-    let old_stream = open_thin_stream(delta_id)?;
-    let old_entries = StreamIter::new(old_stream)?;
-    let builder = Arc::new(Mutex::new(DeltaBuilder::new(old_entries, hashes_file)));
+    let builder = delta_builder(config, delta_id, hashes_file)?;
 
     output
         .report
@@ -506,15 +944,284 @@ fn thin_delta_packer(
         input_file.to_path_buf(),
         input_name,
         input_iter,
-        input_size,
+        Some(input_size),
         builder,
-        mapped_size,
+        Some(mapped_size),
         config.block_size,
-        thin_id,
+        thin,
         config.hash_cache_size_meg,
+        config.index_mode,
+        config.memory_budget_meg,
+        config.enforce_memory_budget,
+        config.delta_compression,
+        config.parents.clone(),
     ))
 }
 
+// Packs a thick device tracked by dm-era.  A delta only reads the blocks
+// written since the era recorded with the stream it's a delta of.
+fn era_packer(
+    output: Arc<Output>,
+    input_file: &Path,
+    input_name: String,
+    config: &config::Config,
+    era_dev: &EraDevice,
+    delta_id: Option<&str>,
+    hashes_file: Arc<Mutex<SlabFile>>,
+) -> Result<Packer> {
+    let input_size = thinp::file_utils::file_size(input_file)?;
+
+    let since = match delta_id {
+        None => None,
+        Some(id) => {
+            let old_config = config::read_stream_config(id)?;
+            let era = old_config
+                .era
+                .ok_or_else(|| anyhow!("stream {} wasn't packed from an era device", id))?;
+            if old_config.dm_uuid != era_dev.dm_uuid {
+                return Err(anyhow!("stream {} is of a different era device", id));
+            }
+            if old_config.size != input_size {
+                return Err(anyhow!("stream {} is a different size to the input", id));
+            }
+            Some(era)
+        }
+    };
+    let info = era_dev.read_changes(since)?;
+    let block_size = info.data_block_size as u64 * 512;
+
+    let input_iter: Box<dyn Iterator<Item = Result<Chunk>>> = match (delta_id, since) {
+        (Some(id), Some(era)) => {
+            let input = OpenOptions::new()
+                .read(true)
+                .write(false)
+                .open(input_file)
+                .context("couldn't open input file/dev")?;
+            let changed: u64 = info.changed.iter().map(|r| r.end - r.start).sum();
+            output.report.info(&format!(
+                "delta of stream {} (era {}, {:.2} written)",
+                id,
+                era,
+                Size(std::cmp::min(changed * block_size, input_size))
+            ));
+            Box::new(EraChunker::new(input, info.changed, block_size, input_size))
+        }
+        _ => Box::new(ThickChunker::new(input_file, 16 * 1024 * 1024)?),
+    };
+    let builder: Arc<Mutex<dyn Builder>> = match delta_id {
+        Some(id) => delta_builder(config, id, hashes_file)?,
+        None => Arc::new(Mutex::new(MappingBuilder::default())),
+    };
+
+    let mut packer = Packer::new(
+        output,
+        input_file.to_path_buf(),
+        input_name,
+        input_iter,
+        Some(input_size),
+        builder,
+        Some(input_size),
+        config.block_size,
+        None,
+        config.hash_cache_size_meg,
+        config.index_mode,
+        config.memory_budget_meg,
+        config.enforce_memory_budget,
+        config.delta_compression,
+        config.parents.clone(),
+    );
+    packer.era = Some(EraSource {
+        era: info.current_era,
+        dm_uuid: era_dev.dm_uuid.clone(),
+    });
+    Ok(packer)
+}
+
+/// Packs one thin device of a pool from the pool's data device, either
+/// in full or as a delta of an already packed thin (the base) given as
+/// its stream id and thin id.  Assumes we've chdir'd to the archive.
+/// Returns the new stream id.
+pub fn pack_pool_thin(
+    output: Arc<Output>,
+    data_dev: &Path,
+    input_name: String,
+    config: &config::Config,
+    pool: &PoolInfo,
+    thin_id: u32,
+    base: Option<(&str, u32)>,
+) -> Result<String> {
+    let thin = &pool.thins[&thin_id];
+    // The hashes file is closed once a stream is packed.
+    let hashes_file = Arc::new(Mutex::new(
+        SlabFileBuilder::open(hashes_path())
+            .write(true)
+            .queue_depth(16)
+            .build()
+            .context("couldn't open hashes slab file")?,
+    ));
+
+    let input = OpenOptions::new()
+        .read(true)
+        .write(false)
+        .open(data_dev)
+        .context("couldn't open pool data device")?;
+
+    let block_size = pool.data_block_size as u64 * 512;
+    let input_size = thin
+        .size
+        .ok_or_else(|| anyhow!("size of thin {} isn't known", thin_id))?;
+    let nr_blocks = input_size.div_ceil(block_size);
+
+    let (regions, builder): (Vec<PoolRegion>, Arc<Mutex<dyn Builder>>) = match base {
+        Some((base_id, base_thin_id)) => {
+            let base_size = config::read_stream_config(base_id)?.size;
+            let regions = delta_regions(
+                &pool.thins[&base_thin_id].runs,
+                base_size / block_size,
+                &thin.runs,
+                nr_blocks,
+            );
+            (
+                regions,
+                delta_builder(config, base_id, hashes_file.clone())?,
+            )
+        }
+        None => (
+            full_regions(&thin.runs, nr_blocks),
+            Arc::new(Mutex::new(MappingBuilder::default())),
+        ),
+    };
+
+    // A delta only reads the changed blocks, but it's still the size of
+    // the whole thin.
+    let mapped_size = thin.details.mapped_blocks * block_size;
+    let input_iter = Box::new(PoolDataChunker::new(input, regions, block_size, input_size));
+
+    let packer = Packer::new(
+        output,
+        data_dev.to_path_buf(),
+        input_name,
+        input_iter,
+        Some(input_size),
+        builder,
+        Some(mapped_size),
+        config.block_size,
+        Some(ThinSource::new(
+            thin_id,
+            thin.details,
+            pool.pool_uuid.clone(),
+            thin.dm_uuid.clone(),
+        )),
+        config.hash_cache_size_meg,
+        config.index_mode,
+        config.memory_budget_meg,
+        config.enforce_memory_budget,
+        config.delta_compression,
+        config.parents.clone(),
+    );
+    packer.pack(hashes_file)
+}
+
+// Assumes we've chdir'd to the archive
+fn stream_configs() -> Result<Vec<(String, config::StreamConfig)>> {
+    let mut streams = Vec::new();
+    for id in stream_ids()? {
+        let cfg = config::read_stream_config(&id)?;
+        streams.push((id, cfg));
+    }
+    Ok(streams)
+}
+
+// A thin's details are the same as when it was packed if it's the same
+// thin, rather than a reuse of its id, and nothing has been snapshotted
+// from it since.  Blocks it has written in place since it was packed
+// can only be shared with a thin snapshotted from it later, and taking
+// that snapshot changes its snapshotted time.
+fn unchanged_since_pack(cfg: &config::StreamConfig, details: &DeviceDetail) -> bool {
+    cfg.creation_time == Some(details.creation_time)
+        && cfg.snapshotted_time == Some(details.snapshotted_time)
+        && cfg.transaction_id == Some(details.transaction_id)
+}
+
+// Picks the most recently packed stream of another thin from the same
+// pool that's still there, and unchanged since, returning it with the
+// thin's id.  The thin being packed is never chosen, since it's changed
+// since.  The stream must be at least as long as the input, since the
+// delta walks it alongside the new one.  If the input is a snapshot of
+// a logical volume, streams of that volume are preferred.
+fn choose_delta_stream(
+    streams: Vec<(String, config::StreamConfig)>,
+    members: &PoolMembers,
+    input_size: u64,
+    lv: Option<&LvName>,
+) -> Option<(String, u32)> {
+    let pool_uuid = members.pool_uuid.as_ref()?;
+    streams
+        .into_iter()
+        .filter(|(_, cfg)| cfg.pool_uuid.as_ref() == Some(pool_uuid) && cfg.size >= input_size)
+        .filter_map(|(id, cfg)| {
+            let thin_id = cfg.thin_id?;
+            let details = members.thins.get(&thin_id)?;
+            if thin_id == members.thin_id || !unchanged_since_pack(&cfg, details) {
+                return None;
+            }
+            let same_lv = lv.is_some_and(|lv| {
+                cfg.vg_name.as_ref() == Some(&lv.vg) && cfg.lv_name.as_ref() == Some(&lv.lv)
+            });
+            Some((same_lv, config::to_date_time(&cfg.pack_time), id, thin_id))
+        })
+        .max_by_key(|(same_lv, time, _, _)| (*same_lv, *time))
+        .map(|(_, _, id, thin_id)| (id, thin_id))
+}
+
+// Packs a thin device as a delta of the best of the given streams, or
+// in full if none of their thins are still in the pool.
+fn auto_delta_packer(
+    output: Arc<Output>,
+    input_file: &Path,
+    input_name: String,
+    config: &config::Config,
+    lv: Option<&LvName>,
+    hashes_file: Arc<Mutex<SlabFile>>,
+) -> Result<Packer> {
+    let members = read_pool_members(input_file)?;
+    let input_size = thinp::file_utils::file_size(input_file)?;
+    match choose_delta_stream(stream_configs()?, &members, input_size, lv) {
+        Some((delta_stream, old_thin_id)) => {
+            output.report.info(&format!(
+                "delta of stream {} (thin {})",
+                delta_stream, old_thin_id
+            ));
+            let mappings = read_thin_delta_from_id(old_thin_id, input_file)?;
+            thin_delta_packer(
+                output,
+                input_file,
+                input_name,
+                config,
+                mappings,
+                &delta_stream,
+                hashes_file,
+            )
+        }
+        None => thin_packer(output, input_file, input_name, config),
+    }
+}
+
+// Picks the most recently packed stream of the same era device.  The
+// device can only be recognised by its dm uuid.
+fn choose_era_stream(
+    streams: Vec<(String, config::StreamConfig)>,
+    dm_uuid: &Option<String>,
+    size: u64,
+) -> Option<String> {
+    dm_uuid.as_ref()?;
+    streams
+        .into_iter()
+        .filter(|(_, cfg)| cfg.era.is_some() && cfg.dm_uuid == *dm_uuid && cfg.size == size)
+        .max_by_key(|(_, cfg)| config::to_date_time(&cfg.pack_time))
+        .map(|(id, _)| id)
+}
+
 // Looks up both --delta-stream and --delta-device
 fn get_delta_args(matches: &ArgMatches) -> Result<Option<(String, PathBuf)>> {
     match (
@@ -535,21 +1242,90 @@ fn get_delta_args(matches: &ArgMatches) -> Result<Option<(String, PathBuf)>> {
 
 pub fn run(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
     let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap()).canonicalize()?;
-    let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
-    let input_name = input_file
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
-    let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap()).canonicalize()?;
+
+    // A logical volume is packed from a temporary snapshot, which is
+    // removed when we're done.  It's kept if a later pack might be a
+    // delta of it, which is only possible for thin snapshots; an old
+    // style CoW snapshot would just slow down writes to the origin.
+    let lv = matches
+        .get_one::<String>("LV")
+        .map(|name| lookup(name))
+        .transpose()?;
+    let keep_snapshot = matches.get_flag("KEEP_SNAPSHOT");
+    if keep_snapshot && lv.as_ref().is_some_and(|lv| !lv.thin) {
+        return Err(anyhow!(
+            "--keep-snapshot needs a thin volume, a thick one can't be packed as a delta"
+        ));
+    }
+    let snap = lv
+        .as_ref()
+        .map(|lv| {
+            LvSnapshot::create(
+                lv,
+                matches.get_one::<String>("COW_SIZE").unwrap(),
+                keep_snapshot,
+            )
+        })
+        .transpose()?;
+    if let Some(snap) = &snap {
+        output
+            .report
+            .info(&format!("snapshot         : {}", snap.name));
+    }
+
+    let input = match &snap {
+        Some(snap) => snap.path.display().to_string(),
+        None => matches.get_one::<String>("INPUT").unwrap().clone(),
+    };
+    let from_stdin = input == "-";
+    let auto_delta = matches.get_flag("AUTO_DELTA");
+    let delta = ["DELTA_STREAM", "DELTA_DEVICE"]
+        .iter()
+        .any(|id| matches.contains_id(id));
+    if from_stdin && (auto_delta || delta) {
+        return Err(anyhow!("a delta can't be packed from stdin"));
+    }
+
+    // The metadata path is relative to where we were run from.
+    let thin_metadata = matches
+        .get_one::<String>("THIN_METADATA")
+        .map(|p| Path::new(p).canonicalize())
+        .transpose()
+        .context("couldn't find thin metadata")?;
+    if from_stdin && thin_metadata.is_some() {
+        return Err(anyhow!(
+            "--thin-metadata needs the pool data device as input"
+        ));
+    }
+
+    let (input_file, input_name) = if from_stdin {
+        (PathBuf::from("-"), "stdin".to_string())
+    } else {
+        let input_file = Path::new(&input);
+        let input_name = match &lv {
+            // Streams are named after the volume, not its snapshot.
+            Some(lv) => lv.name.lv.clone(),
+            None => input_file
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string(),
+        };
+        (input_file.canonicalize()?, input_name)
+    };
 
     env::set_current_dir(archive_dir)?;
-    let config = config::read_config(".", matches)?;
+    let config = config::read_pack_config(".", matches)?;
 
+    let title = if from_stdin {
+        "stdin".to_string()
+    } else {
+        input_file.display().to_string()
+    };
     output
         .report
-        .set_title(&format!("Building packer {} ...", input_file.display()));
+        .set_title(&format!("Building packer {} ...", title));
 
     let hashes_file = Arc::new(Mutex::new(
         SlabFileBuilder::open(hashes_path())
@@ -559,26 +1335,340 @@ pub fn run(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
             .context("couldn't open hashes slab file")?,
     ));
 
-    let packer = if let Some((delta_stream, delta_device)) = get_delta_args(matches)? {
+    let mut packer = if from_stdin {
+        stdin_packer(output.clone(), input_name, &config)
+    } else if let Some(metadata) = &thin_metadata {
+        thin_offline_packer(
+            output.clone(),
+            &input_file,
+            input_name,
+            &config,
+            metadata,
+            *matches.get_one::<u32>("THIN_ID").unwrap(),
+            matches.get_one::<u64>("THIN_SIZE").cloned(),
+            matches
+                .get_one::<String>("DELTA_STREAM")
+                .map(|s| s.as_str()),
+            hashes_file.clone(),
+        )?
+    } else if is_era_device(&input_file)? {
+        if matches.contains_id("DELTA_DEVICE") {
+            return Err(anyhow!("--delta-device isn't used with an era device"));
+        }
+        let era_dev = EraDevice::open(&input_file)?;
+        let delta_stream = match matches.get_one::<String>("DELTA_STREAM") {
+            Some(stream) => Some(stream.clone()),
+            None if auto_delta => choose_era_stream(
+                stream_configs()?,
+                &era_dev.dm_uuid,
+                thinp::file_utils::file_size(&input_file)?,
+            ),
+            None => None,
+        };
+        era_packer(
+            output.clone(),
+            &input_file,
+            input_name,
+            &config,
+            &era_dev,
+            delta_stream.as_deref(),
+            hashes_file.clone(),
+        )?
+    } else if let Some((delta_stream, delta_device)) = get_delta_args(matches)? {
+        let mappings = read_thin_delta(delta_device.as_path(), &input_file)?;
         thin_delta_packer(
             output.clone(),
             &input_file,
             input_name,
             &config,
-            &delta_device,
+            mappings,
             &delta_stream,
             hashes_file.clone(),
         )?
+    } else if auto_delta {
+        if !is_thin_device(&input_file)? {
+            return Err(anyhow!("--auto-delta needs a thin or era device as input"));
+        }
+        auto_delta_packer(
+            output.clone(),
+            &input_file,
+            input_name,
+            &config,
+            None,
+            hashes_file.clone(),
+        )?
+    } else if let Some(lv) = &lv {
+        if lv.thin {
+            auto_delta_packer(
+                output.clone(),
+                &input_file,
+                input_name,
+                &config,
+                Some(&lv.name),
+                hashes_file.clone(),
+            )?
+        } else {
+            thick_packer(output.clone(), &input_file, input_name, &config)?
+        }
     } else if is_thin_device(&input_file)? {
         thin_packer(output.clone(), &input_file, input_name, &config)?
     } else {
         thick_packer(output.clone(), &input_file, input_name, &config)?
     };
 
-    output
-        .report
-        .set_title(&format!("Packing {} ...", input_file.display()));
-    packer.pack(hashes_file)
+    packer.lv = lv.map(|lv| lv.name);
+
+    output.report.set_title(&format!("Packing {} ...", title));
+    packer.pack(hashes_file)?;
+
+    if let Some(snap) = snap {
+        snap.finish()?;
+    }
+    Ok(())
 }
 
 //-----------------------------------------
+
+#[cfg(test)]
+mod auto_delta_tests {
+    use super::*;
+
+    fn mk_stream(pack_time: &str, thin_id: u32, creation_time: u32) -> config::StreamConfig {
+        config::StreamConfig {
+            name: None,
+            source_path: String::from("/dev/mapper/snap"),
+            pack_time: pack_time.to_string(),
+            size: 1 << 20,
+            mapped_size: 1 << 20,
+            packed_size: 1 << 20,
+            thin_id: Some(thin_id),
+            pool_uuid: Some(String::from("pool")),
+            dm_uuid: None,
+            creation_time: Some(creation_time),
+            snapshotted_time: Some(creation_time),
+            transaction_id: Some(0),
+            vg_name: None,
+            lv_name: None,
+            era: None,
+        }
+    }
+
+    #[test]
+    fn most_recent_surviving_thin_is_chosen() {
+        let detail = |creation_time| DeviceDetail {
+            mapped_blocks: 0,
+            transaction_id: 0,
+            creation_time,
+            snapshotted_time: creation_time,
+        };
+        let members = PoolMembers {
+            pool_uuid: Some(String::from("pool")),
+            thin_id: 1,
+            thins: [
+                (1, detail(0)),
+                (2, detail(1)),
+                (3, detail(2)),
+                (4, detail(9)),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        let mut other_pool = mk_stream("2024-01-06T00:00:00+00:00", 3, 2);
+        other_pool.pool_uuid = Some(String::from("other"));
+        let streams = vec![
+            (
+                "a".to_string(),
+                mk_stream("2024-01-01T00:00:00+00:00", 2, 1),
+            ),
+            (
+                "b".to_string(),
+                mk_stream("2024-01-02T00:00:00+00:00", 3, 2),
+            ),
+            // The thin being packed.
+            (
+                "c".to_string(),
+                mk_stream("2024-01-03T00:00:00+00:00", 1, 0),
+            ),
+            // Id 4 has since been reused.
+            (
+                "d".to_string(),
+                mk_stream("2024-01-04T00:00:00+00:00", 4, 3),
+            ),
+            // Thin 5 has been deleted.
+            (
+                "e".to_string(),
+                mk_stream("2024-01-05T00:00:00+00:00", 5, 4),
+            ),
+            ("f".to_string(), other_pool),
+        ];
+        assert_eq!(
+            choose_delta_stream(streams, &members, 1 << 20, None),
+            Some(("b".to_string(), 3))
+        );
+
+        let no_uuid = PoolMembers {
+            pool_uuid: None,
+            ..members
+        };
+        assert_eq!(choose_delta_stream(vec![], &no_uuid, 1 << 20, None), None);
+    }
+
+    #[test]
+    fn streams_of_the_same_volume_are_preferred() {
+        let detail = |creation_time| DeviceDetail {
+            mapped_blocks: 0,
+            transaction_id: 0,
+            creation_time,
+            snapshotted_time: creation_time,
+        };
+        let members = PoolMembers {
+            pool_uuid: Some(String::from("pool")),
+            thin_id: 1,
+            thins: [(1, detail(0)), (2, detail(1)), (3, detail(2))]
+                .into_iter()
+                .collect(),
+        };
+
+        let mk_lv_stream = |pack_time: &str, thin_id: u32, creation_time: u32, lv: &str| {
+            let mut cfg = mk_stream(pack_time, thin_id, creation_time);
+            cfg.vg_name = Some(String::from("vg0"));
+            cfg.lv_name = Some(lv.to_string());
+            cfg
+        };
+        let streams = || {
+            vec![
+                (
+                    "a".to_string(),
+                    mk_lv_stream("2024-01-01T00:00:00+00:00", 2, 1, "data"),
+                ),
+                (
+                    "b".to_string(),
+                    mk_lv_stream("2024-01-02T00:00:00+00:00", 3, 2, "other"),
+                ),
+            ]
+        };
+
+        let lv = LvName::parse("vg0/data").unwrap();
+        assert_eq!(
+            choose_delta_stream(streams(), &members, 1 << 20, Some(&lv)),
+            Some(("a".to_string(), 2))
+        );
+        assert_eq!(
+            choose_delta_stream(streams(), &members, 1 << 20, None),
+            Some(("b".to_string(), 3))
+        );
+    }
+
+    #[test]
+    fn thins_changed_since_their_pack_are_not_chosen() {
+        let detail = |creation_time, snapshotted_time| DeviceDetail {
+            mapped_blocks: 0,
+            transaction_id: 0,
+            creation_time,
+            snapshotted_time,
+        };
+
+        // The origin, thin 1, was packed, then written in place, then
+        // snapshotted as thin 2.  Thin 2 shares the rewritten blocks, so
+        // a delta of the origin's stream would restore stale data.
+        let origin = || {
+            (
+                "o".to_string(),
+                mk_stream("2024-01-01T00:00:00+00:00", 1, 0),
+            )
+        };
+        let snap = || {
+            (
+                "s".to_string(),
+                mk_stream("2024-01-02T00:00:00+00:00", 2, 1),
+            )
+        };
+        let members = PoolMembers {
+            pool_uuid: Some(String::from("pool")),
+            thin_id: 2,
+            thins: [(1, detail(0, 1)), (2, detail(1, 1))].into_iter().collect(),
+        };
+        assert_eq!(
+            choose_delta_stream(vec![origin()], &members, 1 << 20, None),
+            None
+        );
+
+        // Thin 2 was packed before thin 3 was snapshotted from the
+        // origin.  Nothing has been snapshotted from thin 2 since.
+        let members = PoolMembers {
+            pool_uuid: Some(String::from("pool")),
+            thin_id: 3,
+            thins: [(1, detail(0, 2)), (2, detail(1, 1)), (3, detail(2, 2))]
+                .into_iter()
+                .collect(),
+        };
+        assert_eq!(
+            choose_delta_stream(vec![origin(), snap()], &members, 1 << 20, None),
+            Some(("s".to_string(), 2))
+        );
+
+        // A stream shorter than the input can't be a base.
+        assert_eq!(
+            choose_delta_stream(vec![origin(), snap()], &members, 1 << 21, None),
+            None
+        );
+
+        // Streams without a transaction id can't be checked.
+        let mut old = snap();
+        old.1.transaction_id = None;
+        assert_eq!(
+            choose_delta_stream(vec![old], &members, 1 << 20, None),
+            None
+        );
+    }
+
+    #[test]
+    fn most_recent_stream_of_era_device_is_chosen() {
+        let mk_era = |pack_time: &str, dm_uuid: &str, era: Option<u32>| {
+            let mut cfg = mk_stream(pack_time, 0, 0);
+            cfg.thin_id = None;
+            cfg.pool_uuid = None;
+            cfg.dm_uuid = Some(dm_uuid.to_string());
+            cfg.era = era;
+            cfg
+        };
+
+        let mut resized = mk_era("2024-01-05T00:00:00+00:00", "era", Some(7));
+        resized.size *= 2;
+        let streams = vec![
+            (
+                "a".to_string(),
+                mk_era("2024-01-01T00:00:00+00:00", "era", Some(2)),
+            ),
+            (
+                "b".to_string(),
+                mk_era("2024-01-02T00:00:00+00:00", "era", Some(3)),
+            ),
+            (
+                "c".to_string(),
+                mk_era("2024-01-03T00:00:00+00:00", "other", Some(4)),
+            ),
+            // Packed without the era being recorded.
+            (
+                "d".to_string(),
+                mk_era("2024-01-04T00:00:00+00:00", "era", None),
+            ),
+            ("e".to_string(), resized),
+        ];
+
+        let uuid = Some(String::from("era"));
+        assert_eq!(
+            choose_era_stream(streams, &uuid, 1 << 20),
+            Some("b".to_string())
+        );
+
+        // Without a uuid there's no telling which device it was.
+        let mut no_uuid = mk_era("2024-01-01T00:00:00+00:00", "", Some(2));
+        no_uuid.dm_uuid = None;
+        assert_eq!(
+            choose_era_stream(vec![("a".to_string(), no_uuid)], &None, 1 << 20),
+            None
+        );
+    }
+}
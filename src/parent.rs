@@ -0,0 +1,144 @@
+use anyhow::{anyhow, Context, Result};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::archive::calculate_offsets;
+use crate::config;
+use crate::cuckoo_filter::*;
+use crate::hash::*;
+use crate::hash_index::*;
+use crate::paths;
+use crate::slab::builder::*;
+use crate::slab::*;
+
+//-----------------------------------------
+
+// Stream entries select an archive with a u8, and 0 is always the
+// archive itself.
+pub const MAX_PARENTS: usize = u8::MAX as usize;
+
+/// Returns the parents an archive with these direct parents should
+/// record.  Parents of parents are included, since streams in the child
+/// may refer to any archive in the chain.
+pub fn resolve_parents(parents: &[PathBuf], block_size: usize) -> Result<Vec<String>> {
+    let mut r: Vec<String> = Vec::new();
+
+    for p in parents {
+        let dir = p
+            .canonicalize()
+            .with_context(|| format!("couldn't find parent archive {}", p.display()))?;
+        let cfg = config::read_config_file(&dir)
+            .with_context(|| format!("couldn't read config of parent {}", dir.display()))?;
+        if cfg.block_size != block_size {
+            return Err(anyhow!(
+                "parent archive {} has a different block size ({})",
+                dir.display(),
+                cfg.block_size
+            ));
+        }
+
+        let dir = dir.display().to_string();
+        for ancestor in std::iter::once(&dir).chain(cfg.parents.iter()) {
+            if !r.contains(ancestor) {
+                r.push(ancestor.clone());
+            }
+        }
+    }
+
+    if r.len() > MAX_PARENTS {
+        return Err(anyhow!("too many parent archives ({})", r.len()));
+    }
+
+    Ok(r)
+}
+
+//-----------------------------------------
+
+/// A read only view of another archive, used to look up and read data
+/// that the child archive doesn't hold itself.  Nothing is ever written
+/// back to it.
+pub struct ParentArchive {
+    seen: CuckooFilter,
+    hashes: lru::LruCache<u32, ByHash>,
+    slabs: lru::LruCache<u32, ByIndex>,
+
+    data_file: SlabFile,
+    hashes_file: SlabFile,
+}
+
+impl ParentArchive {
+    pub fn open(dir: &Path, cache_nr_entries: usize) -> Result<Self> {
+        let seen = CuckooFilter::read(dir.join(paths::index_path()))
+            .with_context(|| format!("couldn't open index of parent {}", dir.display()))?;
+        let data_file = SlabFileBuilder::open(dir.join(paths::data_path()))
+            .cache_nr_entries(cache_nr_entries)
+            .build()
+            .with_context(|| format!("couldn't open data of parent {}", dir.display()))?;
+        let hashes_file = SlabFileBuilder::open(dir.join(paths::hashes_path()))
+            .build()
+            .with_context(|| format!("couldn't open hashes of parent {}", dir.display()))?;
+
+        let capacity = NonZeroUsize::new(std::cmp::max(cache_nr_entries, 1)).unwrap();
+        Ok(Self {
+            seen,
+            hashes: lru::LruCache::new(capacity),
+            slabs: lru::LruCache::new(capacity),
+            data_file,
+            hashes_file,
+        })
+    }
+
+    pub fn is_known(&mut self, h: &Hash256) -> Result<Option<(u32, u32)>> {
+        let s = match self.seen.test(hash_le_u64(h))? {
+            InsertResult::PossiblyPresent(s) => s,
+            _ => return Ok(None),
+        };
+
+        // The filter could be ahead of the slabs if the parent is being
+        // packed into.
+        if s as usize >= self.data_file.get_nr_slabs() {
+            return Ok(None);
+        }
+
+        let hi = self
+            .hashes
+            .try_get_or_insert(s, || ByHash::new(self.hashes_file.read(s)?))?;
+        Ok(hi.lookup(h).map(|offset| (s, offset as u32)))
+    }
+
+    fn get_info(&mut self, slab: u32) -> Result<&ByIndex> {
+        self.slabs
+            .try_get_or_insert(slab, || ByIndex::new(self.hashes_file.read(slab)?))
+    }
+
+    pub fn data_len(&mut self, slab: u32, offset: u32, nr_entries: u32) -> Result<u64> {
+        let info = self.get_info(slab)?;
+        let (data_begin, data_end) = calculate_offsets(offset, nr_entries, info, None);
+        Ok((data_end - data_begin) as u64)
+    }
+
+    pub fn data_hash(&mut self, slab: u32, offset: u32) -> Result<Hash256> {
+        let info = self.get_info(slab)?;
+        let (_, _, h) = info
+            .get(offset as usize)
+            .ok_or_else(|| anyhow!("no entry {} in parent data slab {}", offset, slab))?;
+        Ok(*h)
+    }
+
+    pub fn data_get(
+        &mut self,
+        slab: u32,
+        offset: u32,
+        nr_entries: u32,
+        partial: Option<(u32, u32)>,
+    ) -> Result<(Arc<Vec<u8>>, usize, usize)> {
+        let info = self.get_info(slab)?;
+        let (data_begin, data_end) = calculate_offsets(offset, nr_entries, info, partial);
+        let data = self.data_file.read(slab)?;
+
+        Ok((data, data_begin, data_end))
+    }
+}
+
+//-----------------------------------------
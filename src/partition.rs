@@ -0,0 +1,187 @@
+use anyhow::{anyhow, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Read, Seek, SeekFrom};
+
+//-----------------------------------------
+
+// Partition tables are only read from the archived stream, so we
+// assume 512 byte sectors unless a GPT header turns up at 4k.
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_PARTITIONS: u64 = 446;
+const MBR_GPT_PROTECTIVE: u8 = 0xee;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+fn read_at<R: Read + Seek>(r: &mut R, pos: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    r.seek(SeekFrom::Start(pos))?;
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn find_gpt_partition<R: Read + Seek>(r: &mut R, n: u32) -> Result<(u64, u64)> {
+    for sector_size in SECTOR_SIZES {
+        let header = read_at(r, sector_size, 92)?;
+        if &header[0..8] != GPT_SIGNATURE {
+            continue;
+        }
+
+        let entries_lba = LittleEndian::read_u64(&header[72..80]);
+        let nr_entries = LittleEndian::read_u32(&header[80..84]);
+        let entry_size = LittleEndian::read_u32(&header[84..88]) as u64;
+        if n == 0 || n > nr_entries || entry_size < 48 {
+            return Err(anyhow!("partition {} not present in GPT", n));
+        }
+
+        // These all come from the disk, so may be garbage.
+        let corrupt = || anyhow!("GPT partition {} is corrupt", n);
+        let entry_pos = entries_lba
+            .checked_mul(sector_size)
+            .and_then(|pos| pos.checked_add((n as u64 - 1) * entry_size))
+            .ok_or_else(corrupt)?;
+        let entry = read_at(r, entry_pos, 48)?;
+        if entry[0..16].iter().all(|b| *b == 0) {
+            return Err(anyhow!("GPT partition {} is unused", n));
+        }
+
+        let first = LittleEndian::read_u64(&entry[32..40]);
+        let last = LittleEndian::read_u64(&entry[40..48]);
+        if last < first {
+            return Err(corrupt());
+        }
+        let offset = first.checked_mul(sector_size).ok_or_else(corrupt)?;
+        let len = (last - first)
+            .checked_add(1)
+            .and_then(|nr| nr.checked_mul(sector_size))
+            .ok_or_else(corrupt)?;
+        offset.checked_add(len).ok_or_else(corrupt)?;
+        return Ok((offset, len));
+    }
+
+    Err(anyhow!("protective MBR found, but no GPT header"))
+}
+
+/// Finds partition n (counting from 1) in the MBR or GPT at the start
+/// of a stream, returning its byte offset and length.  Logical
+/// partitions within an MBR extended partition aren't supported.
+pub fn find_partition<R: Read + Seek>(r: &mut R, n: u32) -> Result<(u64, u64)> {
+    let mbr = read_at(r, 0, 512)?;
+    if LittleEndian::read_u16(&mbr[510..512]) != MBR_SIGNATURE {
+        return Err(anyhow!("no partition table found"));
+    }
+
+    let entry = |i: u64| {
+        let b = (MBR_PARTITIONS + i * 16) as usize;
+        &mbr[b..b + 16]
+    };
+
+    if (0..4).any(|i| entry(i)[4] == MBR_GPT_PROTECTIVE) {
+        return find_gpt_partition(r, n);
+    }
+
+    if n == 0 || n > 4 {
+        return Err(anyhow!("partition {} not present in MBR", n));
+    }
+
+    // The start and length are 32 bit sector counts, so can't overflow.
+    let e = entry(n as u64 - 1);
+    let start = LittleEndian::read_u32(&e[8..12]) as u64;
+    let len = LittleEndian::read_u32(&e[12..16]) as u64;
+    if e[4] == 0 || len == 0 {
+        return Err(anyhow!("MBR partition {} is unused", n));
+    }
+    Ok((start * 512, len * 512))
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod partition_tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use std::io::{Cursor, Write};
+
+    fn mk_mbr(parts: &[(u8, u32, u32)]) -> Vec<u8> {
+        let mut disk = vec![0u8; 64 * 1024];
+        for (i, (ty, start, len)) in parts.iter().enumerate() {
+            let b = MBR_PARTITIONS as usize + i * 16;
+            disk[b + 4] = *ty;
+            LittleEndian::write_u32(&mut disk[b + 8..b + 12], *start);
+            LittleEndian::write_u32(&mut disk[b + 12..b + 16], *len);
+        }
+        LittleEndian::write_u16(&mut disk[510..512], MBR_SIGNATURE);
+        disk
+    }
+
+    fn mk_gpt(sector_size: usize, parts: &[(u64, u64)]) -> Result<Vec<u8>> {
+        let mut disk = mk_mbr(&[(MBR_GPT_PROTECTIVE, 1, u32::MAX)]);
+
+        let mut header = Cursor::new(&mut disk[sector_size..sector_size + 92]);
+        header.write_all(GPT_SIGNATURE)?;
+        header.set_position(72);
+        header.write_u64::<LittleEndian>(2)?;
+        header.write_u32::<LittleEndian>(128)?;
+        header.write_u32::<LittleEndian>(128)?;
+
+        for (i, (first, last)) in parts.iter().enumerate() {
+            let b = 2 * sector_size + i * 128;
+            disk[b..b + 16].fill(0xaf);
+            LittleEndian::write_u64(&mut disk[b + 32..b + 40], *first);
+            LittleEndian::write_u64(&mut disk[b + 40..b + 48], *last);
+        }
+        Ok(disk)
+    }
+
+    #[test]
+    fn mbr_partitions() -> Result<()> {
+        let mut disk = Cursor::new(mk_mbr(&[(0x83, 2048, 1000), (0x82, 4096, 16)]));
+        assert_eq!(find_partition(&mut disk, 1)?, (2048 * 512, 1000 * 512));
+        assert_eq!(find_partition(&mut disk, 2)?, (4096 * 512, 16 * 512));
+        assert!(find_partition(&mut disk, 3).is_err());
+        assert!(find_partition(&mut disk, 5).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gpt_partitions() -> Result<()> {
+        for sector_size in SECTOR_SIZES {
+            let mut disk = Cursor::new(mk_gpt(sector_size as usize, &[(34, 99), (100, 100)])?);
+            assert_eq!(
+                find_partition(&mut disk, 1)?,
+                (34 * sector_size, 66 * sector_size)
+            );
+            assert_eq!(
+                find_partition(&mut disk, 2)?,
+                (100 * sector_size, sector_size)
+            );
+            assert!(find_partition(&mut disk, 3).is_err());
+            assert!(find_partition(&mut disk, 129).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_gpt() -> Result<()> {
+        // A partition ending at the last possible sector.
+        let mut disk = Cursor::new(mk_gpt(512, &[(0, u64::MAX)])?);
+        assert!(find_partition(&mut disk, 1).is_err());
+
+        let mut disk = Cursor::new(mk_gpt(512, &[(u64::MAX / 2, u64::MAX / 2)])?);
+        assert!(find_partition(&mut disk, 1).is_err());
+
+        // The entries can't be beyond the end of the disk.
+        let mut disk = mk_gpt(512, &[(34, 99)])?;
+        LittleEndian::write_u64(&mut disk[512 + 72..512 + 80], u64::MAX);
+        assert!(find_partition(&mut Cursor::new(disk), 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn no_partition_table() {
+        let mut disk = Cursor::new(vec![0u8; 4096]);
+        assert!(find_partition(&mut disk, 1).is_err());
+    }
+}
+
+//-----------------------------------------
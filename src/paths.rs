@@ -6,6 +6,14 @@ pub fn index_path() -> PathBuf {
     ["indexes", "seen"].iter().collect()
 }
 
+pub fn full_index_path() -> PathBuf {
+    ["indexes", "full"].iter().collect()
+}
+
+pub fn similarity_index_path() -> PathBuf {
+    ["indexes", "similar"].iter().collect()
+}
+
 pub fn data_path() -> PathBuf {
     ["data", "data"].iter().collect()
 }
@@ -14,12 +22,32 @@ pub fn hashes_path() -> PathBuf {
     ["data", "hashes"].iter().collect()
 }
 
+pub fn stream_slabs_path() -> PathBuf {
+    ["data", "stream_slabs"].iter().collect()
+}
+
+pub fn stream_slab_hashes_path() -> PathBuf {
+    ["data", "stream_slab_hashes"].iter().collect()
+}
+
 pub fn stream_path(stream: &str) -> PathBuf {
     ["streams", stream, "stream"].iter().collect()
 }
 
+pub fn stream_slab_refs(stream: &str) -> PathBuf {
+    ["streams", stream, "slab_refs"].iter().collect()
+}
+
 pub fn stream_config(stream: &str) -> PathBuf {
     ["streams", stream, "config.yaml"].iter().collect()
 }
 
+pub fn stream_seek_index(stream: &str) -> PathBuf {
+    ["streams", stream, "seek_index"].iter().collect()
+}
+
+pub fn pool_set_config(pool_set: &str) -> PathBuf {
+    ["pools", pool_set, "config.yaml"].iter().collect()
+}
+
 //------------------------------
@@ -0,0 +1,627 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+use rand::prelude::*;
+use rand_chacha::ChaCha20Rng;
+use serde_json::json;
+use serde_json::to_string_pretty;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config;
+use crate::output::Output;
+use crate::pack::pack_pool_thin;
+use crate::thin_metadata::*;
+use crate::unpack::unpack_to_thin;
+
+//-----------------------------------------
+
+// Assumes we've chdir'd to the archive
+fn new_pool_set_id() -> String {
+    let mut rng = ChaCha20Rng::from_entropy();
+    loop {
+        let n: u64 = rng.gen();
+        let name = format!("{:>016x}", n);
+        let path: PathBuf = ["pools", &name].iter().collect();
+        if !path.exists() {
+            return name;
+        }
+    }
+}
+
+// Parses the --thin-size arguments, each of which is THIN_ID:BYTES.
+fn given_thin_sizes(matches: &ArgMatches) -> Result<BTreeMap<u32, u64>> {
+    let mut sizes = BTreeMap::new();
+    if let Some(args) = matches.get_many::<String>("THIN_SIZE") {
+        for arg in args {
+            let bad = || anyhow!("--thin-size should be THIN_ID:BYTES, not '{}'", arg);
+            let (id, size) = arg.split_once(':').ok_or_else(bad)?;
+            sizes.insert(
+                id.parse::<u32>().map_err(|_| bad())?,
+                size.parse::<u64>().map_err(|_| bad())?,
+            );
+        }
+    }
+    Ok(sizes)
+}
+
+// The thin metadata doesn't hold a thin's size, so it comes from the
+// thin's dm table if it's active, or else must be given.  It's never
+// inferred from the mappings, since that would lose any unmapped tail.
+fn set_thin_sizes(info: &mut PoolInfo, given: &BTreeMap<u32, u64>) -> Result<()> {
+    let block_size = info.data_block_size as u64 * 512;
+    for (id, thin) in info.thins.iter_mut() {
+        if let Some(size) = given.get(id) {
+            thin.size = Some(*size);
+        }
+        let size = thin.size.ok_or_else(|| {
+            anyhow!(
+                "size of thin {} isn't known, give it with --thin-size {}:BYTES",
+                id,
+                id
+            )
+        })?;
+        if size < thin.nr_mapped_blocks() * block_size {
+            return Err(anyhow!("thin {} has mappings beyond its size", id));
+        }
+    }
+
+    for id in given.keys() {
+        if !info.thins.contains_key(id) {
+            return Err(anyhow!("--thin-size given for unknown thin {}", id));
+        }
+    }
+    Ok(())
+}
+
+// Thins are packed oldest first, so snapshots come after their origins.
+fn pack_order(pool: &PoolInfo) -> Vec<u32> {
+    let mut ids: Vec<u32> = pool.thins.keys().cloned().collect();
+    ids.sort_by_key(|id| (pool.thins[id].details.creation_time, *id));
+    ids
+}
+
+// Picks the already packed thin sharing the most data blocks with this
+// one.  The base must be at least as long, since the delta walks its
+// stream alongside the new one.
+fn choose_base(pool: &PoolInfo, packed: &[(u32, u64)], thin: &PoolThin, size: u64) -> Option<u32> {
+    packed
+        .iter()
+        .filter(|(_, base_size)| *base_size >= size)
+        .map(|(id, _)| (nr_shared_blocks(&pool.thins[id].runs, &thin.runs), *id))
+        .filter(|(shared, _)| *shared > 0)
+        .max_by_key(|(shared, _)| *shared)
+        .map(|(_, id)| id)
+}
+
+pub fn run_pack(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
+    let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap()).canonicalize()?;
+    let pool = Path::new(matches.get_one::<String>("POOL").unwrap());
+    let pool_name = pool.file_name().unwrap().to_str().unwrap().to_string();
+    let pool = pool.canonicalize()?;
+
+    // The metadata path is relative to where we were run from.
+    let thin_metadata = matches
+        .get_one::<String>("THIN_METADATA")
+        .map(|p| Path::new(p).canonicalize())
+        .transpose()
+        .context("couldn't find thin metadata")?;
+
+    // The metadata snapshot of an active pool is held until we're done.
+    let (mut info, data_dev, _snap) = match &thin_metadata {
+        Some(metadata) => (read_offline_pool(metadata)?, pool.clone(), None),
+        None => {
+            let (info, data_dev, snap) = read_active_pool(&pool)?;
+            (info, data_dev, Some(snap))
+        }
+    };
+    set_thin_sizes(&mut info, &given_thin_sizes(matches)?)?;
+
+    env::set_current_dir(archive_dir)?;
+    let config = config::read_pack_config(".", matches)?;
+
+    // Each thin reports through the pool's output, but the summary is ours.
+    let thin_output = Arc::new(Output {
+        report: output.report.clone(),
+        json: false,
+    });
+
+    let mut packed = Vec::new();
+    let mut thins: Vec<config::PoolSetThin> = Vec::new();
+    for thin_id in pack_order(&info) {
+        let thin = &info.thins[&thin_id];
+        let size = thin.size.unwrap();
+        let base = choose_base(&info, &packed, thin, size);
+
+        output
+            .report
+            .set_title(&format!("Packing thin {} of {} ...", thin_id, pool_name));
+        let base_stream = base.map(|id| {
+            let t = thins.iter().find(|t| t.thin_id == id).unwrap();
+            (t.stream_id.as_str(), id)
+        });
+        let stream_id = pack_pool_thin(
+            thin_output.clone(),
+            &data_dev,
+            format!("{}-{}", pool_name, thin_id),
+            &config,
+            &info,
+            thin_id,
+            base_stream,
+        )?;
+
+        packed.push((thin_id, size));
+        thins.push(config::PoolSetThin {
+            thin_id,
+            stream_id,
+            size,
+            mapped_blocks: thin.details.mapped_blocks,
+            transaction_id: thin.details.transaction_id,
+            creation_time: thin.details.creation_time,
+            snapshotted_time: thin.details.snapshotted_time,
+            base,
+        });
+    }
+
+    let pool_set_id = new_pool_set_id();
+    let cfg = config::PoolSetConfig {
+        name: pool_name,
+        source_path: pool.display().to_string(),
+        pack_time: config::now(),
+        data_block_size: info.data_block_size,
+        thins,
+    };
+    config::write_pool_set_config(&pool_set_id, &cfg)?;
+
+    if output.json {
+        let thins: Vec<_> = cfg
+            .thins
+            .iter()
+            .map(|t| json!({ "thin_id": t.thin_id, "stream_id": t.stream_id, "base": t.base }))
+            .collect();
+        let result = json!({ "pool_set_id": pool_set_id, "thins": thins });
+        println!("{}", to_string_pretty(&result).unwrap());
+    } else {
+        output
+            .report
+            .info(&format!("pool set id      : {}", pool_set_id));
+        for t in &cfg.thins {
+            output
+                .report
+                .info(&format!("thin {:<11} : {}", t.thin_id, t.stream_id));
+        }
+    }
+
+    Ok(())
+}
+
+//-----------------------------------------
+
+// Every base has to be restored before the thins that are deltas of it.
+fn check_restore_order(thins: &[config::PoolSetThin]) -> Result<()> {
+    let mut seen = Vec::new();
+    for t in thins {
+        if let Some(base) = t.base {
+            if !seen.contains(&base) {
+                return Err(anyhow!(
+                    "thin {} is listed before its base {}",
+                    t.thin_id,
+                    base
+                ));
+            }
+        }
+        seen.push(t.thin_id);
+    }
+    Ok(())
+}
+
+// The target pool has to use the same data block size, since thins are
+// restored a block at a time, and have room for at least the thins that
+// aren't snapshots.
+fn check_target(
+    pool_set: &config::PoolSetConfig,
+    data_block_size: u32,
+    nr_free: u64,
+) -> Result<()> {
+    if data_block_size != pool_set.data_block_size {
+        return Err(anyhow!(
+            "pool has a data block size of {} sectors, but the pool set's is {}",
+            data_block_size,
+            pool_set.data_block_size
+        ));
+    }
+
+    let needed: u64 = pool_set
+        .thins
+        .iter()
+        .filter(|t| t.base.is_none())
+        .map(|t| t.mapped_blocks)
+        .sum();
+    if needed > nr_free {
+        return Err(anyhow!(
+            "pool has {} free data blocks, but at least {} are needed",
+            nr_free,
+            needed
+        ));
+    }
+    Ok(())
+}
+
+// What a restore needs from the target pool.
+trait RestorePool {
+    fn create_thin(&self, thin_id: u32) -> Result<()>;
+    fn create_snap(&self, thin_id: u32, origin_id: u32, origin: Option<&str>) -> Result<()>;
+    fn activate_thin(&self, name: &str, thin_id: u32, size: u64) -> Result<PathBuf>;
+    fn deactivate_thin(&self, name: &str) -> Result<()>;
+    fn delete_thin(&self, thin_id: u32) -> Result<()>;
+}
+
+impl RestorePool for TargetPool {
+    fn create_thin(&self, thin_id: u32) -> Result<()> {
+        TargetPool::create_thin(self, thin_id)
+    }
+
+    fn create_snap(&self, thin_id: u32, origin_id: u32, origin: Option<&str>) -> Result<()> {
+        TargetPool::create_snap(self, thin_id, origin_id, origin)
+    }
+
+    fn activate_thin(&self, name: &str, thin_id: u32, size: u64) -> Result<PathBuf> {
+        TargetPool::activate_thin(self, name, thin_id, size)
+    }
+
+    fn deactivate_thin(&self, name: &str) -> Result<()> {
+        TargetPool::deactivate_thin(self, name)
+    }
+
+    fn delete_thin(&self, thin_id: u32) -> Result<()> {
+        TargetPool::delete_thin(self, thin_id)
+    }
+}
+
+// The thins created so far, with their dm names once activated.
+type Created = Vec<(u32, Option<String>)>;
+
+// Each thin is created as a snapshot of its restored base, so the
+// blocks they share are only written once.
+fn restore_thin<P: RestorePool>(
+    pool: &P,
+    t: &config::PoolSetThin,
+    prefix: &str,
+    created: &mut Created,
+    unpack: &mut impl FnMut(&config::PoolSetThin, &str, &Path) -> Result<()>,
+) -> Result<PathBuf> {
+    match t.base {
+        None => pool.create_thin(t.thin_id)?,
+        Some(base) => {
+            let base_name = created
+                .iter()
+                .find(|(id, _)| *id == base)
+                .and_then(|(_, name)| name.as_deref());
+            pool.create_snap(t.thin_id, base, base_name)?;
+        }
+    }
+    created.push((t.thin_id, None));
+
+    let name = format!("{}-{}", prefix, t.thin_id);
+    let path = pool.activate_thin(&name, t.thin_id, t.size)?;
+    created.last_mut().unwrap().1 = Some(name.clone());
+
+    unpack(t, &name, &path)?;
+    Ok(path)
+}
+
+// Newest first, so snapshots go before their origins.  Returns the
+// thins that couldn't be removed.
+fn remove_thins<P: RestorePool>(pool: &P, created: &Created) -> Vec<u32> {
+    let mut left = Vec::new();
+    for (thin_id, name) in created.iter().rev() {
+        let r = match name {
+            Some(name) => pool.deactivate_thin(name),
+            None => Ok(()),
+        }
+        .and_then(|_| pool.delete_thin(*thin_id));
+        if r.is_err() {
+            left.push(*thin_id);
+        }
+    }
+    left
+}
+
+// If a thin can't be restored, the ones already created are deleted
+// again so the pool is left as it was.
+fn restore_thins<P: RestorePool>(
+    pool: &P,
+    thins: &[config::PoolSetThin],
+    prefix: &str,
+    mut unpack: impl FnMut(&config::PoolSetThin, &str, &Path) -> Result<()>,
+) -> Result<BTreeMap<u32, PathBuf>> {
+    let mut created = Vec::new();
+    let mut restored = BTreeMap::new();
+    for t in thins {
+        match restore_thin(pool, t, prefix, &mut created, &mut unpack) {
+            Ok(path) => {
+                restored.insert(t.thin_id, path);
+            }
+            Err(e) => {
+                let left = remove_thins(pool, &created);
+                let msg = if left.is_empty() {
+                    format!("couldn't restore thin {}", t.thin_id)
+                } else {
+                    format!(
+                        "couldn't restore thin {}, and these thins are left in the pool: {:?}",
+                        t.thin_id, left
+                    )
+                };
+                return Err(e.context(msg));
+            }
+        }
+    }
+    Ok(restored)
+}
+
+pub fn run_restore(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
+    let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap()).canonicalize()?;
+    let pool = Path::new(matches.get_one::<String>("POOL").unwrap()).canonicalize()?;
+    let pool_set_id = matches.get_one::<String>("POOL_SET").unwrap();
+
+    let target = TargetPool::open(&pool)?;
+
+    env::set_current_dir(archive_dir)?;
+    let config = config::read_config(".", matches)?;
+    let pool_set = config::read_pool_set_config(pool_set_id)?;
+    check_restore_order(&pool_set.thins)?;
+    check_target(&pool_set, target.data_block_size, target.nr_free_blocks()?)?;
+    let prefix = matches
+        .get_one::<String>("NAME_PREFIX")
+        .unwrap_or(&pool_set.name);
+
+    let thin_output = Arc::new(Output {
+        report: output.report.clone(),
+        json: false,
+    });
+
+    let restored = restore_thins(&target, &pool_set.thins, prefix, |t, name, path| {
+        output
+            .report
+            .set_title(&format!("Restoring thin {} to {} ...", t.thin_id, name));
+        unpack_to_thin(&t.stream_id, &config, path, thin_output.clone())
+    })?;
+
+    if output.json {
+        let thins: Vec<_> = restored
+            .iter()
+            .map(|(id, path)| json!({ "thin_id": id, "device": path }))
+            .collect();
+        let result = json!({ "thins": thins });
+        println!("{}", to_string_pretty(&result).unwrap());
+    } else {
+        for (id, path) in &restored {
+            output
+                .report
+                .info(&format!("thin {:<11} : {}", id, path.display()));
+        }
+    }
+
+    Ok(())
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod pool_tests {
+    use super::*;
+    use thinp::thin::device_detail::DeviceDetail;
+
+    fn mk_thin(creation_time: u32, runs: Vec<(u64, u64, u64)>) -> PoolThin {
+        PoolThin {
+            details: DeviceDetail {
+                mapped_blocks: runs.iter().map(|r| r.2).sum(),
+                transaction_id: 0,
+                creation_time,
+                snapshotted_time: creation_time,
+            },
+            runs,
+            size: None,
+            dm_uuid: None,
+        }
+    }
+
+    #[test]
+    fn snapshots_use_their_origin_as_base() {
+        let mut pool = PoolInfo {
+            data_block_size: 128,
+            thins: Default::default(),
+            pool_uuid: None,
+        };
+        pool.thins.insert(5, mk_thin(0, vec![(0, 0, 100)]));
+        pool.thins
+            .insert(2, mk_thin(3, vec![(0, 0, 50), (50, 200, 50)]));
+        pool.thins.insert(9, mk_thin(1, vec![(0, 300, 100)]));
+        pool.thins
+            .insert(1, mk_thin(3, vec![(0, 0, 10), (10, 500, 90)]));
+
+        assert_eq!(pack_order(&pool), vec![5, 9, 1, 2]);
+
+        let size = |id: u32| pool.thins[&id].nr_mapped_blocks() * 128 * 512;
+        let packed = vec![(5, size(5)), (9, size(9)), (1, size(1))];
+        assert_eq!(
+            choose_base(&pool, &packed, &pool.thins[&2], size(2)),
+            Some(5)
+        );
+        assert_eq!(
+            choose_base(&pool, &packed[1..], &pool.thins[&2], size(2)),
+            Some(1)
+        );
+        assert_eq!(
+            choose_base(&pool, &packed[1..2], &pool.thins[&2], size(2)),
+            None
+        );
+    }
+
+    #[test]
+    fn bases_are_restored_first() {
+        let mk = |thin_id: u32, base: Option<u32>| config::PoolSetThin {
+            thin_id,
+            stream_id: format!("{:016x}", thin_id),
+            size: 1 << 20,
+            mapped_blocks: 0,
+            transaction_id: 0,
+            creation_time: 0,
+            snapshotted_time: 0,
+            base,
+        };
+
+        assert!(check_restore_order(&[mk(1, None), mk(2, Some(1)), mk(3, Some(2))]).is_ok());
+        assert!(check_restore_order(&[mk(2, Some(1)), mk(1, None)]).is_err());
+        assert!(check_restore_order(&[mk(2, Some(2))]).is_err());
+    }
+
+    fn mk_set(thins: &[(u32, Option<u32>, u64)]) -> config::PoolSetConfig {
+        config::PoolSetConfig {
+            name: "set".to_string(),
+            source_path: "/dev/mapper/pool".to_string(),
+            pack_time: config::now(),
+            data_block_size: 128,
+            thins: thins
+                .iter()
+                .map(|(thin_id, base, mapped_blocks)| config::PoolSetThin {
+                    thin_id: *thin_id,
+                    stream_id: format!("{:016x}", thin_id),
+                    size: 1 << 20,
+                    mapped_blocks: *mapped_blocks,
+                    transaction_id: 0,
+                    creation_time: 0,
+                    snapshotted_time: 0,
+                    base: *base,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn target_must_match_and_have_room() {
+        // Thin 2 is a snapshot, so only needs what it changes.
+        let set = mk_set(&[(1, None, 100), (2, Some(1), 100), (3, None, 20)]);
+        assert!(check_target(&set, 128, 120).is_ok());
+        assert!(check_target(&set, 128, 119).is_err());
+        assert!(check_target(&set, 256, 1000).is_err());
+    }
+
+    // Logs the operations done to it.  Fails to delete the thins given.
+    #[derive(Default)]
+    struct FakePool {
+        ops: std::cell::RefCell<Vec<String>>,
+        undeletable: Vec<u32>,
+    }
+
+    impl FakePool {
+        fn log(&self, op: String) {
+            self.ops.borrow_mut().push(op);
+        }
+    }
+
+    impl RestorePool for FakePool {
+        fn create_thin(&self, thin_id: u32) -> Result<()> {
+            self.log(format!("create {}", thin_id));
+            Ok(())
+        }
+
+        fn create_snap(&self, thin_id: u32, origin_id: u32, origin: Option<&str>) -> Result<()> {
+            self.log(format!("snap {} of {} {:?}", thin_id, origin_id, origin));
+            Ok(())
+        }
+
+        fn activate_thin(&self, name: &str, thin_id: u32, _size: u64) -> Result<PathBuf> {
+            self.log(format!("activate {} as {}", thin_id, name));
+            Ok(PathBuf::from(name))
+        }
+
+        fn deactivate_thin(&self, name: &str) -> Result<()> {
+            self.log(format!("deactivate {}", name));
+            Ok(())
+        }
+
+        fn delete_thin(&self, thin_id: u32) -> Result<()> {
+            if self.undeletable.contains(&thin_id) {
+                return Err(anyhow!("busy"));
+            }
+            self.log(format!("delete {}", thin_id));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn snapshots_share_their_restored_base() {
+        let set = mk_set(&[(1, None, 8), (2, Some(1), 8), (3, Some(2), 8), (4, None, 8)]);
+        let pool = FakePool::default();
+        let mut unpacked = Vec::new();
+        let restored = restore_thins(&pool, &set.thins, "set", |t, name, path| {
+            unpacked.push((t.stream_id.clone(), name.to_string(), path.to_path_buf()));
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            *pool.ops.borrow(),
+            vec![
+                "create 1",
+                "activate 1 as set-1",
+                "snap 2 of 1 Some(\"set-1\")",
+                "activate 2 as set-2",
+                "snap 3 of 2 Some(\"set-2\")",
+                "activate 3 as set-3",
+                "create 4",
+                "activate 4 as set-4",
+            ]
+        );
+        assert_eq!(unpacked.len(), 4);
+        assert_eq!(unpacked[2].0, format!("{:016x}", 3));
+        assert_eq!(unpacked[2].1, "set-3");
+        assert_eq!(
+            restored.keys().cloned().collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(restored[&3], PathBuf::from("set-3"));
+    }
+
+    #[test]
+    fn failed_restore_deletes_created_thins() {
+        let set = mk_set(&[(1, None, 8), (2, Some(1), 8), (3, Some(2), 8)]);
+        let fail_on_3 = |t: &config::PoolSetThin, _: &str, _: &Path| {
+            if t.thin_id == 3 {
+                Err(anyhow!("unpack failed"))
+            } else {
+                Ok(())
+            }
+        };
+
+        let pool = FakePool::default();
+        let e = restore_thins(&pool, &set.thins, "set", fail_on_3).unwrap_err();
+        assert_eq!(e.to_string(), "couldn't restore thin 3");
+        assert_eq!(
+            pool.ops.borrow()[6..],
+            [
+                "deactivate set-3",
+                "delete 3",
+                "deactivate set-2",
+                "delete 2",
+                "deactivate set-1",
+                "delete 1",
+            ]
+        );
+
+        // Anything that couldn't be deleted is reported.
+        let pool = FakePool {
+            undeletable: vec![1],
+            ..Default::default()
+        };
+        let e = restore_thins(&pool, &set.thins, "set", fail_on_3).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "couldn't restore thin 3, and these thins are left in the pool: [1]"
+        );
+    }
+}
+
+//-----------------------------------------
@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use rand::prelude::*;
+use rand_chacha::ChaCha20Rng;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::iovec::*;
+use crate::slab::builder::*;
+
+//-----------------------------------------
+
+// Resemblance detection using super-features.  A set of features is
+// taken from each chunk; each feature is the maximum, over every byte
+// position, of a different linear transform of a rolling hash.  Chunks
+// that share a feature are likely to be similar.  Features are grouped
+// into super-features so a single match is good evidence of
+// similarity.
+
+const NR_FEATURES: usize = 12;
+pub const NR_SUPER_FEATURES: usize = 3;
+const FEATURES_PER_SF: usize = NR_FEATURES / NR_SUPER_FEATURES;
+
+// Only positions where the rolling hash has these bits clear are sampled.
+const SAMPLE_MASK: u64 = 0x7;
+
+pub type Sketch = [u64; NR_SUPER_FEATURES];
+
+pub struct Sketcher {
+    gear: Vec<u64>,
+    transforms: Vec<(u64, u64)>,
+}
+
+impl Default for Sketcher {
+    fn default() -> Self {
+        // Always seeded the same, since sketches are persisted.
+        let mut rng = ChaCha20Rng::seed_from_u64(0x5e7c4);
+        let gear = (0..256).map(|_| rng.gen()).collect();
+        let transforms = (0..NR_FEATURES)
+            .map(|_| (rng.gen::<u64>() | 1, rng.gen()))
+            .collect();
+
+        Self { gear, transforms }
+    }
+}
+
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+impl Sketcher {
+    pub fn sketch(&self, iov: &IoVec) -> Sketch {
+        let mut features = [0u64; NR_FEATURES];
+        let mut h = 0u64;
+
+        for v in iov {
+            for b in *v {
+                h = (h << 1).wrapping_add(self.gear[*b as usize]);
+                if h & SAMPLE_MASK == 0 {
+                    for (f, (m, a)) in features.iter_mut().zip(&self.transforms) {
+                        let t = h.wrapping_mul(*m).wrapping_add(*a);
+                        if t > *f {
+                            *f = t;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut sketch = [0u64; NR_SUPER_FEATURES];
+        for (i, sf) in sketch.iter_mut().enumerate() {
+            let mut acc = i as u64;
+            for f in &features[i * FEATURES_PER_SF..(i + 1) * FEATURES_PER_SF] {
+                acc = mix(acc ^ f);
+            }
+            *sf = acc;
+        }
+        sketch
+    }
+}
+
+//-----------------------------------------
+
+// A fixed size, lossy table mapping super-features to the location of
+// a chunk.  Newer chunks overwrite older ones, so it always describes
+// the most recently packed data.
+
+const INDEX_BITS: usize = 20;
+const INDEX_SIZE: usize = 1 << INDEX_BITS;
+const ENTRY_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    // low bit always set for occupied slots
+    sf: u64,
+    slab: u32,
+    offset: u32,
+}
+
+pub struct SimilarityIndex {
+    slots: Vec<Slot>,
+}
+
+impl Default for SimilarityIndex {
+    fn default() -> Self {
+        Self {
+            slots: vec![Slot::default(); INDEX_SIZE],
+        }
+    }
+}
+
+impl SimilarityIndex {
+    pub fn mem_size() -> usize {
+        INDEX_SIZE * std::mem::size_of::<Slot>()
+    }
+
+    fn slot(i: usize, sf: u64) -> (usize, u64) {
+        let sf = mix(sf ^ i as u64) | 1;
+        ((sf >> 1) as usize & (INDEX_SIZE - 1), sf)
+    }
+
+    /// Returns the location of a chunk sharing a super-feature with the sketch.
+    pub fn lookup(&self, sketch: &Sketch) -> Option<(u32, u32)> {
+        for (i, sf) in sketch.iter().enumerate() {
+            let (index, sf) = Self::slot(i, *sf);
+            let s = &self.slots[index];
+            if s.sf == sf {
+                return Some((s.slab, s.offset));
+            }
+        }
+
+        None
+    }
+
+    pub fn insert(&mut self, sketch: &Sketch, loc: (u32, u32)) {
+        for (i, sf) in sketch.iter().enumerate() {
+            let (index, sf) = Self::slot(i, *sf);
+            self.slots[index] = Slot {
+                sf,
+                slab: loc.0,
+                offset: loc.1,
+            };
+        }
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = SlabFileBuilder::open(path).build()?;
+        if file.get_nr_slabs() != 1 {
+            return Err(anyhow!("similarity index should have a single slab"));
+        }
+
+        let buf = file.read(0)?;
+        if buf.len() != INDEX_SIZE * ENTRY_SIZE {
+            return Err(anyhow!("similarity index is the wrong size"));
+        }
+
+        let mut c = Cursor::new(&buf[..]);
+        let mut slots = Vec::with_capacity(INDEX_SIZE);
+        for _ in 0..INDEX_SIZE {
+            let sf = c.read_u64::<LittleEndian>()?;
+            let slab = c.read_u32::<LittleEndian>()?;
+            let offset = c.read_u32::<LittleEndian>()?;
+            slots.push(Slot { sf, slab, offset });
+        }
+
+        Ok(Self { slots })
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut buf = Vec::with_capacity(INDEX_SIZE * ENTRY_SIZE);
+        for s in &self.slots {
+            buf.write_u64::<LittleEndian>(s.sf)?;
+            buf.write_u32::<LittleEndian>(s.slab)?;
+            buf.write_u32::<LittleEndian>(s.offset)?;
+        }
+
+        let mut file = SlabFileBuilder::create(path)
+            .queue_depth(1)
+            .compressed(true)
+            .build()?;
+        file.write_slab(&buf)?;
+        file.close()?;
+        Ok(())
+    }
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod similarity_tests {
+    use super::*;
+
+    fn rand_buffer(rng: &mut ChaCha20Rng, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        rng.fill_bytes(&mut buf);
+        buf
+    }
+
+    fn sketch(s: &Sketcher, data: &[u8]) -> Sketch {
+        // Split the data to check sketches span iovec entries.
+        let iov: IoVec = vec![&data[..100], &data[100..]];
+        s.sketch(&iov)
+    }
+
+    #[test]
+    fn test_similar_chunks_match() {
+        let s = Sketcher::default();
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let base = rand_buffer(&mut rng, 8192);
+        let mut index = SimilarityIndex::default();
+        index.insert(&sketch(&s, &base), (3, 4));
+
+        let mut similar = base.clone();
+        similar[4000..4008].copy_from_slice(&[0xff; 8]);
+        assert_eq!(index.lookup(&sketch(&s, &similar)), Some((3, 4)));
+
+        let unrelated = rand_buffer(&mut rng, 8192);
+        assert_eq!(index.lookup(&sketch(&s, &unrelated)), None);
+    }
+
+    #[test]
+    fn test_write_read() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let path = td.path().join("similar");
+
+        let s = Sketcher::default();
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let data = rand_buffer(&mut rng, 4096);
+
+        let mut index = SimilarityIndex::default();
+        index.insert(&sketch(&s, &data), (1, 2));
+        index.write(&path)?;
+
+        let index = SimilarityIndex::read(&path)?;
+        assert_eq!(index.lookup(&sketch(&s, &data)), Some((1, 2)));
+        Ok(())
+    }
+}
+
+//-----------------------------------------
@@ -33,6 +33,22 @@ impl<T: Default + Copy, N: ArrayLength<T> + ArrayLength<u8>> Stack<T, N> {
         self.values.get_mut(self.indexes[index] as usize).unwrap()
     }
 
+    /// Values stay in the same slot however the stack is rotated.
+    pub fn slot(&self, index: usize) -> usize {
+        self.indexes[index] as usize
+    }
+
+    pub fn index_of(&self, slot: usize) -> usize {
+        self.indexes
+            .iter()
+            .position(|i| *i as usize == slot)
+            .unwrap()
+    }
+
+    pub fn get_slot(&self, slot: usize) -> &T {
+        self.values.get(slot).unwrap()
+    }
+
     pub fn rot(&mut self, index: usize) {
         let tmp = self.indexes[index];
 
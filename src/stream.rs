@@ -1,19 +1,17 @@
 use anyhow::{anyhow, Result};
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use nom::{combinator::fail, multi::*, number::complete::*, IResult};
 use num_enum::TryFromPrimitive;
 use serde_json::json;
 use serde_json::to_string_pretty;
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{Read, Write};
 use std::sync::Arc;
 
 use crate::output::Output;
-use crate::slab::builder::*;
-use crate::slab::*;
 use crate::stack::*;
+use crate::stream_slabs::StreamSlabs;
 
 //-----------------------------------------
 
@@ -82,6 +80,36 @@ fn delta_as_i12(lhs: u32, rhs: u32) -> Option<i16> {
     }
 }
 
+#[test]
+fn test_delta_partial_out_of_range() -> Result<()> {
+    use MapInstruction::*;
+
+    let encode = |begin, end| -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        Partial { begin, end }.pack(&mut buf)?;
+        Delta {
+            slab: 0,
+            offset: 0,
+            len: 100,
+        }
+        .pack(&mut buf)?;
+        Ok(buf)
+    };
+
+    let (entries, _) = unpack(&encode(10, 100)?)?;
+    assert!(matches!(
+        entries[..],
+        [MapEntry::Delta {
+            partial: Some((10, 100)),
+            ..
+        }]
+    ));
+
+    assert!(unpack(&encode(10, 101)?).is_err());
+    assert!(unpack(&encode(50, 10)?).is_err());
+    Ok(())
+}
+
 #[test]
 fn test_delta_as_i12() {
     let tests = vec![
@@ -100,11 +128,10 @@ fn test_delta_as_i12() {
 
 //-----------------------------------------
 
-// FIXME: when encoding it will be common to switch to a different
-// stack entry, emit, switch back and increment a similar amount to
-// what was just emitted.  How do we compile this efficiently?  Keep
-// an array of stack entries in slab order, then do a binary search for
-// the lower bound?
+// When encoding it's common to switch to a different stack entry,
+// emit, then switch back and skip a similar amount to what was just
+// emitted (a snapshot overwriting part of its origin).  The Ret
+// instructions cover this.
 
 #[derive(Copy, Clone, Debug)]
 pub enum MapInstruction {
@@ -147,6 +174,25 @@ pub enum MapInstruction {
     Pos64 { pos: u64 },
 
     Partial { begin: u32, end: u32 },
+
+    // Emits a chunk stored as a delta against the base chunk at
+    // slab:offset.  The delta itself is at the top register.
+    Delta { slab: u32, offset: u32, len: u32 },
+
+    // Rotates the previous register back to the top, and advances its
+    // offset by the length of the last emit.
+    Ret,
+
+    // As Ret, but advances the offset by the given amount.
+    RetAdvance8 { len: u8 },
+
+    // Selects the archive that subsequent data entries refer to; 0 is
+    // this archive, n is the n'th parent.
+    SetArchive { archive: u8 },
+
+    // Returns the vm to its initial state, so the instructions that
+    // follow can be decoded without anything that came before.
+    Reset,
 }
 
 // 4 bit tags
@@ -305,6 +351,26 @@ impl MapInstruction {
                 w.write_u32::<LittleEndian>(*begin)?;
                 w.write_u32::<LittleEndian>(*end)?;
             }
+            Delta { slab, offset, len } => {
+                w.write_u8(pack_tag(TagUnmappedPosPartial, 9))?;
+                w.write_u32::<LittleEndian>(*slab)?;
+                w.write_u32::<LittleEndian>(*offset)?;
+                w.write_u32::<LittleEndian>(*len)?;
+            }
+            SetArchive { archive } => {
+                w.write_u8(pack_tag(TagUnmappedPosPartial, 10))?;
+                w.write_u8(*archive)?;
+            }
+            Reset => {
+                w.write_u8(pack_tag(TagUnmappedPosPartial, 11))?;
+            }
+            Ret => {
+                w.write_u8(pack_tag(TagUnmappedPosPartial, 12))?;
+            }
+            RetAdvance8 { len } => {
+                w.write_u8(pack_tag(TagUnmappedPosPartial, 13))?;
+                w.write_u8(*len)?;
+            }
         }
         Ok(())
     }
@@ -378,6 +444,22 @@ impl MapInstruction {
                     (input, Partial { begin, end })
                 }
                 8 => (input, NextSlab {}),
+                9 => {
+                    let (input, slab) = le_u32(input)?;
+                    let (input, offset) = le_u32(input)?;
+                    let (input, len) = le_u32(input)?;
+                    (input, Delta { slab, offset, len })
+                }
+                10 => {
+                    let (input, archive) = le_u8(input)?;
+                    (input, SetArchive { archive })
+                }
+                11 => (input, Reset {}),
+                12 => (input, Ret {}),
+                13 => {
+                    let (input, len) = le_u8(input)?;
+                    (input, RetAdvance8 { len })
+                }
                 _ => {
                     // Bad length for unmapped tag
                     fail(input)?
@@ -471,6 +553,7 @@ pub enum MapEntry {
         len: u64,
     },
     Data {
+        archive: u8,
         slab: u32,
         offset: u32,
         nr_entries: u32,
@@ -480,6 +563,7 @@ pub enum MapEntry {
         end: u32,
 
         // These are the data fields
+        archive: u8,
         slab: u32,
         offset: u32,
         nr_entries: u32,
@@ -487,6 +571,21 @@ pub enum MapEntry {
     Ref {
         len: u64,
     },
+    Delta {
+        // The chunk is rebuilt by applying the delta stored at
+        // (slab, offset) to the base chunk.  Bases are never deltas
+        // themselves, and both are always in this archive.
+        base_slab: u32,
+        base_offset: u32,
+        slab: u32,
+        offset: u32,
+
+        // Length of the rebuilt chunk.
+        len: u32,
+
+        // Optional sub range of the rebuilt chunk.
+        partial: Option<(u32, u32)>,
+    },
 }
 
 pub trait MapEntryHandler {
@@ -501,14 +600,56 @@ struct Register {
 
 const STACK_SIZE: usize = 16;
 
-#[derive(Default)]
 pub struct VMState {
     fill: u8,
+    archive: u8,
     stack: Stack<Register, generic_array::typenum::U16>,
     partial: Option<(u32, u32)>,
+
+    // Length of the most recent emit, which Ret advances by.
+    last_emit: u32,
+
+    // The stack slots sorted by register, along with their registers,
+    // so the encoder can binary search for the nearest one.  Only the
+    // top of the stack changes between searches, so only its slot can
+    // be out of place.
+    order: [u8; STACK_SIZE],
+    keys: [(u32, u32); STACK_SIZE],
+
+    // Cleared to encode without Ret or the ordered search, so we can
+    // measure what they save.
+    shortcuts: bool,
+}
+
+impl Default for VMState {
+    fn default() -> Self {
+        let mut order = [0; STACK_SIZE];
+        for (i, o) in order.iter_mut().enumerate() {
+            *o = i as u8;
+        }
+
+        Self {
+            fill: 0,
+            archive: 0,
+            stack: Stack::default(),
+            partial: None,
+            last_emit: 0,
+            order,
+            keys: [(0, 0); STACK_SIZE],
+            shortcuts: true,
+        }
+    }
 }
 
 impl VMState {
+    #[cfg(test)]
+    pub fn without_shortcuts() -> Self {
+        Self {
+            shortcuts: false,
+            ..Default::default()
+        }
+    }
+
     fn top(&mut self) -> &mut Register {
         self.stack.get_mut(0)
     }
@@ -541,10 +682,66 @@ impl VMState {
             + (r2.offset as i64 - r1.offset as i64).abs()) as usize
     }
 
+    fn slot_key(&self, slot: u8) -> (u32, u32) {
+        let r = self.stack.get_slot(slot as usize);
+        (r.slab, r.offset)
+    }
+
+    fn rebuild_order(&mut self) {
+        let mut order = self.order;
+        order.sort_by_key(|slot| self.slot_key(*slot));
+        self.order = order;
+        self.keys = order.map(|slot| self.slot_key(slot));
+    }
+
+    // Moves the top's slot to its place in the order.  This must be
+    // called before the top changes, so no other slot is out of place.
+    fn reorder_top(&mut self) {
+        let slot = self.stack.slot(0) as u8;
+        let key = self.slot_key(slot);
+        let old = self.order.iter().position(|s| *s == slot).unwrap();
+        if self.keys[old] == key {
+            return;
+        }
+
+        self.order.copy_within(old + 1.., old);
+        self.keys.copy_within(old + 1.., old);
+        let new = self.keys[..STACK_SIZE - 1].partition_point(|k| *k < key);
+        self.order.copy_within(new..STACK_SIZE - 1, new + 1);
+        self.keys.copy_within(new..STACK_SIZE - 1, new + 1);
+        self.order[new] = slot;
+        self.keys[new] = key;
+    }
+
     // Finds the register that would take the fewest bytes to encode.
-    // This doesn't consider the top of the stack (index 0).
-    // FIXME: so slow
-    fn nearest_register(&self, slab: u32, offset: u32) -> usize {
+    // Only the registers either side of the target in slab order are
+    // considered.
+    fn nearest_register(&mut self, slab: u32, offset: u32) -> usize {
+        if !self.shortcuts {
+            return self.nearest_register_linear(slab, offset);
+        }
+        self.reorder_top();
+
+        let target = Register { slab, offset };
+        let i = self.keys.partition_point(|k| *k < (slab, offset));
+
+        // Ties go to the top of the stack.
+        let mut best = (
+            Self::distance_cost(self.stack.get(0), &target),
+            self.stack.slot(0) as u8,
+        );
+        for j in i.saturating_sub(1)..STACK_SIZE.min(i + 1) {
+            let (slab, offset) = self.keys[j];
+            let cost = Self::distance_cost(&Register { slab, offset }, &target);
+            if cost < best.0 {
+                best = (cost, self.order[j]);
+            }
+        }
+        self.stack.index_of(best.1 as usize)
+    }
+
+    // As nearest_register, but costs every register.
+    fn nearest_register_linear(&self, slab: u32, offset: u32) -> usize {
         let target = Register { slab, offset };
         let mut index = 0;
         let mut min_cost = Self::distance_cost(self.stack.get(index), &target);
@@ -559,6 +756,40 @@ impl VMState {
         index
     }
 
+    // Going back to the previous register is common enough to have its
+    // own instructions.
+    fn select_previous(&mut self, slab: u32, offset: u32, instrs: &mut IVec) -> bool {
+        use MapInstruction::*;
+
+        let prev = *self.stack.get(1);
+        if prev.slab != slab || offset < prev.offset {
+            return false;
+        }
+
+        let len = offset - prev.offset;
+        if len == self.last_emit {
+            instrs.push(Ret);
+        } else if (I4_MAX as u32) < len && len <= u8::MAX as u32 {
+            // Rot + OffsetDelta4 is no bigger below this.
+            instrs.push(RetAdvance8 { len: len as u8 });
+        } else {
+            return false;
+        }
+        self.reorder_top();
+        self.ret(len);
+        true
+    }
+
+    fn advance(&mut self, len: u32) {
+        self.top().offset += len;
+        self.last_emit = len;
+    }
+
+    fn ret(&mut self, len: u32) {
+        self.rot_stack(1);
+        self.top().offset += len;
+    }
+
     fn select_register(&mut self, slab: u32, offset: u32, instrs: &mut IVec) -> Result<()> {
         use MapInstruction::*;
 
@@ -571,6 +802,8 @@ impl VMState {
             // We can encode this with a couple of bytes, so no point doing
             // any stack shuffling which would at best take 2 bytes.
             return Ok(());
+        } else if self.shortcuts && self.select_previous(slab, offset, instrs) {
+            return Ok(());
         }
 
         let index = self.nearest_register(slab, offset);
@@ -693,19 +926,33 @@ impl VMState {
             instrs.push(Emit20 { len });
         }
 
-        let top = self.top();
-        top.offset += len;
+        self.advance(len);
+        Ok(())
+    }
+
+    pub fn encode_reset(&mut self, instrs: &mut IVec) -> Result<()> {
+        instrs.push(MapInstruction::Reset);
+        *self = Self::default();
+        Ok(())
+    }
 
+    pub fn encode_archive(&mut self, archive: u8, instrs: &mut IVec) -> Result<()> {
+        if self.archive != archive {
+            instrs.push(MapInstruction::SetArchive { archive });
+            self.archive = archive;
+        }
         Ok(())
     }
 
     pub fn encode_data(
         &mut self,
+        archive: u8,
         slab: u32,
         offset: u32,
         nr_entries: u32,
         instrs: &mut IVec,
     ) -> Result<()> {
+        self.encode_archive(archive, instrs)?;
         self.select_register(slab, offset, instrs)?;
         self.encode_slab(slab, instrs)?;
         self.encode_offset(offset, instrs)?;
@@ -713,10 +960,111 @@ impl VMState {
         Ok(())
     }
 
+    pub fn encode_delta(
+        &mut self,
+        slab: u32,
+        offset: u32,
+        base_slab: u32,
+        base_offset: u32,
+        len: u32,
+        instrs: &mut IVec,
+    ) -> Result<()> {
+        self.encode_archive(0, instrs)?;
+        self.select_register(slab, offset, instrs)?;
+        self.encode_slab(slab, instrs)?;
+        self.encode_offset(offset, instrs)?;
+        instrs.push(MapInstruction::Delta {
+            slab: base_slab,
+            offset: base_offset,
+            len,
+        });
+        self.advance(1);
+        Ok(())
+    }
+
     pub fn encode_partial(&mut self, begin: u32, end: u32, instrs: &mut IVec) -> Result<()> {
         instrs.push(MapInstruction::Partial { begin, end });
         Ok(())
     }
+
+    /// Saves the state, so decoding can start part way through a stream.
+    /// Only the logical order of the stack matters.
+    pub fn pack<W: Write>(&self, w: &mut W) -> Result<()> {
+        if self.partial.is_some() {
+            return Err(anyhow!("can't save vm state with a pending partial"));
+        }
+
+        w.write_u8(self.fill)?;
+        w.write_u8(self.archive)?;
+        w.write_u32::<LittleEndian>(self.last_emit)?;
+        for i in 0..STACK_SIZE {
+            let reg = self.stack.get(i);
+            w.write_u32::<LittleEndian>(reg.slab)?;
+            w.write_u32::<LittleEndian>(reg.offset)?;
+        }
+        Ok(())
+    }
+
+    pub fn unpack<R: Read>(r: &mut R) -> Result<Self> {
+        let mut state = Self {
+            fill: r.read_u8()?,
+            archive: r.read_u8()?,
+            last_emit: r.read_u32::<LittleEndian>()?,
+            ..Default::default()
+        };
+        for i in 0..STACK_SIZE {
+            let reg = state.stack.get_mut(i);
+            reg.slab = r.read_u32::<LittleEndian>()?;
+            reg.offset = r.read_u32::<LittleEndian>()?;
+        }
+        state.rebuild_order();
+        Ok(state)
+    }
+}
+
+pub const VM_STATE_SIZE: usize = 6 + STACK_SIZE * 8;
+
+#[test]
+fn test_ret() -> Result<()> {
+    use MapInstruction::*;
+
+    let mut vm = VMState::default();
+    let mut instrs = Vec::new();
+    vm.encode_data(0, 10, 0, 100, &mut instrs)?;
+    vm.encode_data(0, 500, 0, 3, &mut instrs)?;
+
+    // Skipping what was just emitted.
+    instrs.clear();
+    vm.encode_data(0, 10, 103, 5, &mut instrs)?;
+    assert!(matches!(instrs[..], [Ret, Emit4 { len: 5 }]));
+
+    // Skipping some other amount.
+    vm.encode_data(0, 900, 0, 2, &mut instrs)?;
+    instrs.clear();
+    vm.encode_data(0, 10, 148, 1, &mut instrs)?;
+    assert!(matches!(
+        instrs[..],
+        [RetAdvance8 { len: 40 }, Emit4 { len: 1 }]
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_nearest_register() -> Result<()> {
+    let mut state = vec![0; 6];
+    for i in 0..STACK_SIZE {
+        let slab = [2000, 3000, 1000, 4000].get(i).cloned().unwrap_or(0);
+        state.write_u32::<LittleEndian>(slab)?;
+        state.write_u32::<LittleEndian>(0)?;
+    }
+    let mut vm = VMState::unpack(&mut &state[..])?;
+
+    assert_eq!(vm.nearest_register(3001, 7), 1);
+    assert_eq!(vm.nearest_register(999, 7), 2);
+    assert_eq!(vm.nearest_register(5000, 0), 3);
+    assert!(vm.nearest_register(1, 0) >= 4);
+    assert_eq!(vm.nearest_register(2000, 7), 0);
+    Ok(())
 }
 
 //--------------------------------
@@ -732,25 +1080,70 @@ type EntryVec = Vec<MapEntry>;
 type PosVec = Vec<(u64, usize)>;
 
 impl MappingUnpacker {
+    /// Starts decoding from a saved state, typically the beginning of a
+    /// stream slab other than the first.
+    pub fn with_state(vm_state: VMState) -> Self {
+        Self { vm_state }
+    }
+
+    pub fn state(&self) -> &VMState {
+        &self.vm_state
+    }
+
     fn emit_run(&mut self, r: &mut Vec<MapEntry>, len: usize) {
         let p = self.vm_state.partial.take();
+        let archive = self.vm_state.archive;
         let top = self.vm_state.top();
         if let Some((begin, end)) = p {
             r.push(MapEntry::Partial {
                 begin,
                 end,
+                archive,
                 slab: top.slab,
                 offset: top.offset,
                 nr_entries: len as u32,
             });
         } else {
             r.push(MapEntry::Data {
+                archive,
                 slab: top.slab,
                 offset: top.offset,
                 nr_entries: len as u32,
             });
         }
-        top.offset += len as u32;
+        self.vm_state.advance(len as u32);
+    }
+
+    fn emit_delta(
+        &mut self,
+        base_slab: u32,
+        base_offset: u32,
+        len: u32,
+        r: &mut Vec<MapEntry>,
+    ) -> Result<()> {
+        let partial = self.vm_state.partial.take();
+        if let Some((begin, end)) = partial {
+            if begin > end || end > len {
+                return Err(anyhow!(
+                    "delta partial {}..{} is outside the rebuilt chunk ({} bytes)",
+                    begin,
+                    end,
+                    len
+                ));
+            }
+        }
+
+        let top = self.vm_state.top();
+        r.push(MapEntry::Delta {
+            base_slab,
+            base_offset,
+            slab: top.slab,
+            offset: top.offset,
+            len,
+            partial,
+        });
+        self.vm_state.advance(1);
+        Ok(())
     }
 
     fn emit_unmapped(&mut self, len: u64, r: &mut Vec<MapEntry>) {
@@ -863,6 +1256,21 @@ impl MappingUnpacker {
                 Partial { begin, end } => {
                     self.vm_state.set_partial(begin, end)?;
                 }
+                Delta { slab, offset, len } => {
+                    self.emit_delta(slab, offset, len, &mut entries)?;
+                }
+                SetArchive { archive } => {
+                    self.vm_state.archive = archive;
+                }
+                Reset => {
+                    self.vm_state = VMState::default();
+                }
+                Ret => {
+                    self.vm_state.ret(self.vm_state.last_emit);
+                }
+                RetAdvance8 { len } => {
+                    self.vm_state.ret(len as u32);
+                }
             }
         }
         Ok((entries, positions))
@@ -888,36 +1296,43 @@ fn unpack_instructions(buf: &[u8]) -> Result<Vec<MapInstruction>> {
 //-----------------------------------------
 
 pub struct StreamIter {
-    file: SlabFile,
+    file: StreamSlabs,
     slab: u32,
     entries: Vec<MapEntry>,
     index: usize,
+
+    // The vm state carries over from one stream slab to the next.
+    unpacker: MappingUnpacker,
 }
 
 impl StreamIter {
-    pub fn new(mut file: SlabFile) -> Result<Self> {
-        let entries = Self::read_slab(&mut file, 0)?;
-        Ok(Self {
+    pub fn new(file: StreamSlabs) -> Result<Self> {
+        let mut r = Self {
             file,
             slab: 0,
-            entries,
+            entries: Vec::new(),
             index: 0,
-        })
+            unpacker: MappingUnpacker::default(),
+        };
+        if r.file.get_nr_slabs() > 0 {
+            r.entries = r.read_slab(0)?;
+        }
+        Ok(r)
     }
 
-    fn read_slab(file: &mut SlabFile, slab: u32) -> Result<Vec<MapEntry>> {
-        let buf = file.read(slab)?;
-        let (entries, _positions) = unpack(&buf)?;
+    fn read_slab(&mut self, slab: u32) -> Result<Vec<MapEntry>> {
+        let buf = self.file.read(slab)?;
+        let (entries, _positions) = self.unpacker.unpack(&buf)?;
         Ok(entries)
     }
 
     fn next_slab(&mut self) -> Result<bool> {
-        if self.slab >= self.file.get_nr_slabs() as u32 {
+        if self.slab + 1 >= self.file.get_nr_slabs() as u32 {
             return Ok(false);
         }
 
         self.slab += 1;
-        let entries = Self::read_slab(&mut self.file, self.slab)?;
+        let entries = self.read_slab(self.slab)?;
         self.entries = entries;
         self.index = 0;
         Ok(true)
@@ -977,10 +1392,15 @@ struct Stats {
     pos32: u64,
     pos64: u64,
     partial: u64,
+    delta: u64,
+    set_archive: u64,
+    reset: u64,
+    ret: u64,
+    ret_advance8: u64,
 }
 
 pub struct Dumper {
-    stream_file: SlabFile,
+    stream_file: StreamSlabs,
     vm_state: VMState,
     stats: Stats,
 }
@@ -988,8 +1408,7 @@ pub struct Dumper {
 impl Dumper {
     // Assumes current directory is the root of the archive.
     pub fn new(stream: &str) -> Result<Self> {
-        let stream_path: PathBuf = ["streams", stream, "stream"].iter().collect();
-        let stream_file = SlabFileBuilder::open(stream_path).build()?;
+        let stream_file = StreamSlabs::open(stream)?;
 
         Ok(Self {
             stream_file,
@@ -1089,15 +1508,15 @@ impl Dumper {
             }
             Emit4 { len } => {
                 self.stats.emit4 += 1;
-                self.vm_state.top().offset += *len as u32;
+                self.vm_state.advance(*len as u32);
             }
             Emit12 { len } => {
                 self.stats.emit12 += 1;
-                self.vm_state.top().offset += *len as u32;
+                self.vm_state.advance(*len as u32);
             }
             Emit20 { len } => {
                 self.stats.emit20 += 1;
-                self.vm_state.top().offset += *len;
+                self.vm_state.advance(*len);
             }
             Pos32 { .. } => {
                 self.stats.pos32 += 1;
@@ -1108,6 +1527,26 @@ impl Dumper {
             Partial { .. } => {
                 self.stats.partial += 1;
             }
+            Delta { .. } => {
+                self.stats.delta += 1;
+                self.vm_state.advance(1);
+            }
+            SetArchive { archive } => {
+                self.stats.set_archive += 1;
+                self.vm_state.archive = *archive;
+            }
+            Reset => {
+                self.stats.reset += 1;
+                self.vm_state = VMState::default();
+            }
+            Ret => {
+                self.stats.ret += 1;
+                self.vm_state.ret(self.vm_state.last_emit);
+            }
+            RetAdvance8 { len } => {
+                self.stats.ret_advance8 += 1;
+                self.vm_state.ret(*len as u32);
+            }
         }
     }
 
@@ -1198,6 +1637,17 @@ impl Dumper {
                 let str = format!("{}..{}", begin, end);
                 format!("   part {:<10}", str)
             }
+            Delta { slab, offset, len } => {
+                format!("   delta {}:{} {}", slab, offset, len)
+            }
+            SetArchive { archive } => {
+                format!("set-archive {}", archive)
+            }
+            Reset => "reset".to_string(),
+            Ret => "   ret".to_string(),
+            RetAdvance8 { len } => {
+                format!("   ret {}", len)
+            }
         }
     }
 
@@ -1224,6 +1674,7 @@ impl Dumper {
                 | Fill32 { .. }
                 | Fill64 { .. }
                 | SetFill { .. }
+                | SetArchive { .. }
                 | Pos32 { .. }
                 | Pos64 { .. }
                 | Unmapped8 { .. }
@@ -1234,36 +1685,8 @@ impl Dumper {
         )
     }
 
-    pub fn dump(&mut self, output: Arc<Output>) -> Result<()> {
-        let nr_slabs = self.stream_file.get_nr_slabs();
-        let mut json_stream = Vec::new();
-
-        for s in 0..nr_slabs {
-            let stream_data = self.stream_file.read(s as u32)?;
-            let entries = unpack_instructions(&stream_data[..])?;
-
-            for (i, e) in entries.iter().enumerate() {
-                self.exec(e);
-
-                if Self::effects_stack(e) {
-                    let stack = self.format_stack()?;
-                    if output.json {
-                        json_stream.push(json!(
-                            {"entry": i, "instruction": format!("{:?}",e), "stack": &stack}
-                        ));
-                    } else {
-                        println!("{:0>10x}   {:20}{:20}", i, self.pp_instr(e), &stack,);
-                    }
-                } else if output.json {
-                    json_stream.push(json!(
-                        {"address": i, "instruction": format!("{:?}",e), "stack":""}
-                    ));
-                } else {
-                    println!("{:0>10x}   {:20}", i, self.pp_instr(e));
-                }
-            }
-        }
-
+    // Instruction frequencies, most common first.
+    fn stats_table(&self) -> Vec<(&'static str, u64)> {
         let mut stats = vec![
             ("rot", self.stats.rot),
             ("dup", self.stats.dup),
@@ -1291,9 +1714,60 @@ impl Dumper {
             ("emit20", self.stats.emit20),
             ("pos32", self.stats.pos32),
             ("pos64", self.stats.pos64),
+            ("delta", self.stats.delta),
+            ("set-archive", self.stats.set_archive),
+            ("reset", self.stats.reset),
+            ("ret", self.stats.ret),
+            ("ret_advance8", self.stats.ret_advance8),
         ];
 
         stats.sort_by(|l, r| r.1.cmp(&l.1));
+        stats
+    }
+
+    /// Runs through the whole stream, returning how often each
+    /// instruction was used.
+    pub fn instruction_stats(&mut self) -> Result<Vec<(&'static str, u64)>> {
+        for s in 0..self.stream_file.get_nr_slabs() {
+            let stream_data = self.stream_file.read(s as u32)?;
+            for e in unpack_instructions(&stream_data[..])? {
+                self.exec(&e);
+            }
+        }
+        Ok(self.stats_table())
+    }
+
+    pub fn dump(&mut self, output: Arc<Output>) -> Result<()> {
+        let nr_slabs = self.stream_file.get_nr_slabs();
+        let mut json_stream = Vec::new();
+
+        for s in 0..nr_slabs {
+            let stream_data = self.stream_file.read(s as u32)?;
+            let entries = unpack_instructions(&stream_data[..])?;
+
+            for (i, e) in entries.iter().enumerate() {
+                self.exec(e);
+
+                if Self::effects_stack(e) {
+                    let stack = self.format_stack()?;
+                    if output.json {
+                        json_stream.push(json!(
+                            {"entry": i, "instruction": format!("{:?}",e), "stack": &stack}
+                        ));
+                    } else {
+                        println!("{:0>10x}   {:20}{:20}", i, self.pp_instr(e), &stack,);
+                    }
+                } else if output.json {
+                    json_stream.push(json!(
+                        {"address": i, "instruction": format!("{:?}",e), "stack":""}
+                    ));
+                } else {
+                    println!("{:0>10x}   {:20}", i, self.pp_instr(e));
+                }
+            }
+        }
+
+        let stats = self.stats_table();
 
         if output.json {
             let hm_stats: HashMap<_, _> = stats.into_iter().collect();
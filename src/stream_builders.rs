@@ -15,6 +15,16 @@ pub trait Builder {
     // I'd rather pass plain 'self' here, but that won't work with runtime
     // polymorphism.
     fn complete(&mut self, w: &mut Vec<u8>) -> Result<()>;
+
+    // Returns the offsets into w, in order, at which stream slabs
+    // should end.  Cuts are only reported once.
+    fn take_cuts(&mut self) -> Vec<usize>;
+
+    // Bytes that were mapped in the stream a delta is built against,
+    // but are unmapped in the new one.
+    fn discarded(&self) -> u64 {
+        0
+    }
 }
 
 pub struct MappingBuilder {
@@ -24,6 +34,14 @@ pub struct MappingBuilder {
     position: u64, // byte len of stream so far
     entry: Option<MapEntry>,
     vm_state: VMState,
+
+    // Stream slabs are cut at the first entry boundary after each
+    // multiple of CUT_SPAN, with the vm state reset.  So identical
+    // regions of two streams encode to identical slabs, which the
+    // stream slab store then dedups.
+    next_cut: u64,
+    cuts: Vec<usize>,
+    reset: bool,
 }
 
 fn pack_instrs<W: Write>(w: &mut W, instrs: &IVec) -> Result<()> {
@@ -36,6 +54,8 @@ fn pack_instrs<W: Write>(w: &mut W, instrs: &IVec) -> Result<()> {
 // FIXME: bump up to 128
 const INDEX_PERIOD: u64 = 128;
 
+pub const CUT_SPAN: u64 = 16 * 1024 * 1024;
+
 impl Default for MappingBuilder {
     fn default() -> Self {
         Self {
@@ -44,6 +64,9 @@ impl Default for MappingBuilder {
             position: 0,
             entry: None,
             vm_state: VMState::default(),
+            next_cut: CUT_SPAN,
+            cuts: Vec::new(),
+            reset: false,
         }
     }
 }
@@ -52,6 +75,12 @@ impl MappingBuilder {
     fn encode_entry(&mut self, e: &MapEntry, instrs: &mut IVec) -> Result<()> {
         use MapEntry::*;
 
+        if self.reset {
+            self.vm_state.encode_reset(instrs)?;
+            self.entries_emitted = 0;
+            self.reset = false;
+        }
+
         match e {
             Fill { byte, len } => {
                 self.vm_state.encode_fill(*byte, *len, instrs)?;
@@ -60,23 +89,45 @@ impl MappingBuilder {
                 self.vm_state.encode_unmapped(*len, instrs)?;
             }
             Data {
+                archive,
                 slab,
                 offset,
                 nr_entries,
             } => {
                 self.vm_state
-                    .encode_data(*slab, *offset, *nr_entries, instrs)?;
+                    .encode_data(*archive, *slab, *offset, *nr_entries, instrs)?;
             }
             Partial {
                 begin,
                 end,
+                archive,
                 slab,
                 offset,
                 nr_entries,
             } => {
                 self.vm_state.encode_partial(*begin, *end, instrs)?;
                 self.vm_state
-                    .encode_data(*slab, *offset, *nr_entries, instrs)?;
+                    .encode_data(*archive, *slab, *offset, *nr_entries, instrs)?;
+            }
+            Delta {
+                base_slab,
+                base_offset,
+                slab,
+                offset,
+                len,
+                partial,
+            } => {
+                if let Some((begin, end)) = partial {
+                    self.vm_state.encode_partial(*begin, *end, instrs)?;
+                }
+                self.vm_state.encode_delta(
+                    *slab,
+                    *offset,
+                    *base_slab,
+                    *base_offset,
+                    *len,
+                    instrs,
+                )?;
             }
             Ref { .. } => return Err(anyhow!("MappingBuilder does not support Ref")),
         }
@@ -94,13 +145,26 @@ impl Builder for MappingBuilder {
     fn next(&mut self, e: &MapEntry, len: u64, w: &mut Vec<u8>) -> Result<()> {
         use MapEntry::*;
 
+        // self.position is where e starts.
+        if self.position >= self.next_cut {
+            if let Some(old_e) = self.entry.take() {
+                let mut instrs = Vec::with_capacity(4);
+                self.encode_entry(&old_e, &mut instrs)?;
+                pack_instrs(w, &instrs)?;
+                self.cuts.push(w.len());
+                self.reset = true;
+            }
+            self.next_cut = (self.position / CUT_SPAN + 1) * CUT_SPAN;
+        }
+
         if self.entry.is_none() {
             self.entry = Some(*e);
             self.position += len;
             return Ok(());
         }
 
-        let mut instrs = Vec::with_capacity(4);
+        // Most entries merge with the last, so don't allocate up front.
+        let mut instrs = Vec::new();
         match (self.entry.take().unwrap(), e) {
             (Fill { byte: b1, len: l1 }, Fill { byte: b2, len: l2 }) if b1 == *b2 => {
                 self.entry = Some(Fill {
@@ -113,29 +177,34 @@ impl Builder for MappingBuilder {
             }
             (
                 Data {
+                    archive: a1,
                     slab: s1,
                     offset: o1,
                     nr_entries: n1,
                 },
                 Data {
+                    archive: a2,
                     slab: s2,
                     offset: o2,
                     nr_entries: n2,
                 },
             ) => {
-                if s1 == *s2 && o1 + n1 == *o2 {
+                if a1 == *a2 && s1 == *s2 && o1 + n1 == *o2 {
                     self.entry = Some(Data {
+                        archive: a1,
                         slab: s1,
                         offset: o1,
                         nr_entries: n1 + n2,
                     });
                 } else {
-                    self.vm_state.encode_data(s1, o1, n1, &mut instrs)?;
-                    self.entry = Some(Data {
-                        slab: *s2,
-                        offset: *o2,
-                        nr_entries: *n2,
-                    });
+                    let old_e = Data {
+                        archive: a1,
+                        slab: s1,
+                        offset: o1,
+                        nr_entries: n1,
+                    };
+                    self.encode_entry(&old_e, &mut instrs)?;
+                    self.entry = Some(*e);
                 }
             }
             (old_e, new_e) => {
@@ -157,6 +226,112 @@ impl Builder for MappingBuilder {
 
         Ok(())
     }
+
+    fn take_cuts(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.cuts)
+    }
+}
+
+//------------------------------
+
+/// Splits an entry of entry_len bytes in two at split_point.  Data is
+/// never touched, the halves just refer to part of the same chunks.
+pub fn split_entry(e: &MapEntry, entry_len: u64, split_point: u64) -> (MapEntry, MapEntry) {
+    use MapEntry::*;
+    assert!(split_point < entry_len);
+
+    match e {
+        Fill { byte, .. } => (
+            Fill {
+                byte: *byte,
+                len: split_point,
+            },
+            Fill {
+                byte: *byte,
+                len: entry_len - split_point,
+            },
+        ),
+        Unmapped { .. } => (
+            Unmapped { len: split_point },
+            Unmapped {
+                len: entry_len - split_point,
+            },
+        ),
+        Data {
+            archive,
+            slab,
+            offset,
+            nr_entries,
+        } => (
+            Partial {
+                begin: 0,
+                end: split_point as u32,
+                archive: *archive,
+                slab: *slab,
+                offset: *offset,
+                nr_entries: *nr_entries,
+            },
+            Partial {
+                begin: split_point as u32,
+                end: entry_len as u32,
+                archive: *archive,
+                slab: *slab,
+                offset: *offset,
+                nr_entries: *nr_entries,
+            },
+        ),
+        Partial {
+            begin,
+            end,
+            archive,
+            slab,
+            offset,
+            nr_entries,
+        } => (
+            Partial {
+                begin: *begin,
+                end: *begin + split_point as u32,
+                archive: *archive,
+                slab: *slab,
+                offset: *offset,
+                nr_entries: *nr_entries,
+            },
+            Partial {
+                begin: *begin + split_point as u32,
+                end: *end,
+                archive: *archive,
+                slab: *slab,
+                offset: *offset,
+                nr_entries: *nr_entries,
+            },
+        ),
+        Ref { .. } => (
+            Ref { len: split_point },
+            Ref {
+                len: entry_len - split_point,
+            },
+        ),
+        Delta {
+            base_slab,
+            base_offset,
+            slab,
+            offset,
+            len,
+            partial,
+        } => {
+            let (begin, end) = partial.unwrap_or((0, *len));
+            let mid = begin + split_point as u32;
+            let mk = |partial| Delta {
+                base_slab: *base_slab,
+                base_offset: *base_offset,
+                slab: *slab,
+                offset: *offset,
+                len: *len,
+                partial: Some(partial),
+            };
+            (mk((begin, mid)), mk((mid, end)))
+        }
+    }
 }
 
 //------------------------------
@@ -166,35 +341,43 @@ pub struct DeltaBuilder {
     old_entry: Option<MapEntry>, // unconsumed remnant from the old_entries
 
     // FIXME: wrap these two up together, lru cache, share somehow with pack?
-    hashes_file: Arc<Mutex<SlabFile>>,
-    slabs: BTreeMap<u32, Arc<ByIndex>>, // FIXME: why an Arc if they're not shared?
+    // The hashes files are indexed by archive id (0 is this archive,
+    // then the parents).
+    hashes_files: Vec<Arc<Mutex<SlabFile>>>,
+    slabs: BTreeMap<(u8, u32), Arc<ByIndex>>, // FIXME: why an Arc if they're not shared?
 
     builder: MappingBuilder,
+    discarded: u64,
 }
 
 impl DeltaBuilder {
-    pub fn new(old_entries: StreamIter, hashes_file: Arc<Mutex<SlabFile>>) -> Self {
+    pub fn new(old_entries: StreamIter, hashes_files: Vec<Arc<Mutex<SlabFile>>>) -> Self {
         Self {
             old_entries,
             old_entry: None,
-            hashes_file,
+            hashes_files,
             slabs: BTreeMap::new(),
             builder: MappingBuilder::default(),
+            discarded: 0,
         }
     }
 
-    fn get_index_(&mut self, slab: u32) -> Result<Arc<ByIndex>> {
-        let mut hashes_file = self.hashes_file.lock().unwrap();
+    fn get_index_(&mut self, archive: u8, slab: u32) -> Result<Arc<ByIndex>> {
+        let hashes_file = self
+            .hashes_files
+            .get(archive as usize)
+            .ok_or_else(|| anyhow!("stream refers to unknown archive {}", archive))?;
+        let mut hashes_file = hashes_file.lock().unwrap();
         let hashes = hashes_file.read(slab)?;
         Ok(Arc::new(ByIndex::new(hashes)?))
     }
 
-    fn get_index(&mut self, slab: u32) -> Result<Arc<ByIndex>> {
-        let index = if let Some(index) = self.slabs.get(&slab) {
+    fn get_index(&mut self, archive: u8, slab: u32) -> Result<Arc<ByIndex>> {
+        let index = if let Some(index) = self.slabs.get(&(archive, slab)) {
             index.clone()
         } else {
-            let r = self.get_index_(slab)?;
-            self.slabs.insert(slab, r.clone());
+            let r = self.get_index_(archive, slab)?;
+            self.slabs.insert((archive, slab), r.clone());
             r
         };
 
@@ -207,11 +390,12 @@ impl DeltaBuilder {
         match e {
             Fill { len, .. } => Ok(*len),
             Data {
+                archive,
                 slab,
                 offset,
                 nr_entries,
             } => {
-                let index = self.get_index(*slab)?;
+                let index = self.get_index(*archive, *slab)?;
                 let mut total_len = 0;
                 for i in *offset..(offset + nr_entries) {
                     let (data_begin, data_end, _) = index.get(i as usize).unwrap();
@@ -222,83 +406,9 @@ impl DeltaBuilder {
             Unmapped { len } => Ok(*len),
             Partial { begin, end, .. } => Ok((end - begin) as u64),
             Ref { len } => Ok(*len),
-        }
-    }
-
-    fn split_entry(
-        &mut self,
-        e: &MapEntry,
-        entry_len: u64,
-        split_point: u64,
-    ) -> (MapEntry, MapEntry) {
-        use MapEntry::*;
-        assert!(split_point < entry_len);
-
-        match e {
-            Fill { byte, .. } => (
-                Fill {
-                    byte: *byte,
-                    len: split_point,
-                },
-                Fill {
-                    byte: *byte,
-                    len: entry_len - split_point,
-                },
-            ),
-            Unmapped { .. } => (
-                Unmapped { len: split_point },
-                Unmapped {
-                    len: entry_len - split_point,
-                },
-            ),
-            Data {
-                slab,
-                offset,
-                nr_entries,
-            } => (
-                Partial {
-                    begin: 0,
-                    end: split_point as u32,
-                    slab: *slab,
-                    offset: *offset,
-                    nr_entries: *nr_entries,
-                },
-                Partial {
-                    begin: split_point as u32,
-                    end: entry_len as u32,
-                    slab: *slab,
-                    offset: *offset,
-                    nr_entries: *nr_entries,
-                },
-            ),
-            Partial {
-                begin,
-                end,
-                slab,
-                offset,
-                nr_entries,
-            } => (
-                Partial {
-                    begin: *begin,
-                    end: *begin + split_point as u32,
-                    slab: *slab,
-                    offset: *offset,
-                    nr_entries: *nr_entries,
-                },
-                Partial {
-                    begin: *begin + split_point as u32,
-                    end: *end,
-                    slab: *slab,
-                    offset: *offset,
-                    nr_entries: *nr_entries,
-                },
-            ),
-            Ref { .. } => (
-                Ref { len: split_point },
-                Ref {
-                    len: entry_len - split_point,
-                },
-            ),
+            Delta { len, partial, .. } => {
+                Ok(partial.map(|(begin, end)| end - begin).unwrap_or(*len) as u64)
+            }
         }
     }
 
@@ -323,7 +433,7 @@ impl DeltaBuilder {
                     let e_len = self.entry_len(&e)?;
 
                     if remaining < e_len {
-                        let (e1, e2) = self.split_entry(&e, e_len, remaining);
+                        let (e1, e2) = split_entry(&e, e_len, remaining);
                         self.builder.next(&e1, remaining, w)?;
                         self.old_entry = Some(e2);
                         remaining = 0;
@@ -338,8 +448,10 @@ impl DeltaBuilder {
         Ok(())
     }
 
-    fn skip_old(&mut self, len: u64) -> Result<()> {
+    // Returns how many of the skipped bytes were mapped.
+    fn skip_old(&mut self, len: u64) -> Result<u64> {
         let mut remaining = len;
+        let mut mapped = 0;
 
         while remaining > 0 {
             let maybe_entry = self.next_old()?;
@@ -347,19 +459,21 @@ impl DeltaBuilder {
             match maybe_entry {
                 Some(e) => {
                     let e_len = self.entry_len(&e)?;
+                    let skipped = std::cmp::min(remaining, e_len);
+                    if !matches!(e, MapEntry::Unmapped { .. }) {
+                        mapped += skipped;
+                    }
 
                     if remaining < e_len {
-                        let (_, e2) = self.split_entry(&e, e_len, remaining);
+                        let (_, e2) = split_entry(&e, e_len, remaining);
                         self.old_entry = Some(e2);
-                        remaining = 0;
-                    } else {
-                        remaining -= e_len;
                     }
+                    remaining -= skipped;
                 }
                 None => return Err(anyhow!("expected short stream")),
             }
         }
-        Ok(())
+        Ok(mapped)
     }
 }
 
@@ -369,6 +483,10 @@ impl Builder for DeltaBuilder {
 
         match e {
             Ref { len } => self.emit_old(*len, w),
+            Unmapped { .. } => {
+                self.discarded += self.skip_old(len)?;
+                self.builder.next(e, len, w)
+            }
             _ => {
                 self.skip_old(len)?;
                 self.builder.next(e, len, w)
@@ -379,6 +497,14 @@ impl Builder for DeltaBuilder {
     fn complete(&mut self, w: &mut Vec<u8>) -> Result<()> {
         self.builder.complete(w)
     }
+
+    fn take_cuts(&mut self) -> Vec<usize> {
+        self.builder.take_cuts()
+    }
+
+    fn discarded(&self) -> u64 {
+        self.discarded
+    }
 }
 
 //------------------------------
@@ -386,10 +512,18 @@ impl Builder for DeltaBuilder {
 #[cfg(test)]
 mod stream_tests {
     use super::*;
+    use rand::prelude::*;
+    use rand_chacha::ChaCha20Rng;
+    use std::time::{Duration, Instant};
 
     fn mk_run(slab: u32, b: u32, e: u32) -> MapEntry {
+        mk_archive_run(0, slab, b, e)
+    }
+
+    fn mk_archive_run(archive: u8, slab: u32, b: u32, e: u32) -> MapEntry {
         assert!((e - b) < u16::MAX as u32);
         MapEntry::Data {
+            archive,
             slab,
             offset: b,
             nr_entries: e - b,
@@ -425,6 +559,49 @@ mod stream_tests {
             vec![mk_run(1, 1, 4)],
             vec![mk_run(1, 1, 1024)],
             vec![mk_run(1, 1, 16000)],
+            vec![
+                mk_run(2, 0, 3),
+                Delta {
+                    base_slab: 1,
+                    base_offset: 7,
+                    slab: 2,
+                    offset: 3,
+                    len: 4096,
+                    partial: None,
+                },
+                mk_run(2, 4, 6),
+                Delta {
+                    base_slab: 0,
+                    base_offset: 0,
+                    slab: 5,
+                    offset: 9,
+                    len: 4096,
+                    partial: Some((100, 200)),
+                },
+            ],
+            vec![
+                mk_run(3, 0, 2),
+                mk_archive_run(1, 3, 2, 4),
+                mk_archive_run(2, 0, 0, 1),
+                Partial {
+                    begin: 10,
+                    end: 20,
+                    archive: 2,
+                    slab: 7,
+                    offset: 1,
+                    nr_entries: 1,
+                },
+                mk_run(3, 2, 5),
+            ],
+            // Switching back to the previous register.
+            vec![
+                mk_run(0, 0, 10),
+                mk_run(5, 0, 3),
+                mk_run(0, 13, 20),
+                mk_run(9, 0, 2),
+                mk_run(0, 60, 61),
+                mk_run(9, 2, 4),
+            ],
         ];
 
         for t in tests {
@@ -448,6 +625,167 @@ mod stream_tests {
             assert_eq!(*t, actual);
         }
     }
+
+    #[test]
+    fn positions_resume() -> Result<()> {
+        // Non adjacent runs, so nothing is merged.
+        let entries: Vec<MapEntry> = (0..1000)
+            .map(|i| mk_run(i % 7, (i * 3) % 1000, (i * 3) % 1000 + 1))
+            .collect();
+
+        let mut builder = MappingBuilder::default();
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        for (i, e) in entries.iter().enumerate() {
+            let buf = if i < 500 { &mut first } else { &mut second };
+            builder.next(e, 16, buf)?;
+        }
+        builder.complete(&mut second)?;
+
+        let mut unpacker = MappingUnpacker::default();
+        let (mut actual, positions) = unpacker.unpack(&first)?;
+        assert!(!positions.is_empty());
+        for (pos, index) in positions {
+            assert_eq!(pos, index as u64 * 16);
+        }
+
+        // Decode the second half from a saved state.
+        let mut state = Vec::new();
+        unpacker.state().pack(&mut state)?;
+        assert_eq!(state.len(), VM_STATE_SIZE);
+        let mut unpacker = MappingUnpacker::with_state(VMState::unpack(&mut &state[..])?);
+        let (rest, _) = unpacker.unpack(&second)?;
+        actual.extend(rest);
+
+        assert_eq!(entries, actual);
+        Ok(())
+    }
+
+    fn build_slabs(entries: &[MapEntry], len: u64) -> Result<Vec<Vec<u8>>> {
+        let mut builder = MappingBuilder::default();
+        let mut buf = Vec::new();
+        for e in entries {
+            builder.next(e, len, &mut buf)?;
+        }
+        builder.complete(&mut buf)?;
+
+        let mut slabs = Vec::new();
+        let mut begin = 0;
+        for cut in builder.take_cuts() {
+            slabs.push(buf[begin..cut].to_vec());
+            begin = cut;
+        }
+        slabs.push(buf[begin..].to_vec());
+        Ok(slabs)
+    }
+
+    #[test]
+    fn cuts_are_content_aligned() -> Result<()> {
+        let len = 1024 * 1024;
+        let nr_entries = 4 * (CUT_SPAN / len) as u32;
+        let old: Vec<MapEntry> = (0..nr_entries)
+            .map(|i| mk_run(i % 7, i * 3, i * 3 + 1))
+            .collect();
+
+        // Change one entry in the second span.
+        let mut new = old.clone();
+        new[20] = mk_run(100, 0, 1);
+
+        let old_slabs = build_slabs(&old, len)?;
+        let new_slabs = build_slabs(&new, len)?;
+        assert_eq!(old_slabs.len(), 4);
+        assert_eq!(new_slabs.len(), 4);
+        for i in [0, 2, 3] {
+            assert_eq!(old_slabs[i], new_slabs[i]);
+        }
+        assert_ne!(old_slabs[1], new_slabs[1]);
+
+        // Each slab decodes without the state left by the ones before.
+        let per_slab = (CUT_SPAN / len) as usize;
+        for (i, slab) in new_slabs.iter().enumerate() {
+            let (actual, _) = unpack(slab)?;
+            assert_eq!(actual, new[i * per_slab..(i + 1) * per_slab]);
+        }
+        Ok(())
+    }
+
+    // Builds the streams of successive snapshots of a device, each of
+    // which rewrites 2% of its origin in short scattered runs, with the
+    // new chunks appended to the archive.  Returns each snapshot's chunks.
+    fn snapshot_generations(nr_chunks: usize, nr_generations: usize) -> Vec<Vec<(u32, u32)>> {
+        const CHUNKS_PER_SLAB: u32 = 1024;
+
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let mut next = 0;
+        let mut alloc = || {
+            next += 1;
+            ((next - 1) / CHUNKS_PER_SLAB, (next - 1) % CHUNKS_PER_SLAB)
+        };
+
+        let mut chunks: Vec<(u32, u32)> = (0..nr_chunks).map(|_| alloc()).collect();
+        let mut generations = Vec::new();
+        for _ in 0..nr_generations {
+            let mut rewritten = 0;
+            while rewritten < nr_chunks / 50 {
+                let len = rng.gen_range(1..=8);
+                let begin = rng.gen_range(0..nr_chunks - len);
+                for c in &mut chunks[begin..begin + len] {
+                    *c = alloc();
+                }
+                rewritten += len;
+            }
+            generations.push(chunks.clone());
+        }
+        generations
+    }
+
+    fn encode_generations(
+        generations: &[Vec<(u32, u32)>],
+        vm_state: fn() -> VMState,
+    ) -> Result<(usize, Duration)> {
+        let mut size = 0;
+        let mut elapsed = Duration::ZERO;
+        for chunks in generations {
+            let entries: Vec<MapEntry> = chunks
+                .iter()
+                .map(|(slab, offset)| mk_run(*slab, *offset, *offset + 1))
+                .collect();
+
+            let mut builder = MappingBuilder {
+                vm_state: vm_state(),
+                ..Default::default()
+            };
+            let mut buf = Vec::new();
+            let start = Instant::now();
+            for e in &entries {
+                builder.next(e, 4096, &mut buf)?;
+            }
+            builder.complete(&mut buf)?;
+            elapsed += start.elapsed();
+            size += buf.len();
+        }
+        Ok((size, elapsed))
+    }
+
+    // The figures in doc/Design.md come from running this with
+    // 'cargo test --release snapshot_streams -- --nocapture'.
+    #[test]
+    fn snapshot_streams_are_smaller_with_ret() -> Result<()> {
+        let generations = snapshot_generations(256 * 1024, 16);
+        let (size, time) = encode_generations(&generations, VMState::default)?;
+        let (old_size, old_time) = encode_generations(&generations, VMState::without_shortcuts)?;
+
+        println!(
+            "{} snapshots: {} bytes in {:?} (without ret or the ordered search: {} bytes in {:?})",
+            generations.len(),
+            size,
+            time,
+            old_size,
+            old_time
+        );
+        assert!(size < old_size);
+        Ok(())
+    }
 }
 
 //-----------------------------------------
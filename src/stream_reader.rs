@@ -0,0 +1,550 @@
+use anyhow::{anyhow, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::archive::{Data, SLAB_SIZE_TARGET};
+use crate::chunk_delta;
+use crate::config;
+use crate::paths::*;
+use crate::slab::builder::*;
+use crate::stream::*;
+use crate::stream_builders::split_entry;
+use crate::stream_slabs::StreamSlabs;
+
+//-----------------------------------------
+
+/// Records where each stream slab starts, both as a logical byte offset
+/// and as the vm state needed to decode it.  Built from the Pos
+/// instructions in the stream when it's written, and kept alongside it.
+pub struct SeekIndex {
+    starts: Vec<u64>,
+    states: Vec<Vec<u8>>,
+}
+
+fn entry_len(archive: &mut Data, e: &MapEntry) -> Result<u64> {
+    use MapEntry::*;
+    match e {
+        Fill { len, .. } | Unmapped { len } => Ok(*len),
+        Data {
+            archive: a,
+            slab,
+            offset,
+            nr_entries,
+        } => archive.data_len(*a, *slab, *offset, *nr_entries),
+        Partial { begin, end, .. } => Ok((end - begin) as u64),
+        Delta { len, partial, .. } => {
+            let (begin, end) = partial.unwrap_or((0, *len));
+            Ok((end - begin) as u64)
+        }
+        Ref { .. } => Err(anyhow!("unexpected MapEntry::Ref in stream")),
+    }
+}
+
+impl SeekIndex {
+    fn build(stream_file: &mut StreamSlabs, archive: &mut Data, size: u64) -> Result<Self> {
+        let nr_slabs = stream_file.get_nr_slabs();
+        let mut starts = Vec::with_capacity(nr_slabs);
+        let mut states = Vec::with_capacity(nr_slabs);
+        let mut unpacker = MappingUnpacker::default();
+        let mut pos = 0;
+
+        for s in 0..nr_slabs {
+            let mut state = Vec::with_capacity(VM_STATE_SIZE);
+            unpacker.state().pack(&mut state)?;
+            starts.push(pos);
+            states.push(state);
+
+            // Only the entries after the last Pos need their lengths
+            // looking up.
+            let buf = stream_file.read(s as u32)?;
+            let (entries, positions) = unpacker.unpack(&buf[..])?;
+            let (mut end, first) = positions.last().cloned().unwrap_or((pos, 0));
+            for e in &entries[first..] {
+                end += entry_len(archive, e)?;
+            }
+            pos = end;
+        }
+
+        if pos != size {
+            return Err(anyhow!(
+                "stream length ({}) doesn't match its config ({})",
+                pos,
+                size
+            ));
+        }
+
+        Ok(Self { starts, states })
+    }
+
+    fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = SlabFileBuilder::open(path).build()?;
+        if file.get_nr_slabs() != 1 {
+            return Err(anyhow!("seek index should have a single slab"));
+        }
+
+        let buf = file.read(0)?;
+        let mut c = Cursor::new(&buf[..]);
+        let nr_slabs = c.read_u32::<LittleEndian>()? as usize;
+        let mut starts = Vec::with_capacity(nr_slabs);
+        let mut states = Vec::with_capacity(nr_slabs);
+        for _ in 0..nr_slabs {
+            starts.push(c.read_u64::<LittleEndian>()?);
+            let mut state = vec![0; VM_STATE_SIZE];
+            c.read_exact(&mut state)?;
+            states.push(state);
+        }
+
+        // Indexes written with a different vm state size get rebuilt.
+        if c.position() != buf.len() as u64 {
+            return Err(anyhow!("seek index has an unexpected size"));
+        }
+
+        Ok(Self { starts, states })
+    }
+
+    fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut buf = Vec::with_capacity(4 + self.starts.len() * (8 + VM_STATE_SIZE));
+        buf.write_u32::<LittleEndian>(self.starts.len() as u32)?;
+        for (start, state) in self.starts.iter().zip(&self.states) {
+            buf.write_u64::<LittleEndian>(*start)?;
+            buf.extend_from_slice(state);
+        }
+
+        let mut file = SlabFileBuilder::create(path)
+            .queue_depth(1)
+            .compressed(true)
+            .build()?;
+        file.write_slab(&buf)?;
+        file.close()?;
+        Ok(())
+    }
+
+    /// Builds and writes the index for a newly written stream.  The
+    /// lengths of its data entries are read back from the archive, so
+    /// any writer must have closed it first.
+    pub fn create(stream: &str, archive: &mut Data) -> Result<()> {
+        let mut stream_file = StreamSlabs::open(stream)?;
+        let size = config::read_stream_config(stream)?.size;
+        let index = Self::build(&mut stream_file, archive, size)?;
+        index
+            .write(stream_seek_index(stream))
+            .context("couldn't write seek index")
+    }
+
+    /// Reads the index for a stream.  Streams packed before indexes were
+    /// written, or whose index is out of date, have it built in memory;
+    /// nothing is written since readers open the archive read only.
+    pub fn open(stream: &str, stream_file: &mut StreamSlabs, archive: &mut Data) -> Result<Self> {
+        if let Ok(index) = Self::read(stream_seek_index(stream)) {
+            if index.starts.len() == stream_file.get_nr_slabs() {
+                return Ok(index);
+            }
+        }
+
+        let size = config::read_stream_config(stream)?.size;
+        Self::build(stream_file, archive, size)
+    }
+
+    /// Returns the stream slab holding the given byte.
+    fn find_slab(&self, pos: u64) -> usize {
+        // Empty slabs share their start with the next one, so take the
+        // last match.
+        self.starts.partition_point(|s| *s <= pos).saturating_sub(1)
+    }
+}
+
+//-----------------------------------------
+
+// Finds the entry holding byte 'target' of a decoded stream slab,
+// returning its index and starting byte.  The walk starts from the
+// closest of the slab start, the cursor and the last Pos before target.
+fn find_entry<F>(
+    entries: &[MapEntry],
+    positions: &[(u64, usize)],
+    slab_start: u64,
+    cursor: (usize, u64),
+    target: u64,
+    mut len_fn: F,
+) -> Result<(usize, u64)>
+where
+    F: FnMut(&MapEntry) -> Result<u64>,
+{
+    let mut start = (0, slab_start);
+    if cursor.1 <= target {
+        start = cursor;
+    }
+
+    let i = positions.partition_point(|(pos, _)| *pos <= target);
+    if i > 0 {
+        let (pos, index) = positions[i - 1];
+        if pos > start.1 {
+            start = (index, pos);
+        }
+    }
+
+    let (mut index, mut pos) = start;
+    loop {
+        let e = entries
+            .get(index)
+            .ok_or_else(|| anyhow!("byte {} is beyond the stream slab", target))?;
+        let len = len_fn(e)?;
+        if pos + len > target {
+            return Ok((index, pos));
+        }
+        pos += len;
+        index += 1;
+    }
+}
+
+struct DecodedSlab {
+    slab: usize,
+    entries: Vec<MapEntry>,
+    positions: Vec<(u64, usize)>,
+
+    // The entry last read from, and its starting byte.
+    cursor: (usize, u64),
+}
+
+/// Random access to the contents of a stream.  Only the stream slab and
+/// data slabs covering the bytes read are touched.
+pub struct StreamReader {
+    archive: Arc<Mutex<Data>>,
+    stream_file: StreamSlabs,
+    index: SeekIndex,
+    size: u64,
+    pos: u64,
+
+    current: Option<DecodedSlab>,
+
+    // The last delta entry rebuilt, keyed by its (slab, offset).
+    delta: Option<((u32, u32), Vec<u8>)>,
+}
+
+/// Opens the archive's data for reading, along with any parents.  The
+/// archive is opened read only, so nothing is written back to it.
+/// Assumes current directory is the root of the archive.
+pub fn open_archive(config: &config::Config) -> Result<Data> {
+    let cache_nr_entries = (1024 * 1024 * config.data_cache_size_meg) / SLAB_SIZE_TARGET;
+    open_archive_with(&config.parents, cache_nr_entries)
+}
+
+/// As open_archive, with the parents and cache size given directly.
+pub fn open_archive_with(parents: &[String], cache_nr_entries: usize) -> Result<Data> {
+    let data_file = SlabFileBuilder::open(data_path())
+        .cache_nr_entries(cache_nr_entries)
+        .build()?;
+    let hashes_file = Arc::new(Mutex::new(SlabFileBuilder::open(hashes_path()).build()?));
+
+    let mut archive = Data::open_read_only(data_file, hashes_file, cache_nr_entries)?;
+    archive.open_parents(parents, cache_nr_entries)?;
+    Ok(archive)
+}
+
+impl StreamReader {
+    // Assumes current directory is the root of the archive.
+    pub fn new(stream: &str, config: &config::Config) -> Result<Self> {
+        Self::with_archive(stream, Arc::new(Mutex::new(open_archive(config)?)))
+    }
+
+    /// Several readers may share the archive, and hence its caches.
+    pub fn with_archive(stream: &str, archive: Arc<Mutex<Data>>) -> Result<Self> {
+        let mut stream_file = StreamSlabs::open(stream)?;
+        let size = config::read_stream_config(stream)?.size;
+        let index = SeekIndex::open(stream, &mut stream_file, &mut archive.lock().unwrap())?;
+
+        Ok(Self {
+            archive,
+            stream_file,
+            index,
+            size,
+            pos: 0,
+            current: None,
+            delta: None,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn load_slab(&mut self, slab: usize) -> Result<()> {
+        if let Some(current) = &self.current {
+            if current.slab == slab {
+                return Ok(());
+            }
+        }
+
+        let state = VMState::unpack(&mut &self.index.states[slab][..])?;
+        let mut unpacker = MappingUnpacker::with_state(state);
+        let buf = self.stream_file.read(slab as u32)?;
+        let (entries, positions) = unpacker.unpack(&buf[..])?;
+        self.current = Some(DecodedSlab {
+            slab,
+            entries,
+            positions,
+            cursor: (0, self.index.starts[slab]),
+        });
+        Ok(())
+    }
+
+    // Copies bytes from 'offset' within an entry, returning how many were copied.
+    fn read_entry(&mut self, e: &MapEntry, offset: u64, out: &mut [u8]) -> Result<usize> {
+        use MapEntry::*;
+
+        let mut data_archive = self.archive.lock().unwrap();
+        let len = entry_len(&mut data_archive, e)?;
+        let n = std::cmp::min(len - offset, out.len() as u64) as usize;
+        let offset = offset as usize;
+        match e {
+            Fill { byte, .. } => out[..n].fill(*byte),
+            Unmapped { .. } => out[..n].fill(0),
+            Data {
+                archive,
+                slab,
+                offset: data_offset,
+                nr_entries,
+            } => {
+                let (data, start, _end) =
+                    data_archive.data_get(*archive, *slab, *data_offset, *nr_entries, None)?;
+                out[..n].copy_from_slice(&data[start + offset..start + offset + n]);
+            }
+            Partial {
+                begin,
+                end,
+                archive,
+                slab,
+                offset: data_offset,
+                nr_entries,
+            } => {
+                let partial = Some((*begin, *end));
+                let (data, start, _end) =
+                    data_archive.data_get(*archive, *slab, *data_offset, *nr_entries, partial)?;
+                out[..n].copy_from_slice(&data[start + offset..start + offset + n]);
+            }
+            Delta {
+                base_slab,
+                base_offset,
+                slab,
+                offset: delta_offset,
+                len,
+                partial,
+            } => {
+                let key = (*slab, *delta_offset);
+                if self.delta.as_ref().map(|(k, _)| *k) != Some(key) {
+                    let (base, base_start, base_end) =
+                        data_archive.data_get(0, *base_slab, *base_offset, 1, None)?;
+                    let (delta, start, end) =
+                        data_archive.data_get(0, *slab, *delta_offset, 1, None)?;
+                    let data = chunk_delta::apply(&base[base_start..base_end], &delta[start..end])?;
+                    if data.len() != *len as usize {
+                        return Err(anyhow!("delta entry rebuilt to the wrong length"));
+                    }
+                    self.delta = Some((key, data));
+                }
+
+                let data = &self.delta.as_ref().unwrap().1;
+                let begin = partial.map(|(begin, _)| begin as usize).unwrap_or(0);
+                out[..n].copy_from_slice(&data[begin + offset..begin + offset + n]);
+            }
+            Ref { .. } => {
+                return Err(anyhow!("unexpected MapEntry::Ref (shouldn't be possible)"));
+            }
+        }
+
+        Ok(n)
+    }
+
+    // Returns the entry holding the current position, and the byte it starts at.
+    fn locate(&mut self) -> Result<(MapEntry, u64)> {
+        let slab = self.index.find_slab(self.pos);
+        self.load_slab(slab)?;
+
+        let current = self.current.as_mut().unwrap();
+        let mut archive = self.archive.lock().unwrap();
+        let (index, start) = find_entry(
+            &current.entries,
+            &current.positions,
+            self.index.starts[slab],
+            current.cursor,
+            self.pos,
+            |e| entry_len(&mut archive, e),
+        )?;
+        current.cursor = (index, start);
+        Ok((current.entries[index], start))
+    }
+
+    /// Returns whether the bytes at the current position are mapped, and
+    /// how many of the following bytes are in the same state.  Lets
+    /// callers skip unmapped regions without reading them.  None is
+    /// returned at the end of the stream.
+    pub fn extent(&mut self) -> Result<Option<(bool, u64)>> {
+        if self.pos >= self.size {
+            return Ok(None);
+        }
+
+        let (e, start) = self.locate()?;
+        let len = entry_len(&mut self.archive.lock().unwrap(), &e)?;
+        let mapped = !matches!(e, MapEntry::Unmapped { .. });
+        Ok(Some((mapped, start + len - self.pos)))
+    }
+
+    /// Finds the first byte at or after 'from' that is mapped (or
+    /// unmapped), for SEEK_DATA and SEEK_HOLE.  The end of the stream
+    /// counts as a hole.  Leaves the position at the result.
+    pub fn find_extent(&mut self, from: u64, mapped: bool) -> Result<Option<u64>> {
+        if from >= self.size {
+            return Ok(None);
+        }
+
+        self.pos = from;
+        while let Some((m, len)) = self.extent()? {
+            if m == mapped {
+                return Ok(Some(self.pos));
+            }
+            self.pos += len;
+        }
+
+        Ok(if mapped { None } else { Some(self.size) })
+    }
+
+    /// Returns the entry at the current position, trimmed to start
+    /// there and to be no longer than max_len, along with its length.
+    /// The position moves past it.  None at the end of the stream.
+    pub fn next_entry(&mut self, max_len: u64) -> Result<Option<(MapEntry, u64)>> {
+        if self.pos >= self.size || max_len == 0 {
+            return Ok(None);
+        }
+
+        let (mut e, start) = self.locate()?;
+        let mut len = entry_len(&mut self.archive.lock().unwrap(), &e)?;
+        let skip = self.pos - start;
+        if skip > 0 {
+            e = split_entry(&e, len, skip).1;
+            len -= skip;
+        }
+        if len > max_len {
+            e = split_entry(&e, len, max_len).0;
+            len = max_len;
+        }
+
+        self.pos += len;
+        Ok(Some((e, len)))
+    }
+
+    fn read_(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut copied = 0;
+        while copied < buf.len() && self.pos < self.size {
+            let (e, start) = self.locate()?;
+            let n = self.read_entry(&e, self.pos - start, &mut buf[copied..])?;
+            copied += n;
+            self.pos += n as u64;
+        }
+
+        Ok(copied)
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_(buf).map_err(io::Error::other)
+    }
+}
+
+impl Seek for StreamReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+//-----------------------------------------
+
+#[cfg(test)]
+mod stream_reader_tests {
+    use super::*;
+
+    fn fill_len(e: &MapEntry) -> Result<u64> {
+        match e {
+            MapEntry::Fill { len, .. } => Ok(*len),
+            _ => Err(anyhow!("unexpected entry")),
+        }
+    }
+
+    #[test]
+    fn find_entry_uses_positions() -> Result<()> {
+        // Entry i is i + 1 bytes long, and starts 1000 bytes into the stream.
+        let entries: Vec<MapEntry> = (0..100)
+            .map(|i| MapEntry::Fill {
+                byte: 0,
+                len: i + 1,
+            })
+            .collect();
+        let mut starts = Vec::new();
+        let mut pos = 1000;
+        for i in 0..100 {
+            starts.push(pos);
+            pos += i + 1;
+        }
+        let positions: Vec<(u64, usize)> = (1..10).map(|i| (starts[i * 10], i * 10)).collect();
+
+        for target in [1000, 1001, 1500, 2000, pos - 1] {
+            let expected = starts.partition_point(|s| *s <= target) - 1;
+
+            // From the start of the slab.
+            let r = find_entry(&entries, &positions, 1000, (0, 1000), target, fill_len)?;
+            assert_eq!(r, (expected, starts[expected]));
+
+            // From a cursor that is past the target.
+            let r = find_entry(
+                &entries,
+                &positions,
+                1000,
+                (99, starts[99]),
+                target,
+                fill_len,
+            )?;
+            assert_eq!(r, (expected, starts[expected]));
+        }
+
+        assert!(find_entry(&entries, &positions, 1000, (0, 1000), pos, fill_len).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn seek_index_round_trip() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let path = td.path().join("seek_index");
+
+        let index = SeekIndex {
+            starts: vec![0, 4096, 4096, 1 << 40],
+            states: (0..4u8).map(|i| vec![i; VM_STATE_SIZE]).collect(),
+        };
+        index.write(&path)?;
+
+        let index2 = SeekIndex::read(&path)?;
+        assert_eq!(index.starts, index2.starts);
+        assert_eq!(index.states, index2.states);
+        assert_eq!(index2.find_slab(0), 0);
+        assert_eq!(index2.find_slab(4096), 2);
+        assert_eq!(index2.find_slab(1 << 41), 3);
+        Ok(())
+    }
+}
+
+//-----------------------------------------
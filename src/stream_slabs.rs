@@ -0,0 +1,248 @@
+use anyhow::{anyhow, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::hash::*;
+use crate::paths::*;
+use crate::slab::builder::*;
+use crate::slab::*;
+
+//-----------------------------------------
+
+// Stream slabs are deduplicated across streams.  Each unique slab is
+// stored once in a shared slab file, and a stream just holds the list of
+// shared slabs it's made from.  The builder cuts slabs at fixed logical
+// positions, so streams of similar devices share most of their slabs.
+//
+// Streams packed before this existed have their slabs in a private
+// file, which we still read.
+
+const HASH_RECORD_SIZE: usize = 4 + 32;
+
+fn read_refs<P: AsRef<Path>>(path: P) -> Result<Vec<u32>> {
+    let mut file = SlabFileBuilder::open(path).build()?;
+    if file.get_nr_slabs() != 1 {
+        return Err(anyhow!("stream slab refs should have a single slab"));
+    }
+
+    let buf = file.read(0)?;
+    let mut c = Cursor::new(&buf[..]);
+    let nr_refs = c.read_u32::<LittleEndian>()? as usize;
+    let mut refs = Vec::with_capacity(nr_refs);
+    for _ in 0..nr_refs {
+        refs.push(c.read_u32::<LittleEndian>()?);
+    }
+    Ok(refs)
+}
+
+fn write_refs<P: AsRef<Path>>(path: P, refs: &[u32]) -> Result<u64> {
+    let mut buf = Vec::with_capacity(4 + refs.len() * 4);
+    buf.write_u32::<LittleEndian>(refs.len() as u32)?;
+    for r in refs {
+        buf.write_u32::<LittleEndian>(*r)?;
+    }
+
+    let mut file = SlabFileBuilder::create(path)
+        .queue_depth(1)
+        .compressed(true)
+        .build()?;
+    file.write_slab(&buf)?;
+    file.close()?;
+    Ok(file.get_file_size())
+}
+
+// Each record is the index of a shared slab followed by the hash of its
+// contents.  Records are only appended once the slabs they describe have
+// been committed, and ones referring to slabs that don't exist are
+// ignored.
+fn read_slab_hashes(nr_slabs: usize) -> Result<HashMap<Hash256, u32>> {
+    let mut hashes = HashMap::new();
+    let mut buf = Vec::new();
+    match std::fs::File::open(stream_slab_hashes_path()) {
+        Ok(mut file) => {
+            file.read_to_end(&mut buf)?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(hashes),
+        Err(e) => return Err(e.into()),
+    }
+
+    for rec in buf.chunks_exact(HASH_RECORD_SIZE) {
+        let slab = u32::from_le_bytes(rec[0..4].try_into().unwrap());
+        if (slab as usize) < nr_slabs {
+            hashes.insert(*Hash256::from_slice(&rec[4..]), slab);
+        }
+    }
+    Ok(hashes)
+}
+
+fn append_slab_hashes(new_hashes: &[(u32, Hash256)]) -> Result<()> {
+    let mut buf = Vec::with_capacity(new_hashes.len() * HASH_RECORD_SIZE);
+    for (slab, h) in new_hashes {
+        buf.write_u32::<LittleEndian>(*slab)?;
+        buf.extend_from_slice(h);
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(stream_slab_hashes_path())?;
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+//-----------------------------------------
+
+/// Read access to the slabs of a stream, wherever they're stored.
+pub struct StreamSlabs {
+    // None if the stream has its own slab file.
+    refs: Option<Vec<u32>>,
+    file: SlabFile,
+}
+
+impl StreamSlabs {
+    // Assumes current directory is the root of the archive.
+    pub fn open(stream: &str) -> Result<Self> {
+        let refs_path = stream_slab_refs(stream);
+        if refs_path.exists() {
+            let refs = read_refs(refs_path)?;
+            let file = SlabFileBuilder::open(stream_slabs_path()).build()?;
+            Ok(Self {
+                refs: Some(refs),
+                file,
+            })
+        } else {
+            let file = SlabFileBuilder::open(stream_path(stream)).build()?;
+            Ok(Self { refs: None, file })
+        }
+    }
+
+    pub fn get_nr_slabs(&self) -> usize {
+        match &self.refs {
+            Some(refs) => refs.len(),
+            None => self.file.get_nr_slabs(),
+        }
+    }
+
+    pub fn read(&mut self, slab: u32) -> Result<Arc<Vec<u8>>> {
+        match &self.refs {
+            Some(refs) => {
+                let shared = *refs
+                    .get(slab as usize)
+                    .ok_or_else(|| anyhow!("stream slab {} out of range", slab))?;
+                self.file.read(shared)
+            }
+            None => self.file.read(slab),
+        }
+    }
+}
+
+//-----------------------------------------
+
+/// Writes the slabs of a new stream into the shared store, only
+/// storing those it hasn't seen before.
+pub struct StreamSlabsWriter {
+    refs_path: PathBuf,
+    refs: Vec<u32>,
+    file: SlabFile,
+    hashes: HashMap<Hash256, u32>,
+    new_hashes: Vec<(u32, Hash256)>,
+    next_slab: u32,
+
+    initial_size: u64,
+    written: u64,
+}
+
+impl StreamSlabsWriter {
+    // Assumes current directory is the root of the archive.
+    pub fn new(stream: &str) -> Result<Self> {
+        // Archives created before the store existed get one on first use.
+        if !stream_slabs_path().exists() {
+            SlabFileBuilder::create(stream_slabs_path())
+                .queue_depth(1)
+                .compressed(true)
+                .build()?
+                .close()?;
+        }
+
+        let file = SlabFileBuilder::open(stream_slabs_path())
+            .write(true)
+            .queue_depth(16)
+            .build()
+            .context("couldn't open stream slab file")?;
+        let next_slab = file.get_nr_slabs() as u32;
+        let hashes = read_slab_hashes(next_slab as usize)?;
+        let initial_size = file.get_file_size();
+
+        Ok(Self {
+            refs_path: stream_slab_refs(stream),
+            refs: Vec::new(),
+            file,
+            hashes,
+            new_hashes: Vec::new(),
+            next_slab,
+            initial_size,
+            written: 0,
+        })
+    }
+
+    pub fn write_slab(&mut self, data: &[u8]) -> Result<()> {
+        let h = hash_256(data);
+        let slab = match self.hashes.get(&h) {
+            Some(slab) => *slab,
+            None => {
+                let slab = self.next_slab;
+                self.next_slab += 1;
+                self.file.write_slab(data)?;
+                self.hashes.insert(h, slab);
+                self.new_hashes.push((slab, h));
+                slab
+            }
+        };
+        self.refs.push(slab);
+        Ok(())
+    }
+
+    /// Writes out the buffer up to each of the cuts (offsets into buf),
+    /// followed by whatever remains if it's larger than threshold.
+    pub fn complete_slabs(
+        &mut self,
+        buf: &mut Vec<u8>,
+        cuts: &[usize],
+        threshold: usize,
+    ) -> Result<()> {
+        let mut begin = 0;
+        for &cut in cuts {
+            self.write_slab(&buf[begin..cut])?;
+            begin = cut;
+        }
+        buf.drain(..begin);
+
+        if buf.len() > threshold {
+            self.write_slab(buf)?;
+            buf.clear();
+        }
+        Ok(())
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        self.file.close()?;
+        append_slab_hashes(&self.new_hashes)?;
+        self.new_hashes.clear();
+
+        let refs_size = write_refs(&self.refs_path, &self.refs)?;
+        self.written = self.file.get_file_size() - self.initial_size + refs_size;
+        Ok(())
+    }
+
+    /// Bytes of stream metadata added to the archive, only valid after
+    /// close.
+    pub fn get_file_size(&self) -> u64 {
+        self.written
+    }
+}
+
+//-----------------------------------------
@@ -5,6 +5,7 @@ use nom::IResult;
 use roaring::bitmap::RoaringBitmap;
 use std::collections::*;
 use std::fs::OpenOptions;
+use std::io::{BufReader, Read};
 use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -15,14 +16,18 @@ use thinp::pdata::btree::*;
 use thinp::pdata::btree_walker::*;
 use thinp::thin::block_time::*;
 use thinp::thin::device_detail::*;
+use thinp::thin::ir::{self, MetadataVisitor, Visit};
 use thinp::thin::superblock::*;
+use thinp::thin::xml;
 use udev::Enumerator;
 
+use crate::chunkers::PoolRegion;
+
 //---------------------------------
 
-type DevMap = BTreeMap<(u32, u32), DmNameBuf>;
+pub(crate) type DevMap = BTreeMap<(u32, u32), DmNameBuf>;
 
-fn collect_dm_devs(dm: &mut DM) -> Result<DevMap> {
+pub(crate) fn collect_dm_devs(dm: &mut DM) -> Result<DevMap> {
     let mut devs_by_nr = BTreeMap::new();
     for (name, dev, _) in dm.list_devices()? {
         devs_by_nr.insert((dev.major, dev.minor), name);
@@ -118,6 +123,10 @@ pub struct ThinInfo {
     pub data_block_size: u32,
     pub details: DeviceDetail,
     pub provisioned_blocks: RoaringBitmap,
+
+    // The dm uuids of the pool and thin, if they have them.
+    pub pool_uuid: Option<String>,
+    pub dm_uuid: Option<String>,
 }
 
 fn read_info(metadata: &Path, thin_id: u32) -> Result<ThinInfo> {
@@ -159,9 +168,196 @@ fn read_info(metadata: &Path, thin_id: u32) -> Result<ThinInfo> {
         data_block_size: sb.data_block_size,
         details,
         provisioned_blocks,
+        pool_uuid: None,
+        dm_uuid: None,
     })
 }
 
+// A position in a walk of a mapping tree.  Keys of a node lie within
+// [lo, hi), lo being the key its parent holds for it.
+#[derive(Debug, Clone, Copy)]
+enum WalkItem {
+    Node {
+        block: u64,
+        lo: u64,
+        hi: u64,
+        is_root: bool,
+    },
+    Mapping {
+        key: u64,
+        value: BlockTime,
+    },
+}
+
+// Walks a mapping tree in key order, only reading a node when it's
+// expanded.
+struct TreeCursor {
+    // The next item is at the end.
+    stack: Vec<WalkItem>,
+}
+
+impl TreeCursor {
+    fn new(root: u64) -> Self {
+        Self {
+            stack: vec![WalkItem::Node {
+                block: root,
+                lo: 0,
+                hi: u64::MAX,
+                is_root: true,
+            }],
+        }
+    }
+
+    fn peek(&self) -> Option<WalkItem> {
+        self.stack.last().cloned()
+    }
+
+    fn pop(&mut self) {
+        self.stack.pop();
+    }
+
+    // Replaces the node at the top with its children.
+    fn expand<F>(&mut self, read_node: &F) -> Result<()>
+    where
+        F: Fn(u64, bool) -> Result<Node<BlockTime>>,
+    {
+        let (block, hi, is_root) = match self.stack.pop() {
+            Some(WalkItem::Node {
+                block, hi, is_root, ..
+            }) => (block, hi, is_root),
+            _ => return Err(anyhow!("internal error: expanding a mapping")),
+        };
+
+        match read_node(block, is_root)? {
+            Node::Internal { keys, values, .. } => {
+                for i in (0..keys.len()).rev() {
+                    let child_hi = keys.get(i + 1).cloned().unwrap_or(hi);
+                    self.stack.push(WalkItem::Node {
+                        block: values[i],
+                        lo: keys[i],
+                        hi: child_hi,
+                        is_root: false,
+                    });
+                }
+            }
+            Node::Leaf { keys, values, .. } => {
+                for (key, value) in keys.iter().zip(values.iter()).rev() {
+                    self.stack.push(WalkItem::Mapping {
+                        key: *key,
+                        value: *value,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn lo_key(item: &WalkItem) -> u64 {
+    match item {
+        WalkItem::Node { lo, .. } => *lo,
+        WalkItem::Mapping { key, .. } => *key,
+    }
+}
+
+// Walks the two mapping trees in step.  Snapshots share most of their
+// nodes, and a node shared by both trees holds the same mappings in
+// each, so it's skipped without being read.  Only the nodes that differ
+// are expanded, so the cost is proportional to the size of the delta
+// rather than that of the devices.
+fn diff_mapping_trees<F>(
+    old_root: u64,
+    new_root: u64,
+    read_node: F,
+) -> Result<(RoaringBitmap, RoaringBitmap)>
+where
+    F: Fn(u64, bool) -> Result<Node<BlockTime>>,
+{
+    use WalkItem::*;
+
+    let mut additions = RoaringBitmap::default();
+    let mut removals = RoaringBitmap::default();
+    let mut old = TreeCursor::new(old_root);
+    let mut new = TreeCursor::new(new_root);
+
+    loop {
+        match (old.peek(), new.peek()) {
+            (None, None) => break,
+            (Some(Node { .. }), None) => old.expand(&read_node)?,
+            (None, Some(Node { .. })) => new.expand(&read_node)?,
+            (Some(Mapping { key, .. }), None) => {
+                removals.insert(key as u32);
+                old.pop();
+            }
+            (None, Some(Mapping { key, .. })) => {
+                additions.insert(key as u32);
+                new.pop();
+            }
+            (Some(Node { block: b1, .. }), Some(Node { block: b2, .. })) if b1 == b2 => {
+                // Shared, so nothing has changed in here.
+                old.pop();
+                new.pop();
+            }
+            (Some(Mapping { key: k1, value: v1 }), Some(Mapping { key: k2, value: v2 })) => {
+                use std::cmp::Ordering::*;
+                match k1.cmp(&k2) {
+                    Less => {
+                        removals.insert(k1 as u32);
+                        old.pop();
+                    }
+                    Greater => {
+                        additions.insert(k2 as u32);
+                        new.pop();
+                    }
+                    Equal => {
+                        if v1 != v2 {
+                            additions.insert(k1 as u32);
+                        }
+                        old.pop();
+                        new.pop();
+                    }
+                }
+            }
+            (Some(Mapping { key, .. }), Some(n @ Node { .. })) => {
+                // Nothing in the new tree can match a key before the node.
+                if key < lo_key(&n) {
+                    removals.insert(key as u32);
+                    old.pop();
+                } else {
+                    new.expand(&read_node)?;
+                }
+            }
+            (Some(n @ Node { .. }), Some(Mapping { key, .. })) => {
+                if key < lo_key(&n) {
+                    additions.insert(key as u32);
+                    new.pop();
+                } else {
+                    old.expand(&read_node)?;
+                }
+            }
+            (
+                Some(Node {
+                    lo: lo1, hi: hi1, ..
+                }),
+                Some(Node {
+                    lo: lo2, hi: hi2, ..
+                }),
+            ) => {
+                // Expand the one that starts first, or the wider of the
+                // two, so the walks converge on the same level of the
+                // trees where sharing can be spotted.
+                if (lo1, std::cmp::Reverse(hi1)) <= (lo2, std::cmp::Reverse(hi2)) {
+                    old.expand(&read_node)?;
+                } else {
+                    new.expand(&read_node)?;
+                }
+            }
+        }
+    }
+
+    Ok((additions, removals))
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct DeltaInfo {
@@ -170,6 +366,8 @@ pub struct DeltaInfo {
     pub details: DeviceDetail,
     pub additions: RoaringBitmap,
     pub removals: RoaringBitmap,
+    pub pool_uuid: Option<String>,
+    pub dm_uuid: Option<String>,
 }
 
 fn read_delta_info(metadata: &Path, old_thin_id: u32, new_thin_id: u32) -> Result<DeltaInfo> {
@@ -197,44 +395,706 @@ fn read_delta_info(metadata: &Path, old_thin_id: u32, new_thin_id: u32) -> Resul
     let mut path = vec![];
     let roots: BTreeMap<u64, u64> = btree_to_map(&mut path, engine.clone(), true, sb.mapping_root)?;
 
-    // This is slow, we need to walk both trees simultaneously to do delta properly.
-    let old_root = roots.get(&(old_thin_id as u64)).unwrap();
-    let old_mappings: BTreeMap<u64, BlockTime> =
-        btree_to_map(&mut path, engine.clone(), true, *old_root)?;
+    let old_root = roots
+        .get(&(old_thin_id as u64))
+        .ok_or_else(|| anyhow!("couldn't find mappings for thin device {}", old_thin_id))?;
+    let new_root = roots
+        .get(&(new_thin_id as u64))
+        .ok_or_else(|| anyhow!("couldn't find mappings for thin device {}", new_thin_id))?;
 
-    let new_root = roots.get(&(new_thin_id as u64)).unwrap();
-    let new_mappings: BTreeMap<u64, BlockTime> = btree_to_map(&mut path, engine, true, *new_root)?;
+    let ignore_non_fatal = true;
+    let read_node = |block: u64, is_root: bool| -> Result<Node<BlockTime>> {
+        let b = engine.read(block)?;
+        Ok(unpack_node::<BlockTime>(
+            &[block],
+            b.get_data(),
+            ignore_non_fatal,
+            is_root,
+        )?)
+    };
+    let (additions, removals) = diff_mapping_trees(*old_root, *new_root, read_node)?;
 
-    let mut additions = RoaringBitmap::default();
-    let mut removals = RoaringBitmap::default();
-    for (k, v1) in &old_mappings {
-        if let Some(v2) = new_mappings.get(k) {
-            if *v2 == *v1 {
-                // mapping hasn't changed
-            } else {
-                additions.insert(*k as u32);
+    Ok(DeltaInfo {
+        thin_id: new_thin_id,
+        data_block_size: sb.data_block_size,
+        details,
+        additions,
+        removals,
+        pool_uuid: None,
+        dm_uuid: None,
+    })
+}
+
+//---------------------------------
+
+/// The mappings of a thin device read from the pool metadata directly,
+/// rather than through an active pool.  Each run of provisioned blocks
+/// is (thin_begin, data_begin, len), in data blocks, and runs are in
+/// order.
+#[derive(Debug)]
+pub struct OfflineThinInfo {
+    pub thin_id: u32,
+    pub data_block_size: u32,
+    pub runs: Vec<(u64, u64, u64)>,
+}
+
+impl OfflineThinInfo {
+    pub fn nr_provisioned(&self) -> u64 {
+        self.runs.iter().map(|(_, _, len)| len).sum()
+    }
+
+    // The thin size isn't held in the metadata, this is just the end of
+    // the last mapping.
+    pub fn nr_mapped_blocks(&self) -> u64 {
+        self.runs
+            .last()
+            .map(|(begin, _, len)| begin + len)
+            .unwrap_or(0)
+    }
+}
+
+// Merges (thin, data) block pairs, in thin order, into runs.
+fn build_runs(mappings: &[(u64, u64)]) -> Vec<(u64, u64, u64)> {
+    let mut runs: Vec<(u64, u64, u64)> = Vec::new();
+    for (thin_b, data_b) in mappings {
+        if let Some((begin, data_begin, len)) = runs.last_mut() {
+            if *begin + *len == *thin_b && *data_begin + *len == *data_b {
+                *len += 1;
+                continue;
             }
-        } else {
-            // unmapped
-            removals.insert(*k as u32);
         }
+        runs.push((*thin_b, *data_b, 1));
     }
+    runs
+}
+
+#[derive(Default)]
+struct DataBlockCollector {
+    mappings: Mutex<Vec<(u64, u64)>>,
+}
 
-    for k in new_mappings.keys() {
-        if !old_mappings.contains_key(k) {
-            additions.insert(*k as u32);
+impl NodeVisitor<BlockTime> for DataBlockCollector {
+    fn visit(
+        &self,
+        _path: &[u64],
+        _kr: &KeyRange,
+        _header: &NodeHeader,
+        keys: &[u64],
+        values: &[BlockTime],
+    ) -> btree::Result<()> {
+        let mut mappings = self.mappings.lock().unwrap();
+        for (k, v) in keys.iter().zip(values.iter()) {
+            mappings.push((*k, v.block));
         }
+        Ok(())
     }
 
-    Ok(DeltaInfo {
-        thin_id: new_thin_id,
+    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
+        Ok(())
+    }
+
+    fn end_walk(&self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+fn read_runs(engine: Arc<dyn IoEngine + Send + Sync>, root: u64) -> Result<Vec<(u64, u64, u64)>> {
+    let ignore_non_fatal = true;
+    let walker = BTreeWalker::new(engine, ignore_non_fatal);
+    let collector = DataBlockCollector::default();
+    let mut path = vec![];
+    walker.walk(&mut path, &collector, root)?;
+
+    let mut mappings = collector.mappings.into_inner().unwrap();
+    mappings.sort_unstable();
+    Ok(build_runs(&mappings))
+}
+
+// Reads a metadata device, or an image of one.  The live superblock is
+// used, since there's no pool to have taken a metadata snapshot.
+fn read_offline_binary(metadata: &Path, thin_id: u32) -> Result<OfflineThinInfo> {
+    let engine = Arc::new(SyncIoEngine::new_with(metadata, false, false)?);
+    let sb = read_superblock(&*engine, SUPERBLOCK_LOCATION)?;
+
+    let mut path = vec![];
+    let roots: BTreeMap<u64, u64> = btree_to_map(&mut path, engine.clone(), true, sb.mapping_root)?;
+    let root = roots
+        .get(&(thin_id as u64))
+        .ok_or_else(|| anyhow!("couldn't find thin device with id {}", thin_id))?;
+
+    Ok(OfflineThinInfo {
+        thin_id,
         data_block_size: sb.data_block_size,
-        details,
-        additions,
-        removals,
+        runs: read_runs(engine, *root)?,
     })
 }
 
+// Collects every device out of thin_dump xml.
+#[derive(Default)]
+struct XmlCollector {
+    data_block_size: u32,
+    current: Option<u32>,
+    thins: BTreeMap<u32, PoolThin>,
+}
+
+impl MetadataVisitor for XmlCollector {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.data_block_size = sb.data_block_size;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, _name: &str) -> Result<Visit> {
+        Err(anyhow!("shared definitions in thin xml aren't supported"))
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        let details = DeviceDetail {
+            mapped_blocks: d.mapped_blocks,
+            transaction_id: d.transaction,
+            creation_time: d.creation_time,
+            snapshotted_time: d.snap_time,
+        };
+        self.thins.insert(
+            d.dev_id,
+            PoolThin {
+                details,
+                runs: Vec::new(),
+                size: None,
+                dm_uuid: None,
+            },
+        );
+        self.current = Some(d.dev_id);
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.current = None;
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        let thin = self
+            .current
+            .and_then(|id| self.thins.get_mut(&id))
+            .ok_or_else(|| anyhow!("mapping outside a device in thin xml"))?;
+        thin.runs.push((m.thin_begin, m.data_begin, m.len));
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, _name: &str) -> Result<Visit> {
+        Err(anyhow!("shared definitions in thin xml aren't supported"))
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        Ok(Visit::Continue)
+    }
+}
+
+fn read_pool_xml(metadata: &Path) -> Result<PoolInfo> {
+    let input = BufReader::new(OpenOptions::new().read(true).open(metadata)?);
+    let mut collector = XmlCollector::default();
+    xml::read(input, &mut collector)?;
+
+    for thin in collector.thins.values_mut() {
+        thin.runs.sort_unstable();
+        for w in thin.runs.windows(2) {
+            if w[0].0 + w[0].2 > w[1].0 {
+                return Err(anyhow!("overlapping mappings in thin xml"));
+            }
+        }
+    }
+
+    Ok(PoolInfo {
+        data_block_size: collector.data_block_size,
+        thins: collector.thins,
+        pool_uuid: None,
+    })
+}
+
+fn read_offline_xml(metadata: &Path, thin_id: u32) -> Result<OfflineThinInfo> {
+    let mut pool = read_pool_xml(metadata)?;
+    let thin = pool
+        .thins
+        .remove(&thin_id)
+        .ok_or_else(|| anyhow!("couldn't find thin device with id {}", thin_id))?;
+
+    Ok(OfflineThinInfo {
+        thin_id,
+        data_block_size: pool.data_block_size,
+        runs: thin.runs,
+    })
+}
+
+fn is_xml(metadata: &Path) -> Result<bool> {
+    let mut first = [0u8; 64];
+    let n = OpenOptions::new()
+        .read(true)
+        .open(metadata)
+        .context("couldn't open thin metadata")?
+        .read(&mut first)?;
+    Ok(first[..n]
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|b| *b == b'<'))
+}
+
+/// Reads the mappings of a thin device from either a metadata
+/// device/image, or the xml output of thin_dump.
+pub fn read_offline_thin_mappings(metadata: &Path, thin_id: u32) -> Result<OfflineThinInfo> {
+    if is_xml(metadata)? {
+        read_offline_xml(metadata, thin_id)
+    } else {
+        read_offline_binary(metadata, thin_id)
+    }
+}
+
+//---------------------------------
+
+// Splits [0, nr_blocks) into segments that are either unmapped (None),
+// or mapped contiguously from the given data block.
+fn segments(runs: &[(u64, u64, u64)], nr_blocks: u64) -> Vec<(u64, u64, Option<u64>)> {
+    let mut segs = Vec::new();
+    let mut pos = 0;
+    for (begin, data_begin, len) in runs {
+        let end = std::cmp::min(begin + len, nr_blocks);
+        if *begin >= end {
+            continue;
+        }
+        if pos < *begin {
+            segs.push((pos, *begin, None));
+        }
+        segs.push((*begin, end, Some(*data_begin)));
+        pos = end;
+    }
+    if pos < nr_blocks {
+        segs.push((pos, nr_blocks, None));
+    }
+    segs
+}
+
+fn push_region(regions: &mut Vec<PoolRegion>, r: PoolRegion) {
+    use PoolRegion::*;
+
+    if let Some(last) = regions.last_mut() {
+        match (last, r) {
+            (Unmapped(l), Unmapped(n)) | (Unchanged(l), Unchanged(n)) => {
+                *l += n;
+                return;
+            }
+            (
+                Mapped {
+                    data_begin: d1,
+                    len: l1,
+                },
+                Mapped {
+                    data_begin: d2,
+                    len: l2,
+                },
+            ) if *d1 + *l1 == d2 => {
+                *l1 += l2;
+                return;
+            }
+            _ => {}
+        }
+    }
+    regions.push(r);
+}
+
+/// The regions of a thin device of nr_blocks, in data blocks.
+pub fn full_regions(runs: &[(u64, u64, u64)], nr_blocks: u64) -> Vec<PoolRegion> {
+    let mut regions = Vec::new();
+    for (begin, end, data) in segments(runs, nr_blocks) {
+        let r = match data {
+            Some(data_begin) => PoolRegion::Mapped {
+                data_begin,
+                len: end - begin,
+            },
+            None => PoolRegion::Unmapped(end - begin),
+        };
+        push_region(&mut regions, r);
+    }
+    regions
+}
+
+/// The regions of a thin device relative to an older one (its base).
+/// Blocks mapped to the same data block in both, or unmapped in both,
+/// are unchanged.  The base is only nr_base_blocks long, anything past
+/// that can't be unchanged.
+pub fn delta_regions(
+    base: &[(u64, u64, u64)],
+    nr_base_blocks: u64,
+    runs: &[(u64, u64, u64)],
+    nr_blocks: u64,
+) -> Vec<PoolRegion> {
+    let old = segments(base, nr_base_blocks);
+    let new = segments(runs, nr_blocks);
+
+    let mut regions = Vec::new();
+    let mut old_it = old.iter().peekable();
+    for (begin, end, data) in new {
+        let mut pos = begin;
+        while pos < end {
+            // Skip old segments that end before pos.
+            while old_it.peek().is_some_and(|(_, e, _)| *e <= pos) {
+                old_it.next();
+            }
+
+            let (seg_end, old_data) = match old_it.peek() {
+                Some((b, e, d)) => {
+                    assert!(*b <= pos);
+                    (std::cmp::min(*e, end), d.map(|d| d + (pos - b)))
+                }
+                // Past the end of the base.
+                None => (end, None),
+            };
+            let past_base = pos >= nr_base_blocks;
+            let new_data = data.map(|d| d + (pos - begin));
+            let len = seg_end - pos;
+
+            let r = match new_data {
+                Some(_) if new_data == old_data => PoolRegion::Unchanged(len),
+                Some(data_begin) => PoolRegion::Mapped { data_begin, len },
+                None if old_data.is_none() && !past_base => PoolRegion::Unchanged(len),
+                None => PoolRegion::Unmapped(len),
+            };
+            push_region(&mut regions, r);
+            pos = seg_end;
+        }
+    }
+    regions
+}
+
+/// The number of blocks mapped to the same data block in both devices.
+pub fn nr_shared_blocks(a: &[(u64, u64, u64)], b: &[(u64, u64, u64)]) -> u64 {
+    let mut shared = 0;
+    let mut a_it = a.iter().peekable();
+    let mut b_it = b.iter().peekable();
+    while let (Some((a_begin, a_data, a_len)), Some((b_begin, b_data, b_len))) =
+        (a_it.peek(), b_it.peek())
+    {
+        let begin = std::cmp::max(a_begin, b_begin);
+        let end = std::cmp::min(a_begin + a_len, b_begin + b_len);
+        if begin < &end && a_data + (begin - a_begin) == b_data + (begin - b_begin) {
+            shared += end - begin;
+        }
+        if a_begin + a_len <= b_begin + b_len {
+            a_it.next();
+        } else {
+            b_it.next();
+        }
+    }
+    shared
+}
+
+//---------------------------------
+
+/// A thin device in a pool, as found in the pool metadata.  Runs are as
+/// for OfflineThinInfo.  The size is only known if the thin is active.
+#[derive(Debug)]
+pub struct PoolThin {
+    pub details: DeviceDetail,
+    pub runs: Vec<(u64, u64, u64)>,
+    pub size: Option<u64>,
+    pub dm_uuid: Option<String>,
+}
+
+impl PoolThin {
+    pub fn nr_provisioned(&self) -> u64 {
+        self.runs.iter().map(|(_, _, len)| len).sum()
+    }
+
+    pub fn nr_mapped_blocks(&self) -> u64 {
+        self.runs
+            .last()
+            .map(|(begin, _, len)| begin + len)
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub struct PoolInfo {
+    pub data_block_size: u32,
+    pub thins: BTreeMap<u32, PoolThin>,
+    pub pool_uuid: Option<String>,
+}
+
+fn read_pool_binary(engine: Arc<dyn IoEngine + Send + Sync>, sb: &Superblock) -> Result<PoolInfo> {
+    let mut path = vec![];
+    let details: BTreeMap<u64, DeviceDetail> =
+        btree_to_map(&mut path, engine.clone(), true, sb.details_root)?;
+    let roots: BTreeMap<u64, u64> = btree_to_map(&mut path, engine.clone(), true, sb.mapping_root)?;
+
+    let mut thins = BTreeMap::new();
+    for (thin_id, root) in roots {
+        let details = *details
+            .get(&thin_id)
+            .ok_or_else(|| anyhow!("thin device {} has no details", thin_id))?;
+        let runs = read_runs(engine.clone(), root)?;
+        thins.insert(
+            thin_id as u32,
+            PoolThin {
+                details,
+                runs,
+                size: None,
+                dm_uuid: None,
+            },
+        );
+    }
+
+    Ok(PoolInfo {
+        data_block_size: sb.data_block_size,
+        thins,
+        pool_uuid: None,
+    })
+}
+
+/// Reads every thin device from a metadata device/image, or thin_dump
+/// xml.
+pub fn read_offline_pool(metadata: &Path) -> Result<PoolInfo> {
+    if is_xml(metadata)? {
+        read_pool_xml(metadata)
+    } else {
+        let engine = Arc::new(SyncIoEngine::new_with(metadata, false, false)?);
+        let sb = read_superblock(&*engine, SUPERBLOCK_LOCATION)?;
+        read_pool_binary(engine, &sb)
+    }
+}
+
+/// Holds a metadata snapshot of an active pool.  The pool won't reuse
+/// any data block the snapshot refers to until it's released, so
+/// blocks can be read from the data device while the pool is in use.
+pub struct MetadataSnap {
+    pool_name: DmNameBuf,
+}
+
+impl MetadataSnap {
+    fn reserve(dm: &DM, pool_name: DmNameBuf) -> Result<Self> {
+        dm.target_msg(&DevId::Name(&pool_name), None, "reserve_metadata_snap")?;
+        Ok(Self { pool_name })
+    }
+}
+
+impl Drop for MetadataSnap {
+    fn drop(&mut self) {
+        if let Ok(dm) = DM::new() {
+            let _ = dm.target_msg(&DevId::Name(&self.pool_name), None, "release_metadata_snap");
+        }
+    }
+}
+
+struct ActiveThin {
+    size: u64,
+    dm_uuid: Option<String>,
+
+    // Neither suspended nor read only, so its data may change under us.
+    writable: bool,
+}
+
+// The sizes, dm uuids and writability of the active thins in a pool.
+fn active_thins(dm: &mut DM, dm_devs: &DevMap, pool: (u32, u32)) -> BTreeMap<u32, ActiveThin> {
+    let mut thins = BTreeMap::new();
+    for name in dm_devs.values() {
+        if let Ok((len, args)) = get_target(dm, &DevId::Name(name), "thin") {
+            if let Ok((_, details)) = parse_thin_table(&args) {
+                if (details.pool_major, details.pool_minor) == pool {
+                    let writable = dm
+                        .device_info(&DevId::Name(name))
+                        .map(|info| {
+                            !info
+                                .flags()
+                                .intersects(DmFlags::DM_SUSPEND | DmFlags::DM_READONLY)
+                        })
+                        .unwrap_or(true);
+                    thins.insert(
+                        details.id,
+                        ActiveThin {
+                            size: len * 512,
+                            dm_uuid: dm_uuid(dm, name),
+                            writable,
+                        },
+                    );
+                }
+            }
+        }
+    }
+    thins
+}
+
+fn find_pool<P: AsRef<Path>>(
+    dm: &mut DM,
+    dm_devs: &DevMap,
+    pool: P,
+) -> Result<(DmNameBuf, Device, PoolDetails)> {
+    let metadata = std::fs::metadata(pool)?;
+    if !metadata.file_type().is_block_device() {
+        return Err(anyhow!("pool is not a block device"));
+    }
+    let pool_dev = Device::from(metadata.rdev());
+    let pool_name = dm_devs
+        .get(&(pool_dev.major, pool_dev.minor))
+        .ok_or_else(|| anyhow!("pool is not a DM device"))?
+        .clone();
+    let pool_args = get_table(dm, &DevId::Name(&pool_name), "thin-pool")?;
+    let (_, pool_details) =
+        parse_pool_table(&pool_args).map_err(|_| anyhow!("couldn't parse pool table"))?;
+    Ok((pool_name, pool_dev, pool_details))
+}
+
+/// Reads every thin device in an active pool.  Also returns the pool's
+/// data device, and the metadata snapshot the mappings came from, which
+/// should be held while the data is read.  The snapshot doesn't stop a
+/// thin overwriting or reallocating its own blocks, so active thins must
+/// be suspended or read only.
+pub fn read_active_pool<P: AsRef<Path>>(pool: P) -> Result<(PoolInfo, PathBuf, MetadataSnap)> {
+    let mut dm = DM::new()?;
+    let dm_devs = collect_dm_devs(&mut dm)?;
+    let (pool_name, pool_dev, pool_details) = find_pool(&mut dm, &dm_devs, pool)?;
+
+    let metadata_path = find_device(pool_details.metadata_major, pool_details.metadata_minor)
+        .ok_or_else(|| anyhow!("Couldn't find pool metadata device"))?;
+    let data_path = find_device(pool_details.data_major, pool_details.data_minor)
+        .ok_or_else(|| anyhow!("Couldn't find pool data device"))?;
+
+    let snap = MetadataSnap::reserve(&dm, pool_name.clone())?;
+    let engine = Arc::new(SyncIoEngine::new_with(&metadata_path, false, false)?);
+    let sb = read_superblock_snap(&*engine)?;
+    let mut info = read_pool_binary(engine, &sb)?;
+
+    let active = active_thins(&mut dm, &dm_devs, (pool_dev.major, pool_dev.minor));
+    let writable: Vec<String> = active
+        .iter()
+        .filter(|(_, thin)| thin.writable)
+        .map(|(id, _)| id.to_string())
+        .collect();
+    if !writable.is_empty() {
+        return Err(anyhow!(
+            "thins {} are active and writable; suspend them, or make them read only",
+            writable.join(", ")
+        ));
+    }
+
+    for (id, thin) in info.thins.iter_mut() {
+        if let Some(active) = active.get(id) {
+            thin.size = Some(active.size);
+            thin.dm_uuid = active.dm_uuid.clone();
+        }
+    }
+    info.pool_uuid = dm_uuid(&dm, &pool_name);
+
+    Ok((info, data_path, snap))
+}
+
+/// An active pool that thin devices are being created in.
+pub struct TargetPool {
+    dm: DM,
+    pool_name: DmNameBuf,
+    pool_dev: Device,
+    pub data_block_size: u32,
+}
+
+impl TargetPool {
+    pub fn open<P: AsRef<Path>>(pool: P) -> Result<Self> {
+        let mut dm = DM::new()?;
+        let dm_devs = collect_dm_devs(&mut dm)?;
+        let (pool_name, pool_dev, pool_details) = find_pool(&mut dm, &dm_devs, pool)?;
+        Ok(Self {
+            dm,
+            pool_name,
+            pool_dev,
+            data_block_size: pool_details.data_block_size,
+        })
+    }
+
+    fn message(&self, msg: &str) -> Result<()> {
+        self.dm
+            .target_msg(&DevId::Name(&self.pool_name), None, msg)
+            .with_context(|| format!("pool message '{}' failed", msg))?;
+        Ok(())
+    }
+
+    pub fn create_thin(&self, thin_id: u32) -> Result<()> {
+        self.message(&format!("create_thin {}", thin_id))
+    }
+
+    /// The origin has to be suspended while the snapshot is taken, if
+    /// it's active.
+    pub fn create_snap(&self, thin_id: u32, origin_id: u32, origin: Option<&str>) -> Result<()> {
+        let origin = origin.map(DmName::new).transpose()?;
+        if let Some(name) = origin {
+            self.dm.device_suspend(
+                &DevId::Name(name),
+                DmOptions::default().set_flags(DmFlags::DM_SUSPEND),
+            )?;
+        }
+        let r = self.message(&format!("create_snap {} {}", thin_id, origin_id));
+        if let Some(name) = origin {
+            self.dm
+                .device_suspend(&DevId::Name(name), DmOptions::default())?;
+        }
+        r
+    }
+
+    pub fn delete_thin(&self, thin_id: u32) -> Result<()> {
+        self.message(&format!("delete {}", thin_id))
+    }
+
+    /// The number of unused data blocks in the pool.
+    pub fn nr_free_blocks(&self) -> Result<u64> {
+        let (_info, status) = self
+            .dm
+            .table_status(&DevId::Name(&self.pool_name), DmOptions::default())?;
+        let (_, _, _, args) = status
+            .first()
+            .ok_or_else(|| anyhow!("pool has no status"))?;
+        let (_, (used, total)) =
+            parse_pool_status(args).map_err(|_| anyhow!("couldn't parse pool status"))?;
+        Ok(total.saturating_sub(used))
+    }
+
+    /// Activates a thin device, returning its path.
+    pub fn activate_thin(&self, name: &str, thin_id: u32, size: u64) -> Result<PathBuf> {
+        let dm_name = DmName::new(name)?;
+        let id = DevId::Name(dm_name);
+        let table = vec![(
+            0,
+            size / 512,
+            "thin".to_string(),
+            format!(
+                "{}:{} {}",
+                self.pool_dev.major, self.pool_dev.minor, thin_id
+            ),
+        )];
+
+        self.dm.device_create(dm_name, None, DmOptions::default())?;
+        let r = self
+            .dm
+            .table_load(&id, &table, DmOptions::default())
+            .and_then(|_| self.dm.device_suspend(&id, DmOptions::default()));
+        if let Err(e) = r {
+            let _ = self.dm.device_remove(&id, DmOptions::default());
+            return Err(e.into());
+        }
+
+        Ok(["/dev", "mapper", name].iter().collect())
+    }
+
+    /// Removes a thin device activated by activate_thin.
+    pub fn deactivate_thin(&self, name: &str) -> Result<()> {
+        let dm_name = DmName::new(name)?;
+        self.dm
+            .device_remove(&DevId::Name(dm_name), DmOptions::default())?;
+        Ok(())
+    }
+}
+
 //---------------------------------
 
 #[derive(Debug)]
@@ -244,7 +1104,7 @@ struct ThinDetails {
     id: u32,
 }
 
-fn parse_dev(input: &str) -> IResult<&str, (u32, u32)> {
+pub(crate) fn parse_dev(input: &str) -> IResult<&str, (u32, u32)> {
     use nom::character::complete::*;
 
     let (input, major) = u32(input)?;
@@ -276,6 +1136,8 @@ fn parse_thin_table(input: &str) -> IResult<&str, ThinDetails> {
 struct PoolDetails {
     metadata_major: u32,
     metadata_minor: u32,
+    data_major: u32,
+    data_minor: u32,
     data_block_size: u32,
 }
 
@@ -284,7 +1146,7 @@ fn parse_pool_table(input: &str) -> IResult<&str, PoolDetails> {
 
     let (input, (metadata_major, metadata_minor)) = parse_dev(input)?;
     let (input, _) = multispace1(input)?;
-    let (input, (_data_major, _data_minor)) = parse_dev(input)?;
+    let (input, (data_major, data_minor)) = parse_dev(input)?;
     let (input, _) = multispace1(input)?;
     let (input, data_block_size) = u32(input)?;
 
@@ -293,12 +1155,41 @@ fn parse_pool_table(input: &str) -> IResult<&str, PoolDetails> {
         PoolDetails {
             metadata_major,
             metadata_minor,
+            data_major,
+            data_minor,
             data_block_size,
         },
     ))
 }
 
-fn get_table(dm: &mut DM, dev: &DevId, expected_target_type: &str) -> Result<String> {
+// Returns the used and total data blocks from a pool's status, eg,
+//   <transaction id> <used meta>/<total meta> <used data>/<total data> ...
+fn parse_pool_status(input: &str) -> IResult<&str, (u64, u64)> {
+    use nom::character::complete::*;
+
+    let (input, _transaction_id) = u64(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, _) = parse_dev_blocks(input)?;
+    let (input, _) = multispace1(input)?;
+    parse_dev_blocks(input)
+}
+
+fn parse_dev_blocks(input: &str) -> IResult<&str, (u64, u64)> {
+    use nom::character::complete::*;
+
+    let (input, used) = u64(input)?;
+    let (input, _) = char('/')(input)?;
+    let (input, total) = u64(input)?;
+
+    Ok((input, (used, total)))
+}
+
+pub(crate) fn get_table(dm: &mut DM, dev: &DevId, expected_target_type: &str) -> Result<String> {
+    get_target(dm, dev, expected_target_type).map(|(_len, args)| args)
+}
+
+// Returns the length (in sectors) and args of a single target table.
+fn get_target(dm: &mut DM, dev: &DevId, expected_target_type: &str) -> Result<(u64, String)> {
     let (_info, table) = dm.table_status(
         dev,
         DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE),
@@ -309,7 +1200,7 @@ fn get_table(dm: &mut DM, dev: &DevId, expected_target_type: &str) -> Result<Str
         ));
     }
 
-    let (_offset, _len, target_type, args) = &table[0];
+    let (_offset, len, target_type, args) = &table[0];
     if target_type != expected_target_type {
         return Err(anyhow!(format!(
             "dm expected table type {}, dm actual table type {}",
@@ -317,10 +1208,20 @@ fn get_table(dm: &mut DM, dev: &DevId, expected_target_type: &str) -> Result<Str
         )));
     }
 
-    Ok(args.to_string())
+    Ok((*len, args.to_string()))
+}
+
+pub(crate) fn dm_uuid(dm: &DM, name: &DmNameBuf) -> Option<String> {
+    dm.device_info(&DevId::Name(name))
+        .ok()
+        .and_then(|info| info.uuid().map(|uuid| uuid.to_string()))
 }
 
-fn get_thin_details<P: AsRef<Path>>(thin: P, dm_devs: &DevMap, dm: &mut DM) -> Result<ThinDetails> {
+fn get_thin_details<P: AsRef<Path>>(
+    thin: P,
+    dm_devs: &DevMap,
+    dm: &mut DM,
+) -> Result<(DmNameBuf, ThinDetails)> {
     let thin = OpenOptions::new()
         .read(true)
         .write(false)
@@ -346,10 +1247,10 @@ fn get_thin_details<P: AsRef<Path>>(thin: P, dm_devs: &DevMap, dm: &mut DM) -> R
     let (_, thin_details) =
         parse_thin_table(&thin_args).map_err(|_| anyhow!("couldn't parse thin table"))?;
 
-    Ok(thin_details)
+    Ok((thin_name, thin_details))
 }
 
-fn find_device(major: u32, minor: u32) -> Option<PathBuf> {
+pub(crate) fn find_device(major: u32, minor: u32) -> Option<PathBuf> {
     let mut enumerator = Enumerator::new().unwrap();
 
     for device in enumerator.scan_devices().unwrap() {
@@ -366,19 +1267,13 @@ fn find_device(major: u32, minor: u32) -> Option<PathBuf> {
     None
 }
 
-pub fn read_thin_mappings<P: AsRef<Path>>(thin: P) -> Result<ThinInfo> {
-    let mut dm = DM::new()?;
-
-    let dm_devs = collect_dm_devs(&mut dm)?;
-
-    let thin_details = get_thin_details(thin, &dm_devs, &mut dm)?;
-
+// Finds the pool of a thin device, and its metadata device.
+fn thin_pool(dm: &mut DM, dm_devs: &DevMap, thin: &ThinDetails) -> Result<(DmNameBuf, PathBuf)> {
     let pool_name = dm_devs
-        .get(&(thin_details.pool_major, thin_details.pool_minor))
+        .get(&(thin.pool_major, thin.pool_minor))
         .ok_or_else(|| anyhow!("Pool device not found"))?
         .clone();
-    let pool_id = DevId::Name(&pool_name);
-    let pool_args = get_table(&mut dm, &pool_id, "thin-pool")?;
+    let pool_args = get_table(dm, &DevId::Name(&pool_name), "thin-pool")?;
     let (_, pool_details) =
         parse_pool_table(&pool_args).map_err(|_| anyhow!("couldn't parse pool table"))?;
 
@@ -386,48 +1281,361 @@ pub fn read_thin_mappings<P: AsRef<Path>>(thin: P) -> Result<ThinInfo> {
     let metadata_path = find_device(pool_details.metadata_major, pool_details.metadata_minor)
         .ok_or_else(|| anyhow!("Couldn't find pool metadata device"))?;
 
+    Ok((pool_name, metadata_path))
+}
+
+pub fn read_thin_mappings<P: AsRef<Path>>(thin: P) -> Result<ThinInfo> {
+    let mut dm = DM::new()?;
+
+    let dm_devs = collect_dm_devs(&mut dm)?;
+
+    let (thin_name, thin_details) = get_thin_details(thin, &dm_devs, &mut dm)?;
+    let (pool_name, metadata_path) = thin_pool(&mut dm, &dm_devs, &thin_details)?;
+    let pool_id = DevId::Name(&pool_name);
+
     // Parse thin metadata
     dm.target_msg(&pool_id, None, "reserve_metadata_snap")?;
     let r = read_info(&metadata_path, thin_details.id);
     dm.target_msg(&pool_id, None, "release_metadata_snap")?;
 
-    r
+    let mut info = r?;
+    info.pool_uuid = dm_uuid(&dm, &pool_name);
+    info.dm_uuid = dm_uuid(&dm, &thin_name);
+    Ok(info)
 }
 
 //---------------------------------
 
+// The old thin only needs to exist in the pool, it needn't be active.
+fn read_delta_in_pool(
+    dm: &mut DM,
+    dm_devs: &DevMap,
+    old_thin_id: u32,
+    new_thin: (DmNameBuf, ThinDetails),
+) -> Result<DeltaInfo> {
+    let (new_name, new_details) = new_thin;
+    let (pool_name, metadata_path) = thin_pool(dm, dm_devs, &new_details)?;
+    let pool_id = DevId::Name(&pool_name);
+
+    // Parse thin metadata
+    dm.target_msg(&pool_id, None, "reserve_metadata_snap")?;
+    let r = read_delta_info(&metadata_path, old_thin_id, new_details.id);
+    dm.target_msg(&pool_id, None, "release_metadata_snap")?;
+
+    let mut info = r?;
+    info.pool_uuid = dm_uuid(dm, &pool_name);
+    info.dm_uuid = dm_uuid(dm, &new_name);
+    Ok(info)
+}
+
 pub fn read_thin_delta<P: AsRef<Path>>(old_thin: P, new_thin: P) -> Result<DeltaInfo> {
     let mut dm = DM::new()?;
     let dm_devs = collect_dm_devs(&mut dm)?;
 
-    let old_thin_details = get_thin_details(old_thin, &dm_devs, &mut dm)
+    let (_, old_thin_details) = get_thin_details(old_thin, &dm_devs, &mut dm)
         .context("unable to identify --delta-device")?;
-    let new_thin_details =
+    let new_thin =
         get_thin_details(new_thin, &dm_devs, &mut dm).context("unable to identify input file")?;
 
-    if old_thin_details.pool_minor != new_thin_details.pool_minor {
+    if old_thin_details.pool_minor != new_thin.1.pool_minor {
         return Err(anyhow!("thin devices are not from the same pool"));
     }
 
-    let pool_name = dm_devs
-        .get(&(old_thin_details.pool_major, old_thin_details.pool_minor))
-        .ok_or_else(|| anyhow!("Pool device not found"))?
-        .clone();
-    let pool_id = DevId::Name(&pool_name);
-    let pool_args = get_table(&mut dm, &pool_id, "thin-pool")?;
-    let (_, pool_details) =
-        parse_pool_table(&pool_args).map_err(|_| anyhow!("couldn't parse pool table"))?;
+    read_delta_in_pool(&mut dm, &dm_devs, old_thin_details.id, new_thin)
+}
 
-    // Find the metadata dev
-    let metadata_path = find_device(pool_details.metadata_major, pool_details.metadata_minor)
-        .ok_or_else(|| anyhow!("Couldn't find pool metadata device"))?;
+/// As read_thin_delta, but the old thin is given by its id within the
+/// new thin's pool.
+pub fn read_thin_delta_from_id<P: AsRef<Path>>(old_thin_id: u32, new_thin: P) -> Result<DeltaInfo> {
+    let mut dm = DM::new()?;
+    let dm_devs = collect_dm_devs(&mut dm)?;
 
-    // Parse thin metadata
-    dm.target_msg(&pool_id, None, "reserve_metadata_snap")?;
-    let r = read_delta_info(&metadata_path, old_thin_details.id, new_thin_details.id);
-    dm.target_msg(&pool_id, None, "release_metadata_snap")?;
+    let new_thin =
+        get_thin_details(new_thin, &dm_devs, &mut dm).context("unable to identify input file")?;
+    read_delta_in_pool(&mut dm, &dm_devs, old_thin_id, new_thin)
+}
+
+/// The thin devices in the pool of an active thin.
+pub struct PoolMembers {
+    pub pool_uuid: Option<String>,
+    pub thin_id: u32,
+    pub thins: BTreeMap<u32, DeviceDetail>,
+}
+
+pub fn read_pool_members<P: AsRef<Path>>(thin: P) -> Result<PoolMembers> {
+    let mut dm = DM::new()?;
+    let dm_devs = collect_dm_devs(&mut dm)?;
+
+    let (_, thin_details) = get_thin_details(thin, &dm_devs, &mut dm)?;
+    let (pool_name, metadata_path) = thin_pool(&mut dm, &dm_devs, &thin_details)?;
+
+    let snap = MetadataSnap::reserve(&dm, pool_name.clone())?;
+    let engine = Arc::new(SyncIoEngine::new_with(&metadata_path, false, false)?);
+    let sb = read_superblock_snap(&*engine)?;
+    let mut path = vec![];
+    let details: BTreeMap<u64, DeviceDetail> =
+        btree_to_map(&mut path, engine, true, sb.details_root)?;
+    drop(snap);
+
+    Ok(PoolMembers {
+        pool_uuid: dm_uuid(&dm, &pool_name),
+        thin_id: thin_details.id,
+        thins: details.into_iter().map(|(id, d)| (id as u32, d)).collect(),
+    })
+}
+
+//---------------------------------
+
+#[cfg(test)]
+mod delta_tests {
+    use super::*;
+    use rand::prelude::*;
+    use rand_chacha::ChaCha20Rng;
+    use std::cell::RefCell;
+
+    enum TestNode {
+        Internal(Vec<(u64, u64)>),
+        Leaf(Vec<(u64, BlockTime)>),
+    }
+
+    #[derive(Default)]
+    struct TestTrees {
+        nodes: BTreeMap<u64, TestNode>,
+        reads: RefCell<Vec<u64>>,
+    }
+
+    fn header(block: u64, is_leaf: bool, nr_entries: usize) -> NodeHeader {
+        NodeHeader {
+            block,
+            is_leaf,
+            nr_entries: nr_entries as u32,
+            max_entries: 126,
+            value_size: 8,
+        }
+    }
+
+    impl TestTrees {
+        fn add(&mut self, node: TestNode) -> u64 {
+            let block = self.nodes.len() as u64 + 1;
+            self.nodes.insert(block, node);
+            block
+        }
+
+        fn leaf(&mut self, mappings: &[(u64, u64)]) -> u64 {
+            let mappings = mappings
+                .iter()
+                .map(|(k, b)| (*k, BlockTime { block: *b, time: 0 }))
+                .collect();
+            self.add(TestNode::Leaf(mappings))
+        }
+
+        fn internal(&mut self, children: &[u64]) -> u64 {
+            let children = children.iter().map(|c| (self.first_key(*c), *c)).collect();
+            self.add(TestNode::Internal(children))
+        }
+
+        fn first_key(&self, block: u64) -> u64 {
+            match &self.nodes[&block] {
+                TestNode::Internal(children) => children[0].0,
+                TestNode::Leaf(mappings) => mappings[0].0,
+            }
+        }
+
+        fn read_node(&self, block: u64, _is_root: bool) -> Result<Node<BlockTime>> {
+            self.reads.borrow_mut().push(block);
+            Ok(match &self.nodes[&block] {
+                TestNode::Internal(children) => Node::Internal {
+                    header: header(block, false, children.len()),
+                    keys: children.iter().map(|c| c.0).collect(),
+                    values: children.iter().map(|c| c.1).collect(),
+                },
+                TestNode::Leaf(mappings) => Node::Leaf {
+                    header: header(block, true, mappings.len()),
+                    keys: mappings.iter().map(|m| m.0).collect(),
+                    values: mappings.iter().map(|m| m.1).collect(),
+                },
+            })
+        }
+
+        fn diff(&self, old: u64, new: u64) -> (Vec<u32>, Vec<u32>) {
+            let (additions, removals) =
+                diff_mapping_trees(old, new, |b, r| self.read_node(b, r)).unwrap();
+            (additions.iter().collect(), removals.iter().collect())
+        }
+
+        // Builds a two level tree over the mappings, with a leaf for each
+        // 16 keys.  Leaves with identical contents are reused, the way a
+        // snapshot shares them.
+        fn build(
+            &mut self,
+            mappings: &BTreeMap<u64, u64>,
+            leaves: &mut BTreeMap<Vec<(u64, u64)>, u64>,
+        ) -> u64 {
+            let entries: Vec<(u64, u64)> = mappings.iter().map(|(k, v)| (*k, *v)).collect();
+            let mut children = Vec::new();
+            for chunk in entries.chunk_by(|a, b| a.0 / 16 == b.0 / 16) {
+                let leaf = match leaves.get(chunk) {
+                    Some(leaf) => *leaf,
+                    None => {
+                        let leaf = self.leaf(chunk);
+                        leaves.insert(chunk.to_vec(), leaf);
+                        leaf
+                    }
+                };
+                children.push(leaf);
+            }
+            self.internal(&children)
+        }
+    }
+
+    #[test]
+    fn shared_nodes_are_skipped() {
+        let mut trees = TestTrees::default();
+        let shared = trees.leaf(&[(0, 10), (1, 11), (2, 12)]);
+        let old_leaf = trees.leaf(&[(100, 20), (101, 21), (103, 23)]);
+        let new_leaf = trees.leaf(&[(100, 20), (101, 99), (102, 22)]);
+        let old = trees.internal(&[shared, old_leaf]);
+        let new = trees.internal(&[shared, new_leaf]);
+
+        let (additions, removals) = trees.diff(old, new);
+        assert_eq!(additions, vec![101, 102]);
+        assert_eq!(removals, vec![103]);
+        assert!(!trees.reads.borrow().contains(&shared));
+    }
+
+    #[test]
+    fn trees_of_different_depths() {
+        let mut trees = TestTrees::default();
+        let shared = trees.leaf(&[(0, 10), (1, 11)]);
+        let extra = trees.leaf(&[(50, 30), (51, 31)]);
+        let new = trees.internal(&[shared, extra]);
+
+        assert_eq!(trees.diff(shared, new), (vec![50, 51], vec![]));
+        assert_eq!(trees.diff(new, shared), (vec![], vec![50, 51]));
+        assert_eq!(trees.diff(new, new), (vec![], vec![]));
+    }
+
+    #[test]
+    fn matches_a_full_comparison() {
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let mut trees = TestTrees::default();
+        let mut leaves = BTreeMap::new();
 
-    r
+        let mut old_mappings = BTreeMap::new();
+        for k in 0..1000 {
+            if rng.gen_bool(0.7) {
+                old_mappings.insert(k, rng.gen_range(0..1000000));
+            }
+        }
+
+        let mut new_mappings = old_mappings.clone();
+        for _ in 0..20 {
+            let k = rng.gen_range(0..1000);
+            match rng.gen_range(0..3) {
+                0 => {
+                    new_mappings.remove(&k);
+                }
+                _ => {
+                    new_mappings.insert(k, rng.gen_range(0..1000000));
+                }
+            }
+        }
+
+        let old = trees.build(&old_mappings, &mut leaves);
+        let new = trees.build(&new_mappings, &mut leaves);
+
+        let mut expected_additions = Vec::new();
+        let mut expected_removals = Vec::new();
+        for (k, v) in &old_mappings {
+            match new_mappings.get(k) {
+                Some(v2) if v2 == v => {}
+                Some(_) => expected_additions.push(*k as u32),
+                None => expected_removals.push(*k as u32),
+            }
+        }
+        for k in new_mappings.keys() {
+            if !old_mappings.contains_key(k) {
+                expected_additions.push(*k as u32);
+            }
+        }
+        expected_additions.sort();
+
+        let (additions, removals) = trees.diff(old, new);
+        assert_eq!(additions, expected_additions);
+        assert_eq!(removals, expected_removals);
+
+        // Most of the leaves are shared, and shouldn't have been read.
+        let nr_leaves = 1000 / 16;
+        assert!(trees.reads.borrow().len() < nr_leaves);
+    }
+}
+
+#[cfg(test)]
+mod region_tests {
+    use super::*;
+    use PoolRegion::*;
+
+    #[test]
+    fn full_regions_merge_contiguous_runs() {
+        let runs = vec![(2, 10, 3), (5, 13, 2)];
+        assert_eq!(
+            full_regions(&runs, 10),
+            vec![
+                Unmapped(2),
+                Mapped {
+                    data_begin: 10,
+                    len: 5
+                },
+                Unmapped(3)
+            ]
+        );
+    }
+
+    #[test]
+    fn delta_regions_only_read_changed_blocks() {
+        let base = vec![(0, 0, 4), (6, 20, 2)];
+        let new = vec![(0, 0, 2), (2, 50, 1), (3, 3, 1), (6, 20, 2), (8, 30, 2)];
+        assert_eq!(
+            delta_regions(&base, 8, &new, 10),
+            vec![
+                Unchanged(2),
+                Mapped {
+                    data_begin: 50,
+                    len: 1
+                },
+                Unchanged(5),
+                Mapped {
+                    data_begin: 30,
+                    len: 2
+                },
+            ]
+        );
+        assert_eq!(nr_shared_blocks(&base, &new), 5);
+    }
+
+    #[test]
+    fn delta_regions_unmap_discarded_blocks() {
+        let base = vec![(0, 0, 4)];
+        let new = vec![(0, 0, 2)];
+        assert_eq!(
+            delta_regions(&base, 4, &new, 4),
+            vec![Unchanged(2), Unmapped(2)]
+        );
+    }
+}
+
+#[cfg(test)]
+mod table_tests {
+    use super::*;
+
+    #[test]
+    fn pool_status() {
+        let (_, blocks) =
+            parse_pool_status("3 141/4161600 2000/16384 - rw discard_passdown queue_if_no_space -")
+                .unwrap();
+        assert_eq!(blocks, (2000, 16384));
+        assert!(parse_pool_status("Fail").is_err());
+    }
 }
 
 //---------------------------------
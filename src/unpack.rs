@@ -10,20 +10,22 @@ use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::os::unix::io::AsRawFd;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use crate::archive;
 use crate::archive::SLAB_SIZE_TARGET;
+use crate::chunk_delta;
 use crate::chunkers::*;
 use crate::config;
+use crate::lvm::{activate, LvName};
 use crate::output::Output;
-use crate::paths::*;
+use crate::partition;
 use crate::run_iter::*;
-use crate::slab::builder::*;
-use crate::slab::*;
 use crate::stream;
 use crate::stream::*;
+use crate::stream_reader::{open_archive_with, StreamReader};
+use crate::stream_slabs::StreamSlabs;
 use crate::thin_metadata::*;
 
 //-----------------------------------------
@@ -32,27 +34,48 @@ use crate::thin_metadata::*;
 trait UnpackDest {
     fn handle_mapped(&mut self, data: &[u8]) -> Result<()>;
     fn handle_unmapped(&mut self, len: u64) -> Result<()>;
+
+    // Runs of zeroes can often be written without passing any data.
+    fn handle_zeroes(&mut self, len: u64) -> Result<()> {
+        write_fill(self, 0, len)
+    }
+
     fn complete(&mut self) -> Result<()>;
 }
 
+fn write_fill<D: UnpackDest + ?Sized>(dest: &mut D, byte: u8, len: u64) -> Result<()> {
+    // len may be very big, so we have to be prepared to write in chunks.
+    const MAX_BUFFER: u64 = 16 * 1024 * 1024;
+    let bytes: Vec<u8> = vec![byte; std::cmp::min(len, MAX_BUFFER) as usize];
+    let mut written = 0;
+    while written < len {
+        let write_len = std::cmp::min(len - written, MAX_BUFFER);
+        dest.handle_mapped(&bytes[..write_len as usize])?;
+        written += write_len;
+    }
+    Ok(())
+}
+
 struct Unpacker<D: UnpackDest> {
-    stream_file: SlabFile,
+    stream_file: StreamSlabs,
     archive: archive::Data,
     dest: D,
 }
 
 impl<D: UnpackDest> Unpacker<D> {
     // Assumes current directory is the root of the archive.
-    fn new(stream: &str, cache_nr_entries: usize, dest: D) -> Result<Self> {
-        let data_file = SlabFileBuilder::open(data_path())
-            .cache_nr_entries(cache_nr_entries)
-            .build()?;
-        let hashes_file = Arc::new(Mutex::new(SlabFileBuilder::open(hashes_path()).build()?));
-        let stream_file = SlabFileBuilder::open(stream_path(stream)).build()?;
+    fn new(
+        stream: &str,
+        config: &config::Config,
+        cache_nr_entries: usize,
+        dest: D,
+    ) -> Result<Self> {
+        let stream_file = StreamSlabs::open(stream)?;
+        let archive = open_archive_with(&config.parents, cache_nr_entries)?;
 
         Ok(Self {
             stream_file,
-            archive: archive::Data::new(data_file, hashes_file, cache_nr_entries)?,
+            archive,
             dest,
         })
     }
@@ -60,33 +83,30 @@ impl<D: UnpackDest> Unpacker<D> {
     fn unpack_entry(&mut self, e: &MapEntry) -> Result<()> {
         use MapEntry::*;
         match e {
+            Fill { byte: 0, len } => {
+                self.dest.handle_zeroes(*len)?;
+            }
             Fill { byte, len } => {
-                // len may be very big, so we have to be prepared to write in chunks.
-                // FIXME: if we're writing to a file would this be zeroes anyway?  fallocate?
-                const MAX_BUFFER: u64 = 16 * 1024 * 1024;
-                let mut written = 0;
-                while written < *len {
-                    let write_len = std::cmp::min(*len - written, MAX_BUFFER);
-                    let bytes: Vec<u8> = vec![*byte; write_len as usize];
-                    self.dest.handle_mapped(&bytes[..])?;
-                    written += write_len;
-                }
+                write_fill(&mut self.dest, *byte, *len)?;
             }
             Unmapped { len } => {
                 self.dest.handle_unmapped(*len)?;
             }
             Data {
+                archive,
                 slab,
                 offset,
                 nr_entries,
             } => {
                 let (data, start, end) =
-                    self.archive.data_get(*slab, *offset, *nr_entries, None)?;
+                    self.archive
+                        .data_get(*archive, *slab, *offset, *nr_entries, None)?;
                 self.dest.handle_mapped(&data[start..end])?;
             }
             Partial {
                 begin,
                 end,
+                archive,
                 slab,
                 offset,
                 nr_entries,
@@ -94,9 +114,31 @@ impl<D: UnpackDest> Unpacker<D> {
                 let partial = Some((*begin, *end));
                 let (data, start, end) =
                     self.archive
-                        .data_get(*slab, *offset, *nr_entries, partial)?;
+                        .data_get(*archive, *slab, *offset, *nr_entries, partial)?;
                 self.dest.handle_mapped(&data[start..end])?;
             }
+            Delta {
+                base_slab,
+                base_offset,
+                slab,
+                offset,
+                len,
+                partial,
+            } => {
+                // Bases are always plain data, so this is at most two reads.
+                let (base, base_start, base_end) =
+                    self.archive
+                        .data_get(0, *base_slab, *base_offset, 1, None)?;
+                let (delta, start, end) = self.archive.data_get(0, *slab, *offset, 1, None)?;
+                let data = chunk_delta::apply(&base[base_start..base_end], &delta[start..end])?;
+                if data.len() != *len as usize {
+                    return Err(anyhow!("delta entry rebuilt to the wrong length"));
+                }
+
+                let (begin, end) = partial.unwrap_or((0, *len));
+                self.dest
+                    .handle_mapped(&data[begin as usize..end as usize])?;
+            }
             Ref { .. } => {
                 // Can't get here.
                 return Err(anyhow!("unexpected MapEntry::Ref (shouldn't be possible)"));
@@ -135,22 +177,68 @@ impl<D: UnpackDest> Unpacker<D> {
 
         self.dest.complete()?;
         output.report.progress(100);
-        let end_time: DateTime<Utc> = Utc::now();
-        let elapsed = end_time - start_time;
-        let elapsed = elapsed.num_milliseconds() as f64 / 1000.0;
+        report_speed(&output, total, start_time);
+
+        Ok(())
+    }
+}
 
-        if output.json {
-            let result = json!({ "bytes_per_second": (total as f64 / elapsed) as u64 });
-            println!("{}", to_string_pretty(&result).unwrap());
+fn report_speed(output: &Output, total: u64, start_time: DateTime<Utc>) {
+    let end_time: DateTime<Utc> = Utc::now();
+    let elapsed = end_time - start_time;
+    let elapsed = elapsed.num_milliseconds() as f64 / 1000.0;
+
+    if output.json {
+        let result = json!({ "bytes_per_second": (total as f64 / elapsed) as u64 });
+        println!("{}", to_string_pretty(&result).unwrap());
+    } else {
+        output.report.info(&format!(
+            "speed            : {:.2}/s",
+            Size((total as f64 / elapsed) as u64)
+        ));
+    }
+}
+
+// Unpacks just part of a stream.  The seek index lets us start at the
+// right stream slab, and unmapped regions are skipped without reading.
+fn unpack_range<D: UnpackDest>(
+    reader: &mut StreamReader,
+    mut dest: D,
+    begin: u64,
+    len: u64,
+    output: Arc<Output>,
+) -> Result<()> {
+    const MAX_BUFFER: u64 = 16 * 1024 * 1024;
+
+    output.report.progress(0);
+    let start_time: DateTime<Utc> = Utc::now();
+
+    reader.seek(io::SeekFrom::Start(begin))?;
+    let mut buf = Vec::new();
+    let mut done = 0;
+    while done < len {
+        let (mapped, extent) = reader
+            .extent()?
+            .ok_or_else(|| anyhow!("stream ended before the range"))?;
+        let n = std::cmp::min(std::cmp::min(extent, len - done), MAX_BUFFER);
+        if mapped {
+            buf.resize(n as usize, 0);
+            reader.read_exact(&mut buf)?;
+            dest.handle_mapped(&buf)?;
         } else {
-            output.report.info(&format!(
-                "speed            : {:.2}/s",
-                Size((total as f64 / elapsed) as u64)
-            ));
+            reader.seek(io::SeekFrom::Current(n as i64))?;
+            dest.handle_unmapped(n)?;
         }
 
-        Ok(())
+        done += n;
+        output.report.progress(((done * 100) / len) as u8);
     }
+
+    dest.complete()?;
+    output.report.progress(100);
+    report_speed(&output, len, start_time);
+
+    Ok(())
 }
 
 //-----------------------------------------
@@ -184,6 +272,7 @@ impl<W: Write> UnpackDest for ThickDest<W> {
     }
 
     fn complete(&mut self) -> Result<()> {
+        self.output.flush()?;
         Ok(())
     }
 }
@@ -193,11 +282,197 @@ impl<W: Write> UnpackDest for ThickDest<W> {
 // defined in include/uapi/linux/fs.h
 const BLK_IOC_CODE: u8 = 0x12;
 const BLKDISCARD_SEQ: u8 = 119;
+const BLKZEROOUT_SEQ: u8 = 127;
 nix::ioctl_write_ptr_bad!(
     ioctl_blkdiscard,
     nix::request_code_none!(BLK_IOC_CODE, BLKDISCARD_SEQ),
     [u64; 2]
 );
+nix::ioctl_write_ptr_bad!(
+    ioctl_blkzeroout,
+    nix::request_code_none!(BLK_IOC_CODE, BLKZEROOUT_SEQ),
+    [u64; 2]
+);
+
+// Zero runs shorter than this are cheaper to just write.
+const MIN_ZERO_RUN: u64 = 64 * 1024;
+
+// Errors meaning the device or filesystem can't do what we asked, rather
+// than that something went wrong.  EINVAL isn't one of them, it's what a
+// misaligned range gets.
+fn unsupported(e: nix::errno::Errno) -> bool {
+    use nix::errno::Errno::*;
+    matches!(e, EOPNOTSUPP | ENOTTY)
+}
+
+//-----------------------------------------
+
+// A regular file.  Unmapped regions, and long runs of zeroes, are left
+// as holes.  A new file is all hole to begin with so these are just
+// skipped; an existing one has the holes punched.
+struct FileDest {
+    output: File,
+    pos: u64,
+    fresh: bool,
+
+    // Cleared if the filesystem can't punch holes.
+    punch_holes: bool,
+}
+
+impl FileDest {
+    fn new(output: File, fresh: bool) -> Self {
+        Self {
+            output,
+            pos: 0,
+            fresh,
+            punch_holes: true,
+        }
+    }
+
+    fn punch_hole(&mut self, len: u64) -> Result<bool> {
+        let r = unsafe {
+            libc::fallocate(
+                self.output.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                self.pos as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if r == 0 {
+            return Ok(true);
+        }
+
+        let e = nix::errno::Errno::last();
+        if unsupported(e) {
+            Ok(false)
+        } else {
+            Err(e).context("couldn't punch hole in output")
+        }
+    }
+
+    fn hole(&mut self, len: u64) -> Result<()> {
+        if !self.fresh {
+            if self.punch_holes && !self.punch_hole(len)? {
+                self.punch_holes = false;
+            }
+            if !self.punch_holes {
+                return self.handle_mapped_zeroes(len);
+            }
+        }
+
+        self.pos += len;
+        self.output.seek(io::SeekFrom::Start(self.pos))?;
+        Ok(())
+    }
+
+    fn handle_mapped_zeroes(&mut self, len: u64) -> Result<()> {
+        write_bytes(&mut self.output, 0, len)?;
+        self.pos += len;
+        Ok(())
+    }
+}
+
+impl UnpackDest for FileDest {
+    fn handle_mapped(&mut self, data: &[u8]) -> Result<()> {
+        self.output.write_all(data)?;
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    fn handle_unmapped(&mut self, len: u64) -> Result<()> {
+        self.hole(len)
+    }
+
+    fn handle_zeroes(&mut self, len: u64) -> Result<()> {
+        if len < MIN_ZERO_RUN {
+            self.handle_mapped_zeroes(len)
+        } else {
+            self.hole(len)
+        }
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        // A trailing hole isn't part of a new file until we extend it.
+        if self.fresh {
+            self.output.set_len(self.pos)?;
+        }
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+//-----------------------------------------
+
+// A thick block device.  Unmapped regions, and long runs of zeroes, are
+// zeroed with BLKZEROOUT.  A discard needn't leave zeroes behind, but
+// BLKZEROOUT lets the device unmap, or use write-zeroes, where it can.
+struct BlockDest {
+    output: File,
+    pos: u64,
+
+    // Cleared if the device won't take BLKZEROOUT.
+    zeroout: bool,
+}
+
+// The ioctls only take sector aligned ranges.
+fn sector_aligned(pos: u64, len: u64) -> bool {
+    (pos | len) & 511 == 0
+}
+
+impl BlockDest {
+    fn new(output: File) -> Self {
+        Self {
+            output,
+            pos: 0,
+            zeroout: true,
+        }
+    }
+
+    fn zero(&mut self, len: u64) -> Result<()> {
+        if self.zeroout && sector_aligned(self.pos, len) {
+            match unsafe { ioctl_blkzeroout(self.output.as_raw_fd(), &[self.pos, len]) } {
+                Ok(_) => {
+                    self.pos += len;
+                    self.output.seek(io::SeekFrom::Start(self.pos))?;
+                    return Ok(());
+                }
+                Err(e) if unsupported(e) => self.zeroout = false,
+                Err(e) => return Err(e).context("BLKZEROOUT failed"),
+            }
+        }
+
+        write_bytes(&mut self.output, 0, len)?;
+        self.pos += len;
+        Ok(())
+    }
+}
+
+impl UnpackDest for BlockDest {
+    fn handle_mapped(&mut self, data: &[u8]) -> Result<()> {
+        self.output.write_all(data)?;
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    fn handle_unmapped(&mut self, len: u64) -> Result<()> {
+        self.zero(len)
+    }
+
+    fn handle_zeroes(&mut self, len: u64) -> Result<()> {
+        if len < MIN_ZERO_RUN {
+            write_fill(self, 0, len)
+        } else {
+            self.zero(len)
+        }
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+//-----------------------------------------
 
 struct ThinDest {
     block_size: u64,
@@ -208,22 +483,19 @@ struct ThinDest {
     // (provisioned, len bytes)
     run: Option<(bool, u64)>,
     writes_avoided: u64,
+
+    // Cleared if the pool has discards disabled.
+    discards: bool,
 }
 
 impl ThinDest {
-    fn issue_discard(&mut self, len: u64) -> Result<()> {
-        let begin = self.pos;
-        let end = begin + len;
-
-        // Discards should always be block aligned
-        assert_eq!(begin % self.block_size, 0);
-        assert_eq!(end % self.block_size, 0);
-
-        unsafe {
-            ioctl_blkdiscard(self.output.as_raw_fd(), &[begin, len])?;
+    // Returns false if the discard wasn't supported.
+    fn issue_discard(&mut self, len: u64) -> Result<bool> {
+        match unsafe { ioctl_blkdiscard(self.output.as_raw_fd(), &[self.pos, len]) } {
+            Ok(_) => Ok(true),
+            Err(e) if unsupported(e) => Ok(false),
+            Err(e) => Err(e.into()),
         }
-
-        Ok(())
     }
 
     //------------------
@@ -248,9 +520,42 @@ impl ThinDest {
         Ok(())
     }
 
+    // Only whole blocks can be discarded.  Unmapped regions from sparse
+    // files or derived streams needn't be block aligned, so the partial
+    // blocks at either end are zeroed instead.
     fn discard(&mut self, len: u64) -> Result<()> {
-        self.issue_discard(len)?;
-        self.forward(len)?;
+        let end = self.pos + len;
+        let whole_begin = std::cmp::min(self.pos.div_ceil(self.block_size) * self.block_size, end);
+        let whole_end = std::cmp::max(end / self.block_size * self.block_size, whole_begin);
+
+        self.zero_partial(whole_begin - self.pos)?;
+        if whole_end > whole_begin {
+            self.discard_blocks(whole_end - whole_begin)?;
+        }
+        self.zero_partial(end - whole_end)
+    }
+
+    fn discard_blocks(&mut self, len: u64) -> Result<()> {
+        if self.discards && !self.issue_discard(len)? {
+            self.discards = false;
+        }
+
+        if self.discards {
+            self.forward(len)
+        } else {
+            // The region has to read back as zeroes, even if it stays
+            // provisioned.
+            write_bytes(&mut self.output, 0, len)?;
+            self.pos += len;
+            Ok(())
+        }
+    }
+
+    // Part of a provisioned block, so it stays provisioned either way.
+    fn zero_partial(&mut self, len: u64) -> Result<()> {
+        if len > 0 {
+            self.handle_mapped_provisioned(&vec![0; len as usize])?;
+        }
         Ok(())
     }
 
@@ -361,14 +666,157 @@ impl UnpackDest for ThinDest {
 
 //-----------------------------------------
 
+// Returns the (offset, length) to unpack if only part of the stream was asked for.
+fn requested_range(matches: &ArgMatches, reader: &mut StreamReader) -> Result<(u64, u64)> {
+    let size = reader.size();
+    let (begin, len) = if let Some(n) = matches.get_one::<u32>("PARTITION") {
+        partition::find_partition(reader, *n)?
+    } else {
+        let begin = matches.get_one::<u64>("OFFSET").cloned().unwrap_or(0);
+        let len = match matches.get_one::<u64>("LENGTH") {
+            Some(len) => *len,
+            None => size.saturating_sub(begin),
+        };
+        (begin, len)
+    };
+
+    match begin.checked_add(len) {
+        Some(end) if len > 0 && end <= size => Ok((begin, len)),
+        _ => Err(anyhow!(
+            "range {}+{} is not within the stream (size {})",
+            begin,
+            len,
+            size
+        )),
+    }
+}
+
+fn unpack_to<D: UnpackDest>(
+    stream: &str,
+    config: &config::Config,
+    range: Option<(StreamReader, u64, u64)>,
+    dest: D,
+    output: Arc<Output>,
+    total: u64,
+) -> Result<()> {
+    match range {
+        Some((mut reader, begin, len)) => unpack_range(&mut reader, dest, begin, len, output),
+        None => {
+            let cache_nr_entries = (1024 * 1024 * config.data_cache_size_meg) / SLAB_SIZE_TARGET;
+            let mut u = Unpacker::new(stream, config, cache_nr_entries, dest)?;
+            u.unpack(output, total)
+        }
+    }
+}
+
+// Returns the range to unpack, or None for the whole stream.
+fn partial_range(
+    matches: &ArgMatches,
+    stream: &str,
+    config: &config::Config,
+) -> Result<Option<(StreamReader, u64, u64)>> {
+    // A partial restore writes the range to the start of the output.
+    let partial = ["OFFSET", "LENGTH", "PARTITION"]
+        .iter()
+        .any(|id| matches.contains_id(id));
+    if partial {
+        let mut reader = StreamReader::new(stream, config)?;
+        let (begin, len) = requested_range(matches, &mut reader)?;
+        Ok(Some((reader, begin, len)))
+    } else {
+        Ok(None)
+    }
+}
+
+// Unmapped regions are written as zeroes, since a pipe can't have holes.
+fn unpack_to_stdout(
+    matches: &ArgMatches,
+    archive_dir: &Path,
+    stream: &str,
+    report_output: Arc<Output>,
+) -> Result<()> {
+    if matches.get_flag("CREATE") {
+        return Err(anyhow!("--create can't be used when unpacking to stdout"));
+    }
+    if report_output.json {
+        return Err(anyhow!("--json can't be used when unpacking to stdout"));
+    }
+
+    env::set_current_dir(archive_dir)?;
+    let stream_cfg = config::read_stream_config(stream)?;
+    let config = config::read_config(".", matches)?;
+    let range = partial_range(matches, stream, &config)?;
+    let total = range
+        .as_ref()
+        .map(|(_, _, len)| *len)
+        .unwrap_or(stream_cfg.size);
+
+    report_output.report.set_title("Unpacking to stdout ...");
+    let dest = ThickDest {
+        output: io::BufWriter::new(io::stdout().lock()),
+    };
+    unpack_to(stream, &config, range, dest, report_output, total)
+}
+
+fn thin_dest(output_file: &Path, output: fs::File, output_size: u64) -> Result<ThinDest> {
+    let mappings = read_thin_mappings(output_file)?;
+    let block_size = mappings.data_block_size as u64 * 512;
+    let provisioned = RunIter::new(
+        mappings.provisioned_blocks,
+        (output_size / block_size) as u32,
+    );
+
+    Ok(ThinDest {
+        block_size,
+        output,
+        pos: 0,
+        provisioned,
+        run: None,
+        writes_avoided: 0,
+        discards: true,
+    })
+}
+
+/// Unpacks a whole stream to an existing thin device of the same size,
+/// only writing blocks whose contents differ.  Assumes we've chdir'd to
+/// the archive.
+pub fn unpack_to_thin(
+    stream: &str,
+    config: &config::Config,
+    thin: &Path,
+    report_output: Arc<Output>,
+) -> Result<()> {
+    let stream_cfg = config::read_stream_config(stream)?;
+    let output = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(thin)
+        .context("Couldn't open output")?;
+    let output_size = thinp::file_utils::file_size(thin)?;
+    if output_size != stream_cfg.size {
+        return Err(anyhow!("Destination size doesn't not match stream size"));
+    }
+
+    let dest = thin_dest(thin, output, output_size)?;
+    unpack_to(stream, config, None, dest, report_output, stream_cfg.size)
+}
+
 pub fn run_unpack(matches: &ArgMatches, report_output: Arc<Output>) -> Result<()> {
     let archive_dir = Path::new(matches.get_one::<String>("ARCHIVE").unwrap())
         .canonicalize()
         .context("Bad archive dir")?;
-    let output_file = Path::new(matches.get_one::<String>("OUTPUT").unwrap());
+    let output_file = match matches.get_one::<String>("LV") {
+        Some(lv) => activate(&LvName::parse(lv)?)?,
+        None => PathBuf::from(matches.get_one::<String>("OUTPUT").unwrap()),
+    };
+    let output_file = output_file.as_path();
     let stream = matches.get_one::<String>("STREAM").unwrap();
     let create = matches.get_flag("CREATE");
 
+    if output_file == Path::new("-") {
+        return unpack_to_stdout(matches, &archive_dir, stream, report_output);
+    }
+
     let output = if create {
         fs::OpenOptions::new()
             .read(false)
@@ -383,52 +831,42 @@ pub fn run_unpack(matches: &ArgMatches, report_output: Arc<Output>) -> Result<()
             .open(output_file)
             .context("Couldn't open output")?
     };
+
+    // The output is looked at again once we're in the archive dir.
+    let output_file = output_file.canonicalize()?;
+    let output_file = output_file.as_path();
     env::set_current_dir(archive_dir)?;
     let stream_cfg = config::read_stream_config(stream)?;
+    let config = config::read_config(".", matches)?;
+
+    let range = partial_range(matches, stream, &config)?;
+    let total = range
+        .as_ref()
+        .map(|(_, _, len)| *len)
+        .unwrap_or(stream_cfg.size);
 
     report_output
         .report
         .set_title(&format!("Unpacking {} ...", output_file.display()));
     if create {
-        let config = config::read_config(".", matches)?;
-        let cache_nr_entries = (1024 * 1024 * config.data_cache_size_meg) / SLAB_SIZE_TARGET;
-
-        let dest = ThickDest { output };
-        let mut u = Unpacker::new(stream, cache_nr_entries, dest)?;
-        u.unpack(report_output, stream_cfg.size)
+        let dest = FileDest::new(output, true);
+        unpack_to(stream, &config, range, dest, report_output, total)
     } else {
         // Check the size matches the stream size.
-        let stream_size = stream_cfg.size;
         let output_size = thinp::file_utils::file_size(output_file)?;
-        if output_size != stream_size {
+        if output_size != total {
             return Err(anyhow!("Destination size doesn't not match stream size"));
         }
 
-        let config = config::read_config(".", matches)?;
-        let cache_nr_entries = (1024 * 1024 * config.data_cache_size_meg) / SLAB_SIZE_TARGET;
-
         if is_thin_device(output_file)? {
-            let mappings = read_thin_mappings(output_file)?;
-            let block_size = mappings.data_block_size as u64 * 512;
-            let provisioned = RunIter::new(
-                mappings.provisioned_blocks,
-                (output_size / block_size) as u32,
-            );
-
-            let dest = ThinDest {
-                block_size,
-                output,
-                pos: 0,
-                provisioned,
-                run: None,
-                writes_avoided: 0,
-            };
-            let mut u = Unpacker::new(stream, cache_nr_entries, dest)?;
-            u.unpack(report_output, stream_size)
+            let dest = thin_dest(output_file, output, output_size)?;
+            unpack_to(stream, &config, range, dest, report_output, total)
+        } else if output.metadata()?.is_file() {
+            let dest = FileDest::new(output, false);
+            unpack_to(stream, &config, range, dest, report_output, total)
         } else {
-            let dest = ThickDest { output };
-            let mut u = Unpacker::new(stream, cache_nr_entries, dest)?;
-            u.unpack(report_output, stream_size)
+            let dest = BlockDest::new(output);
+            unpack_to(stream, &config, range, dest, report_output, total)
         }
     }
 }
@@ -440,15 +878,20 @@ struct VerifyDest {
     chunk: Option<Chunk>,
     chunk_offset: u64,
     total_verified: u64,
+
+    // A hole in a file reads as zeroes, so whether a region was packed
+    // as a hole or as zeroes doesn't matter.  On a thin device it does.
+    holes_are_zeroes: bool,
 }
 
 impl VerifyDest {
-    fn new(input_it: Box<dyn Iterator<Item = Result<Chunk>>>) -> Self {
+    fn new(input_it: Box<dyn Iterator<Item = Result<Chunk>>>, holes_are_zeroes: bool) -> Self {
         Self {
             input_it,
             chunk: None,
             chunk_offset: 0,
             total_verified: 0,
+            holes_are_zeroes,
         }
     }
 }
@@ -519,6 +962,11 @@ impl VerifyDest {
         Ok(())
     }
 
+    fn at_hole(&mut self) -> Result<bool> {
+        self.ensure_chunk()?;
+        Ok(matches!(self.chunk, Some(Chunk::Unmapped(_))))
+    }
+
     fn get_unmapped(&mut self, max_len: u64) -> Result<u64> {
         self.ensure_chunk()?;
         match &self.chunk {
@@ -544,6 +992,17 @@ impl UnpackDest for VerifyDest {
         let mut remaining = expected.len() as u64;
         let mut offset = 0;
         while remaining > 0 {
+            if self.holes_are_zeroes && self.at_hole()? {
+                let len = self.get_unmapped(remaining)?;
+                let expected = &expected[offset as usize..(offset + len) as usize];
+                if expected.iter().any(|b| *b != 0) {
+                    return Err(self.fail("data mismatch"));
+                }
+                remaining -= len;
+                offset += len;
+                continue;
+            }
+
             let actual = self.peek_data(remaining)?;
             let actual_len = actual.len() as u64;
             if actual != &expected[offset as usize..(offset + actual_len) as usize] {
@@ -560,6 +1019,17 @@ impl UnpackDest for VerifyDest {
     fn handle_unmapped(&mut self, len: u64) -> Result<()> {
         let mut remaining = len;
         while remaining > 0 {
+            if self.holes_are_zeroes && !self.at_hole()? {
+                let actual = self.peek_data(remaining)?;
+                let actual_len = actual.len() as u64;
+                if actual.iter().any(|b| *b != 0) {
+                    return Err(self.fail("expected unmapped, got data"));
+                }
+                self.consume_data(actual_len)?;
+                remaining -= actual_len;
+                continue;
+            }
+
             let len = self.get_unmapped(remaining)?;
             remaining -= len;
         }
@@ -576,8 +1046,13 @@ impl UnpackDest for VerifyDest {
 }
 
 fn thick_verifier(input_file: &Path) -> Result<VerifyDest> {
-    let input_it = Box::new(ThickChunker::new(input_file, 16 * 1024 * 1024)?);
-    Ok(VerifyDest::new(input_it))
+    if fs::metadata(input_file)?.is_file() {
+        let input_it = Box::new(SparseChunker::new(input_file, 16 * 1024 * 1024)?);
+        Ok(VerifyDest::new(input_it, true))
+    } else {
+        let input_it = Box::new(ThickChunker::new(input_file, 16 * 1024 * 1024)?);
+        Ok(VerifyDest::new(input_it, false))
+    }
 }
 
 fn thin_verifier(input_file: &Path) -> Result<VerifyDest> {
@@ -600,7 +1075,7 @@ fn thin_verifier(input_file: &Path) -> Result<VerifyDest> {
         mappings.data_block_size as u64 * 512,
     ));
 
-    Ok(VerifyDest::new(input_it))
+    Ok(VerifyDest::new(input_it, false))
 }
 
 pub fn run_verify(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
@@ -627,8 +1102,93 @@ pub fn run_verify(matches: &ArgMatches, output: Arc<Output>) -> Result<()> {
         thick_verifier(&input_file)?
     };
 
-    let mut u = Unpacker::new(stream, cache_nr_entries, dest)?;
+    let mut u = Unpacker::new(stream, &config, cache_nr_entries, dest)?;
     u.unpack(output, stream_cfg.size)
 }
 
 //-----------------------------------------
+
+#[cfg(test)]
+mod thin_dest_tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+
+    const BLOCK_SIZE: u64 = 64 * 1024;
+
+    // A regular file stands in for the thin.  It won't take discards, so
+    // discarded blocks are zeroed, which is what a thin reads back.
+    fn thin(nr_blocks: u32, provisioned: &[u32]) -> ThinDest {
+        let output = tempfile::tempfile().unwrap();
+        output.set_len(nr_blocks as u64 * BLOCK_SIZE).unwrap();
+        for b in provisioned {
+            output
+                .write_all_at(&vec![0xff; BLOCK_SIZE as usize], *b as u64 * BLOCK_SIZE)
+                .unwrap();
+        }
+
+        ThinDest {
+            block_size: BLOCK_SIZE,
+            output,
+            pos: 0,
+            provisioned: RunIter::new(provisioned.iter().cloned().collect(), nr_blocks),
+            run: None,
+            writes_avoided: 0,
+            discards: true,
+        }
+    }
+
+    fn contents(dest: &ThinDest) -> Vec<u8> {
+        let mut buf = vec![0; dest.pos as usize];
+        dest.output.read_exact_at(&mut buf, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn sparse_file_restores_onto_provisioned_thin() {
+        let nr_blocks = 64;
+        let size = nr_blocks as u64 * BLOCK_SIZE;
+
+        // Holes start and end on filesystem blocks, not thin blocks.
+        let input = tempfile::NamedTempFile::new().unwrap();
+        let file = input.as_file();
+        file.set_len(size).unwrap();
+        file.write_all_at(&[1; 12 * 1024 + 100], 0).unwrap();
+        file.write_all_at(&[2; 8000], 1_500_000).unwrap();
+        let mut expected = vec![0; size as usize];
+        file.read_exact_at(&mut expected, 0).unwrap();
+
+        let provisioned: Vec<u32> = (0..nr_blocks).filter(|b| !(40..44).contains(b)).collect();
+        let mut dest = thin(nr_blocks, &provisioned);
+        for chunk in SparseChunker::new(input.path(), BLOCK_SIZE).unwrap() {
+            match chunk.unwrap() {
+                Chunk::Mapped(data) => dest.handle_mapped(&data).unwrap(),
+                Chunk::Unmapped(len) => dest.handle_unmapped(len).unwrap(),
+                Chunk::Ref(_) => panic!("unexpected ref"),
+            }
+        }
+        dest.complete().unwrap();
+
+        assert_eq!(dest.pos, size);
+        assert!(contents(&dest) == expected);
+    }
+
+    #[test]
+    fn derived_unmapped_segment_restores_onto_provisioned_thin() {
+        // eg, derive STREAM:0+5000 unmapped:200000 STREAM:205000
+        let nr_blocks = 8;
+        let size = nr_blocks as u64 * BLOCK_SIZE;
+        let mut expected = vec![3; size as usize];
+        expected[5000..205000].fill(0);
+
+        let mut dest = thin(nr_blocks, &[0, 1, 2, 3, 5, 6, 7]);
+        dest.handle_mapped(&expected[..5000]).unwrap();
+        dest.handle_unmapped(200000).unwrap();
+        dest.handle_mapped(&expected[205000..]).unwrap();
+        dest.complete().unwrap();
+
+        assert_eq!(dest.pos, size);
+        assert!(contents(&dest) == expected);
+    }
+}
+
+//-----------------------------------------
@@ -18,6 +18,14 @@ pub struct PackStats {
     pub data_written: u64,
     pub mapped_size: u64,
     pub fill_size: u64,
+    #[serde(default)]
+    pub delta_size: u64,
+    #[serde(default)]
+    pub delta_saved: u64,
+    #[serde(default)]
+    pub parent_size: u64,
+    #[serde(default)]
+    pub discarded_size: u64,
 }
 #[derive(Deserialize, Serialize, Debug)]
 pub struct PackResponse {
@@ -25,6 +33,19 @@ pub struct PackResponse {
     pub stats: PackStats,
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PoolSetThinResponse {
+    pub thin_id: u32,
+    pub stream_id: String,
+    pub base: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PackPoolResponse {
+    pub pool_set_id: String,
+    pub thins: Vec<PoolSetThinResponse>,
+}
+
 impl BlkArchive {
     pub fn new(archive: &Path) -> Result<Self> {
         Self::new_with(archive, 4096, true)
@@ -47,12 +68,42 @@ impl BlkArchive {
         })
     }
 
+    pub fn new_with_index_mode(archive: &Path, index_mode: &str) -> Result<Self> {
+        run_ok(create_cmd(args!["-a", archive, "--index-mode", index_mode]))?;
+        Ok(Self {
+            archive: archive.to_path_buf(),
+        })
+    }
+
+    pub fn new_with_delta_compression(archive: &Path) -> Result<Self> {
+        run_ok(create_cmd(args!["-a", archive, "--delta-compression", "y"]))?;
+        Ok(Self {
+            archive: archive.to_path_buf(),
+        })
+    }
+
+    pub fn new_with_parent(archive: &Path, parent: &BlkArchive) -> Result<Self> {
+        run_ok(create_cmd(args![
+            "-a",
+            archive,
+            "--parent",
+            &parent.archive
+        ]))?;
+        Ok(Self {
+            archive: archive.to_path_buf(),
+        })
+    }
+
     pub fn from_path(archive: &Path) -> Result<Self> {
         Ok(Self {
             archive: archive.to_path_buf(),
         })
     }
 
+    pub fn path(&self) -> &Path {
+        &self.archive
+    }
+
     pub fn data_size(&self) -> std::io::Result<u64> {
         fn file_size(path: &PathBuf) -> std::io::Result<u64> {
             fs::metadata(path).map(|meta| meta.len())
@@ -63,16 +114,76 @@ impl BlkArchive {
         Ok(data_size)
     }
 
+    pub fn stream_slabs_size(&self) -> std::io::Result<u64> {
+        fs::metadata(self.archive.join("data/stream_slabs")).map(|meta| meta.len())
+    }
+
+    pub fn stream_config(&self, stream: &str) -> Result<serde_yaml_ng::Value> {
+        let input = fs::read_to_string(
+            self.archive
+                .join("streams")
+                .join(stream)
+                .join("config.yaml"),
+        )?;
+        Ok(serde_yaml_ng::from_str(&input)?)
+    }
+
     pub fn pack_cmd(&self, input: &Path) -> Command {
         pack_cmd(args!["-a", &self.archive, &input, "-j"])
     }
 
+    pub fn pack_with_budget_cmd(&self, input: &Path, budget_meg: &str, enforce: bool) -> Command {
+        let mut args = args![
+            "-a",
+            &self.archive,
+            &input,
+            "-j",
+            "--memory-budget-meg",
+            budget_meg
+        ]
+        .to_vec();
+        if enforce {
+            args.push(std::ffi::OsStr::new("--enforce-memory-budget"));
+        }
+        pack_cmd(args)
+    }
+
+    pub fn pack_with_args_cmd(&self, input: &Path, args: &[&str]) -> Command {
+        let mut all_args = args!["-a", &self.archive, &input, "-j"].to_vec();
+        all_args.extend(args.iter().map(std::ffi::OsStr::new));
+        pack_cmd(all_args)
+    }
+
+    pub fn pack_with_args(&self, input: &Path, args: &[&str]) -> Result<PackResponse> {
+        let stdout = run_ok(self.pack_with_args_cmd(input, args))?;
+        Ok(serde_json::from_str(&stdout)?)
+    }
+
+    pub fn pack_pool_cmd(&self, pool: &Path, args: &[&str]) -> Command {
+        let mut all_args = args!["-a", &self.archive, &pool, "-j"].to_vec();
+        all_args.extend(args.iter().map(std::ffi::OsStr::new));
+        pack_pool_cmd(all_args)
+    }
+
+    pub fn pack_pool(&self, pool: &Path, args: &[&str]) -> Result<PackPoolResponse> {
+        let stdout = run_ok(self.pack_pool_cmd(pool, args))?;
+        Ok(serde_json::from_str(&stdout)?)
+    }
+
     pub fn pack(&self, input: &Path) -> Result<PackResponse> {
         let stdout = run_ok(self.pack_cmd(input))?;
         let response: PackResponse = serde_json::from_str(&stdout)?;
         Ok(response)
     }
 
+    pub fn pack_stdin(&self, input: &Path) -> Result<PackResponse> {
+        let cmd = pack_cmd(args!["-a", &self.archive, "-", "-j"]);
+        eprintln!("run_ok: {} < {}", cmd, input.display());
+        let output = cmd.to_expr().stdin_path(input).stdout_capture().run()?;
+        let response: PackResponse = serde_json::from_slice(&output.stdout)?;
+        Ok(response)
+    }
+
     pub fn unpack_cmd(&self, stream: &str, output: &Path, create: bool) -> Command {
         let mut args = args!["-a", &self.archive, "-s", stream, &output].to_vec();
         if create {
@@ -86,6 +197,62 @@ impl BlkArchive {
         Ok(())
     }
 
+    pub fn unpack_stdout(&self, stream: &str, output: &Path) -> Result<()> {
+        let cmd = unpack_cmd(args!["-a", &self.archive, "-s", stream, "-"]);
+        eprintln!("run_ok: {} > {}", cmd, output.display());
+        cmd.to_expr().stdout_path(output).run()?;
+        Ok(())
+    }
+
+    pub fn unpack_range(
+        &self,
+        stream: &str,
+        output: &Path,
+        create: bool,
+        range: &[&str],
+    ) -> Result<()> {
+        let mut args = args!["-a", &self.archive, "-s", stream, &output].to_vec();
+        if create {
+            args.push(std::ffi::OsStr::new("--create"));
+        }
+        args.extend(range.iter().map(std::ffi::OsStr::new));
+        run_ok(unpack_cmd(args))?;
+        Ok(())
+    }
+
+    // Returns the id of the new stream.
+    pub fn derive(&self, args: &[&str]) -> Result<String> {
+        let mut all_args = args!["-a", &self.archive, "-j"].to_vec();
+        all_args.extend(args.iter().map(std::ffi::OsStr::new));
+        let stdout = run_ok(derive_cmd(all_args))?;
+        let response: serde_json::Value = serde_json::from_str(&stdout)?;
+        Ok(response["stream_id"].as_str().unwrap().to_string())
+    }
+
+    pub fn diff(&self, old: &str, new: &str) -> Result<serde_json::Value> {
+        let stdout = run_ok(diff_cmd(args![
+            "-a",
+            &self.archive,
+            "-s",
+            old,
+            "-s",
+            new,
+            "-j"
+        ]))?;
+        Ok(serde_json::from_str(&stdout)?)
+    }
+
+    pub fn dump_stream(&self, stream: &str, args: &[&str]) -> Result<String> {
+        let mut all_args = args!["-a", &self.archive, "-s", stream].to_vec();
+        all_args.extend(args.iter().map(std::ffi::OsStr::new));
+        run_ok(dump_stream_cmd(all_args))
+    }
+
+    pub fn index_info(&self) -> Result<serde_json::Value> {
+        let stdout = run_ok(index_info_cmd(args!["-a", &self.archive, "-j"]))?;
+        Ok(serde_json::from_str(&stdout)?)
+    }
+
     pub fn verify_cmd(&self, input: &Path, stream: &str) -> Command {
         verify_cmd(args!["-a", &self.archive, "-s", stream, input])
     }
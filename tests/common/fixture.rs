@@ -1,11 +1,11 @@
 use anyhow::{anyhow, Result};
-use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
 use thinp::file_utils::create_sized_file;
 
 use crate::common::blk_archive::*;
 use crate::common::block_visitor::*;
-use crate::common::random::Pattern;
+use crate::common::random::{Generator, Pattern};
 use crate::common::test_dir::*;
 
 //-----------------------------------------
@@ -30,6 +30,26 @@ pub fn create_input_file(
     Ok(path)
 }
 
+// Creates a sparse file with random data in the given (offset, len)
+// extents, and holes everywhere else.
+pub fn create_sparse_input_file(
+    td: &mut TestDir,
+    size: u64,
+    extents: &[(u64, u64)],
+) -> Result<PathBuf> {
+    let path = td.mk_path("sparse.bin");
+    let file = std::fs::File::create(&path)?;
+    file.set_len(size)?;
+
+    let mut gen = Generator::new(Pattern::LCG);
+    for (i, (offset, len)) in extents.iter().enumerate() {
+        let mut buf = vec![0; *len as usize];
+        gen.fill_buffer(i as u64 + 1, &mut buf)?;
+        file.write_all_at(&buf, *offset)?;
+    }
+    Ok(path)
+}
+
 pub fn verify_file(path: &Path, size: u64, seed: u64, pattern: Pattern) -> Result<()> {
     let actual_size = std::fs::metadata(path)?.len();
     if actual_size != size {
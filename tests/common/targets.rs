@@ -44,6 +44,46 @@ where
     target_cmd("unpack", args)
 }
 
+pub fn derive_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    target_cmd("derive", args)
+}
+
+pub fn diff_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    target_cmd("diff", args)
+}
+
+pub fn pack_pool_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    target_cmd("pack-pool", args)
+}
+
+pub fn dump_stream_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    target_cmd("dump-stream", args)
+}
+
+pub fn index_info_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    target_cmd("index-info", args)
+}
+
 pub fn verify_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
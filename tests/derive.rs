@@ -0,0 +1,76 @@
+use anyhow::Result;
+use std::fs;
+
+mod common;
+
+use crate::common::random::Pattern;
+use common::blk_archive::*;
+use common::fixture::*;
+use common::test_dir::*;
+
+//-----------------------------------------
+
+#[test]
+fn derive_range_and_fills() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let input = create_input_file(&mut td, 16 * 1024 * 1024, 1, Pattern::LCG)?;
+    let stream = archive.pack(&input)?.stream_id;
+    let data = fs::read(&input)?;
+    let data_size = archive.data_size()?;
+
+    // Unaligned, so entries have to be split.
+    let (begin, len) = (1024 * 1024 + 1234, 4 * 1024 * 1024 + 77);
+    let derived = archive.derive(&[
+        &format!("{}:{}+{}", stream, begin, len),
+        "zero:4096",
+        &format!("{}:{}", stream, 15 * 1024 * 1024),
+    ])?;
+    assert_eq!(archive.data_size()?, data_size);
+
+    let mut expected = data[begin..begin + len].to_vec();
+    expected.extend_from_slice(&[0; 4096]);
+    expected.extend_from_slice(&data[15 * 1024 * 1024..]);
+
+    let output = td.mk_path("derived.bin");
+    archive.unpack(&derived, &output, true)?;
+    assert_eq!(fs::read(&output)?, expected);
+    Ok(())
+}
+
+#[test]
+fn derive_zeroed_range() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive_dir = td.mk_path("test_arch");
+    let archive = BlkArchive::new(&archive_dir)?;
+    let nr_streams = || fs::read_dir(archive_dir.join("streams")).unwrap().count();
+    let stream_slabs_size = || {
+        fs::metadata(archive_dir.join("data/stream_slabs"))
+            .unwrap()
+            .len()
+    };
+
+    let input = create_input_file(&mut td, 8 * 1024 * 1024, 1, Pattern::LCG)?;
+    let stream = archive.pack(&input)?.stream_id;
+    let mut expected = fs::read(&input)?;
+
+    let derived = archive.derive(&[&stream, "--zero", "100000+300000", "--zero", "8388000+608"])?;
+    expected[100000..400000].fill(0);
+    expected[8388000..].fill(0);
+
+    let output = td.mk_path("erased.bin");
+    archive.unpack(&derived, &output, true)?;
+    assert_eq!(fs::read(&output)?, expected);
+
+    // Zeroing past the end is an error, and leaves no stream, or stream
+    // slabs, behind.
+    let before = (nr_streams(), stream_slabs_size());
+    assert!(archive
+        .derive(&[&stream, "--zero", "7000000+1388609"])
+        .is_err());
+    assert_eq!((nr_streams(), stream_slabs_size()), before);
+    Ok(())
+}
+
+//-----------------------------------------
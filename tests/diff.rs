@@ -0,0 +1,97 @@
+use anyhow::Result;
+use std::fs;
+
+mod common;
+
+use crate::common::random::Pattern;
+use common::fixture::*;
+use common::test_dir::*;
+
+//-----------------------------------------
+
+fn changed_ranges(diff: &serde_json::Value) -> Vec<(u64, u64)> {
+    diff["changed"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| {
+            let offset = r["offset"].as_u64().unwrap();
+            (offset, offset + r["len"].as_u64().unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn diff_identical_streams() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let input = create_input_file(&mut td, 16 * 1024 * 1024, 1, Pattern::LCG)?;
+    let s1 = archive.pack(&input)?.stream_id;
+    let s2 = archive.pack(&input)?.stream_id;
+
+    let diff = archive.diff(&s1, &s2)?;
+    assert_eq!(diff["changed_bytes"].as_u64(), Some(0));
+    assert!(changed_ranges(&diff).is_empty());
+    Ok(())
+}
+
+#[test]
+fn diff_finds_changes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let file_size = 16 * 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    let old = archive.pack(&input)?.stream_id;
+
+    let mut data = fs::read(&input)?;
+    let edits = [(1024 * 1024, 4096), (9 * 1024 * 1024 + 7, 100)];
+    for (offset, len) in edits {
+        data[offset..offset + len].fill(0xff);
+    }
+    // Zeroing the tail turns it into a fill.
+    data[15 * 1024 * 1024..].fill(0);
+    let modified = td.mk_path("modified.bin");
+    fs::write(&modified, &data)?;
+    let new = archive.pack(&modified)?.stream_id;
+
+    let diff = archive.diff(&old, &new)?;
+    let ranges = changed_ranges(&diff);
+    let covered = |b: u64, e: u64| ranges.iter().any(|(rb, re)| *rb <= b && e <= *re);
+    for (offset, len) in edits {
+        assert!(covered(offset as u64, (offset + len) as u64));
+    }
+    assert!(covered(15 * 1024 * 1024, file_size));
+
+    // Everything else is shared, give or take the chunks either side.
+    let total: u64 = ranges.iter().map(|(b, e)| e - b).sum();
+    assert_eq!(diff["changed_bytes"].as_u64(), Some(total));
+    assert!(total < 2 * 1024 * 1024);
+
+    // The reverse diff has the same ranges.
+    assert_eq!(changed_ranges(&archive.diff(&new, &old)?), ranges);
+    Ok(())
+}
+
+#[test]
+fn diff_different_sizes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let input = create_input_file(&mut td, 8 * 1024 * 1024, 1, Pattern::LCG)?;
+    let long = archive.pack(&input)?.stream_id;
+    let data = fs::read(&input)?;
+    let truncated = td.mk_path("truncated.bin");
+    fs::write(&truncated, &data[..6 * 1024 * 1024])?;
+    let short = archive.pack(&truncated)?.stream_id;
+
+    let diff = archive.diff(&short, &long)?;
+    let ranges = changed_ranges(&diff);
+    let last = ranges.last().unwrap();
+    assert_eq!(last.1, 8 * 1024 * 1024);
+    assert!(last.0 <= 6 * 1024 * 1024);
+    Ok(())
+}
+
+//-----------------------------------------
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use std::fs;
+
+mod common;
+
+use crate::common::random::Pattern;
+use common::blk_archive::BlkArchive;
+use common::fixture::*;
+use common::test_dir::*;
+
+//-----------------------------------------
+
+// Packs an LCG file with a zeroed region in the middle.
+fn pack_with_fill(td: &mut TestDir) -> Result<(BlkArchive, String, u64)> {
+    let archive = create_archive(td, true)?;
+
+    let file_size = 16 * 1024 * 1024;
+    let input = create_input_file(td, file_size, 1, Pattern::LCG)?;
+    let mut data = fs::read(&input)?;
+    data[4 * 1024 * 1024..8 * 1024 * 1024].fill(0);
+    fs::write(&input, &data)?;
+
+    let stream = archive.pack(&input)?.stream_id;
+    Ok((archive, stream, file_size))
+}
+
+#[test]
+fn dump_stream_json_entries() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (archive, stream, file_size) = pack_with_fill(&mut td)?;
+
+    let stdout = archive.dump_stream(&stream, &["--format", "json"])?;
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&stdout)?;
+
+    let mut offset = 0;
+    for e in &entries {
+        assert_eq!(e["offset"].as_u64(), Some(offset));
+        offset += e["len"].as_u64().unwrap();
+    }
+    assert_eq!(offset, file_size);
+
+    assert!(entries
+        .iter()
+        .any(|e| e["kind"] == "fill" && e["byte"] == 0));
+    assert!(entries
+        .iter()
+        .any(|e| e["kind"] == "data" && e["slab"].is_u64()));
+    Ok(())
+}
+
+#[test]
+fn dump_stream_csv_entries() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (archive, stream, file_size) = pack_with_fill(&mut td)?;
+
+    let stdout = archive.dump_stream(&stream, &["--format", "csv"])?;
+    let mut lines = stdout.lines();
+    let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+    assert_eq!(&header[..3], &["offset", "len", "kind"]);
+
+    let mut total = 0;
+    for line in lines {
+        let row: Vec<&str> = line.split(',').collect();
+        assert_eq!(row.len(), header.len());
+        total += row[1].parse::<u64>()?;
+    }
+    assert_eq!(total, file_size);
+    Ok(())
+}
+
+#[test]
+fn dump_stream_stats() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (archive, stream, file_size) = pack_with_fill(&mut td)?;
+
+    let stdout = archive.dump_stream(&stream, &["--stats", "--format", "json"])?;
+    let stats: serde_json::Value = serde_json::from_str(&stdout)?;
+
+    assert_eq!(stats["size"].as_u64(), Some(file_size));
+    let data = stats["data_bytes"].as_u64().unwrap();
+    let fill = stats["fill_bytes"].as_u64().unwrap();
+    let unmapped = stats["unmapped_bytes"].as_u64().unwrap();
+    assert_eq!(data + fill + unmapped, file_size);
+    assert!(data > 0 && fill > 0);
+    assert!(stats["data_slabs"].as_u64().unwrap() >= 1);
+    assert!(stats["instructions"].as_object().unwrap().len() > 1);
+    Ok(())
+}
+
+//-----------------------------------------
@@ -4,11 +4,64 @@ use rand::Rng;
 mod common;
 
 use blk_archive::archive;
-use common::blk_archive::PackResponse;
-use common::fixture::{create_archive, create_input_file, BLOCK_SIZE};
+use common::blk_archive::{BlkArchive, PackResponse};
+use common::fixture::{create_archive, create_input_file, create_sparse_input_file, BLOCK_SIZE};
+use common::process::{run_fail, run_ok};
 use common::random::Pattern;
 use common::test_dir::*;
 
+#[test]
+fn pack_merges_filter_levels() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive_dir = td.mk_path("test_arch");
+    let archive = BlkArchive::new_with(&archive_dir, 512, false)?;
+
+    // Each pack of new data adds a level, until another would leave
+    // none spare and they're merged.
+    let file_size = 8 * 1024 * 1024;
+    let mut inputs = Vec::new();
+    let mut levels = Vec::new();
+    for i in 1..=6 {
+        // Blocks are seeded with seed ^ block, so keep the seeds apart.
+        let input = create_input_file(&mut td, file_size, i << 32, Pattern::LCG)?;
+        archive.pack(&input)?;
+        levels.push(archive.index_info()?["filter"]["levels"].as_u64().unwrap());
+        inputs.push(input);
+    }
+    assert_eq!(levels[..2], [2, 3]);
+    assert!(levels.contains(&1));
+    assert!(levels.iter().all(|l| *l < 4));
+
+    // Nothing is lost by the merge, beyond the odd false positive.
+    for input in &inputs {
+        let response = archive.pack(input)?;
+        assert!(response.stats.data_written < file_size / 1000);
+    }
+    Ok(())
+}
+
+#[test]
+fn filter_levels_are_capped_mid_pack() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive_dir = td.mk_path("test_arch");
+    let archive = BlkArchive::new_with(&archive_dir, 512, false)?;
+
+    // Nothing can be reserved for a pack from stdin, so the filter fills
+    // part way through.  It's never given more than four levels, and
+    // what didn't fit is added back once the pack completes.
+    let file_size = 64 * 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    archive.pack_stdin(&input)?;
+    assert_eq!(
+        archive.index_info()?["filter"]["levels"].as_u64().unwrap(),
+        1
+    );
+
+    let response = archive.pack(&input)?;
+    assert!(response.stats.data_written < file_size / 1000);
+    Ok(())
+}
+
 //-----------------------------------------
 
 #[test]
@@ -39,6 +92,22 @@ fn pack_same_file_multiple_times() -> Result<()> {
         .try_for_each(|s| archive.verify(&input, &s.stream_id))
 }
 
+#[test]
+fn pack_same_file_again_writes_nothing() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, false)?;
+
+    // Big enough to span several data slabs, so some entries are the
+    // first in their slab.
+    let file_size = 16 * 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    archive.pack(&input)?;
+
+    let response = archive.pack(&input)?;
+    assert_eq!(response.stats.data_written, 0);
+    archive.verify(&input, &response.stream_id)
+}
+
 fn pack_common_verify_stats(file_size: u64, pattern: Pattern) -> Result<PackResponse> {
     let mut td = TestDir::new()?;
     let seed = 1;
@@ -105,4 +174,424 @@ fn pack_random_verify_stats() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn pack_sparse_file() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let mb = 1024 * 1024;
+    let extents = [(0, 8 * mb), (40 * mb, 8 * mb)];
+    let input = create_sparse_input_file(&mut td, 64 * mb, &extents)?;
+    let response = archive.pack(&input)?;
+
+    // The holes are never read, so only the extents count as mapped.
+    assert_eq!(response.stats.mapped_size, 16 * mb);
+    assert_eq!(response.stats.fill_size, 0);
+    archive.verify(&input, &response.stream_id)?;
+
+    // A copy with the holes filled in still matches.
+    let dense = td.mk_path("dense.bin");
+    std::fs::write(&dense, std::fs::read(&input)?)?;
+    archive.verify(&dense, &response.stream_id)
+}
+
+#[test]
+fn pack_from_thin_xml() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    // 64k data blocks, with distinct contents so misplaced reads show.
+    let block_size = 64 * 1024;
+    let data_dev = td.mk_path("data.bin");
+    let data: Vec<u8> = (0..16 * block_size)
+        .map(|i| (i / block_size * 7 + i % 251) as u8)
+        .collect();
+    std::fs::write(&data_dev, &data)?;
+
+    let metadata = td.mk_path("metadata.xml");
+    std::fs::write(
+        &metadata,
+        r#"<superblock uuid="" time="1" transaction="2" version="2" data_block_size="128" nr_data_blocks="16">
+  <device dev_id="1" mapped_blocks="3" transaction="0" creation_time="0" snap_time="0">
+    <range_mapping origin_begin="0" data_begin="10" length="2" time="0"/>
+    <single_mapping origin_block="5" data_block="3" time="0"/>
+  </device>
+  <device dev_id="2" mapped_blocks="1" transaction="0" creation_time="0" snap_time="0">
+    <single_mapping origin_block="0" data_block="4" time="0"/>
+  </device>
+</superblock>
+"#,
+    )?;
+
+    let thin_size = 8 * block_size;
+    let size_arg = thin_size.to_string();
+    let args = [
+        "--thin-metadata",
+        metadata.to_str().unwrap(),
+        "--thin-id",
+        "1",
+        "--thin-size",
+        &size_arg,
+    ];
+    let response = archive.pack_with_args(&data_dev, &args)?;
+    assert_eq!(response.stats.mapped_size, 3 * block_size as u64);
+
+    let mut expected = vec![0; thin_size];
+    for (thin_b, data_b) in [(0, 10), (1, 11), (5, 3)] {
+        expected[thin_b * block_size..(thin_b + 1) * block_size]
+            .copy_from_slice(&data[data_b * block_size..(data_b + 1) * block_size]);
+    }
+    let output = td.mk_path("thin.bin");
+    archive.unpack(&response.stream_id, &output, true)?;
+    assert_eq!(std::fs::read(&output)?, expected);
+
+    // An unknown device, or a size that cuts off mappings, are errors.
+    let bad_id = [
+        "--thin-metadata",
+        metadata.to_str().unwrap(),
+        "--thin-id",
+        "3",
+    ];
+    run_fail(archive.pack_with_args_cmd(&data_dev, &bad_id))?;
+    let too_small = [
+        "--thin-metadata",
+        metadata.to_str().unwrap(),
+        "--thin-id",
+        "1",
+        "--thin-size",
+        "65536",
+    ];
+    run_fail(archive.pack_with_args_cmd(&data_dev, &too_small))?;
+    Ok(())
+}
+
+#[test]
+fn pack_thin_delta_with_discards() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let block_size = 64 * 1024;
+    let data_dev = td.mk_path("data.bin");
+    let data: Vec<u8> = (0..16 * block_size)
+        .map(|i| (i / block_size * 7 + i % 251) as u8)
+        .collect();
+    std::fs::write(&data_dev, &data)?;
+
+    // Thin 2 is a snapshot of thin 1 that has had blocks 2-3 and the
+    // tail discarded, and block 4 overwritten.  Everything in thin 3
+    // has been discarded.
+    let metadata = td.mk_path("metadata.xml");
+    std::fs::write(
+        &metadata,
+        r#"<superblock uuid="" time="1" transaction="2" version="2" data_block_size="128" nr_data_blocks="16">
+  <device dev_id="1" mapped_blocks="8" transaction="0" creation_time="0" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="8" time="0"/>
+  </device>
+  <device dev_id="2" mapped_blocks="4" transaction="1" creation_time="1" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="2" time="0"/>
+    <single_mapping origin_block="4" data_block="12" time="1"/>
+    <single_mapping origin_block="5" data_block="5" time="0"/>
+  </device>
+  <device dev_id="3" mapped_blocks="0" transaction="1" creation_time="1" snap_time="1">
+  </device>
+</superblock>
+"#,
+    )?;
+
+    let thin_size = 8 * block_size;
+    let size_arg = thin_size.to_string();
+    let pack_thin = |id: &str, delta: Option<&str>| {
+        let mut args = vec![
+            "--thin-metadata",
+            metadata.to_str().unwrap(),
+            "--thin-id",
+            id,
+            "--thin-size",
+            &size_arg,
+        ];
+        if let Some(stream) = delta {
+            args.extend(["--delta-stream", stream]);
+        }
+        archive.pack_with_args(&data_dev, &args)
+    };
+
+    let base = pack_thin("1", None)?;
+    assert_eq!(base.stats.discarded_size, 0);
+
+    // Only the overwritten block is read.
+    let snap = pack_thin("2", Some(&base.stream_id))?;
+    assert_eq!(snap.stats.discarded_size, 4 * block_size as u64);
+    assert_eq!(snap.stats.mapped_size, block_size as u64);
+
+    let mut expected = vec![0; thin_size];
+    for (thin_b, data_b) in [(0, 0), (1, 1), (4, 12), (5, 5)] {
+        expected[thin_b * block_size..(thin_b + 1) * block_size]
+            .copy_from_slice(&data[data_b * block_size..(data_b + 1) * block_size]);
+    }
+    let output = td.mk_path("snap.bin");
+    archive.unpack(&snap.stream_id, &output, true)?;
+    assert_eq!(std::fs::read(&output)?, expected);
+
+    let empty = pack_thin("3", Some(&base.stream_id))?;
+    assert_eq!(empty.stats.discarded_size, thin_size as u64);
+    let output = td.mk_path("empty.bin");
+    archive.unpack(&empty.stream_id, &output, true)?;
+    assert_eq!(std::fs::read(&output)?, vec![0; thin_size]);
+
+    // The base has to be a stream of a thin device.
+    let input = create_input_file(&mut td, thin_size as u64, 1, Pattern::LCG)?;
+    let thick = archive.pack(&input)?;
+    run_fail(archive.pack_with_args_cmd(
+        &data_dev,
+        &[
+            "--thin-metadata",
+            metadata.to_str().unwrap(),
+            "--thin-id",
+            "2",
+            "--delta-stream",
+            &thick.stream_id,
+        ],
+    ))?;
+    Ok(())
+}
+
+#[test]
+fn pack_pool_from_thin_xml() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive_dir = td.mk_path("test_arch");
+    let archive = BlkArchive::new_with(&archive_dir, 4096, true)?;
+
+    let block_size = 64 * 1024;
+    let data_dev = td.mk_path("data.bin");
+    let data: Vec<u8> = (0..16 * block_size)
+        .map(|i| (i / block_size * 7 + i % 251) as u8)
+        .collect();
+    std::fs::write(&data_dev, &data)?;
+
+    // Thin 2 is a snapshot of thin 1, that has since had block 3
+    // overwritten and block 5 discarded.  Thin 3 is unrelated.
+    let metadata = td.mk_path("metadata.xml");
+    std::fs::write(
+        &metadata,
+        r#"<superblock uuid="" time="2" transaction="3" version="2" data_block_size="128" nr_data_blocks="16">
+  <device dev_id="1" mapped_blocks="7" transaction="0" creation_time="0" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="6" time="0"/>
+    <single_mapping origin_block="7" data_block="7" time="0"/>
+  </device>
+  <device dev_id="2" mapped_blocks="6" transaction="1" creation_time="1" snap_time="1">
+    <range_mapping origin_begin="0" data_begin="0" length="3" time="0"/>
+    <single_mapping origin_block="3" data_block="12" time="1"/>
+    <single_mapping origin_block="4" data_block="4" time="0"/>
+    <single_mapping origin_block="7" data_block="7" time="0"/>
+  </device>
+  <device dev_id="3" mapped_blocks="2" transaction="2" creation_time="2" snap_time="2">
+    <range_mapping origin_begin="0" data_begin="13" length="2" time="2"/>
+  </device>
+</superblock>
+"#,
+    )?;
+
+    // The metadata doesn't hold the thins' sizes.
+    let mut args = vec!["--thin-metadata", metadata.to_str().unwrap()];
+    run_fail(archive.pack_pool_cmd(&data_dev, &args))?;
+    let sizes = [
+        format!("1:{}", 8 * block_size),
+        format!("2:{}", 8 * block_size),
+        format!("3:{}", 3 * block_size),
+    ];
+    for size in &sizes {
+        args.extend(["--thin-size", size.as_str()]);
+    }
+    let response = archive.pack_pool(&data_dev, &args)?;
+    let bases: Vec<(u32, Option<u32>)> =
+        response.thins.iter().map(|t| (t.thin_id, t.base)).collect();
+    assert_eq!(bases, vec![(1, None), (2, Some(1)), (3, None)]);
+    assert!(archive_dir
+        .join("pools")
+        .join(&response.pool_set_id)
+        .join("config.yaml")
+        .exists());
+
+    let image = |mappings: &[(usize, usize)], nr_blocks: usize| {
+        let mut image = vec![0; nr_blocks * block_size];
+        for (thin_b, data_b) in mappings {
+            image[thin_b * block_size..(thin_b + 1) * block_size]
+                .copy_from_slice(&data[data_b * block_size..(data_b + 1) * block_size]);
+        }
+        image
+    };
+    let expected = [
+        image(&[(0, 0), (1, 1), (2, 2), (3, 3), (4, 4), (5, 5), (7, 7)], 8),
+        image(&[(0, 0), (1, 1), (2, 2), (3, 12), (4, 4), (7, 7)], 8),
+        image(&[(0, 13), (1, 14)], 3),
+    ];
+    for (thin, expected) in response.thins.iter().zip(expected.iter()) {
+        let output = td.mk_path(&format!("thin{}.bin", thin.thin_id));
+        archive.unpack(&thin.stream_id, &output, true)?;
+        assert_eq!(&std::fs::read(&output)?, expected);
+    }
+
+    // Thin 2 is sized by its own mappings, not just those it changed.
+    let mapped_sizes: Vec<u64> = response
+        .thins
+        .iter()
+        .map(|t| {
+            archive.stream_config(&t.stream_id).unwrap()["mapped_size"]
+                .as_u64()
+                .unwrap()
+        })
+        .collect();
+    let block_size = block_size as u64;
+    assert_eq!(
+        mapped_sizes,
+        vec![7 * block_size, 6 * block_size, 2 * block_size]
+    );
+    Ok(())
+}
+
+#[test]
+fn pack_with_disk_index() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = BlkArchive::new_with_index_mode(&td.mk_path("test_arch"), "disk")?;
+
+    let file_size = 16 * 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    let first = archive.pack(&input)?;
+    assert_eq!(first.stats.data_written, file_size);
+
+    // The second pack finds everything via the full index.
+    let second = archive.pack(&input)?;
+    assert_eq!(second.stats.data_written, 0);
+
+    archive.verify(&input, &first.stream_id)?;
+    archive.verify(&input, &second.stream_id)
+}
+
+#[test]
+fn pack_near_duplicates_as_deltas() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = BlkArchive::new_with_delta_compression(&td.mk_path("test_arch"))?;
+
+    let file_size = 16 * 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    let first = archive.pack(&input)?;
+
+    // Stamp a few bytes every 16k, so many chunks differ only slightly.
+    let mut data = std::fs::read(&input)?;
+    for offset in (1000..data.len()).step_by(16 * 1024) {
+        data[offset..offset + 8].copy_from_slice(b"modified");
+    }
+    let modified = td.mk_path("modified.bin");
+    std::fs::write(&modified, &data)?;
+
+    let second = archive.pack(&modified)?;
+    assert!(second.stats.delta_size > file_size / 8);
+    assert!(second.stats.data_written < file_size / 32);
+    assert!(second.stats.delta_saved > 0);
+    assert!(second.stats.delta_saved < second.stats.delta_size);
+
+    // The deltas themselves are deduplicated.
+    let third = archive.pack(&modified)?;
+    assert_eq!(third.stats.data_written, 0);
+    assert_eq!(third.stats.delta_saved, 0);
+
+    archive.verify(&input, &first.stream_id)?;
+    archive.verify(&modified, &second.stream_id)?;
+    archive.verify(&modified, &third.stream_id)
+}
+
+#[test]
+fn pack_shares_stream_slabs() -> Result<()> {
+    let mut td = TestDir::new()?;
+    // The full index guarantees repacks find all their data.
+    let archive = BlkArchive::new_with_index_mode(&td.mk_path("test_arch"), "disk")?;
+
+    let file_size = 64 * 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    let first = archive.pack(&input)?;
+    let stream_size = archive.stream_slabs_size()?;
+
+    // An identical stream adds no stream slabs.
+    let second = archive.pack(&input)?;
+    assert_eq!(archive.stream_slabs_size()?, stream_size);
+
+    // Changing the last few blocks only changes the last slab.
+    let mut data = std::fs::read(&input)?;
+    let len = data.len();
+    data[len - 16384..].fill(0xaa);
+    let modified = td.mk_path("modified.bin");
+    std::fs::write(&modified, &data)?;
+
+    let third = archive.pack(&modified)?;
+    let header_size = 16;
+    assert!(archive.stream_slabs_size()? - stream_size < (stream_size - header_size) / 2);
+
+    archive.verify(&input, &first.stream_id)?;
+    archive.verify(&input, &second.stream_id)?;
+    archive.verify(&modified, &third.stream_id)
+}
+
+#[test]
+fn pack_with_parent_archives() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let parent = BlkArchive::new(&td.mk_path("parent"))?;
+
+    let file_size = 16 * 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    parent.pack(&input)?;
+    let parent_size = parent.data_size()?;
+
+    // Everything is found in the parent, which is left untouched.
+    let child = BlkArchive::new_with_parent(&td.mk_path("child"), &parent)?;
+    let response = child.pack(&input)?;
+    assert_eq!(response.stats.data_written, 0);
+    assert_eq!(response.stats.parent_size, file_size);
+    assert_eq!(parent.data_size()?, parent_size);
+    child.verify(&input, &response.stream_id)?;
+
+    // The grandparent is inherited.
+    let grandchild = BlkArchive::new_with_parent(&td.mk_path("grandchild"), &child)?;
+    let response = grandchild.pack(&input)?;
+    assert_eq!(response.stats.data_written, 0);
+    grandchild.verify(&input, &response.stream_id)
+}
+
+#[test]
+fn pack_enforces_memory_budget() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+    let input = create_input_file(&mut td, 16 * 1024 * 1024, 1, Pattern::LCG)?;
+
+    // The hash cache alone defaults to 1G.
+    run_fail(archive.pack_with_budget_cmd(&input, "16", true))?;
+
+    // Without enforcement it's only a warning.
+    run_ok(archive.pack_with_budget_cmd(&input, "16", false))?;
+    Ok(())
+}
+
+#[test]
+fn index_info_tracks_entries() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let before = archive.index_info()?;
+    assert_eq!(before["filter"]["entries"], 0);
+
+    let input = create_input_file(&mut td, 16 * 1024 * 1024, 1, Pattern::LCG)?;
+    archive.pack(&input)?;
+
+    let after = archive.index_info()?;
+    let entries = after["filter"]["entries"].as_u64().unwrap();
+    let capacity = after["filter"]["capacity"].as_u64().unwrap();
+    assert!(entries > 0);
+    assert!(entries <= capacity);
+    assert_eq!(
+        after["next_resize"]["free_entries"].as_u64().unwrap(),
+        capacity - entries
+    );
+    Ok(())
+}
+
 //-----------------------------------------
@@ -0,0 +1,77 @@
+use anyhow::Result;
+use rand::Rng;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+mod common;
+
+use blk_archive::config;
+use blk_archive::stream_reader::StreamReader;
+use common::fixture::{create_archive, create_input_file};
+use common::random::Pattern;
+use common::test_dir::*;
+
+//-----------------------------------------
+
+fn check_random_reads(reader: &mut StreamReader, expected: &[u8]) -> Result<()> {
+    let size = expected.len() as u64;
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..100 {
+        let pos = rng.gen_range(0..size);
+        let len = std::cmp::min(rng.gen_range(1..256 * 1024), size - pos);
+        assert_eq!(reader.seek(SeekFrom::Start(pos))?, pos);
+
+        let mut buf = vec![0; len as usize];
+        reader.read_exact(&mut buf)?;
+        assert!(buf[..] == expected[pos as usize..(pos + len) as usize]);
+    }
+
+    // Reads stop at the end of the stream.
+    reader.seek(SeekFrom::End(-10))?;
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    assert!(buf[..] == expected[expected.len() - 10..]);
+    Ok(())
+}
+
+#[test]
+fn read_and_seek_a_packed_stream() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let file_size = 16 * 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    let stream = archive.pack(&input)?.stream_id;
+    let expected = std::fs::read(&input)?;
+
+    // The reader works relative to the root of the archive.
+    let cwd = std::env::current_dir()?;
+    std::env::set_current_dir(archive.path())?;
+    let config = config::read_config_file(".")?;
+    let filter: PathBuf = ["indexes", "seen"].iter().collect();
+    let filter_modified = std::fs::metadata(&filter)?.modified()?;
+
+    // The seek index is written along with the stream.
+    let seek_index: PathBuf = ["streams", &stream, "seek_index"].iter().collect();
+    assert!(seek_index.exists());
+    let mut reader = StreamReader::new(&stream, &config)?;
+    assert_eq!(reader.size(), file_size);
+    check_random_reads(&mut reader, &expected)?;
+    drop(reader);
+
+    // Without one it's built in memory, but readers don't write it.
+    std::fs::remove_file(&seek_index)?;
+    let mut reader = StreamReader::new(&stream, &config)?;
+    check_random_reads(&mut reader, &expected)?;
+    assert!(!seek_index.exists());
+
+    // The archive is closed when the reader is dropped, without
+    // writing anything back.
+    drop(reader);
+    assert_eq!(std::fs::metadata(&filter)?.modified()?, filter_modified);
+    std::env::set_current_dir(cwd)?;
+    Ok(())
+}
+
+//-----------------------------------------
@@ -1,4 +1,7 @@
 use anyhow::Result;
+use std::fs;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use thinp::file_utils::create_sized_file;
 
 mod common;
 
@@ -23,4 +26,139 @@ fn unpack_file() -> Result<()> {
     verify_file(&output, file_size, seed, Pattern::LCG)
 }
 
+#[test]
+fn unpack_recreates_holes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let mb = 1024 * 1024;
+    let file_size = 64 * mb;
+    let input = create_sparse_input_file(&mut td, file_size, &[(16 * mb, 8 * mb)])?;
+    let stream = archive.pack(&input)?.stream_id;
+
+    let output = td.mk_path("output.bin");
+    archive.unpack(&stream, &output, true)?;
+    assert_eq!(fs::read(&output)?, fs::read(&input)?);
+
+    // Leading and trailing holes are kept.
+    let allocated = fs::metadata(&output)?.blocks() * 512;
+    assert!(allocated < 16 * mb);
+    Ok(())
+}
+
+#[test]
+fn unpack_punches_holes() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let mb = 1024 * 1024;
+    let file_size = 64 * mb;
+    let input = create_sparse_input_file(&mut td, file_size, &[(16 * mb, 8 * mb)])?;
+
+    // Zeroes that were really written are packed as a fill.
+    let zeroes = vec![0; 8 * mb as usize];
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&input)?
+        .write_all_at(&zeroes, 32 * mb)?;
+    let stream = archive.pack(&input)?.stream_id;
+
+    let output = td.mk_path("output.bin");
+    fs::write(&output, vec![0xff; file_size as usize])?;
+    archive.unpack(&stream, &output, false)?;
+    assert_eq!(fs::read(&output)?, fs::read(&input)?);
+
+    // Only the data extent should still be allocated.
+    let allocated = fs::metadata(&output)?.blocks() * 512;
+    assert!(allocated < 16 * mb);
+    Ok(())
+}
+
+#[test]
+fn unpack_byte_range() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let file_size = 16 * 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    let stream = archive.pack(&input)?.stream_id;
+    let expected = fs::read(&input)?;
+
+    let (begin, len) = (3 * 1024 * 1024 + 12345, 5 * 1024 * 1024 + 123);
+    let output = td.mk_path("range.bin");
+    let (b, l) = (begin.to_string(), len.to_string());
+    archive.unpack_range(&stream, &output, true, &["--offset", &b, "--length", &l])?;
+    assert_eq!(fs::read(&output)?, &expected[begin..begin + len]);
+
+    // Into an existing file, up to the end of the stream.
+    let output = td.mk_path("tail.bin");
+    create_sized_file(&output, file_size - begin as u64)?;
+    archive.unpack_range(&stream, &output, false, &["--offset", &b])?;
+    assert_eq!(fs::read(&output)?, &expected[begin..]);
+    Ok(())
+}
+
+#[test]
+fn unpack_range_outside_stream() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    let file_size = 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    let stream = archive.pack(&input)?.stream_id;
+
+    let output = td.mk_path("range.bin");
+    let r = archive.unpack_range(
+        &stream,
+        &output,
+        true,
+        &["--offset", "4096", "--length", "1048576"],
+    );
+    assert!(r.is_err());
+    Ok(())
+}
+
+#[test]
+fn unpack_partition() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    // An MBR with a single 8M partition starting at 1M.  The other
+    // entries are zeroed, so they can't look like a GPT.
+    let file_size = 16 * 1024 * 1024;
+    let input = create_input_file(&mut td, file_size, 1, Pattern::LCG)?;
+    let mut disk = fs::read(&input)?;
+    disk[446..510].fill(0);
+    disk[446 + 4] = 0x83;
+    disk[446 + 8..446 + 12].copy_from_slice(&2048u32.to_le_bytes());
+    disk[446 + 12..446 + 16].copy_from_slice(&16384u32.to_le_bytes());
+    disk[510] = 0x55;
+    disk[511] = 0xaa;
+    fs::write(&input, &disk)?;
+    let stream = archive.pack(&input)?.stream_id;
+
+    let output = td.mk_path("part.bin");
+    archive.unpack_range(&stream, &output, true, &["--partition", "1"])?;
+    assert_eq!(fs::read(&output)?, &disk[1024 * 1024..9 * 1024 * 1024]);
+    Ok(())
+}
+
+#[test]
+fn pack_stdin_unpack_stdout() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let archive = create_archive(&mut td, true)?;
+
+    // Not a multiple of the chunker's read size.
+    let file_size = 20 * 1024 * 1024 + 12288;
+    let seed = 1;
+    let input = create_input_file(&mut td, file_size, seed, Pattern::LCG)?;
+    let response = archive.pack_stdin(&input)?;
+    assert_eq!(response.stats.mapped_size, file_size);
+    archive.verify(&input, &response.stream_id)?;
+
+    let output = td.mk_path("output.bin");
+    archive.unpack_stdout(&response.stream_id, &output)?;
+    verify_file(&output, file_size, seed, Pattern::LCG)
+}
+
 //-----------------------------------------